@@ -0,0 +1,123 @@
+#![no_std]
+#![no_main]
+// The entry macro turns `static mut` into `&'static mut`
+#![allow(static_mut_refs)]
+
+extern crate panic_semihosting;
+
+use core::cell::RefCell;
+use core::ops::DerefMut;
+
+use cortex_m::interrupt::Mutex;
+use cortex_m_rt::entry;
+
+use nrf51::interrupt;
+
+use ubit::buttons::{self, Buttons};
+use ubit::executor::{self, Either};
+use ubit::leds::{self, images, Display, DisplayTimer, Image};
+use ubit::package;
+use ubit::radio::{self, RadioRx};
+use ubit::time::Clock;
+use ubit::timer::Timer;
+
+static DISPLAY: Mutex<RefCell<Option<Display>>> = Mutex::new(RefCell::new(None));
+static DISPLAY_TIMER: Mutex<RefCell<Option<DisplayTimer<ubit::TIMER0>>>> =
+    Mutex::new(RefCell::new(None));
+static CLOCK: Mutex<RefCell<Option<Clock>>> = Mutex::new(RefCell::new(None));
+static RDIO: Mutex<RefCell<Option<RadioRx>>> = Mutex::new(RefCell::new(None));
+static BTN: Mutex<RefCell<Option<Buttons>>> = Mutex::new(RefCell::new(None));
+
+fn show(image: Image) {
+    cortex_m::interrupt::free(|cs| {
+        if let Some(display) = DISPLAY.borrow(cs).borrow_mut().deref_mut() {
+            display.display(image);
+        }
+    });
+}
+
+#[entry]
+fn main() -> ! {
+    static mut RADIO_QUEUE: radio::TxQueue = radio::TxQueue::new();
+
+    let ubit::Board { display, buttons, mut radio, timer0, rtc1, .. } =
+        ubit::Board::take().unwrap();
+    radio.set_group(1);
+    let (_, radio_rx) = radio.split(RADIO_QUEUE);
+
+    cortex_m::interrupt::free(move |cs| {
+        *DISPLAY.borrow(cs).borrow_mut() = Some(display);
+        *DISPLAY_TIMER.borrow(cs).borrow_mut() = Some(DisplayTimer::new(timer0));
+        *CLOCK.borrow(cs).borrow_mut() = Some(Clock::new(rtc1));
+        *RDIO.borrow(cs).borrow_mut() = Some(radio_rx);
+        *BTN.borrow(cs).borrow_mut() = Some(buttons);
+    });
+
+    if let Some(mut p) = cortex_m::Peripherals::take() {
+        p.NVIC.enable(ubit::Interrupt::TIMER0);
+        p.NVIC.enable(ubit::Interrupt::RTC1);
+        p.NVIC.enable(ubit::Interrupt::RADIO);
+        p.NVIC.enable(ubit::Interrupt::GPIOTE);
+    }
+
+    executor::block_on(async {
+        leds::scroll("Hello", 120, show).await;
+        loop {
+            match executor::select(radio::recv(), buttons::changed()).await {
+                Either::First(data) => {
+                    let p = package::Package::unpack(&data[..]);
+                    if let package::PackageData::Integer(_) = p.data {
+                        show(images::HEART);
+                    }
+                    else {
+                        show(images::GHOST);
+                    }
+                }
+                Either::Second((true, _)) => show(images::HAPPY),
+                Either::Second((_, true)) => show(images::SAD),
+                Either::Second(_) => continue,
+            }
+            Timer::after_millis(500).await;
+            show(images::CLEAR);
+        }
+    })
+}
+
+#[interrupt]
+fn TIMER0() {
+    cortex_m::interrupt::free(|cs| {
+        if let (Some(timer), Some(display)) = (
+            DISPLAY_TIMER.borrow(cs).borrow_mut().deref_mut(),
+            DISPLAY.borrow(cs).borrow_mut().deref_mut())
+        {
+            timer.interrupt(display);
+        }
+    });
+}
+
+#[interrupt]
+fn RTC1() {
+    cortex_m::interrupt::free(|cs| {
+        if let Some(clock) = CLOCK.borrow(cs).borrow_mut().deref_mut() {
+            clock.interrupt();
+        }
+    });
+}
+
+#[interrupt]
+fn RADIO() {
+    cortex_m::interrupt::free(|cs| {
+        if let Some(radio) = RDIO.borrow(cs).borrow_mut().deref_mut() {
+            radio.wake();
+        }
+    });
+}
+
+#[interrupt]
+fn GPIOTE() {
+    cortex_m::interrupt::free(|cs| {
+        if let Some(buttons) = BTN.borrow(cs).borrow_mut().deref_mut() {
+            buttons.wake();
+        }
+    });
+}
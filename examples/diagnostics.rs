@@ -0,0 +1,124 @@
+#![no_std]
+#![no_main]
+// The entry macro turns `static mut` into `&'static mut`
+#![allow(static_mut_refs)]
+
+//! Radio diagnostics
+//!
+//! Sweeps the channels at reset and shows the signal strength as a bar
+//! graph. Hold button A at reset to send link test datagrams, hold button B
+//! to receive them and show the share received. Results are written to the
+//! UART.
+
+extern crate panic_semihosting;
+
+use core::cell::RefCell;
+use core::fmt::Write;
+use core::ops::DerefMut;
+
+use cortex_m::interrupt::Mutex;
+use cortex_m_rt::entry;
+
+use nrf51::interrupt;
+
+use ubit::diagnostics::{self, LinkTestReceiver, LinkTestSender, Scan};
+use ubit::executor;
+use ubit::leds::{images, Display, DisplayTimer, Image};
+use ubit::time::Clock;
+use ubit::timer::Timer;
+
+/// Group of the link test
+const GROUP: u8 = 1;
+/// Number of sweeps over the channels
+const SWEEPS: u32 = 16;
+
+static DISPLAY: Mutex<RefCell<Option<Display>>> = Mutex::new(RefCell::new(None));
+static DISPLAY_TIMER: Mutex<RefCell<Option<DisplayTimer<ubit::TIMER0>>>> =
+    Mutex::new(RefCell::new(None));
+static CLOCK: Mutex<RefCell<Option<Clock>>> = Mutex::new(RefCell::new(None));
+
+fn show(image: Image) {
+    cortex_m::interrupt::free(|cs| {
+        if let Some(display) = DISPLAY.borrow(cs).borrow_mut().deref_mut() {
+            display.display(image);
+        }
+    });
+}
+
+#[entry]
+fn main() -> ! {
+    static mut SCAN: Scan = Scan::new();
+
+    let ubit::Board { display, buttons, mut radio, uart, timer0, rtc1, .. } =
+        ubit::Board::take().unwrap();
+    let (mut tx, _) = uart.split();
+    let (send, receive) = buttons.pressed();
+    radio.set_group(GROUP);
+
+    cortex_m::interrupt::free(move |cs| {
+        *DISPLAY.borrow(cs).borrow_mut() = Some(display);
+        *DISPLAY_TIMER.borrow(cs).borrow_mut() = Some(DisplayTimer::new(timer0));
+        *CLOCK.borrow(cs).borrow_mut() = Some(Clock::new(rtc1));
+    });
+
+    if let Some(mut p) = cortex_m::Peripherals::take() {
+        p.NVIC.enable(ubit::Interrupt::TIMER0);
+        p.NVIC.enable(ubit::Interrupt::RTC1);
+    }
+
+    executor::block_on(async {
+        if send {
+            let mut sender = LinkTestSender::new(GROUP);
+            loop {
+                sender.send(&mut radio);
+                if sender.sent().is_multiple_of(100) {
+                    write!(tx, "sent {}\r\n", sender.sent()).ok();
+                    let blink = sender.sent().is_multiple_of(200);
+                    show(if blink { images::HEART } else { images::CLEAR });
+                }
+                Timer::after_millis(10).await;
+            }
+        }
+        else if receive {
+            let mut receiver = LinkTestReceiver::new(GROUP);
+            radio.start_receive();
+            loop {
+                for _ in 0..100 {
+                    receiver.poll(&mut radio);
+                    Timer::after_millis(5).await;
+                }
+                write!(tx, "{}\r\n", receiver.report()).ok();
+                show(receiver.report().image());
+            }
+        }
+        else {
+            diagnostics::sweep(&mut radio, SCAN, SWEEPS);
+            write!(tx, "{}\r\n", SCAN).ok();
+            show(SCAN.image());
+            loop {
+                Timer::after_millis(1000).await;
+            }
+        }
+    })
+}
+
+#[interrupt]
+fn TIMER0() {
+    cortex_m::interrupt::free(|cs| {
+        if let (Some(timer), Some(display)) = (
+            DISPLAY_TIMER.borrow(cs).borrow_mut().deref_mut(),
+            DISPLAY.borrow(cs).borrow_mut().deref_mut())
+        {
+            timer.interrupt(display);
+        }
+    });
+}
+
+#[interrupt]
+fn RTC1() {
+    cortex_m::interrupt::free(|cs| {
+        if let Some(clock) = CLOCK.borrow(cs).borrow_mut().deref_mut() {
+            clock.interrupt();
+        }
+    });
+}
@@ -0,0 +1,175 @@
+#![no_std]
+#![no_main]
+// The rtfm 0.4 app macro expands to code triggering these lints
+#![allow(deprecated, static_mut_refs)]
+
+extern crate panic_semihosting;
+
+use core::fmt::Write;
+
+use rtfm::app;
+
+use ubit::buttons::Buttons;
+use ubit::hal::serial;
+use ubit::leds::images;
+use ubit::leds::{Display, DisplayTimer};
+use ubit::package;
+use ubit::radio::{self, PackageBuffer, RadioRx};
+use ubit::time::{self, Clock, Duration, TimerId, Timers};
+
+/// State changes are counted in steps of 125 ms
+const STEP: Duration = Duration::from_millis(125);
+
+pub struct ProgramState {
+    current_state: u32,
+    next_state: u32,
+    clock: Clock,
+    timers: Timers<1>,
+    timer: Option<TimerId>,
+}
+
+impl ProgramState {
+    pub fn new(rtc: ubit::RTC1) -> Self {
+        let mut state = ProgramState {
+            current_state: 0,
+            next_state: 0,
+            clock: Clock::new(rtc),
+            timers: Timers::new(),
+            timer: None,
+        };
+        state.change_state(0, Some(10));
+        state
+    }
+
+    pub fn change_state(&mut self, state: u32, delay: Option<u32>)
+    {
+        self.next_state = state;
+        let steps = u64::from(delay.unwrap_or(1));
+        let delay = Duration::from_ticks(STEP.as_ticks() * steps);
+        if let Some(id) = self.timer.take() {
+            self.timers.cancel(id);
+        }
+        self.timer = self.timers.start_once(time::now(), delay);
+        self.clock.schedule(self.timers.next_deadline());
+    }
+
+    pub fn rtc_interrupt(&mut self) -> Option<u32>
+    {
+        if !self.clock.interrupt() {
+            return None;
+        }
+        let changed = self.timers.poll_expired(time::now()).is_some();
+        if changed {
+            self.timer = None;
+        }
+        self.clock.schedule(self.timers.next_deadline());
+        if changed {
+            self.current_state = self.next_state;
+            Some(self.current_state)
+        }
+        else {
+            None
+        }
+    }
+}
+
+#[app(device = ubit)]
+const APP: () = {
+    static mut DISPLAY: Display = ();
+    static mut DISPLAY_TIMER: DisplayTimer<ubit::TIMER0> = ();
+    static mut BUTTONS: Buttons = ();
+    static mut RADIO: RadioRx = ();
+    static mut STATE: ProgramState = ();
+    static mut TX: serial::Tx<ubit::UART0> = ();
+
+    #[init]
+    fn init() -> init::LateResources {
+        static mut RADIO_BUFFERS: radio::Buffers = radio::Buffers::new();
+        static mut RADIO_QUEUE: radio::TxQueue = radio::TxQueue::new();
+
+        let board = ubit::Board::new(device, RADIO_BUFFERS);
+        let (serial_tx, _) = board.uart.split();
+
+        let mut radio = board.radio;
+        radio.set_group(1);
+        let (_, radio_rx) = radio.split(RADIO_QUEUE);
+
+        init::LateResources {
+            DISPLAY: board.display,
+            DISPLAY_TIMER: DisplayTimer::new(board.timer0),
+            BUTTONS: board.buttons,
+            RADIO: radio_rx,
+            STATE: ProgramState::new(board.rtc1),
+            TX: serial_tx,
+        }
+    }
+
+    #[interrupt(priority = 2, resources = [DISPLAY_TIMER, DISPLAY])]
+    fn TIMER0() {
+        resources.DISPLAY_TIMER.interrupt(&mut resources.DISPLAY);
+    }
+
+    #[interrupt(resources = [STATE, DISPLAY])]
+    fn RTC1() {
+        let current_state = match resources.STATE.rtc_interrupt() {
+            Some(current_state) => current_state,
+            None => return,
+        };
+        let (image, next_state, next_delay) = match current_state {
+            0 => (images::MID_DOT, 1, 1),
+            1 => (images::LITTLE_HEART, 2, 1),
+            2 => (images::HEART, 3, 1),
+            3 => (images::LITTLE_HEART, 4, 1),
+            4 => (images::MID_DOT, 5, 1),
+            5 => (images::CLEAR, 0, 1),
+            100 | 200 => (images::HAPPY, 0, 10),
+            101 | 201 => (images::SAD, 0, 10),
+            102 | 202 => (images::GHOST, 0, 10),
+            _ => (images::CLEAR, 0, 1),
+        };
+        resources.DISPLAY.lock(|display| display.display(image));
+        resources.STATE.change_state(next_state, Some(next_delay));
+    }
+
+    #[interrupt(resources = [BUTTONS, STATE])]
+    fn GPIOTE() {
+        match resources.BUTTONS.interrupt() {
+            (false, false) => (),
+            (true, false) => { resources.STATE.change_state(100, None); }
+            (false, true) => { resources.STATE.change_state(101, None); }
+            (true, true) => { resources.STATE.change_state(102, None); }
+        }
+    }
+
+    #[interrupt(resources = [RADIO], spawn = [received])]
+    fn RADIO() {
+        if let Some(package) = resources.RADIO.interrupt() {
+            let _ = spawn.received(package);
+        }
+    }
+
+    #[task(capacity = 4, resources = [STATE, TX])]
+    fn received(data: PackageBuffer) {
+        let p = package::Package::unpack(&data[..]);
+        if p.header.datagram_header.length() >= 16 {
+            match p.data {
+                package::PackageData::Integer(value)
+                | package::PackageData::IntegerValue(value) => {
+                    if (0..3).contains(&value) {
+                        resources.STATE.change_state(200 + (value as u32), None);
+                    }
+                }
+                package::PackageData::Other => {
+                    write!(resources.TX, "Other Package\n\r").unwrap();
+                }
+                package::PackageData::Unknown => {
+                    write!(resources.TX, "Unknown Package\n\r").unwrap();
+                }
+            }
+        }
+    }
+
+    extern "C" {
+        fn SWI0();
+    }
+};
@@ -1,6 +1,8 @@
 MEMORY
 {
   /* NOTE K = KiBi = 1024 bytes */
-  FLASH : ORIGIN = 0x00000000, LENGTH = 256K
+  FLASH : ORIGIN = 0x00000000, LENGTH = 252K
+  /* Key value store, see STORAGE_START and STORAGE_PAGES in src/storage.rs */
+  STORAGE : ORIGIN = 0x0003F000, LENGTH = 4K
   RAM : ORIGIN = 0x20000000, LENGTH = 16K
 }
@@ -0,0 +1,534 @@
+//! On-air format of packages
+//!
+//! The radio adds the preamble, the address, the CRC and the whitening to
+//! the frames in `radio::Buffers`. This does the same in software, from a
+//! `radio::RadioConfig`, so that changes to the configuration can be
+//! checked on the host and packages can be decoded from bits captured with
+//! a software defined radio.
+//!
+//! ```notrust
+//! let mut deframer: Deframer = Deframer::new(RadioConfig::makecode());
+//! for bit in bits {
+//!     if let Some((group, package)) = deframer.push(bit) {
+//!         ...
+//!     }
+//! }
+//! ```
+//!
+//! ## Reference
+//!
+//! * nRF51 Series Reference Manual 3.0, chapter 16.1
+
+use crate::ble;
+use crate::radio::{RadioConfig, MAX_FRAME_SIZE, MAX_PACKAGE_SIZE};
+
+/// Size of the longest address, a 4 byte base address and the prefix
+pub const ADDRESS_SIZE: usize = 5;
+/// Largest packet on air in bytes, the preamble, the address, the fields,
+/// the largest payload and a 3 byte CRC
+pub const ON_AIR_SIZE: usize = 1 + ADDRESS_SIZE + MAX_FRAME_SIZE + 3;
+
+/// The address of `config`, in the order it is sent, the base address
+/// followed by the prefix, and its length
+pub fn address(config: &RadioConfig) -> ([u8; ADDRESS_SIZE], usize) {
+    let base = usize::from(config.base_length);
+    let mut address = [0u8; ADDRESS_SIZE];
+    address[..base].copy_from_slice(&config.base_address.to_le_bytes()[4 - base..]);
+    address[base] = config.prefix;
+    (address, base + 1)
+}
+
+/// The preamble, it alternates and goes on into the first address bit
+pub fn preamble(config: &RadioConfig) -> u8 {
+    if address(config).0[0] & 1 == 0 { 0xaa } else { 0x55 }
+}
+
+fn bit(buffer: &[u8], index: usize) -> bool {
+    buffer[index / 8] & (1 << (index % 8)) != 0
+}
+
+/// Read `count` bits at bit `index`, least significant bit first
+fn read_bits(buffer: &[u8], index: usize, count: usize) -> u32 {
+    (0..count).fold(0, |value, offset| value | u32::from(bit(buffer, index + offset)) << offset)
+}
+
+/// Write the `count` lowest bits of `value` at bit `index`, least
+/// significant bit first
+fn write_bits(buffer: &mut [u8], index: usize, value: u32, count: usize) {
+    for offset in 0..count {
+        let mask = 1 << ((index + offset) % 8);
+        if (value >> offset) & 1 != 0 {
+            buffer[(index + offset) / 8] |= mask;
+        }
+        else {
+            buffer[(index + offset) / 8] &= !mask;
+        }
+    }
+}
+
+/// Number of bits of the S0, length and S1 fields on air
+fn header_bits(config: &RadioConfig) -> usize {
+    8 * usize::from(config.s0) + usize::from(config.length_bits) + usize::from(config.s1_bits)
+}
+
+fn crc_bits(config: &RadioConfig) -> usize {
+    8 * config.crc.bytes() as usize
+}
+
+/// CRC of the bits `start` to `end` of `buffer`, with the CRC of `config`
+///
+/// The bits are taken in the order they are sent, the least significant
+/// bit of each byte first. The CRC itself is sent most significant bit
+/// first.
+pub fn crc(config: &RadioConfig, buffer: &[u8], start: usize, end: usize) -> u32 {
+    let width = crc_bits(config);
+    if width == 0 {
+        return 0;
+    }
+    let mask = (1u32 << width) - 1;
+    let mut crc = config.crc_preset & mask;
+    for index in start..end {
+        let feedback = (crc >> (width - 1)) & 1 != 0;
+        crc = (crc << 1) & mask;
+        if feedback != bit(buffer, index) {
+            crc ^= config.crc_polynomial & mask;
+        }
+    }
+    crc
+}
+
+/// # On-air packet
+///
+/// ```notrust
+/// | 1 byte   | 2 ... 4 bytes | 1 byte | 0 or 8 bits | 1 ... 8 bits | 0 ... 8 bits |
+/// ---------------------------------------------------------------------------------
+/// | preamble | base address  | prefix | S0          | length       | S1           |
+///
+/// | 0 ... max length bytes | 0 ... 3 bytes
+/// ----------------------------------------
+/// | payload                | CRC
+/// ```
+/// All fields are sent least significant bit first, the CRC most
+/// significant bit first. The CRC covers the address, unless
+/// `RadioConfig::crc_skip_address`, the fields and the payload. The fields,
+/// the payload and the CRC are whitened, see `ble::whiten`, when whitening
+/// is on. For MakeCode the fields are the length byte and the prefix is the
+/// group.
+///
+/// Writes the packet of `frame`, the fields as in `radio::Buffers` followed
+/// by the payload, to `buffer` and returns its length in bytes. Returns 0 if
+/// the payload is longer than `config` allows or the packet does not fit
+/// `buffer`.
+pub fn encode(config: &RadioConfig, frame: &[u8], buffer: &mut [u8]) -> usize {
+    let header = config.header_size();
+    let length = match frame.get(usize::from(config.s0)) {
+        Some(length) => usize::from(*length),
+        None => return 0,
+    };
+    if length > usize::from(config.max_length)
+        || length >= 1 << config.length_bits
+        || header + length > frame.len()
+    {
+        return 0;
+    }
+    let (address, address_size) = address(config);
+    let start = 1 + address_size;
+    let bits = 8 * start + header_bits(config) + 8 * length + crc_bits(config);
+    let size = bits.div_ceil(8);
+    if size > buffer.len() {
+        return 0;
+    }
+    buffer[..size].iter_mut().for_each(|byte| *byte = 0);
+    buffer[0] = preamble(config);
+    buffer[1..start].copy_from_slice(&address[..address_size]);
+    let mut index = 8 * start;
+    if config.s0 {
+        write_bits(buffer, index, u32::from(frame[0]), 8);
+        index += 8;
+    }
+    write_bits(buffer, index, length as u32, usize::from(config.length_bits));
+    index += usize::from(config.length_bits);
+    write_bits(buffer, index, u32::from(frame[header - 1]), usize::from(config.s1_bits));
+    index += usize::from(config.s1_bits);
+    for byte in &frame[header..header + length] {
+        write_bits(buffer, index, u32::from(*byte), 8);
+        index += 8;
+    }
+    let crc_start = if config.crc_skip_address { 8 * start } else { 8 };
+    let crc = crc(config, buffer, crc_start, index);
+    let width = crc_bits(config);
+    for offset in 0..width {
+        write_bits(buffer, index + offset, (crc >> (width - 1 - offset)) & 1, 1);
+    }
+    if let Some(whitening) = config.whitening {
+        ble::whiten(whitening, &mut buffer[start..size]);
+        // Bits past the end of the packet are not sent
+        if !bits.is_multiple_of(8) {
+            buffer[size - 1] &= (1 << (bits % 8)) - 1;
+        }
+    }
+    size
+}
+
+/// Decode a packet starting with the preamble in `buffer`, see `encode`
+///
+/// Writes the fields and the payload to `frame` and returns the prefix and
+/// the frame length. Returns `None` if the base address is not the one of
+/// `config`, the packet is cut short, the payload is too long for `config`
+/// or `frame` or the CRC does not match.
+pub fn decode(config: &RadioConfig, buffer: &[u8], frame: &mut [u8]) -> Option<(u8, usize)> {
+    let (address, address_size) = address(config);
+    let start = 1 + address_size;
+    if buffer.len() < start || buffer[1..address_size] != address[..address_size - 1] {
+        return None;
+    }
+    let prefix = buffer[address_size];
+    let size = buffer.len().min(ON_AIR_SIZE);
+    let mut packet = [0u8; ON_AIR_SIZE];
+    packet[..size].copy_from_slice(&buffer[..size]);
+    if let Some(whitening) = config.whitening {
+        ble::whiten(whitening, &mut packet[start..size]);
+    }
+    let header = config.header_size();
+    let mut index = 8 * start;
+    if 8 * size < index + header_bits(config) || frame.len() < header {
+        return None;
+    }
+    if config.s0 {
+        frame[0] = read_bits(&packet, index, 8) as u8;
+        index += 8;
+    }
+    let length = read_bits(&packet, index, usize::from(config.length_bits)) as usize;
+    index += usize::from(config.length_bits);
+    frame[usize::from(config.s0)] = length as u8;
+    if config.s1_bits > 0 {
+        frame[header - 1] = read_bits(&packet, index, usize::from(config.s1_bits)) as u8;
+        index += usize::from(config.s1_bits);
+    }
+    let width = crc_bits(config);
+    if length > usize::from(config.max_length)
+        || header + length > frame.len()
+        || 8 * size < index + 8 * length + width
+    {
+        return None;
+    }
+    for byte in frame[header..header + length].iter_mut() {
+        *byte = read_bits(&packet, index, 8) as u8;
+        index += 8;
+    }
+    let crc_start = if config.crc_skip_address { 8 * start } else { 8 };
+    let expected = crc(config, &packet, crc_start, index);
+    let received = (0..width)
+        .fold(0, |crc, offset| crc << 1 | u32::from(bit(&packet, index + offset)));
+    if received != expected {
+        return None;
+    }
+    Some((prefix, header + length))
+}
+
+/// # Deframer
+///
+/// Finds packets of a `RadioConfig` in a stream of bits, in the order they
+/// were received, by looking for the preamble and the base address, and
+/// decodes them into frames of `N` bytes, as the radio would with
+/// `radio::Buffers<N>`.
+pub struct Deframer<const N: usize = MAX_PACKAGE_SIZE> {
+    config: RadioConfig,
+    /// The latest bits, the newest in the most significant bit
+    window: u64,
+    /// Bits collected since the preamble, `None` while searching
+    collected: Option<usize>,
+    buffer: [u8; ON_AIR_SIZE],
+    /// Number of packets found with a CRC error or a bad length
+    errors: u32,
+}
+
+impl<const N: usize> Deframer<N> {
+    /// Find the packets of `config`, of any prefix
+    pub const fn new(config: RadioConfig) -> Self {
+        Deframer { config, window: 0, collected: None, buffer: [0u8; ON_AIR_SIZE], errors: 0 }
+    }
+
+    /// The packet format
+    pub fn config(&self) -> &RadioConfig {
+        &self.config
+    }
+
+    /// Number of packets found with a CRC error or a bad length
+    pub fn errors(&self) -> u32 {
+        self.errors
+    }
+
+    /// Add the next bit, returns the prefix and the frame when a packet has
+    /// been received
+    pub fn push(&mut self, bit: bool) -> Option<(u8, [u8; N])> {
+        let config = self.config;
+        let (address, address_size) = address(&config);
+        // Preamble and base address, the first bit sent lowest
+        let sync_bits = 8 * address_size;
+        let sync = address[..address_size - 1].iter().enumerate()
+            .fold(u64::from(preamble(&config)), |sync, (index, byte)| {
+                sync | u64::from(*byte) << (8 * (index + 1))
+            });
+        self.window = (self.window >> 1) | u64::from(bit) << (sync_bits - 1);
+        let index = match self.collected {
+            Some(index) => index,
+            None => {
+                if self.window != sync {
+                    return None;
+                }
+                self.buffer[..address_size].copy_from_slice(&sync.to_le_bytes()[..address_size]);
+                self.collected = Some(sync_bits);
+                return None;
+            }
+        };
+        write_bits(&mut self.buffer, index, u32::from(bit), 1);
+        let index = index + 1;
+        self.collected = Some(index);
+        // Wait for the fields, then for the payload and the CRC
+        let start = 1 + address_size;
+        let fields = header_bits(&config);
+        if index < 8 * start + fields {
+            return None;
+        }
+        let mut header = [0u8; 3];
+        let header_size = fields.div_ceil(8);
+        header[..header_size].copy_from_slice(&self.buffer[start..start + header_size]);
+        if let Some(whitening) = config.whitening {
+            ble::whiten(whitening, &mut header[..header_size]);
+        }
+        let length = read_bits(&header, 8 * usize::from(config.s0),
+            usize::from(config.length_bits)) as usize;
+        if length > usize::from(config.max_length) || config.header_size() + length > N {
+            self.errors = self.errors.wrapping_add(1);
+            self.collected = None;
+            return None;
+        }
+        if index < 8 * start + fields + 8 * length + crc_bits(&config) {
+            return None;
+        }
+        self.collected = None;
+        let mut frame = [0u8; N];
+        match decode(&config, &self.buffer[..index.div_ceil(8)], &mut frame) {
+            Some((prefix, _)) => Some((prefix, frame)),
+            None => {
+                self.errors = self.errors.wrapping_add(1);
+                None
+            }
+        }
+    }
+}
+
+impl<const N: usize> Default for Deframer<N> {
+    /// A deframer of MakeCode packages
+    fn default() -> Self {
+        Self::new(RadioConfig::makecode())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::package::PackageRef;
+    use crate::radio::{ConfigError, CrcLength};
+
+    /// A MakeCode integer value package in group 0, from an independent
+    /// encoder of the nRF51 packet format, not a capture
+    const MAKECODE_FRAME: [u8; 17] = [16, 1, 0, 1, 0, 0xe8, 0x03, 0, 0, 0x78, 0x56, 0x34, 0x12,
+        42, 0, 0, 0];
+    const MAKECODE_PACKET: [u8; 25] = [0xaa, 0x74, 0x69, 0x62, 0x75, 0x00, 0x88, 0x09, 0x24,
+        0xca, 0x3b, 0x14, 0x72, 0xa3, 0xf4, 0x2d, 0x3e, 0xfb, 0xbb, 0x33, 0x6c, 0x5d, 0x4c,
+        0x72, 0x8c];
+
+    /// A 3 byte base address, a 6-bit length, a 3-bit S1 field, an 8-bit
+    /// CRC and no whitening, the packet ends in the middle of a byte
+    fn odd_config() -> RadioConfig {
+        RadioConfig::makecode()
+            .base_address(0xe7e7_e7e7, 3)
+            .prefix(0x42)
+            .length_bits(6)
+            .s1_bits(3)
+            .max_length(16)
+            .crc(CrcLength::One, 0x07, 0xff)
+            .whitening(None)
+    }
+    const ODD_FRAME: [u8; 5] = [3, 0b101, 0xde, 0xad, 0xbe];
+    const ODD_PACKET: [u8; 11] = [0x55, 0xe7, 0xe7, 0xe7, 0x42, 0x43, 0xbd, 0x5b, 0x7d, 0xab,
+        0x01];
+
+    fn bits(packet: &[u8]) -> impl Iterator<Item = bool> + '_ {
+        packet.iter().flat_map(|byte| (0..8).map(move |bit| byte & (1 << bit) != 0))
+    }
+
+    #[test]
+    fn encodes_makecode_packet() {
+        let config = RadioConfig::makecode().prefix(0);
+        let mut buffer = [0u8; ON_AIR_SIZE];
+        let size = encode(&config, &MAKECODE_FRAME, &mut buffer);
+        assert_eq!(&buffer[..size], &MAKECODE_PACKET);
+    }
+
+    #[test]
+    fn decodes_makecode_packet() {
+        let mut frame = [0u8; MAX_PACKAGE_SIZE];
+        let decoded = decode(&RadioConfig::makecode(), &MAKECODE_PACKET, &mut frame);
+        assert_eq!(decoded, Some((0, MAKECODE_FRAME.len())));
+        assert_eq!(&frame[..MAKECODE_FRAME.len()], &MAKECODE_FRAME);
+    }
+
+    #[test]
+    fn encodes_and_decodes_fields_of_any_size() {
+        let config = odd_config();
+        assert_eq!(config.validate(MAX_PACKAGE_SIZE), Ok(()));
+        let mut buffer = [0u8; ON_AIR_SIZE];
+        let size = encode(&config, &ODD_FRAME, &mut buffer);
+        assert_eq!(&buffer[..size], &ODD_PACKET);
+        let mut frame = [0u8; 8];
+        assert_eq!(decode(&config, &ODD_PACKET, &mut frame), Some((0x42, ODD_FRAME.len())));
+        assert_eq!(&frame[..ODD_FRAME.len()], &ODD_FRAME);
+    }
+
+    #[test]
+    fn round_trips_every_length() {
+        let configs = [
+            RadioConfig::makecode(),
+            RadioConfig::makecode().s0(true).crc_skip_address(true),
+            RadioConfig::makecode().crc(CrcLength::Three, 0x0000_065b, 0x0055_5555).whitening(None),
+            odd_config(),
+        ];
+        for config in configs {
+            let header = config.header_size();
+            for length in 0..=usize::from(config.max_length) {
+                let mut frame = [0u8; MAX_FRAME_SIZE];
+                frame[..header].fill(0x05);
+                frame[usize::from(config.s0)] = length as u8;
+                for (index, byte) in frame[header..header + length].iter_mut().enumerate() {
+                    *byte = (index * 37 + length) as u8;
+                }
+                let mut buffer = [0u8; ON_AIR_SIZE];
+                let size = encode(&config, &frame[..header + length], &mut buffer);
+                assert_ne!(size, 0);
+                let mut decoded = [0u8; MAX_FRAME_SIZE];
+                assert_eq!(decode(&config, &buffer[..size], &mut decoded),
+                    Some((config.prefix, header + length)));
+                assert_eq!(decoded[..header + length], frame[..header + length]);
+            }
+        }
+    }
+
+    #[test]
+    fn rejects_damaged_packets() {
+        let config = RadioConfig::makecode();
+        let mut frame = [0u8; MAX_PACKAGE_SIZE];
+        for bit in 8..8 * MAKECODE_PACKET.len() {
+            let mut packet = MAKECODE_PACKET;
+            packet[bit / 8] ^= 1 << (bit % 8);
+            let decoded = decode(&config, &packet, &mut frame);
+            // A flipped prefix bit is another group, the CRC catches it
+            assert_eq!(decoded, None, "bit {}", bit);
+        }
+        assert_eq!(decode(&config, &MAKECODE_PACKET[..24], &mut frame), None);
+    }
+
+    #[test]
+    fn configuration_changes_are_checked() {
+        let mut frame = [0u8; MAX_PACKAGE_SIZE];
+        let changes = [
+            RadioConfig::makecode().whitening(Some(0x19)),
+            RadioConfig::makecode().whitening(None),
+            RadioConfig::makecode().crc(CrcLength::Two, 0x8005, 0xffff),
+            RadioConfig::makecode().crc(CrcLength::Two, 0x1021, 0),
+            RadioConfig::makecode().crc_skip_address(true),
+            RadioConfig::makecode().base_address(0x7562_6975, 4),
+            RadioConfig::makecode().max_length(15),
+        ];
+        for config in changes {
+            assert_eq!(decode(&config, &MAKECODE_PACKET, &mut frame), None, "{:?}", config);
+        }
+        // The prefix is not checked, any group is received
+        assert!(decode(&RadioConfig::makecode().prefix(7), &MAKECODE_PACKET, &mut frame)
+            .is_some());
+    }
+
+    #[test]
+    fn encode_enforces_max_length() {
+        let config = RadioConfig::makecode().max_length(15);
+        let mut buffer = [0u8; ON_AIR_SIZE];
+        assert_eq!(encode(&config, &MAKECODE_FRAME, &mut buffer), 0);
+        assert_eq!(encode(&RadioConfig::makecode(), &MAKECODE_FRAME[..10], &mut buffer), 0);
+        assert_eq!(encode(&RadioConfig::makecode(), &MAKECODE_FRAME, &mut buffer[..24]), 0);
+    }
+
+    #[test]
+    fn deframer_finds_packets_in_noise() {
+        let mut deframer: Deframer = Deframer::new(RadioConfig::makecode());
+        let noise = [0x3c, 0xa5, 0x55, 0xaa, 0x0f];
+        let stream = noise.iter().chain(&MAKECODE_PACKET).chain(&MAKECODE_PACKET).chain(&noise);
+        let mut found = Vec::new();
+        for bit in stream.flat_map(|byte| bits(core::slice::from_ref(byte))) {
+            if let Some((group, package)) = deframer.push(bit) {
+                assert_eq!(&package[..MAKECODE_FRAME.len()], &MAKECODE_FRAME);
+                found.push(group);
+            }
+        }
+        assert_eq!(found, [0, 0]);
+        assert_eq!(deframer.errors(), 0);
+    }
+
+    #[test]
+    fn deframer_receives_largest_makecode_frame() {
+        // A MakeCode string package with the longest string, 19 bytes, the
+        // length byte is 32
+        let mut frame = [0u8; MAX_PACKAGE_SIZE];
+        frame[..14].copy_from_slice(&[32, 1, 3, 1, 2, 0, 0, 0, 0, 0x78, 0x56, 0x34, 0x12, 19]);
+        frame[14..].copy_from_slice(b"nineteen characters");
+        let config = RadioConfig::makecode().prefix(3);
+        assert_eq!(config.max_length, 32);
+        assert_eq!(config.validate(MAX_PACKAGE_SIZE), Ok(()));
+        assert_eq!(config.validate(MAX_PACKAGE_SIZE - 1), Err(ConfigError::MaxLength));
+        let mut packet = [0u8; ON_AIR_SIZE];
+        let size = encode(&config, &frame, &mut packet);
+        assert_eq!(size, 1 + ADDRESS_SIZE + MAX_PACKAGE_SIZE + 2);
+
+        let mut deframer: Deframer = Deframer::default();
+        let found: Vec<_> = bits(&packet[..size]).filter_map(|bit| deframer.push(bit)).collect();
+        assert_eq!(found, [(3, frame)]);
+        let package = PackageRef::new(&found[0].1).unwrap();
+        assert_eq!(package.as_str(), Some("nineteen characters"));
+
+        // A byte short
+        let mut deframer: Deframer<{ MAX_PACKAGE_SIZE - 1 }> = Deframer::default();
+        assert_eq!(bits(&packet[..size]).filter_map(|bit| deframer.push(bit)).count(), 0);
+        assert_eq!(deframer.errors(), 1);
+    }
+
+    #[test]
+    fn deframer_follows_config() {
+        let mut deframer: Deframer<8> = Deframer::new(odd_config());
+        let packets = bits(&ODD_PACKET).filter_map(|bit| deframer.push(bit)).count();
+        assert_eq!(packets, 1);
+
+        // The MakeCode packet does not fit 8 byte frames
+        let mut deframer: Deframer<8> = Deframer::new(RadioConfig::makecode());
+        assert_eq!(bits(&MAKECODE_PACKET).filter_map(|bit| deframer.push(bit)).count(), 0);
+        assert_eq!(deframer.errors(), 1);
+
+        // Nor a largest payload of 15 bytes
+        let mut deframer: Deframer = Deframer::new(RadioConfig::makecode().max_length(15));
+        assert_eq!(bits(&MAKECODE_PACKET).filter_map(|bit| deframer.push(bit)).count(), 0);
+        assert_eq!(deframer.errors(), 1);
+    }
+
+    #[test]
+    fn deframer_counts_crc_errors() {
+        let mut deframer: Deframer = Deframer::default();
+        let mut packet = MAKECODE_PACKET;
+        packet[20] ^= 0x10;
+        let stream = packet.iter().chain(&MAKECODE_PACKET);
+        let found = stream.flat_map(|byte| bits(core::slice::from_ref(byte)))
+            .filter_map(|bit| deframer.push(bit))
+            .count();
+        assert_eq!(found, 1);
+        assert_eq!(deframer.errors(), 1);
+    }
+}
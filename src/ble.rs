@@ -0,0 +1,805 @@
+//! Bluetooth low energy advertising
+//!
+//! Non-connectable advertisements sent with the raw radio, without a
+//! SoftDevice, so that phones can discover the board. The advertising data
+//! can be an iBeacon, an Eddystone-URL or Eddystone-UID frame or custom
+//! manufacturer data.
+//!
+//! Advertisements of other devices can be received as well, see
+//! `Radio::scan`, and are decoded into an `AdvReport`.
+//!
+//! The packet format, CRC and whitening are done by the radio,
+//! `encode` does the same in software so that packets can be checked on the
+//! host.
+//!
+//! ```notrust
+//! let data = AdvData::eddystone_url("https://microbit.org", -20).unwrap();
+//! let pdu = AdvPdu::non_connectable(ble::static_address(&board.ficr), &data);
+//! let mut advertiser = Advertiser::<1>::new(ble::DEFAULT_INTERVAL, serial_number);
+//! advertiser.add(pdu);
+//! loop {
+//!     if let Some(pdu) = advertiser.next(time::now()) {
+//!         radio.advertise(pdu.as_bytes());
+//!     }
+//!     ...
+//! }
+//! ```
+//!
+//! ```notrust
+//! let mut scanner = Scanner::new(ble::DEFAULT_SCAN_WINDOW);
+//! loop {
+//!     if let Some(report) = radio.scan(&mut scanner) {
+//!         if let Some(name) = report.name() { ... }
+//!     }
+//! }
+//! ```
+//!
+//! ## Reference
+//!
+//! * Bluetooth Core Specification 4.2, Vol 6, Part B
+//! * <https://github.com/google/eddystone/blob/master/protocol-specification.md>
+
+use nrf51::FICR;
+
+use crate::random::Random;
+use crate::time::{Duration, Instant};
+
+/// Access address of advertising packets
+pub const ACCESS_ADDRESS: u32 = 0x8e89_bed6;
+/// CRC preset of advertising packets
+pub const CRC_INIT: u32 = 0x55_5555;
+/// CRC polynomial, x^24 + x^10 + x^9 + x^6 + x^4 + x^3 + x + 1
+pub const CRC_POLY: u32 = 0x00_065b;
+/// Advertising channels, the channel index and the frequency above 2400 MHz
+pub const ADVERTISING_CHANNELS: [(u8, u8); 3] = [(37, 2), (38, 26), (39, 80)];
+/// Largest advertising data
+pub const MAX_ADV_DATA: usize = 31;
+/// Largest PDU, the header, the advertiser address and the data
+pub const MAX_PDU_SIZE: usize = 2 + 6 + MAX_ADV_DATA;
+/// Largest packet encoded by `encode`, the access address, the PDU and the
+/// CRC
+pub const MAX_PACKET_SIZE: usize = 4 + MAX_PDU_SIZE + 3;
+/// Time between advertising events
+pub const DEFAULT_INTERVAL: Duration = Duration::from_millis(100);
+/// Longest random delay added to the advertising interval
+pub const MAX_ADV_DELAY: Duration = Duration::from_millis(10);
+/// Time spent on each advertising channel when scanning
+pub const DEFAULT_SCAN_WINDOW: Duration = Duration::from_millis(100);
+
+/// AD type of the flags
+pub const AD_FLAGS: u8 = 0x01;
+/// AD type of an incomplete list of 16-bit service UUIDs
+pub const AD_INCOMPLETE_SERVICE_UUIDS_16: u8 = 0x02;
+/// AD type of the complete list of 16-bit service UUIDs
+pub const AD_SERVICE_UUIDS_16: u8 = 0x03;
+/// AD type of an incomplete list of 128-bit service UUIDs
+pub const AD_INCOMPLETE_SERVICE_UUIDS_128: u8 = 0x06;
+/// AD type of the complete list of 128-bit service UUIDs
+pub const AD_SERVICE_UUIDS_128: u8 = 0x07;
+/// AD type of the shortened local name
+pub const AD_SHORTENED_NAME: u8 = 0x08;
+/// AD type of the complete local name
+pub const AD_COMPLETE_NAME: u8 = 0x09;
+/// AD type of the transmit power level
+pub const AD_TX_POWER: u8 = 0x0a;
+/// AD type of 16-bit UUID service data
+pub const AD_SERVICE_DATA_16: u8 = 0x16;
+/// AD type of manufacturer specific data
+pub const AD_MANUFACTURER: u8 = 0xff;
+
+/// Flags of a non-connectable advertiser, LE general discoverable and BR/EDR
+/// not supported
+const FLAGS: u8 = 0x06;
+/// Company identifier of Apple, used by iBeacon
+const APPLE: u16 = 0x004c;
+/// 16-bit UUID of the Eddystone service
+const EDDYSTONE: u16 = 0xfeaa;
+
+/// Device address, least significant byte first as on air
+pub type Address = [u8; 6];
+
+/// The random static address of the device, from FICR
+pub fn static_address(ficr: &FICR) -> Address {
+    let low = ficr.deviceaddr[0].read().bits();
+    let high = ficr.deviceaddr[1].read().bits();
+    let mut address = [0u8; 6];
+    address[..4].copy_from_slice(&low.to_le_bytes());
+    address[4..].copy_from_slice(&high.to_le_bytes()[..2]);
+    // The two most significant bits of a random static address are set
+    address[5] |= 0xc0;
+    address
+}
+
+/// # Advertising data
+///
+/// A sequence of AD structures.
+///
+/// ```notrust
+/// | 0      | 1       | 2 ...
+/// ---------------------------
+/// | length | AD type | data
+/// ```
+/// The length counts the AD type and the data.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct AdvData {
+    buffer: [u8; MAX_ADV_DATA],
+    length: usize,
+}
+
+impl AdvData {
+    /// Advertising data with the flags of a non-connectable advertiser
+    pub fn new() -> Self {
+        let mut data = AdvData { buffer: [0u8; MAX_ADV_DATA], length: 0 };
+        data.push(AD_FLAGS, &[FLAGS]);
+        data
+    }
+
+    /// Advertising data without any AD structure
+    pub const fn empty() -> Self {
+        AdvData { buffer: [0u8; MAX_ADV_DATA], length: 0 }
+    }
+
+    /// Add an AD structure, returns `false` if it does not fit
+    pub fn push(&mut self, ad_type: u8, data: &[u8]) -> bool {
+        let end = self.length + 2 + data.len();
+        if end > MAX_ADV_DATA {
+            return false;
+        }
+        self.buffer[self.length] = (data.len() + 1) as u8;
+        self.buffer[self.length + 1] = ad_type;
+        self.buffer[self.length + 2..end].copy_from_slice(data);
+        self.length = end;
+        true
+    }
+
+    /// Advertising data received from another device, returns `None` if
+    /// it is too large
+    pub fn from_bytes(data: &[u8]) -> Option<Self> {
+        if data.len() > MAX_ADV_DATA {
+            return None;
+        }
+        let mut buffer = [0u8; MAX_ADV_DATA];
+        buffer[..data.len()].copy_from_slice(data);
+        Some(AdvData { buffer, length: data.len() })
+    }
+
+    /// The advertising data
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.buffer[..self.length]
+    }
+
+    /// The AD structures
+    pub fn structures(&self) -> AdStructures<'_> {
+        AdStructures { data: self.as_bytes() }
+    }
+
+    /// An iBeacon with `uuid`, `major` and `minor`, `power` is the signal
+    /// strength at 1 m in dBm
+    pub fn ibeacon(uuid: &[u8; 16], major: u16, minor: u16, power: i8) -> Self {
+        let mut beacon = [0u8; 25];
+        beacon[0..2].copy_from_slice(&APPLE.to_le_bytes());
+        // iBeacon type and length
+        beacon[2] = 0x02;
+        beacon[3] = 0x15;
+        beacon[4..20].copy_from_slice(uuid);
+        beacon[20..22].copy_from_slice(&major.to_be_bytes());
+        beacon[22..24].copy_from_slice(&minor.to_be_bytes());
+        beacon[24] = power as u8;
+        let mut data = AdvData::new();
+        data.push(AD_MANUFACTURER, &beacon);
+        data
+    }
+
+    /// Custom manufacturer data, returns `None` if the data is too large
+    pub fn manufacturer(company: u16, payload: &[u8]) -> Option<Self> {
+        let mut manufacturer = [0u8; MAX_ADV_DATA];
+        let length = 2 + payload.len();
+        if length > manufacturer.len() {
+            return None;
+        }
+        manufacturer[..2].copy_from_slice(&company.to_le_bytes());
+        manufacturer[2..length].copy_from_slice(payload);
+        let mut data = AdvData::new();
+        if data.push(AD_MANUFACTURER, &manufacturer[..length]) { Some(data) } else { None }
+    }
+
+    /// An Eddystone frame, the service UUID and the service data
+    fn eddystone(frame: &[u8]) -> Option<Self> {
+        let mut service = [0u8; MAX_ADV_DATA];
+        let length = 2 + frame.len();
+        if length > service.len() {
+            return None;
+        }
+        service[..2].copy_from_slice(&EDDYSTONE.to_le_bytes());
+        service[2..length].copy_from_slice(frame);
+        let mut data = AdvData::new();
+        data.push(AD_SERVICE_UUIDS_16, &EDDYSTONE.to_le_bytes());
+        if data.push(AD_SERVICE_DATA_16, &service[..length]) { Some(data) } else { None }
+    }
+
+    /// An Eddystone-URL frame, `power` is the signal strength at 0 m in dBm
+    ///
+    /// Returns `None` if the URL does not start with http:// or https:// or
+    /// is too long when encoded.
+    pub fn eddystone_url(url: &str, power: i8) -> Option<Self> {
+        let mut frame = [0u8; 2 + 1 + 17];
+        frame[0] = 0x10;
+        frame[1] = power as u8;
+        let length = encode_url(url, &mut frame[2..])?;
+        AdvData::eddystone(&frame[..2 + length])
+    }
+
+    /// An Eddystone-UID frame, `power` is the signal strength at 0 m in dBm
+    pub fn eddystone_uid(namespace: &[u8; 10], instance: &[u8; 6], power: i8) -> Self {
+        let mut frame = [0u8; 20];
+        frame[0] = 0x00;
+        frame[1] = power as u8;
+        frame[2..12].copy_from_slice(namespace);
+        frame[12..18].copy_from_slice(instance);
+        // The last two bytes are reserved
+        AdvData::eddystone(&frame).unwrap()
+    }
+}
+
+impl Default for AdvData {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+const URL_SCHEMES: [&str; 4] = ["http://www.", "https://www.", "http://", "https://"];
+const URL_EXPANSIONS: [&str; 14] = [
+    ".com/", ".org/", ".edu/", ".net/", ".info/", ".biz/", ".gov/",
+    ".com", ".org", ".edu", ".net", ".info", ".biz", ".gov",
+];
+
+/// Encode `url` as in Eddystone-URL, returns the encoded length
+fn encode_url(url: &str, buffer: &mut [u8]) -> Option<usize> {
+    let (scheme, prefix) = URL_SCHEMES.iter().enumerate()
+        .find(|(_, prefix)| url.starts_with(*prefix))?;
+    let mut rest = &url[prefix.len()..];
+    let mut length = 0;
+    *buffer.get_mut(length)? = scheme as u8;
+    length += 1;
+    while !rest.is_empty() {
+        let expansion = URL_EXPANSIONS.iter().enumerate()
+            .find(|(_, expansion)| rest.starts_with(*expansion));
+        let byte = match expansion {
+            Some((code, expansion)) => {
+                rest = &rest[expansion.len()..];
+                code as u8
+            }
+            None => {
+                let byte = rest.as_bytes()[0];
+                if !(0x21..0x7f).contains(&byte) {
+                    return None;
+                }
+                rest = &rest[1..];
+                byte
+            }
+        };
+        *buffer.get_mut(length)? = byte;
+        length += 1;
+    }
+    Some(length)
+}
+
+/// # Advertising PDU
+///
+/// ```notrust
+/// | 0              | 1      | 2 ... 7            | 8 ...
+/// --------------------------------------------------------
+/// | type and flags | length | advertiser address | data
+/// ```
+/// The type is ADV_NONCONN_IND, 2, and the TxAdd flag, bit 6, is set for a
+/// random address. The length counts the address and the data.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct AdvPdu {
+    buffer: [u8; MAX_PDU_SIZE],
+    length: usize,
+}
+
+impl AdvPdu {
+    /// A non-connectable undirected advertisement from the random
+    /// `address`
+    pub fn non_connectable(address: Address, data: &AdvData) -> Self {
+        let mut buffer = [0u8; MAX_PDU_SIZE];
+        let data = data.as_bytes();
+        buffer[0] = 0x02 | 0x40;
+        buffer[1] = (6 + data.len()) as u8;
+        buffer[2..8].copy_from_slice(&address);
+        buffer[8..8 + data.len()].copy_from_slice(data);
+        AdvPdu { buffer, length: 8 + data.len() }
+    }
+
+    /// The PDU, as the radio sends it
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.buffer[..self.length]
+    }
+}
+
+/// A decoded AD structure
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AdStructure<'a> {
+    Flags(u8),
+    /// 16-bit service UUIDs, two bytes each, least significant byte first
+    ServiceUuids16(&'a [u8]),
+    /// 128-bit service UUIDs, 16 bytes each, least significant byte first
+    ServiceUuids128(&'a [u8]),
+    /// The complete or shortened local name
+    Name(&'a str),
+    /// Transmit power level in dBm
+    TxPower(i8),
+    ServiceData16 { uuid: u16, data: &'a [u8] },
+    Manufacturer { company: u16, data: &'a [u8] },
+    /// Other AD types, and the known ones when malformed
+    Other { ad_type: u8, data: &'a [u8] },
+}
+
+impl<'a> AdStructure<'a> {
+    fn decode(ad_type: u8, data: &'a [u8]) -> Self {
+        let uuid = || u16::from_le_bytes([data[0], data[1]]);
+        match ad_type {
+            AD_FLAGS if data.len() == 1 => AdStructure::Flags(data[0]),
+            AD_INCOMPLETE_SERVICE_UUIDS_16 | AD_SERVICE_UUIDS_16
+                if data.len().is_multiple_of(2) => AdStructure::ServiceUuids16(data),
+            AD_INCOMPLETE_SERVICE_UUIDS_128 | AD_SERVICE_UUIDS_128
+                if data.len().is_multiple_of(16) => AdStructure::ServiceUuids128(data),
+            AD_SHORTENED_NAME | AD_COMPLETE_NAME => match core::str::from_utf8(data) {
+                Ok(name) => AdStructure::Name(name),
+                Err(_) => AdStructure::Other { ad_type, data },
+            },
+            AD_TX_POWER if data.len() == 1 => AdStructure::TxPower(data[0] as i8),
+            AD_SERVICE_DATA_16 if data.len() >= 2 =>
+                AdStructure::ServiceData16 { uuid: uuid(), data: &data[2..] },
+            AD_MANUFACTURER if data.len() >= 2 =>
+                AdStructure::Manufacturer { company: uuid(), data: &data[2..] },
+            _ => AdStructure::Other { ad_type, data },
+        }
+    }
+}
+
+/// Iterator over the AD structures of advertising data, stops at the first
+/// malformed structure
+pub struct AdStructures<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Iterator for AdStructures<'a> {
+    type Item = AdStructure<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let length = usize::from(*self.data.first()?);
+        // A zero length ends the data early
+        if length == 0 || length >= self.data.len() {
+            self.data = &[];
+            return None;
+        }
+        let structure = AdStructure::decode(self.data[1], &self.data[2..=length]);
+        self.data = &self.data[length + 1..];
+        Some(structure)
+    }
+}
+
+/// Advertising PDU types
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PduType {
+    AdvInd,
+    AdvDirectInd,
+    AdvNonconnInd,
+    ScanReq,
+    ScanRsp,
+    ConnectReq,
+    AdvScanInd,
+    Unknown,
+}
+
+impl From<u8> for PduType {
+    fn from(value: u8) -> PduType {
+        match value {
+            0 => PduType::AdvInd,
+            1 => PduType::AdvDirectInd,
+            2 => PduType::AdvNonconnInd,
+            3 => PduType::ScanReq,
+            4 => PduType::ScanRsp,
+            5 => PduType::ConnectReq,
+            6 => PduType::AdvScanInd,
+            _ => PduType::Unknown,
+        }
+    }
+}
+
+/// # Advertising report
+///
+/// An ADV_IND, ADV_NONCONN_IND or SCAN_RSP PDU received from another
+/// device, see `AdvPdu` for the format. The advertising data of a SCAN_RSP
+/// is the scan response data.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct AdvReport {
+    pdu_type: PduType,
+    address: Address,
+    random: bool,
+    rssi: i8,
+    channel: u8,
+    data: AdvData,
+}
+
+impl AdvReport {
+    /// Decode a PDU received on `channel` with signal strength `rssi`
+    ///
+    /// Returns `None` for other PDU types and malformed PDUs.
+    pub fn parse(pdu: &[u8], rssi: i8, channel: u8) -> Option<AdvReport> {
+        let pdu_type = PduType::from(pdu.first()? & 0x0f);
+        match pdu_type {
+            PduType::AdvInd | PduType::AdvNonconnInd | PduType::ScanRsp => (),
+            _ => return None,
+        }
+        let length = usize::from(*pdu.get(1)? & 0x3f);
+        if length < 6 || 2 + length > pdu.len() {
+            return None;
+        }
+        let mut address = [0u8; 6];
+        address.copy_from_slice(&pdu[2..8]);
+        Some(AdvReport {
+            pdu_type,
+            address,
+            random: pdu[0] & 0x40 != 0,
+            rssi,
+            channel,
+            data: AdvData::from_bytes(&pdu[8..2 + length])?,
+        })
+    }
+
+    /// The PDU type
+    pub fn pdu_type(&self) -> PduType {
+        self.pdu_type
+    }
+    /// The advertiser address
+    pub fn address(&self) -> Address {
+        self.address
+    }
+    /// Is the advertiser address random, rather than public
+    pub fn is_random(&self) -> bool {
+        self.random
+    }
+    /// Signal strength in dBm
+    pub fn rssi(&self) -> i8 {
+        self.rssi
+    }
+    /// The advertising channel the PDU was received on
+    pub fn channel(&self) -> u8 {
+        self.channel
+    }
+    /// The advertising data
+    pub fn data(&self) -> &AdvData {
+        &self.data
+    }
+    /// The AD structures
+    pub fn structures(&self) -> AdStructures<'_> {
+        self.data.structures()
+    }
+
+    /// The flags, if advertised
+    pub fn flags(&self) -> Option<u8> {
+        self.structures().find_map(|structure| match structure {
+            AdStructure::Flags(flags) => Some(flags),
+            _ => None,
+        })
+    }
+
+    /// The local name, if advertised
+    pub fn name(&self) -> Option<&str> {
+        self.structures().find_map(|structure| match structure {
+            AdStructure::Name(name) => Some(name),
+            _ => None,
+        })
+    }
+
+    /// The company identifier and the manufacturer data, if advertised
+    pub fn manufacturer(&self) -> Option<(u16, &[u8])> {
+        self.structures().find_map(|structure| match structure {
+            AdStructure::Manufacturer { company, data } => Some((company, data)),
+            _ => None,
+        })
+    }
+
+    /// The advertised 16-bit service UUIDs
+    pub fn service_uuids(&self) -> impl Iterator<Item = u16> + '_ {
+        self.structures()
+            .filter_map(|structure| match structure {
+                AdStructure::ServiceUuids16(uuids) => Some(uuids),
+                _ => None,
+            })
+            .flat_map(|uuids| uuids.chunks(2).map(|uuid| u16::from_le_bytes([uuid[0], uuid[1]])))
+    }
+}
+
+/// CRC of `pdu`, in the order the bytes are sent
+///
+/// The CRC is sent most significant bit first, the returned bytes are bit
+/// reversed so that they are sent least significant bit first like all
+/// other bytes.
+pub fn crc24(pdu: &[u8]) -> [u8; 3] {
+    let mut crc = CRC_INIT;
+    for byte in pdu {
+        for bit in 0..8 {
+            let feedback = (crc >> 23) & 1 != u32::from((byte >> bit) & 1);
+            crc = (crc << 1) & 0x00ff_ffff;
+            if feedback {
+                crc ^= CRC_POLY;
+            }
+        }
+    }
+    [
+        ((crc >> 16) as u8).reverse_bits(),
+        ((crc >> 8) as u8).reverse_bits(),
+        (crc as u8).reverse_bits(),
+    ]
+}
+
+/// Whiten or dewhiten `data` sent on `channel`
+///
+/// The whitening sequence is x^7 + x^4 + 1, started with the channel index
+/// and a set bit 6, as the radio DATAWHITEIV.
+pub fn whiten(channel: u8, data: &mut [u8]) {
+    let mut state = (channel & 0x3f) | 0x40;
+    for byte in data.iter_mut() {
+        for bit in 0..8 {
+            let out = state & 0x01;
+            *byte ^= out << bit;
+            state >>= 1;
+            if out != 0 {
+                state ^= 0x44;
+            }
+        }
+    }
+}
+
+/// Encode `pdu` as sent on `channel`
+///
+/// Writes the access address, the PDU and the CRC, with the PDU and the CRC
+/// whitened, to `buffer`. All bytes are sent least significant bit first,
+/// after the preamble 0xaa. Returns the length, or 0 if the PDU is too
+/// large.
+pub fn encode(pdu: &[u8], channel: u8, buffer: &mut [u8; MAX_PACKET_SIZE]) -> usize {
+    if pdu.len() < 2 || pdu.len() > MAX_PDU_SIZE {
+        return 0;
+    }
+    let end = 4 + pdu.len();
+    buffer[..4].copy_from_slice(&ACCESS_ADDRESS.to_le_bytes());
+    buffer[4..end].copy_from_slice(pdu);
+    buffer[end..end + 3].copy_from_slice(&crc24(pdu));
+    whiten(channel, &mut buffer[4..end + 3]);
+    end + 3
+}
+
+/// # Advertiser
+///
+/// Takes turns between up to `PDUS` advertisements, one per advertising
+/// event. Events are `interval` apart, plus a random delay of up to
+/// `MAX_ADV_DELAY` so that advertisers do not keep colliding.
+pub struct Advertiser<const PDUS: usize> {
+    pdus: [Option<AdvPdu>; PDUS],
+    interval: Duration,
+    index: usize,
+    next: Instant,
+    random: Random,
+}
+
+impl<const PDUS: usize> Advertiser<PDUS> {
+    /// Create an advertiser, `seed` seeds the random delays
+    pub fn new(interval: Duration, seed: u32) -> Self {
+        Advertiser {
+            pdus: [None; PDUS],
+            interval,
+            index: 0,
+            next: Instant::from_ticks(0),
+            random: Random::new(seed),
+        }
+    }
+
+    /// Add an advertisement, returns `false` if there is no room
+    pub fn add(&mut self, pdu: AdvPdu) -> bool {
+        match self.pdus.iter_mut().find(|slot| slot.is_none()) {
+            Some(slot) => {
+                *slot = Some(pdu);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Remove all advertisements
+    pub fn clear(&mut self) {
+        self.pdus = [None; PDUS];
+    }
+
+    /// The advertisement to send now, if an advertising event is due
+    pub fn next(&mut self, now: Instant) -> Option<&AdvPdu> {
+        if now < self.next || self.pdus.iter().all(|slot| slot.is_none()) {
+            return None;
+        }
+        let delay = self.random.jitter(MAX_ADV_DELAY);
+        self.next = now + self.interval + delay;
+        loop {
+            let index = self.index;
+            self.index = (self.index + 1) % PDUS;
+            if self.pdus[index].is_some() {
+                return self.pdus[index].as_ref();
+            }
+        }
+    }
+}
+
+/// # Scanner
+///
+/// Hops between the advertising channels, `window` on each.
+pub struct Scanner {
+    window: Duration,
+    index: usize,
+    next_hop: Option<Instant>,
+}
+
+impl Scanner {
+    pub fn new(window: Duration) -> Self {
+        Scanner { window, index: 0, next_hop: None }
+    }
+
+    /// The advertising channel to scan
+    pub fn channel(&self) -> u8 {
+        ADVERTISING_CHANNELS[self.index].0
+    }
+
+    /// Move on to the next channel when the window is over, returns the
+    /// channel to scan when it changes and when scanning starts
+    pub fn hop(&mut self, now: Instant) -> Option<u8> {
+        match self.next_hop {
+            Some(next_hop) if now < next_hop => None,
+            Some(_) => {
+                self.index = (self.index + 1) % ADVERTISING_CHANNELS.len();
+                self.next_hop = Some(now + self.window);
+                Some(self.channel())
+            }
+            None => {
+                self.next_hop = Some(now + self.window);
+                Some(self.channel())
+            }
+        }
+    }
+
+    /// Start over on the first channel, call when scanning is stopped
+    pub fn reset(&mut self) {
+        self.index = 0;
+        self.next_hop = None;
+    }
+}
+
+/// Frequency above 2400 MHz of advertising `channel`
+pub fn frequency(channel: u8) -> Option<u8> {
+    ADVERTISING_CHANNELS.iter()
+        .find(|(index, _)| *index == channel)
+        .map(|(_, frequency)| *frequency)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ADDRESS: Address = [0x11, 0x22, 0x33, 0x44, 0x55, 0xc6];
+
+    /// ADV_NONCONN_IND with the flags and the name "micro:bit"
+    const PDU: [u8; 22] = [
+        0x42, 0x14, 0x11, 0x22, 0x33, 0x44, 0x55, 0xc6, 0x02, 0x01, 0x06,
+        0x0a, 0x09, 0x6d, 0x69, 0x63, 0x72, 0x6f, 0x3a, 0x62, 0x69, 0x74,
+    ];
+    /// `PDU` on channel 37, from the CRC and whitening shift registers as
+    /// drawn in the Core Specification, Vol 6, Part B, Section 3.1
+    const PACKET: [u8; 29] = [
+        0xd6, 0xbe, 0x89, 0x8e, 0xcf, 0xc6, 0x46, 0x83, 0x0e, 0xe3, 0x33, 0x76, 0x77, 0x30,
+        0x17, 0x42, 0x9f, 0x1a, 0x91, 0x80, 0x34, 0x86, 0x91, 0xb2, 0xf7, 0x27, 0x52, 0x42,
+        0xa0,
+    ];
+
+    fn pdu() -> AdvPdu {
+        let mut data = AdvData::new();
+        assert!(data.push(AD_COMPLETE_NAME, b"micro:bit"));
+        AdvPdu::non_connectable(ADDRESS, &data)
+    }
+
+    #[test]
+    fn builds_advertising_pdu() {
+        assert_eq!(pdu().as_bytes(), PDU);
+    }
+
+    #[test]
+    fn crc24_of_advertising_pdu() {
+        assert_eq!(crc24(&PDU), [0x61, 0x9a, 0x1a]);
+        assert_eq!(crc24(&[]), [0xaa, 0xaa, 0xaa]);
+    }
+
+    #[test]
+    fn encodes_whitened_packet() {
+        let mut buffer = [0u8; MAX_PACKET_SIZE];
+        assert_eq!(encode(&PDU, 37, &mut buffer), PACKET.len());
+        assert_eq!(buffer[..PACKET.len()], PACKET);
+        let mut pdu = [0u8; 25];
+        pdu.copy_from_slice(&PACKET[4..]);
+        whiten(37, &mut pdu);
+        assert_eq!(pdu[..22], PDU);
+        assert_eq!(pdu[22..], crc24(&PDU));
+    }
+
+    #[test]
+    fn whitening_sequences() {
+        // The start of the sequences of channel 37 and 38
+        let mut data = [0u8; 4];
+        whiten(37, &mut data);
+        assert_eq!(data, [0x8d, 0xd2, 0x57, 0xa1]);
+        let mut data = [0u8; 2];
+        whiten(38, &mut data);
+        assert_eq!(data, [0xd6, 0xc5]);
+    }
+
+    #[test]
+    fn parses_own_advertisement() {
+        let report = AdvReport::parse(pdu().as_bytes(), -40, 38).unwrap();
+        assert_eq!(report.pdu_type(), PduType::AdvNonconnInd);
+        assert_eq!(report.address(), ADDRESS);
+        assert!(report.is_random());
+        assert_eq!(report.flags(), Some(0x06));
+        assert_eq!(report.name(), Some("micro:bit"));
+        assert!(AdvReport::parse(&PDU[..21], -40, 38).is_none());
+    }
+
+    #[test]
+    fn eddystone_url_encoding() {
+        let data = AdvData::eddystone_url("https://www.example.com/hello", -20).unwrap();
+        assert_eq!(data.as_bytes(), [
+            0x02, 0x01, 0x06, 0x03, 0x03, 0xaa, 0xfe,
+            0x13, 0x16, 0xaa, 0xfe, 0x10, 0xec, 0x01,
+            b'e', b'x', b'a', b'm', b'p', b'l', b'e', 0x00, b'h', b'e', b'l', b'l', b'o',
+        ]);
+        let data = AdvData::eddystone_url("http://goo.gl/S6zT6P", 0).unwrap();
+        assert_eq!(&data.as_bytes()[11..], b"\x10\x00\x02goo.gl/S6zT6P");
+        let data = AdvData::eddystone_url("https://micro.org", 0).unwrap();
+        assert_eq!(&data.as_bytes()[13..], b"\x03micro\x08");
+    }
+
+    #[test]
+    fn eddystone_url_limits() {
+        // 17 encoded bytes after the scheme fill the advertising data
+        let data = AdvData::eddystone_url("https://abcdefghijklmnopq", 0).unwrap();
+        assert_eq!(data.as_bytes().len(), MAX_ADV_DATA);
+        assert!(AdvData::eddystone_url("https://abcdefghijklmnopqr", 0).is_none());
+        assert!(AdvData::eddystone_url("ftp://example.com", 0).is_none());
+        assert!(AdvData::eddystone_url("https://exa mple.com", 0).is_none());
+    }
+
+    #[test]
+    fn advertising_data_limits() {
+        let mut data = AdvData::empty();
+        assert!(data.push(AD_MANUFACTURER, &[0u8; MAX_ADV_DATA - 2]));
+        assert_eq!(data.as_bytes().len(), MAX_ADV_DATA);
+        assert!(!data.push(AD_FLAGS, &[]));
+        let mut data = AdvData::new();
+        assert!(!data.push(AD_MANUFACTURER, &[0u8; MAX_ADV_DATA - 4]));
+        assert_eq!(data, AdvData::new());
+        // The flags, the length, the type and the company leave 24 bytes
+        assert!(AdvData::manufacturer(0x0059, &[0u8; 24]).is_some());
+        assert!(AdvData::manufacturer(0x0059, &[0u8; 25]).is_none());
+        assert!(AdvData::from_bytes(&[0u8; MAX_ADV_DATA]).is_some());
+        assert!(AdvData::from_bytes(&[0u8; MAX_ADV_DATA + 1]).is_none());
+        let beacon = AdvData::ibeacon(&[0x42; 16], 1, 2, -59);
+        assert_eq!(beacon.as_bytes().len(), 3 + 2 + 25);
+        let pdu = AdvPdu::non_connectable(ADDRESS, &beacon);
+        assert!(pdu.as_bytes().len() <= MAX_PDU_SIZE);
+    }
+
+    #[test]
+    fn stops_at_malformed_structure() {
+        let data = AdvData::from_bytes(&[0x02, 0x01, 0x06, 0x05, 0x09, b'a']).unwrap();
+        let mut structures = data.structures();
+        assert_eq!(structures.next(), Some(AdStructure::Flags(0x06)));
+        assert_eq!(structures.next(), None);
+    }
+}
@@ -0,0 +1,176 @@
+//! The micro:bit board
+//!
+//! Splits the nRF51 peripherals into configured micro:bit components.
+
+use nrf51::{
+    Peripherals, CCM, CLOCK, ECB, FICR, NVMC, PPI, RNG, RTC0, RTC1, TEMP, TIMER0, TIMER1,
+    TIMER2, UART0, TWI1,
+};
+use nrf51_hal::gpio::gpio::{PIN1, PIN16, PIN18, PIN2, PIN20, PIN21, PIN22, PIN23, PIN3};
+use nrf51_hal::gpio::{Floating, Input};
+use nrf51_hal::i2c::I2c;
+use nrf51_hal::prelude::*;
+use nrf51_hal::serial::{Serial, BAUD115200};
+
+use crate::buttons::Buttons;
+use crate::leds::Display;
+use crate::radio::{Buffers, Radio};
+
+/// Free pins on the edge connector
+///
+/// Pins used by the display, the buttons or the I2C bus are not included.
+pub struct EdgePins {
+    /// Edge connector pad 0, GPIO 3
+    pub p0: PIN3<Input<Floating>>,
+    /// Edge connector pad 1, GPIO 2
+    pub p1: PIN2<Input<Floating>>,
+    /// Edge connector pad 2, GPIO 1
+    pub p2: PIN1<Input<Floating>>,
+    /// Edge connector pin 8, GPIO 18
+    pub p8: PIN18<Input<Floating>>,
+    /// Edge connector pin 12, GPIO 20
+    pub p12: PIN20<Input<Floating>>,
+    /// Edge connector pin 13 (SPI SCK), GPIO 23
+    pub p13: PIN23<Input<Floating>>,
+    /// Edge connector pin 14 (SPI MISO), GPIO 22
+    pub p14: PIN22<Input<Floating>>,
+    /// Edge connector pin 15 (SPI MOSI), GPIO 21
+    pub p15: PIN21<Input<Floating>>,
+    /// Edge connector pin 16, GPIO 16
+    pub p16: PIN16<Input<Floating>>,
+}
+
+/// # The micro:bit
+///
+/// All the on-board components, wired and configured.
+///
+///  * The high frequency clock is running from the 16 MHz crystal
+///  * The low frequency clock is running at 32.768 kHz
+///  * The UART is connected to the USB interface chip at 115200 baud
+///  * The I2C bus is connected to the accelerometer and magnetometer
+///
+/// Peripherals without a fixed purpose on the board are handed out as is.
+pub struct Board {
+    pub display: Display,
+    pub buttons: Buttons,
+    pub radio: Radio,
+    pub uart: Serial<UART0>,
+    pub i2c: I2c<TWI1>,
+    pub pins: EdgePins,
+    pub clock: CLOCK,
+    pub rtc0: RTC0,
+    pub rtc1: RTC1,
+    pub timer0: TIMER0,
+    pub timer1: TIMER1,
+    pub timer2: TIMER2,
+    pub rng: RNG,
+    pub temp: TEMP,
+    pub ecb: ECB,
+    pub ccm: CCM,
+    pub nvmc: NVMC,
+    pub ppi: PPI,
+    pub ficr: FICR,
+}
+
+impl Board {
+    /// Take the board, returns `None` if the peripherals already have been
+    /// taken
+    pub fn take() -> Option<Self> {
+        static mut RADIO_BUFFERS: Buffers = Buffers::new();
+        Peripherals::take().map(|p| {
+            // Peripherals can only be taken once, so this is the only
+            // reference to the buffers
+            let buffers = unsafe { &mut *core::ptr::addr_of_mut!(RADIO_BUFFERS) };
+            Board::new(p, buffers)
+        })
+    }
+
+    /// Build the board from the nRF51 peripherals and the radio buffers
+    pub fn new(p: Peripherals, radio_buffers: &'static mut Buffers) -> Self {
+        start_clocks(&p.CLOCK);
+
+        let gpio = p.GPIO.split();
+
+        let display = Display::new(
+            gpio.pin4.into_push_pull_output(),
+            gpio.pin5.into_push_pull_output(),
+            gpio.pin6.into_push_pull_output(),
+            gpio.pin7.into_push_pull_output(),
+            gpio.pin8.into_push_pull_output(),
+            gpio.pin9.into_push_pull_output(),
+            gpio.pin10.into_push_pull_output(),
+            gpio.pin11.into_push_pull_output(),
+            gpio.pin12.into_push_pull_output(),
+            gpio.pin13.into_push_pull_output(),
+            gpio.pin14.into_push_pull_output(),
+            gpio.pin15.into_push_pull_output(),
+        );
+
+        let buttons = Buttons::new(
+            p.GPIOTE,
+            gpio.pin17.into_floating_input(),
+            gpio.pin26.into_floating_input(),
+        );
+
+        let uart = Serial::uart0(
+            p.UART0,
+            gpio.pin24.into_push_pull_output().downgrade(),
+            gpio.pin25.into_floating_input().downgrade(),
+            BAUD115200,
+        );
+
+        let i2c = I2c::i2c1(
+            p.TWI1,
+            gpio.pin30.into_open_drain_input().downgrade(),
+            gpio.pin0.into_open_drain_input().downgrade(),
+        );
+
+        let pins = EdgePins {
+            p0: gpio.pin3,
+            p1: gpio.pin2,
+            p2: gpio.pin1,
+            p8: gpio.pin18,
+            p12: gpio.pin20,
+            p13: gpio.pin23,
+            p14: gpio.pin22,
+            p15: gpio.pin21,
+            p16: gpio.pin16,
+        };
+
+        Board {
+            display,
+            buttons,
+            radio: Radio::new(p.RADIO, radio_buffers),
+            uart,
+            i2c,
+            pins,
+            clock: p.CLOCK,
+            rtc0: p.RTC0,
+            rtc1: p.RTC1,
+            timer0: p.TIMER0,
+            timer1: p.TIMER1,
+            timer2: p.TIMER2,
+            rng: p.RNG,
+            temp: p.TEMP,
+            ecb: p.ECB,
+            ccm: p.CCM,
+            nvmc: p.NVMC,
+            ppi: p.PPI,
+            ficr: p.FICR,
+        }
+    }
+}
+
+/// Start the 16 MHz crystal oscillator and the 32.768 kHz clock
+fn start_clocks(clock: &CLOCK) {
+    clock.xtalfreq.write(|w| w.xtalfreq()._16mhz());
+    clock.events_hfclkstarted.reset();
+    clock.tasks_hfclkstart.write(|w| unsafe { w.bits(1) });
+    while clock.events_hfclkstarted.read().bits() == 0 {}
+    clock.events_hfclkstarted.reset();
+
+    clock.events_lfclkstarted.reset();
+    clock.tasks_lfclkstart.write(|w| unsafe { w.bits(1) });
+    while clock.events_lfclkstarted.read().bits() == 0 {}
+    clock.events_lfclkstarted.reset();
+}
@@ -0,0 +1,90 @@
+//! On-board buttons
+
+use nrf51::GPIOTE;
+use nrf51_hal::gpio::gpio::{PIN17, PIN26};
+use nrf51_hal::gpio::{Floating, Input};
+use nrf51_hal::hal::digital::v2::InputPin;
+
+use crate::signal::{Signal, Wait};
+
+/// GPIO pin of button A
+pub const BUTTON_A_PIN: u8 = 17;
+/// GPIO pin of button B
+pub const BUTTON_B_PIN: u8 = 26;
+
+static CHANGED: Signal<(bool, bool)> = Signal::new();
+
+/// Wait for the button state passed on by `Buttons::wake`, (A, B)
+pub fn changed() -> Wait<'static, (bool, bool)> {
+    CHANGED.wait()
+}
+
+/// # The micro:bit buttons
+///
+/// Button A and B, configured to generate GPIOTE events on every edge.
+/// GPIOTE channel 0 is used for button A and channel 1 for button B.
+///
+/// The buttons are pulled up externally, a pressed button reads low.
+pub struct Buttons {
+    gpiote: GPIOTE,
+    button_a: PIN17<Input<Floating>>,
+    button_b: PIN26<Input<Floating>>,
+}
+
+impl Buttons {
+    /// Configure GPIOTE to generate interrupts on button edges
+    pub fn new(
+        gpiote: GPIOTE,
+        button_a: PIN17<Input<Floating>>,
+        button_b: PIN26<Input<Floating>>,
+    ) -> Self {
+        gpiote.config[0].write(|w| unsafe {
+            w.mode().event().psel().bits(BUTTON_A_PIN).polarity().toggle()
+        });
+        gpiote.config[1].write(|w| unsafe {
+            w.mode().event().psel().bits(BUTTON_B_PIN).polarity().toggle()
+        });
+        gpiote.events_in[0].write(|w| unsafe { w.bits(0) });
+        gpiote.events_in[1].write(|w| unsafe { w.bits(0) });
+        gpiote.intenset.write(|w| w.in0().set_bit().in1().set_bit());
+        Buttons { gpiote, button_a, button_b }
+    }
+
+    /// Is button A pressed
+    pub fn is_a_pressed(&self) -> bool {
+        self.button_a.is_low().unwrap_or(false)
+    }
+
+    /// Is button B pressed
+    pub fn is_b_pressed(&self) -> bool {
+        self.button_b.is_low().unwrap_or(false)
+    }
+
+    /// Get the state of both buttons, (A, B)
+    pub fn pressed(&self) -> (bool, bool) {
+        (self.is_a_pressed(), self.is_b_pressed())
+    }
+
+    /// Clear button events, call this from the GPIOTE interrupt
+    pub fn clear_events(&mut self) {
+        self.gpiote.events_in[0].write(|w| unsafe { w.bits(0) });
+        self.gpiote.events_in[1].write(|w| unsafe { w.bits(0) });
+    }
+
+    /// Handle the GPIOTE interrupt, returns the state of both buttons, (A, B)
+    pub fn interrupt(&mut self) -> (bool, bool) {
+        self.clear_events();
+        self.pressed()
+    }
+
+    /// Handle the GPIOTE interrupt and pass the button state on to `changed`
+    pub fn wake(&mut self) {
+        CHANGED.signal(self.interrupt());
+    }
+
+    /// Release the GPIOTE peripheral and the button pins
+    pub fn free(self) -> (GPIOTE, PIN17<Input<Floating>>, PIN26<Input<Floating>>) {
+        self.gpiote.intenclr.write(|w| w.in0().set_bit().in1().set_bit());
+        (self.gpiote, self.button_a, self.button_b)
+    }
+}
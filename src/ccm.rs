@@ -0,0 +1,357 @@
+//! AES-CCM authenticated encryption
+//!
+//! CCM as done by the nRF51 CCM peripheral for Bluetooth low energy, a
+//! 13-byte nonce, a 4-byte MIC, a 2-byte length field and one header byte
+//! of additional authenticated data. `SoftCcm` computes the same in
+//! software with any `BlockCipher`.
+//!
+//! The nonce is laid out as the Bluetooth nonce,
+//!
+//! ```notrust
+//! | 0 ... 4                                  | 5 ... 12
+//! ------------------------------------------------------
+//! | 39-bit counter, direction in bit 7 of 4  | IV
+//! ```
+//!
+//! ## Reference
+//!
+//! * <https://tools.ietf.org/html/rfc3610>
+//! * Bluetooth Core Specification v4.0, Vol 6, Part E, Section 2
+
+use core::sync::atomic::{compiler_fence, Ordering};
+
+use nrf51::CCM;
+
+use crate::aes::{Block, BlockCipher, Key, BLOCK_SIZE};
+
+pub type Nonce = [u8; 13];
+pub type Mic = [u8; MIC_SIZE];
+
+/// Size of the message integrity check
+pub const MIC_SIZE: usize = 4;
+/// Largest message the peripheral can encrypt
+pub const MAX_LENGTH: usize = 27;
+/// Bits of the header byte that are authenticated
+pub const HEADER_MASK: u8 = 0xe3;
+
+/// Errors sealing a message
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CcmError {
+    /// The message is empty or longer than `MAX_LENGTH`
+    Length,
+    /// The peripheral reported an error
+    Peripheral,
+}
+
+/// # CCM
+///
+/// Encrypts and authenticates messages of up to `MAX_LENGTH` bytes.
+pub trait Ccm {
+    /// Encrypt `data` in place and return the MIC, `data` is left as it
+    /// was on error
+    fn seal(&mut self, nonce: &Nonce, header: u8, data: &mut [u8]) -> Result<Mic, CcmError>;
+    /// Decrypt `data` in place, returns `false` if the MIC does not match,
+    /// `data` must then be thrown away
+    fn open(&mut self, nonce: &Nonce, header: u8, data: &mut [u8], mic: &Mic) -> bool;
+}
+
+/// # Software CCM
+///
+/// CCM on top of a `BlockCipher`, `aes::Ecb` or `aes::SoftAes`. Takes the
+/// same messages as the peripheral, 1 to `MAX_LENGTH` bytes.
+pub struct SoftCcm<C: BlockCipher> {
+    cipher: C,
+}
+
+impl<C: BlockCipher> SoftCcm<C> {
+    pub fn new(cipher: C) -> Self {
+        SoftCcm { cipher }
+    }
+
+    /// Release the block cipher
+    pub fn free(self) -> C {
+        self.cipher
+    }
+
+    /// Counter block `index`
+    fn counter(nonce: &Nonce, index: u16) -> Block {
+        let mut block = [0u8; BLOCK_SIZE];
+        // Flags, L = 2
+        block[0] = 0x01;
+        block[1..14].copy_from_slice(nonce);
+        block[14..16].copy_from_slice(&index.to_be_bytes());
+        block
+    }
+
+    /// Encrypt or decrypt `data` with the key stream, the first block of
+    /// the key stream is returned for the MIC
+    fn crypt(&mut self, nonce: &Nonce, data: &mut [u8]) -> Block {
+        for (index, chunk) in data.chunks_mut(BLOCK_SIZE).enumerate() {
+            let mut stream = Self::counter(nonce, index as u16 + 1);
+            self.cipher.encrypt_block(&mut stream);
+            for (byte, stream) in chunk.iter_mut().zip(stream.iter()) {
+                *byte ^= stream;
+            }
+        }
+        let mut stream = Self::counter(nonce, 0);
+        self.cipher.encrypt_block(&mut stream);
+        stream
+    }
+
+    /// CBC-MAC of the header and the plain text
+    fn authenticate(&mut self, nonce: &Nonce, header: u8, data: &[u8]) -> Mic {
+        let mut block = [0u8; BLOCK_SIZE];
+        // Flags, additional data, M = 4, L = 2
+        block[0] = 0x40 | (((MIC_SIZE as u8 - 2) / 2) << 3) | 0x01;
+        block[1..14].copy_from_slice(nonce);
+        block[14..16].copy_from_slice(&(data.len() as u16).to_be_bytes());
+        self.cipher.encrypt_block(&mut block);
+        // Additional data, one byte with a 2-byte length
+        block[1] ^= 0x01;
+        block[2] ^= header & HEADER_MASK;
+        self.cipher.encrypt_block(&mut block);
+        for chunk in data.chunks(BLOCK_SIZE) {
+            for (byte, data) in block.iter_mut().zip(chunk.iter()) {
+                *byte ^= data;
+            }
+            self.cipher.encrypt_block(&mut block);
+        }
+        let mut mic = [0u8; MIC_SIZE];
+        mic.copy_from_slice(&block[..MIC_SIZE]);
+        mic
+    }
+}
+
+impl<C: BlockCipher> Ccm for SoftCcm<C> {
+    fn seal(&mut self, nonce: &Nonce, header: u8, data: &mut [u8]) -> Result<Mic, CcmError> {
+        if data.is_empty() || data.len() > MAX_LENGTH {
+            return Err(CcmError::Length);
+        }
+        let mut mic = self.authenticate(nonce, header, data);
+        let stream = self.crypt(nonce, data);
+        for (byte, stream) in mic.iter_mut().zip(stream.iter()) {
+            *byte ^= stream;
+        }
+        Ok(mic)
+    }
+
+    fn open(&mut self, nonce: &Nonce, header: u8, data: &mut [u8], mic: &Mic) -> bool {
+        if data.is_empty() || data.len() > MAX_LENGTH {
+            return false;
+        }
+        let stream = self.crypt(nonce, data);
+        let expected = self.authenticate(nonce, header, data);
+        let difference = expected.iter().zip(mic.iter()).zip(stream.iter())
+            .fold(0, |difference, ((expected, mic), stream)| difference | (expected ^ mic ^ stream));
+        difference == 0
+    }
+}
+
+/// Key and nonce read by the CCM peripheral
+#[repr(C)]
+struct CcmConfig {
+    key: Key,
+    counter: [u8; 8],
+    direction: u8,
+    iv: [u8; 8],
+}
+
+/// Size of the packet header used by the peripheral, header, length and a
+/// reserved byte
+const PACKET_HEADER_SIZE: usize = 3;
+/// Scratch area needed by the peripheral
+const SCRATCH_SIZE: usize = 43;
+
+/// # CCM peripheral
+///
+/// Encrypts and decrypts with the CCM peripheral, waiting for it to
+/// complete. Messages must not be empty, the peripheral does not
+/// authenticate empty messages.
+///
+/// ## Reference
+///
+/// * nRF51 Series Reference Manual, chapter 25, AES CCM Mode Encryption
+pub struct HwCcm {
+    ccm: CCM,
+    config: CcmConfig,
+}
+
+impl HwCcm {
+    pub fn new(ccm: CCM, key: &Key) -> Self {
+        ccm.enable.write(|w| w.enable().enabled());
+        ccm.shorts.write(|w| w.endksgen_crypt().enabled());
+        HwCcm {
+            ccm,
+            config: CcmConfig {
+                key: *key,
+                counter: [0u8; 8],
+                direction: 0,
+                iv: [0u8; 8],
+            },
+        }
+    }
+
+    /// Disable and release the peripheral
+    pub fn free(self) -> CCM {
+        self.ccm.enable.write(|w| w.enable().disabled());
+        self.ccm
+    }
+
+    /// Run the peripheral from `input` to `output`, returns `false` on error
+    fn run(&mut self, nonce: &Nonce, input: &[u8], output: &mut [u8]) -> bool {
+        self.config.counter[..5].copy_from_slice(&nonce[..5]);
+        self.config.counter[4] &= 0x7f;
+        self.config.direction = nonce[4] >> 7;
+        self.config.iv.copy_from_slice(&nonce[5..]);
+        let mut scratch = [0u8; SCRATCH_SIZE];
+        let ccm = &self.ccm;
+        ccm.cnfptr.write(|w| unsafe { w.bits(&self.config as *const _ as u32) });
+        ccm.inptr.write(|w| unsafe { w.bits(input.as_ptr() as u32) });
+        ccm.outptr.write(|w| unsafe { w.bits(output.as_mut_ptr() as u32) });
+        ccm.scratchptr.write(|w| unsafe { w.bits(scratch.as_mut_ptr() as u32) });
+        ccm.events_endksgen.reset();
+        ccm.events_endcrypt.reset();
+        ccm.events_error.reset();
+        compiler_fence(Ordering::AcqRel);
+        ccm.tasks_ksgen.write(|w| unsafe { w.bits(1) });
+        while ccm.events_endcrypt.read().bits() == 0 && ccm.events_error.read().bits() == 0 {}
+        compiler_fence(Ordering::AcqRel);
+        ccm.events_error.read().bits() == 0
+    }
+}
+
+impl Ccm for HwCcm {
+    fn seal(&mut self, nonce: &Nonce, header: u8, data: &mut [u8]) -> Result<Mic, CcmError> {
+        if data.is_empty() || data.len() > MAX_LENGTH {
+            return Err(CcmError::Length);
+        }
+        let length = data.len();
+        let mut input = [0u8; PACKET_HEADER_SIZE + MAX_LENGTH];
+        let mut output = [0u8; PACKET_HEADER_SIZE + MAX_LENGTH + MIC_SIZE];
+        input[0] = header;
+        input[1] = length as u8;
+        input[PACKET_HEADER_SIZE..PACKET_HEADER_SIZE + length].copy_from_slice(data);
+        self.ccm.mode.write(|w| w.mode().encryption());
+        if !self.run(nonce, &input, &mut output) {
+            return Err(CcmError::Peripheral);
+        }
+        data.copy_from_slice(&output[PACKET_HEADER_SIZE..PACKET_HEADER_SIZE + length]);
+        let mut mic = [0u8; MIC_SIZE];
+        let offset = PACKET_HEADER_SIZE + length;
+        mic.copy_from_slice(&output[offset..offset + MIC_SIZE]);
+        Ok(mic)
+    }
+
+    fn open(&mut self, nonce: &Nonce, header: u8, data: &mut [u8], mic: &Mic) -> bool {
+        if data.is_empty() || data.len() > MAX_LENGTH {
+            return false;
+        }
+        let length = data.len();
+        let mut input = [0u8; PACKET_HEADER_SIZE + MAX_LENGTH + MIC_SIZE];
+        let mut output = [0u8; PACKET_HEADER_SIZE + MAX_LENGTH];
+        input[0] = header;
+        input[1] = (length + MIC_SIZE) as u8;
+        input[PACKET_HEADER_SIZE..PACKET_HEADER_SIZE + length].copy_from_slice(data);
+        let offset = PACKET_HEADER_SIZE + length;
+        input[offset..offset + MIC_SIZE].copy_from_slice(mic);
+        self.ccm.mode.write(|w| w.mode().decryption());
+        if !self.run(nonce, &input, &mut output) {
+            return false;
+        }
+        data.copy_from_slice(&output[PACKET_HEADER_SIZE..PACKET_HEADER_SIZE + length]);
+        self.ccm.micstatus.read().micstatus().is_check_passed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::aes::SoftAes;
+
+    const KEY: Key = [
+        0x00, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07,
+        0x08, 0x09, 0x0a, 0x0b, 0x0c, 0x0d, 0x0e, 0x0f,
+    ];
+    const NONCE: Nonce = [
+        0x10, 0x11, 0x12, 0x13, 0x14, 0x15, 0x16, 0x17, 0x18, 0x19, 0x1a, 0x1b, 0x1c,
+    ];
+
+    /// RFC 3610 CCM with M = 4, L = 2 and the masked header as additional
+    /// data, computed with an independent implementation
+    const VECTORS: [(u8, &[u8], &[u8], Mic); 3] = [
+        (0x03, b"*", &[0x56], [0x09, 0xed, 0xf6, 0xf0]),
+        (0x01, b"0123456789abcdef", &[
+            0x4c, 0xd0, 0x42, 0x72, 0x8c, 0x69, 0xd8, 0xe3,
+            0x83, 0x60, 0x29, 0xf3, 0x4c, 0x63, 0x09, 0xe7,
+        ], [0x5e, 0x3e, 0xd3, 0x85]),
+        (0x1f, b"micro:bit secure datagram!!", &[
+            0x11, 0x88, 0x13, 0x33, 0xd7, 0x66, 0x8c, 0xbd, 0xcf, 0x79, 0x3b, 0xf4, 0x4c, 0x72,
+            0x1e, 0xe4, 0x0a, 0xb7, 0x34, 0x0d, 0x1e, 0xf0, 0xec, 0x4b, 0x50, 0x6c, 0x3b,
+        ], [0x0a, 0x44, 0x76, 0xfa]),
+    ];
+
+    fn ccm() -> SoftCcm<SoftAes> {
+        SoftCcm::new(SoftAes::new(&KEY))
+    }
+
+    #[test]
+    fn seals_test_vectors() {
+        let mut ccm = ccm();
+        for (header, plain, cipher, mic) in VECTORS {
+            let mut data = [0u8; MAX_LENGTH];
+            let data = &mut data[..plain.len()];
+            data.copy_from_slice(plain);
+            assert_eq!(ccm.seal(&NONCE, header, data), Ok(mic));
+            assert_eq!(data, cipher);
+            assert!(ccm.open(&NONCE, header, data, &mic));
+            assert_eq!(data, plain);
+        }
+    }
+
+    #[test]
+    fn header_bits_outside_mask_are_not_authenticated() {
+        let mut ccm = ccm();
+        let (header, plain, cipher, mic) = VECTORS[2];
+        let mut data = [0u8; MAX_LENGTH];
+        data.copy_from_slice(cipher);
+        assert!(ccm.open(&NONCE, header ^ !HEADER_MASK, &mut data, &mic));
+        assert_eq!(data, plain);
+    }
+
+    #[test]
+    fn tampering_fails_to_open() {
+        let mut ccm = ccm();
+        let (header, _, cipher, mic) = VECTORS[2];
+        for bit in 0..cipher.len() * 8 {
+            let mut data = [0u8; MAX_LENGTH];
+            data.copy_from_slice(cipher);
+            data[bit / 8] ^= 1 << (bit % 8);
+            assert!(!ccm.open(&NONCE, header, &mut data, &mic));
+        }
+        for bit in 0..MIC_SIZE * 8 {
+            let mut data = [0u8; MAX_LENGTH];
+            data.copy_from_slice(cipher);
+            let mut mic = mic;
+            mic[bit / 8] ^= 1 << (bit % 8);
+            assert!(!ccm.open(&NONCE, header, &mut data, &mic));
+        }
+        for bit in (0..8).filter(|bit| HEADER_MASK & (1 << bit) != 0) {
+            let mut data = [0u8; MAX_LENGTH];
+            data.copy_from_slice(cipher);
+            assert!(!ccm.open(&NONCE, header ^ (1 << bit), &mut data, &mic));
+        }
+        let mut data = [0u8; MAX_LENGTH];
+        data.copy_from_slice(cipher);
+        let mut nonce = NONCE;
+        nonce[12] ^= 1;
+        assert!(!ccm.open(&nonce, header, &mut data, &mic));
+    }
+
+    #[test]
+    fn rejects_lengths_the_peripheral_rejects() {
+        let mut ccm = ccm();
+        assert_eq!(ccm.seal(&NONCE, 0, &mut []), Err(CcmError::Length));
+        assert_eq!(ccm.seal(&NONCE, 0, &mut [0u8; MAX_LENGTH + 1]), Err(CcmError::Length));
+        assert!(!ccm.open(&NONCE, 0, &mut [], &[0u8; MIC_SIZE]));
+    }
+}
@@ -2,19 +2,89 @@
 
 use core::convert::From;
 
-#[derive(Clone, PartialEq)]
+/// Datagram version
+pub const VERSION: u8 = 1;
+/// Size of the datagram header, including the length
+pub const HEADER_SIZE: usize = 4;
+
+/// Protocol of a datagram
+///
+/// `Other` is meant for numbers not assigned here, `Other(3)` is the same
+/// protocol as `Reliable`, they compare equal and are sent as the same
+/// number. Use `DatagramProtocol::new` to get the assigned variant of a
+/// number.
+#[derive(Clone, Debug)]
 pub enum DatagramProtocol {
     Datagram,
     EventBus,
+    Reliable,
+    Fragment,
+    Secure,
+    TimeSync,
+    Presence,
+    LinkTest,
+    /// Protocol number not assigned here, such as an application protocol
+    Other(u8),
     Unknown,
 }
 
+impl DatagramProtocol {
+    /// The protocol of number `id`, `Other` only for numbers not assigned
+    pub fn new(id: u8) -> DatagramProtocol {
+        DatagramProtocol::from(id)
+    }
+
+    /// The protocol number sent in the header
+    pub fn id(&self) -> u8 {
+        u8::from(self.clone())
+    }
+
+    /// Is this an `Other` with a number assigned to another variant
+    pub fn is_assigned_other(&self) -> bool {
+        matches!(self, DatagramProtocol::Other(_))
+            && !matches!(DatagramProtocol::new(self.id()), DatagramProtocol::Other(_))
+    }
+}
+
+impl PartialEq for DatagramProtocol {
+    /// Protocols are equal when they are sent as the same number
+    fn eq(&self, other: &DatagramProtocol) -> bool {
+        self.id() == other.id()
+    }
+}
+
+impl Eq for DatagramProtocol {}
+
 impl From<u8> for DatagramProtocol {
     fn from(value: u8) -> DatagramProtocol {
         match value {
             1 => DatagramProtocol::Datagram,
             2 => DatagramProtocol::EventBus,
-            _ => DatagramProtocol::Unknown,
+            3 => DatagramProtocol::Reliable,
+            4 => DatagramProtocol::Fragment,
+            5 => DatagramProtocol::Secure,
+            6 => DatagramProtocol::TimeSync,
+            7 => DatagramProtocol::Presence,
+            8 => DatagramProtocol::LinkTest,
+            0xff => DatagramProtocol::Unknown,
+            _ => DatagramProtocol::Other(value),
+        }
+    }
+}
+
+impl From<DatagramProtocol> for u8 {
+    fn from(value: DatagramProtocol) -> u8 {
+        match value {
+            DatagramProtocol::Datagram => 1,
+            DatagramProtocol::EventBus => 2,
+            DatagramProtocol::Reliable => 3,
+            DatagramProtocol::Fragment => 4,
+            DatagramProtocol::Secure => 5,
+            DatagramProtocol::TimeSync => 6,
+            DatagramProtocol::Presence => 7,
+            DatagramProtocol::LinkTest => 8,
+            DatagramProtocol::Other(value) => value,
+            DatagramProtocol::Unknown => 0xff,
         }
     }
 }
@@ -28,9 +98,17 @@ impl From<u8> for DatagramProtocol {
 /// ```
 /// Datagram length is length of package without the length itselfe
 /// Protocol is either
-///  * 0, Datagram
-///  * 1, EventBus
+///  * 1, Datagram
+///  * 2, EventBus
+///  * 3, Reliable, see `reliable`
+///  * 4, Fragment, see `fragment`
+///  * 5, Secure, see `secure`
+///  * 6, TimeSync, see `timesync`
+///  * 7, Presence, see `presence`
+///  * 8, LinkTest, see `diagnostics`
 ///
+/// Other numbers are kept as `DatagramProtocol::Other`, applications can
+/// route them to their own handlers with `dispatch::Dispatcher`.
 pub struct DatagramHeader
 {
     length: u8,
@@ -40,11 +118,32 @@ pub struct DatagramHeader
 }
 
 impl DatagramHeader {
-    /// Unpack a PackageHeader from the byte slice
+    /// Create a header for a payload of `payload_length` bytes
+    pub fn new(group: u8, protocol: DatagramProtocol, payload_length: usize) -> DatagramHeader {
+        DatagramHeader {
+            length: (payload_length + 3) as u8,
+            version: VERSION,
+            group,
+            protocol,
+        }
+    }
+
+    /// Pack the DatagramHeader into the byte slice
+    pub fn pack(&self, buffer: &mut [u8]) {
+        assert!(buffer.len() >= HEADER_SIZE);
+        buffer[0] = self.length;
+        buffer[1] = self.version;
+        buffer[2] = self.group;
+        buffer[3] = u8::from(self.protocol.clone());
+    }
+
+    /// Unpack a DatagramHeader from the byte slice
+    ///
+    /// The protocol is `DatagramProtocol::Unknown` if the slice is shorter
+    /// than the header or the length does not cover it.
     pub fn unpack(buffer: &[u8]) -> DatagramHeader {
-        assert!(buffer.len() >= 4);
-        let length = buffer[0];
-        if length >= 3 {
+        let length = buffer.first().copied().unwrap_or(0);
+        if length >= 3 && buffer.len() >= HEADER_SIZE {
             DatagramHeader {
                 length,
                 version: buffer[1],
@@ -82,3 +181,41 @@ impl DatagramHeader {
         self.protocol.clone()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn protocols_are_compared_by_number() {
+        assert!(DatagramProtocol::Other(1) == DatagramProtocol::Datagram);
+        assert!(DatagramProtocol::Other(0xff) == DatagramProtocol::Unknown);
+        assert!(DatagramProtocol::Other(0x42) != DatagramProtocol::Other(0x43));
+        assert!(matches!(DatagramProtocol::new(3), DatagramProtocol::Reliable));
+        assert!(matches!(DatagramProtocol::new(0x42), DatagramProtocol::Other(0x42)));
+        for id in 0..=255 {
+            assert_eq!(DatagramProtocol::new(id).id(), id);
+            assert!(!DatagramProtocol::new(id).is_assigned_other());
+        }
+        assert!(DatagramProtocol::Other(3).is_assigned_other());
+        assert!(!DatagramProtocol::Other(0).is_assigned_other());
+    }
+
+    #[test]
+    fn unpacks_header() {
+        let header = DatagramHeader::unpack(&[7, VERSION, 3, 5, 0, 0, 0, 0]);
+        assert_eq!(header.length(), 7);
+        assert_eq!(header.payload_length(), 4);
+        assert_eq!(header.group(), 3);
+        assert!(header.protocol() == DatagramProtocol::Secure);
+    }
+
+    #[test]
+    fn short_buffers_are_unknown() {
+        for buffer in [&[][..], &[3], &[3, VERSION, 0], &[2, VERSION, 0, 1]] {
+            let header = DatagramHeader::unpack(buffer);
+            assert!(header.protocol() == DatagramProtocol::Unknown);
+            assert_eq!(header.payload_length(), 0);
+        }
+    }
+}
@@ -0,0 +1,548 @@
+//! Radio diagnostics
+//!
+//! An energy detect sweep over the channels, to find the quietest channel,
+//! and a link test, where one board sends numbered datagrams and another
+//! counts the datagrams lost, received with CRC errors and their signal
+//! strength.
+//!
+//! Results implement `core::fmt::Display`, to be written to the UART, and
+//! can be shown on the LED matrix.
+//!
+//! ```notrust
+//! let mut scan = Scan::new();
+//! diagnostics::sweep(&mut radio, &mut scan, 8);
+//! write!(tx, "{}", scan);
+//! radio.set_channel(scan.quietest());
+//! ```
+
+use core::fmt;
+
+use byteorder::{ByteOrder, LittleEndian};
+
+use crate::datagram::{self, DatagramHeader, DatagramProtocol};
+use crate::leds::Image;
+use crate::link::Link;
+use crate::radio::{PackageBuffer, Radio, MAX_PACKAGE_SIZE};
+
+/// Number of channels swept, 2400 MHz to 2483 MHz
+pub const CHANNELS: usize = 84;
+/// Number of bins in the signal strength histogram
+pub const BINS: usize = 8;
+/// Lower edge of the first histogram bin in dBm, lower values are counted
+/// in the first bin
+pub const HISTOGRAM_FLOOR: i8 = -100;
+/// Width of a histogram bin in dB, higher values are counted in the last bin
+pub const BIN_WIDTH: i8 = 10;
+/// Size of the link test datagram following the datagram header
+pub const TEST_SIZE: usize = MAX_PACKAGE_SIZE - datagram::HEADER_SIZE;
+
+/// Histogram bin of `rssi`
+fn bin(rssi: i8) -> usize {
+    let bin = (i16::from(rssi) - i16::from(HISTOGRAM_FLOOR)) / i16::from(BIN_WIDTH);
+    bin.max(0).min(BINS as i16 - 1) as usize
+}
+
+/// # Channel scan
+///
+/// Signal strength samples per channel, collected by `sweep`.
+pub struct Scan {
+    histogram: [[u16; BINS]; CHANNELS],
+    sum: [i32; CHANNELS],
+    max: [i8; CHANNELS],
+}
+
+impl Scan {
+    pub const fn new() -> Self {
+        Scan {
+            histogram: [[0; BINS]; CHANNELS],
+            sum: [0; CHANNELS],
+            max: [i8::MIN; CHANNELS],
+        }
+    }
+
+    /// Add a sample of `channel`, in dBm
+    pub fn add(&mut self, channel: u8, rssi: i8) {
+        let channel = usize::from(channel);
+        if channel >= CHANNELS {
+            return;
+        }
+        let count = &mut self.histogram[channel][bin(rssi)];
+        *count = count.saturating_add(1);
+        self.sum[channel] += i32::from(rssi);
+        self.max[channel] = self.max[channel].max(rssi);
+    }
+
+    /// Number of samples of `channel`
+    pub fn samples(&self, channel: u8) -> u32 {
+        self.histogram.get(usize::from(channel))
+            .map_or(0, |bins| bins.iter().map(|count| u32::from(*count)).sum())
+    }
+
+    /// Histogram of `channel`, the number of samples in each bin
+    pub fn histogram(&self, channel: u8) -> [u16; BINS] {
+        self.histogram.get(usize::from(channel)).copied().unwrap_or([0; BINS])
+    }
+
+    /// Mean signal strength of `channel` in dBm
+    pub fn mean(&self, channel: u8) -> Option<i8> {
+        let samples = self.samples(channel);
+        if samples == 0 {
+            return None;
+        }
+        Some((self.sum[usize::from(channel)] / samples as i32) as i8)
+    }
+
+    /// Highest signal strength of `channel` in dBm
+    pub fn max(&self, channel: u8) -> Option<i8> {
+        if self.samples(channel) == 0 { None } else { Some(self.max[usize::from(channel)]) }
+    }
+
+    /// The channel with the lowest mean signal strength, with the lowest
+    /// peak when several are as quiet
+    pub fn quietest(&self) -> u8 {
+        (0..CHANNELS as u8)
+            .filter_map(|channel| Some((self.mean(channel)?, self.max(channel)?, channel)))
+            .min()
+            .map_or(crate::radio::DEFAULT_CHANNEL, |(_, _, channel)| channel)
+    }
+
+    /// The scan as a bar graph on the LED matrix
+    ///
+    /// Each column is a fifth of the channels, the bar shows the highest
+    /// signal strength of them, one LED for every `BIN_WIDTH` dB above
+    /// `HISTOGRAM_FLOOR` + `BIN_WIDTH`.
+    pub fn image(&self) -> Image {
+        let mut image = [[0u8; 5]; 5];
+        let width = CHANNELS.div_ceil(5);
+        let mut heights = [0usize; 5];
+        for (column, height) in heights.iter_mut().enumerate() {
+            let start = column * width;
+            let end = (start + width).min(CHANNELS);
+            let peak = (start as u8..end as u8).filter_map(|channel| self.max(channel)).max();
+            *height = peak.map_or(0, |peak| bin(peak).min(5));
+        }
+        for (row, leds) in image.iter_mut().enumerate() {
+            for (led, height) in leds.iter_mut().zip(heights.iter()) {
+                if 4 - row < *height {
+                    *led = 0xff;
+                }
+            }
+        }
+        image
+    }
+}
+
+impl Default for Scan {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl fmt::Display for Scan {
+    /// One line per channel, the frequency, the mean and the highest
+    /// signal strength and the histogram
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "MHz  mean  max")?;
+        for bin in 0..BINS {
+            write!(f, " {:5}", i16::from(HISTOGRAM_FLOOR) + bin as i16 * i16::from(BIN_WIDTH))?;
+        }
+        writeln!(f)?;
+        for channel in 0..CHANNELS as u8 {
+            let (mean, max) = match (self.mean(channel), self.max(channel)) {
+                (Some(mean), Some(max)) => (mean, max),
+                _ => continue,
+            };
+            write!(f, "{} {:5} {:4}", 2400 + u16::from(channel), mean, max)?;
+            for count in self.histogram(channel).iter() {
+                write!(f, " {:5}", count)?;
+            }
+            writeln!(f)?;
+        }
+        writeln!(f, "Quietest channel {}", self.quietest())
+    }
+}
+
+/// Sweep the channels `samples` times, adding the signal strength samples
+/// to `scan`
+///
+/// The radio goes back to its channel and starts receiving when done.
+pub fn sweep(radio: &mut Radio, scan: &mut Scan, samples: u32) {
+    let channel = radio.channel();
+    for _ in 0..samples {
+        for sweep_channel in 0..CHANNELS as u8 {
+            scan.add(sweep_channel, radio.measure_rssi(sweep_channel));
+        }
+    }
+    radio.set_channel(channel);
+    radio.start_receive();
+}
+
+/// # Link test datagram
+///
+/// ```notrust
+/// | 0 ... 3         | 4 ... 7  | 8 ... 32
+/// --------------------------------------
+/// | datagram header | sequence | pattern
+/// ```
+/// The pattern is the sequence number plus the offset in every byte, so
+/// that errors not caught by the CRC are found.
+fn pack_test(group: u8, sequence: u32, buffer: &mut PackageBuffer) {
+    DatagramHeader::new(group, DatagramProtocol::LinkTest, TEST_SIZE).pack(buffer);
+    let slice = &mut buffer[datagram::HEADER_SIZE..];
+    LittleEndian::write_u32(&mut slice[0..=3], sequence);
+    for (offset, byte) in slice.iter_mut().enumerate().skip(4) {
+        *byte = (sequence as u8).wrapping_add(offset as u8);
+    }
+}
+
+/// Unpack a link test datagram, returns the sequence number and if the
+/// pattern is intact
+fn unpack_test(group: u8, buffer: &PackageBuffer) -> Option<(u32, bool)> {
+    let header = DatagramHeader::unpack(buffer);
+    if header.protocol() != DatagramProtocol::LinkTest
+        || header.version() != datagram::VERSION
+        || header.group() != group
+        || header.payload_length() != TEST_SIZE
+    {
+        return None;
+    }
+    let slice = &buffer[datagram::HEADER_SIZE..];
+    let sequence = LittleEndian::read_u32(&slice[0..=3]);
+    let intact = slice.iter().enumerate().skip(4)
+        .all(|(offset, byte)| *byte == (sequence as u8).wrapping_add(offset as u8));
+    Some((sequence, intact))
+}
+
+/// # Link test sender
+///
+/// Sends numbered datagrams for a `LinkTestReceiver` to count.
+pub struct LinkTestSender {
+    group: u8,
+    sequence: u32,
+}
+
+impl LinkTestSender {
+    pub fn new(group: u8) -> Self {
+        LinkTestSender { group, sequence: 0 }
+    }
+
+    /// Number of datagrams sent
+    pub fn sent(&self) -> u32 {
+        self.sequence
+    }
+
+    /// Send the next datagram, returns `false` if it could not be sent
+    pub fn send<L: Link>(&mut self, link: &mut L) -> bool {
+        let mut frame = [0u8; MAX_PACKAGE_SIZE];
+        pack_test(self.group, self.sequence, &mut frame);
+        self.sequence = self.sequence.wrapping_add(1);
+        link.transmit(&frame)
+    }
+}
+
+/// Link test results
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Report {
+    /// Datagrams received intact
+    pub received: u32,
+    /// Datagrams never received, counted from the sequence numbers
+    pub lost: u32,
+    /// Datagrams received with a CRC error
+    pub crc_errors: u32,
+    /// Datagrams received with a valid CRC and a broken pattern
+    pub corrupt: u32,
+    /// Mean, lowest and highest signal strength in dBm, if measured
+    pub rssi: Option<(i8, i8, i8)>,
+}
+
+impl Report {
+    /// Number of datagrams sent during the test
+    pub fn sent(&self) -> u32 {
+        self.received + self.corrupt + self.lost
+    }
+
+    /// Datagrams not received intact, in parts per thousand
+    pub fn error_rate(&self) -> u32 {
+        let sent = self.sent();
+        if sent == 0 {
+            return 0;
+        }
+        ((u64::from(self.lost + self.corrupt) * 1000) / u64::from(sent)) as u32
+    }
+
+    /// The share of datagrams received intact on the LED matrix, one LED
+    /// for every 4 %
+    pub fn image(&self) -> Image {
+        let mut image = [[0u8; 5]; 5];
+        if self.sent() == 0 {
+            return image;
+        }
+        let lit = ((1000 - self.error_rate()) * 25 / 1000) as usize;
+        for index in 0..lit {
+            image[4 - index / 5][index % 5] = 0xff;
+        }
+        image
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "sent {} received {} lost {} crc {} corrupt {} per {}.{}%",
+            self.sent(), self.received, self.lost, self.crc_errors, self.corrupt,
+            self.error_rate() / 10, self.error_rate() % 10)?;
+        if let Some((mean, min, max)) = self.rssi {
+            write!(f, " rssi {} ({} .. {}) dBm", mean, min, max)?;
+        }
+        Ok(())
+    }
+}
+
+/// # Link test receiver
+///
+/// Counts the datagrams of a `LinkTestSender`. The test starts over when
+/// the sender starts over.
+pub struct LinkTestReceiver {
+    group: u8,
+    next: Option<u32>,
+    crc_start: u32,
+    report: Report,
+    rssi_sum: i64,
+    rssi_samples: u32,
+}
+
+impl LinkTestReceiver {
+    pub fn new(group: u8) -> Self {
+        LinkTestReceiver {
+            group,
+            next: None,
+            crc_start: 0,
+            report: Report::default(),
+            rssi_sum: 0,
+            rssi_samples: 0,
+        }
+    }
+
+    /// The results so far
+    pub fn report(&self) -> Report {
+        self.report
+    }
+
+    /// Receive all datagrams from the link
+    ///
+    /// Datagrams of other protocols are dropped.
+    pub fn poll<L: Link>(&mut self, link: &mut L) {
+        while let Some(frame) = link.receive() {
+            self.handle(&frame, link.rssi(), link.crc_errors());
+        }
+        if self.next.is_some() {
+            self.report.crc_errors = link.crc_errors().wrapping_sub(self.crc_start);
+        }
+    }
+
+    /// Handle a received datagram with signal strength `rssi`, `crc_errors`
+    /// is the number of CRC errors counted by the link so far
+    ///
+    /// Returns `true` if the datagram was a link test datagram.
+    pub fn handle(&mut self, frame: &PackageBuffer, rssi: Option<i8>, crc_errors: u32) -> bool {
+        let (sequence, intact) = match unpack_test(self.group, frame) {
+            Some(test) => test,
+            None => return false,
+        };
+        match self.next {
+            Some(next) if sequence >= next => self.report.lost += sequence - next,
+            Some(_) => {
+                // The sender started over
+                self.crc_start = crc_errors;
+                self.report = Report::default();
+                self.rssi_sum = 0;
+                self.rssi_samples = 0;
+            }
+            // CRC errors are counted from the first datagram
+            None => self.crc_start = crc_errors,
+        }
+        self.report.crc_errors = crc_errors.wrapping_sub(self.crc_start);
+        self.next = Some(sequence.wrapping_add(1));
+        if intact {
+            self.report.received += 1;
+        }
+        else {
+            self.report.corrupt += 1;
+        }
+        if let Some(rssi) = rssi {
+            self.rssi_sum += i64::from(rssi);
+            self.rssi_samples += 1;
+            let mean = (self.rssi_sum / i64::from(self.rssi_samples)) as i8;
+            self.report.rssi = Some(match self.report.rssi {
+                Some((_, min, max)) => (mean, min.min(rssi), max.max(rssi)),
+                None => (rssi, rssi, rssi),
+            });
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::radio::DEFAULT_CHANNEL;
+    use crate::sim::{SimLink, Simulator};
+    use crate::time::Instant;
+
+    const GROUP: u8 = 7;
+
+    /// A simulator link which counts CRC errors
+    struct CrcLink<'a> {
+        link: SimLink<'a, 2>,
+        crc_errors: u32,
+    }
+
+    impl Link for CrcLink<'_> {
+        fn transmit(&mut self, frame: &PackageBuffer) -> bool {
+            self.link.transmit(frame)
+        }
+
+        fn receive(&mut self) -> Option<PackageBuffer> {
+            self.link.receive()
+        }
+
+        fn now(&self) -> Instant {
+            self.link.now()
+        }
+
+        fn rssi(&self) -> Option<i8> {
+            self.link.rssi()
+        }
+
+        fn crc_errors(&self) -> u32 {
+            self.crc_errors
+        }
+    }
+
+    /// Send link test datagram `sequence` from node 0, with a broken
+    /// pattern if `corrupt`
+    fn send(sim: &mut Simulator<2>, sequence: u32, corrupt: bool) {
+        let mut frame = [0u8; MAX_PACKAGE_SIZE];
+        pack_test(GROUP, sequence, &mut frame);
+        if corrupt {
+            frame[MAX_PACKAGE_SIZE - 1] ^= 0x10;
+        }
+        assert!(sim.link(0).transmit(&frame));
+    }
+
+    fn poll(sim: &mut Simulator<2>, receiver: &mut LinkTestReceiver, crc_errors: u32) {
+        receiver.poll(&mut CrcLink { link: sim.link(1), crc_errors });
+    }
+
+    #[test]
+    fn bins_are_clamped() {
+        assert_eq!(bin(i8::MIN), 0);
+        assert_eq!(bin(HISTOGRAM_FLOOR - 1), 0);
+        assert_eq!(bin(HISTOGRAM_FLOOR), 0);
+        assert_eq!(bin(HISTOGRAM_FLOOR + BIN_WIDTH - 1), 0);
+        assert_eq!(bin(HISTOGRAM_FLOOR + BIN_WIDTH), 1);
+        assert_eq!(bin(-31), BINS - 2);
+        assert_eq!(bin(-30), BINS - 1);
+        assert_eq!(bin(i8::MAX), BINS - 1);
+    }
+
+    #[test]
+    fn quietest_channel() {
+        let mut scan = Scan::new();
+        assert_eq!(scan.quietest(), DEFAULT_CHANNEL);
+        // As quiet, the lowest channel wins
+        scan.add(60, -80);
+        scan.add(50, -80);
+        assert_eq!(scan.quietest(), 50);
+        // The same mean, the lowest peak wins
+        let mut scan = Scan::new();
+        for (channel, rssi) in [(20, [-90, -70]), (30, [-95, -65]), (40, [-85, -75])] {
+            scan.add(channel, rssi[0]);
+            scan.add(channel, rssi[1]);
+            assert_eq!(scan.mean(channel), Some(-80));
+        }
+        assert_eq!(scan.quietest(), 40);
+        scan.add(10, -100);
+        assert_eq!(scan.quietest(), 10);
+        // Out of range channels are ignored
+        scan.add(CHANNELS as u8, -127);
+        assert_eq!(scan.samples(CHANNELS as u8), 0);
+        assert_eq!(scan.quietest(), 10);
+    }
+
+    #[test]
+    fn report_error_rate_and_image() {
+        let report = Report::default();
+        assert_eq!(report.error_rate(), 0);
+        assert_eq!(report.image(), [[0; 5]; 5]);
+        let report = Report { received: 990, lost: 7, corrupt: 3, ..Report::default() };
+        assert_eq!(report.sent(), 1000);
+        assert_eq!(report.error_rate(), 10);
+        let report = Report { received: 50, lost: 40, corrupt: 10, ..Report::default() };
+        assert_eq!(report.error_rate(), 500);
+        // 12 LEDs, from the bottom left
+        assert_eq!(report.image(), [
+            [0; 5],
+            [0; 5],
+            [0xff, 0xff, 0, 0, 0],
+            [0xff; 5],
+            [0xff; 5],
+        ]);
+        let report = Report { received: 10, ..Report::default() };
+        assert_eq!(report.image(), [[0xff; 5]; 5]);
+        let report = Report { lost: 10, ..Report::default() };
+        assert_eq!(report.error_rate(), 1000);
+        assert_eq!(report.image(), [[0; 5]; 5]);
+    }
+
+    #[test]
+    fn counts_lost_and_corrupt_datagrams() {
+        let mut sim = Simulator::<2>::new(1);
+        let mut receiver = LinkTestReceiver::new(GROUP);
+        // CRC errors before the first datagram are not counted
+        poll(&mut sim, &mut receiver, 4);
+        assert_eq!(receiver.report(), Report::default());
+        for sequence in 10..20 {
+            if sequence == 13 || sequence == 14 {
+                continue;
+            }
+            send(&mut sim, sequence, sequence == 17);
+        }
+        // Another group
+        let mut frame = [0u8; MAX_PACKAGE_SIZE];
+        pack_test(GROUP + 1, 20, &mut frame);
+        sim.link(0).transmit(&frame);
+        sim.set_rssi(0, 1, -50);
+        send(&mut sim, 21, false);
+        poll(&mut sim, &mut receiver, 7);
+        let report = receiver.report();
+        assert_eq!(report.received, 8);
+        assert_eq!(report.corrupt, 1);
+        assert_eq!(report.lost, 3);
+        assert_eq!(report.crc_errors, 0);
+        assert_eq!(report.sent(), 12);
+        assert_eq!(report.rssi, Some((-58, -60, -50)));
+        poll(&mut sim, &mut receiver, 10);
+        assert_eq!(receiver.report().crc_errors, 3);
+    }
+
+    #[test]
+    fn starts_over_when_the_sender_does() {
+        let mut sim = Simulator::<2>::new(1);
+        let mut receiver = LinkTestReceiver::new(GROUP);
+        for sequence in 0..5 {
+            send(&mut sim, sequence, false);
+        }
+        poll(&mut sim, &mut receiver, 1);
+        poll(&mut sim, &mut receiver, 3);
+        assert_eq!(receiver.report().received, 5);
+        assert_eq!(receiver.report().crc_errors, 2);
+        // More CRC errors, then the sender starts over
+        send(&mut sim, 0, false);
+        send(&mut sim, 2, false);
+        poll(&mut sim, &mut receiver, 6);
+        let report = receiver.report();
+        assert_eq!((report.received, report.lost, report.crc_errors), (2, 1, 0));
+        poll(&mut sim, &mut receiver, 8);
+        assert_eq!(receiver.report().crc_errors, 2);
+    }
+}
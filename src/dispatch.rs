@@ -0,0 +1,175 @@
+//! Protocol dispatcher
+//!
+//! Several protocols share the radio, MakeCode packages, EventBus and
+//! application protocols with their own numbers. A `Dispatcher` reads the
+//! protocol number of each received datagram and hands the datagram to the
+//! handler registered for it, datagrams of other protocols go to the
+//! default handler, if any.
+//!
+//! ```notrust
+//! let mut packages = |header: &DatagramHeader, frame: &[u8]| { ... };
+//! let mut custom = |header: &DatagramHeader, frame: &[u8]| { ... };
+//! let mut dispatcher: Dispatcher<4> = Dispatcher::new();
+//! dispatcher.register(DatagramProtocol::Datagram, &mut packages);
+//! dispatcher.register(DatagramProtocol::Other(0x42), &mut custom);
+//! loop {
+//!     dispatcher.poll(&mut radio);
+//!     ...
+//! }
+//! ```
+
+use crate::datagram::{DatagramHeader, DatagramProtocol, HEADER_SIZE};
+use crate::link::Link;
+
+/// # Handler
+///
+/// Takes the datagrams of a protocol. `frame[0]` is the datagram length as
+/// in `datagram::DatagramHeader`, the payload starts at
+/// `datagram::HEADER_SIZE`.
+pub trait Handler {
+    /// Handle a received datagram
+    fn handle(&mut self, header: &DatagramHeader, frame: &[u8]);
+}
+
+impl<F: FnMut(&DatagramHeader, &[u8])> Handler for F {
+    fn handle(&mut self, header: &DatagramHeader, frame: &[u8]) {
+        self(header, frame)
+    }
+}
+
+/// # Dispatcher
+///
+/// Routes datagrams to up to `HANDLERS` handlers by protocol number. The
+/// protocol number is the raw byte of the header, so numbers this crate
+/// does not know reach their handlers as `DatagramProtocol::Other`.
+pub struct Dispatcher<'a, const HANDLERS: usize> {
+    handlers: [Option<(u8, &'a mut dyn Handler)>; HANDLERS],
+    default: Option<&'a mut dyn Handler>,
+    /// Datagrams without a handler
+    unhandled: u32,
+}
+
+impl<'a, const HANDLERS: usize> Dispatcher<'a, HANDLERS> {
+    pub fn new() -> Self {
+        Dispatcher {
+            handlers: core::array::from_fn(|_| None),
+            default: None,
+            unhandled: 0,
+        }
+    }
+
+    /// Register `handler` for `protocol`, replacing the handler registered
+    /// before, returns `false` if all handlers are taken
+    ///
+    /// `Other` with an assigned number, `Other(3)` for `Reliable` for
+    /// example, is refused and returns `false` too, register the assigned
+    /// protocol instead.
+    pub fn register(&mut self, protocol: DatagramProtocol, handler: &'a mut dyn Handler) -> bool {
+        if protocol.is_assigned_other() {
+            return false;
+        }
+        let id = protocol.id();
+        let index = self.handlers.iter()
+            .position(|entry| matches!(entry, Some((entry_id, _)) if *entry_id == id))
+            .or_else(|| self.handlers.iter().position(|entry| entry.is_none()));
+        match index {
+            Some(index) => {
+                self.handlers[index] = Some((id, handler));
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Remove the handler of `protocol`, its datagrams go to the default
+    /// handler again
+    pub fn unregister(&mut self, protocol: DatagramProtocol) {
+        let id = protocol.id();
+        for entry in self.handlers.iter_mut() {
+            if matches!(entry, Some((entry_id, _)) if *entry_id == id) {
+                *entry = None;
+            }
+        }
+    }
+
+    /// Set the handler of datagrams with no registered protocol
+    pub fn set_default(&mut self, handler: &'a mut dyn Handler) {
+        self.default = Some(handler);
+    }
+
+    /// Number of datagrams that had no handler, not even a default one
+    pub fn unhandled(&self) -> u32 {
+        self.unhandled
+    }
+
+    /// Hand `frame` to the handler of its protocol, returns `false` if there
+    /// is none or the datagram is too short to have a protocol
+    pub fn dispatch(&mut self, frame: &[u8]) -> bool {
+        if frame.len() < HEADER_SIZE || usize::from(frame[0]) < HEADER_SIZE - 1 {
+            self.unhandled = self.unhandled.wrapping_add(1);
+            return false;
+        }
+        let header = DatagramHeader::unpack(frame);
+        let id = frame[3];
+        let handler = self.handlers.iter_mut()
+            .filter_map(|entry| entry.as_mut())
+            .find(|(entry_id, _)| *entry_id == id)
+            .map(|(_, handler)| handler)
+            .or(self.default.as_mut());
+        match handler {
+            Some(handler) => {
+                handler.handle(&header, frame);
+                true
+            }
+            None => {
+                self.unhandled = self.unhandled.wrapping_add(1);
+                false
+            }
+        }
+    }
+
+    /// Dispatch all datagrams received on `link`, returns their number
+    pub fn poll<L: Link>(&mut self, link: &mut L) -> usize {
+        let mut received = 0;
+        while let Some(frame) = link.receive() {
+            self.dispatch(&frame);
+            received += 1;
+        }
+        received
+    }
+}
+
+impl<'a, const HANDLERS: usize> Default for Dispatcher<'a, HANDLERS> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn routes_by_protocol_number() {
+        let mut reliable = 0;
+        let mut custom = 0;
+        let mut other = 0;
+        {
+            let mut reliable = |_: &DatagramHeader, _: &[u8]| reliable += 1;
+            let mut custom = |_: &DatagramHeader, _: &[u8]| custom += 1;
+            let mut other = |_: &DatagramHeader, _: &[u8]| other += 1;
+            let mut dispatcher: Dispatcher<2> = Dispatcher::new();
+            assert!(dispatcher.register(DatagramProtocol::Reliable, &mut reliable));
+            assert!(dispatcher.register(DatagramProtocol::Other(0x42), &mut custom));
+            assert!(dispatcher.dispatch(&[4, 1, 0, 3, 0]));
+            assert!(dispatcher.dispatch(&[4, 1, 0, 0x42, 0]));
+            assert!(!dispatcher.dispatch(&[4, 1, 0, 0x43, 0]));
+            assert!(!dispatcher.dispatch(&[2, 1, 0]));
+            assert_eq!(dispatcher.unhandled(), 2);
+            // Other(3) would take over Reliable
+            assert!(!dispatcher.register(DatagramProtocol::Other(3), &mut other));
+            assert!(dispatcher.dispatch(&[4, 1, 0, 3, 0]));
+        }
+        assert_eq!((reliable, custom, other), (2, 1, 0));
+    }
+}
@@ -0,0 +1,831 @@
+//! Enhanced ShockBurst
+//!
+//! The packet format of the nRF24L01+, with dynamic payload length, the
+//! 9-bit packet control field, automatic acknowledgements with payload and
+//! retransmissions, to talk to nRF24L01+ modules.
+//!
+//! The on-air format and the transmitter and receiver state machines do not
+//! use the radio, so they can be checked on the host. `EsbRadio` runs them
+//! on the radio.
+//!
+//! ```notrust
+//! static mut BUFFERS: EsbBuffers = Buffers::new();
+//!
+//! let (radio, _) = board.radio.free();
+//! let mut esb = EsbRadio::new(radio, unsafe { &mut BUFFERS }, Config::default());
+//! match esb.send(&Payload::new(0, b"forward").unwrap(), false) {
+//!     Ok(Some(ack)) => ...,
+//!     Ok(None) => ...,
+//!     Err(TxError::MaxRetransmits) => ...,
+//! }
+//! ```
+//!
+//! ## Reference
+//!
+//! * nRF24L01+ Product Specification 1.0, chapter 7
+//! * <https://infocenter.nordicsemi.com/topic/sdk_nrf5_v17.1.0/esb_users_guide.html>
+
+use core::sync::atomic::compiler_fence;
+use core::sync::atomic::Ordering;
+
+use nrf51::RADIO;
+
+use crate::radio::Buffers;
+use crate::time::{self, Duration};
+
+/// Largest payload
+pub const MAX_PAYLOAD: usize = 32;
+/// Size of the radio buffers, the length, the S1 byte and the largest
+/// payload
+pub const BUFFER_SIZE: usize = 2 + MAX_PAYLOAD;
+/// Number of pipes, addresses the radio receives on
+pub const PIPES: usize = 8;
+/// Largest packet on air in bytes, preamble, a 5 byte address, the packet
+/// control field, the payload and a 2 byte CRC
+pub const ON_AIR_SIZE: usize = 42;
+
+/// The radio buffers for ESB, larger than the MakeCode buffers
+pub type EsbBuffers = Buffers<BUFFER_SIZE>;
+
+/// Air data rate
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Bitrate {
+    Kbit250,
+    Mbit1,
+    Mbit2,
+}
+
+/// CRC length, the nRF24L01+ CRCO bit
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CrcLength {
+    /// 8-bit CRC, polynomial x^8 + x^2 + x + 1, preset 0xff
+    One,
+    /// 16-bit CRC, polynomial x^16 + x^12 + x^5 + 1, preset 0xffff
+    Two,
+}
+
+impl CrcLength {
+    fn bits(self) -> usize {
+        match self {
+            CrcLength::One => 8,
+            CrcLength::Two => 16,
+        }
+    }
+
+    fn preset(self) -> u32 {
+        match self {
+            CrcLength::One => 0xff,
+            CrcLength::Two => 0xffff,
+        }
+    }
+
+    fn polynomial(self) -> u32 {
+        match self {
+            CrcLength::One => 0x07,
+            CrcLength::Two => 0x1021,
+        }
+    }
+}
+
+/// # ESB configuration
+///
+/// Addresses are given least significant byte first, as they are written
+/// to the nRF24L01+ address registers. Pipes 2 to 7 share all but the
+/// least significant byte with pipe 1. The defaults are the reset values of
+/// the nRF24L01+, except for pipes 6 and 7 which it does not have.
+#[derive(Clone, Copy, Debug)]
+pub struct Config {
+    /// Address length in bytes, 3 to 5
+    pub address_length: usize,
+    /// Address of pipe 0
+    pub pipe0: [u8; 5],
+    /// Address of pipe 1
+    pub pipe1: [u8; 5],
+    /// Least significant address byte of pipe 2 to 7
+    pub prefixes: [u8; PIPES - 2],
+    /// Pipes to receive on, bit `n` for pipe `n`
+    pub rx_pipes: u8,
+    /// Channel, the frequency is 2400 MHz + `channel` MHz
+    pub channel: u8,
+    pub bitrate: Bitrate,
+    pub crc: CrcLength,
+    /// Time to wait for an acknowledgement in µs, the nRF24L01+ ARD
+    pub retransmit_delay: u16,
+    /// Number of retransmissions, the nRF24L01+ ARC
+    pub retransmit_count: u8,
+}
+
+impl Config {
+    /// Address of `pipe`, the unused bytes of short addresses are 0
+    pub fn address(&self, pipe: usize) -> [u8; 5] {
+        let mut address = match pipe {
+            0 => self.pipe0,
+            1 => self.pipe1,
+            _ => {
+                let mut address = self.pipe1;
+                address[0] = self.prefixes[pipe - 2];
+                address
+            }
+        };
+        for byte in address.iter_mut().skip(self.address_length) {
+            *byte = 0;
+        }
+        address
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            address_length: 5,
+            pipe0: [0xe7; 5],
+            pipe1: [0xc2; 5],
+            prefixes: [0xc3, 0xc4, 0xc5, 0xc6, 0xc7, 0xc8],
+            rx_pipes: 0b0000_0011,
+            channel: 2,
+            bitrate: Bitrate::Mbit2,
+            crc: CrcLength::One,
+            retransmit_delay: 250,
+            retransmit_count: 3,
+        }
+    }
+}
+
+/// # Packet control field
+///
+/// ```notrust
+/// | 8 ... 3        | 2 ... 1 | 0
+/// ------------------------------------
+/// | payload length | PID     | NO_ACK
+/// ```
+/// The packet identity (PID) is counted for every new packet, so that the
+/// receiver can drop retransmissions. A packet with NO_ACK set is not
+/// acknowledged.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Pcf {
+    pub length: u8,
+    pub pid: u8,
+    pub no_ack: bool,
+}
+
+impl Pcf {
+    /// The 9 bits of the field
+    pub fn to_bits(&self) -> u16 {
+        (u16::from(self.length & 0x3f) << 3) | (u16::from(self.pid & 0x03) << 1)
+            | u16::from(self.no_ack)
+    }
+
+    /// The field from its 9 bits
+    pub fn from_bits(bits: u16) -> Pcf {
+        Pcf {
+            length: ((bits >> 3) & 0x3f) as u8,
+            pid: ((bits >> 1) & 0x03) as u8,
+            no_ack: bits & 0x01 != 0,
+        }
+    }
+}
+
+/// A payload and the pipe it is sent or received on
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Payload {
+    pipe: u8,
+    length: u8,
+    data: [u8; MAX_PAYLOAD],
+}
+
+impl Payload {
+    /// Create a payload, returns `None` if `data` is too large or `pipe`
+    /// does not exist
+    pub fn new(pipe: u8, data: &[u8]) -> Option<Payload> {
+        if data.len() > MAX_PAYLOAD || usize::from(pipe) >= PIPES {
+            return None;
+        }
+        let mut payload = Payload { pipe, length: data.len() as u8, data: [0u8; MAX_PAYLOAD] };
+        payload.data[..data.len()].copy_from_slice(data);
+        Some(payload)
+    }
+    /// The pipe
+    pub fn pipe(&self) -> u8 {
+        self.pipe
+    }
+    /// The payload data
+    pub fn data(&self) -> &[u8] {
+        &self.data[..usize::from(self.length)]
+    }
+}
+
+fn bit(buffer: &[u8], index: usize) -> bool {
+    buffer[index / 8] & (0x80 >> (index % 8)) != 0
+}
+
+/// Read `count` bits at bit `index`, most significant bit first
+fn read_bits(buffer: &[u8], index: usize, count: usize) -> u32 {
+    (index..index + count).fold(0, |value, index| (value << 1) | u32::from(bit(buffer, index)))
+}
+
+/// Write the `count` lowest bits of `value` at bit `index`, most
+/// significant bit first
+fn write_bits(buffer: &mut [u8], index: usize, value: u32, count: usize) {
+    for offset in 0..count {
+        let mask = 0x80 >> ((index + offset) % 8);
+        if (value >> (count - 1 - offset)) & 1 != 0 {
+            buffer[(index + offset) / 8] |= mask;
+        }
+        else {
+            buffer[(index + offset) / 8] &= !mask;
+        }
+    }
+}
+
+/// CRC of the bits `start` to `end` of `buffer`, most significant bit first
+pub fn crc(buffer: &[u8], start: usize, end: usize, length: CrcLength) -> u16 {
+    let width = length.bits();
+    let mask = (1u32 << width) - 1;
+    let mut crc = length.preset();
+    for index in start..end {
+        let feedback = (crc >> (width - 1)) & 1 != 0;
+        crc = (crc << 1) & mask;
+        if feedback != bit(buffer, index) {
+            crc ^= length.polynomial();
+        }
+    }
+    crc as u16
+}
+
+/// # On-air packet
+///
+/// ```notrust
+/// | 1 byte   | 3 ... 5 bytes | 9 bits | 0 ... 32 bytes | 1 ... 2 bytes
+/// ----------------------------------------------------------------------
+/// | preamble | address       | PCF    | payload        | CRC
+/// ```
+/// All fields are sent most significant bit first and the address most
+/// significant byte first. The preamble is 0xaa when the address starts
+/// with a 1 and 0x55 otherwise. The CRC covers the address, the PCF and
+/// the payload.
+///
+/// Writes the packet to `buffer` and returns its length in bits, or 0 if
+/// the address or the payload has the wrong length. The length in `pcf` is
+/// replaced with the payload length.
+pub fn encode(address: &[u8], pcf: Pcf, payload: &[u8], length: CrcLength,
+    buffer: &mut [u8; ON_AIR_SIZE]) -> usize
+{
+    if address.len() < 3 || address.len() > 5 || payload.len() > MAX_PAYLOAD {
+        return 0;
+    }
+    *buffer = [0u8; ON_AIR_SIZE];
+    let first = address[address.len() - 1] & 0x80 != 0;
+    write_bits(buffer, 0, if first { 0xaa } else { 0x55 }, 8);
+    let mut index = 8;
+    for byte in address.iter().rev() {
+        write_bits(buffer, index, u32::from(*byte), 8);
+        index += 8;
+    }
+    let pcf = Pcf { length: payload.len() as u8, ..pcf };
+    write_bits(buffer, index, u32::from(pcf.to_bits()), 9);
+    index += 9;
+    for byte in payload {
+        write_bits(buffer, index, u32::from(*byte), 8);
+        index += 8;
+    }
+    let checksum = crc(buffer, 8, index, length);
+    write_bits(buffer, index, u32::from(checksum), length.bits());
+    index + length.bits()
+}
+
+/// Decode a packet starting with the preamble in `buffer`, see `encode`
+///
+/// Returns the address, least significant byte first, and the PCF, and
+/// writes the payload to `payload`. Returns `None` if the packet is cut
+/// short or the CRC does not match.
+pub fn decode(buffer: &[u8], address_length: usize, length: CrcLength,
+    payload: &mut [u8; MAX_PAYLOAD]) -> Option<([u8; 5], Pcf)>
+{
+    if !(3..=5).contains(&address_length) {
+        return None;
+    }
+    let available = buffer.len() * 8;
+    let mut index = 8;
+    let mut address = [0u8; 5];
+    if index + address_length * 8 + 9 > available {
+        return None;
+    }
+    for byte in address[..address_length].iter_mut().rev() {
+        *byte = read_bits(buffer, index, 8) as u8;
+        index += 8;
+    }
+    let pcf = Pcf::from_bits(read_bits(buffer, index, 9) as u16);
+    index += 9;
+    let size = usize::from(pcf.length);
+    if size > MAX_PAYLOAD || index + size * 8 + length.bits() > available {
+        return None;
+    }
+    for byte in payload[..size].iter_mut() {
+        *byte = read_bits(buffer, index, 8) as u8;
+        index += 8;
+    }
+    let checksum = crc(buffer, 8, index, length);
+    if read_bits(buffer, index, length.bits()) != u32::from(checksum) {
+        return None;
+    }
+    Some((address, pcf))
+}
+
+/// Transmission errors
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TxError {
+    /// No acknowledgement was received after the last retransmission
+    MaxRetransmits,
+}
+
+/// # Primary transmitter
+///
+/// Numbers the packets of each pipe and counts the retransmissions.
+pub struct Ptx {
+    retransmit_count: u8,
+    pids: [u8; PIPES],
+    attempts: u8,
+}
+
+impl Ptx {
+    pub fn new(retransmit_count: u8) -> Self {
+        Ptx { retransmit_count, pids: [0; PIPES], attempts: 0 }
+    }
+
+    /// Start sending a new packet with `length` bytes on `pipe`, returns its
+    /// PCF
+    pub fn start(&mut self, pipe: u8, length: u8, no_ack: bool) -> Pcf {
+        let pid = &mut self.pids[usize::from(pipe) % PIPES];
+        *pid = (*pid + 1) & 0x03;
+        self.attempts = 1;
+        Pcf { length, pid: *pid, no_ack }
+    }
+
+    /// No acknowledgement was received, returns `true` if the packet shall
+    /// be sent again
+    pub fn retransmit(&mut self) -> bool {
+        if self.attempts > self.retransmit_count {
+            return false;
+        }
+        self.attempts += 1;
+        true
+    }
+
+    /// Number of times the current packet has been sent
+    pub fn attempts(&self) -> u8 {
+        self.attempts
+    }
+}
+
+/// What to do with a packet received by a `Prx`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Reception {
+    /// The packet has not been received before
+    pub new: bool,
+    /// Send an acknowledgement with this PCF and payload
+    pub ack: Option<(Pcf, Option<Payload>)>,
+}
+
+/// # Primary receiver
+///
+/// Drops retransmitted packets and keeps the payloads to send with the
+/// acknowledgements of each pipe. A packet is a retransmission when it has
+/// the PID and the CRC of the packet received before on the pipe.
+pub struct Prx {
+    last: [Option<(u8, u16)>; PIPES],
+    queued: [Option<Payload>; PIPES],
+    /// Acknowledgement payload sent with the last packet, sent again when
+    /// the packet is retransmitted
+    sent: [Option<Payload>; PIPES],
+}
+
+impl Prx {
+    pub const fn new() -> Self {
+        Prx { last: [None; PIPES], queued: [None; PIPES], sent: [None; PIPES] }
+    }
+
+    /// Queue a payload for the acknowledgement of the next packet on its
+    /// pipe, returns `false` if a payload is already queued
+    pub fn set_ack_payload(&mut self, payload: Payload) -> bool {
+        let queued = &mut self.queued[usize::from(payload.pipe)];
+        if queued.is_some() {
+            return false;
+        }
+        *queued = Some(payload);
+        true
+    }
+
+    /// Handle a packet received on `pipe` with `pcf` and `crc`
+    pub fn receive(&mut self, pipe: u8, pcf: Pcf, crc: u16) -> Reception {
+        let pipe = usize::from(pipe) % PIPES;
+        let new = self.last[pipe] != Some((pcf.pid, crc));
+        if new {
+            self.last[pipe] = Some((pcf.pid, crc));
+            self.sent[pipe] = None;
+        }
+        let ack = if pcf.no_ack {
+            None
+        }
+        else {
+            if new {
+                self.sent[pipe] = self.queued[pipe].take();
+            }
+            let payload = self.sent[pipe];
+            let length = payload.map_or(0, |payload| payload.length);
+            Some((Pcf { length, pid: pcf.pid, no_ack: false }, payload))
+        };
+        Reception { new, ack }
+    }
+}
+
+impl Default for Prx {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Base address register value of `address`, the base bytes sent most
+/// significant bit first and short bases in the most significant bytes
+fn base_address(address: &[u8; 5], length: usize) -> u32 {
+    let mut base = [0u8; 4];
+    base[..length - 1].copy_from_slice(&address[1..length]);
+    u32::from_be_bytes([
+        base[0].reverse_bits(), base[1].reverse_bits(),
+        base[2].reverse_bits(), base[3].reverse_bits(),
+    ])
+}
+
+/// # ESB radio
+///
+/// The radio in Enhanced ShockBurst mode. Transmissions wait for the
+/// acknowledgement and retransmit, received packets are acknowledged. The
+/// largest packet, a length byte, S1 and `MAX_PAYLOAD` bytes, does not fit
+/// the MakeCode `radio::Buffers`, so the radio takes `EsbBuffers`.
+///
+/// Timing uses the RTC1 time, see `time::Clock`. `try_receive` must be
+/// called from the RADIO interrupt to acknowledge in time.
+pub struct EsbRadio {
+    radio: RADIO,
+    buffers: &'static mut EsbBuffers,
+    config: Config,
+    ptx: Ptx,
+    prx: Prx,
+}
+
+impl EsbRadio {
+    /// Configure the radio for ESB, the radio shall be disabled, as it is
+    /// after `Radio::free`
+    pub fn new(radio: RADIO, buffers: &'static mut EsbBuffers, config: Config) -> Self {
+        assert!(radio.state.read().state().is_disabled());
+        assert!((3..=5).contains(&config.address_length));
+
+        match config.bitrate {
+            Bitrate::Kbit250 => radio.mode.write(|w| w.mode().nrf_250kbit()),
+            Bitrate::Mbit1 => radio.mode.write(|w| w.mode().nrf_1mbit()),
+            Bitrate::Mbit2 => radio.mode.write(|w| w.mode().nrf_2mbit()),
+        }
+        radio.txpower.write(|w| w.txpower()._0d_bm());
+
+        unsafe {
+            // 6-bit length and 3-bit S1, the PID and NO_ACK
+            radio.pcnf0.write(|w| w.lflen().bits(6).s0len().clear_bit().s1len().bits(3));
+            // Most significant bit first, no whitening
+            radio.pcnf1.write(|w| w
+                .maxlen().bits(MAX_PAYLOAD as u8)
+                .balen().bits(config.address_length as u8 - 1)
+                .endian().big()
+                .whiteen().disabled()
+            );
+            match config.crc {
+                CrcLength::One => radio.crccnf.write(|w| w.len().one().skipaddr().include()),
+                CrcLength::Two => radio.crccnf.write(|w| w.len().two().skipaddr().include()),
+            }
+            radio.crcinit.write(|w| w.bits(config.crc.preset()));
+            radio.crcpoly.write(|w| w.bits(config.crc.polynomial()));
+
+            radio.base0.write(|w| w.bits(base_address(&config.pipe0, config.address_length)));
+            radio.base1.write(|w| w.bits(base_address(&config.pipe1, config.address_length)));
+            let prefix = |pipe: usize| u32::from(config.address(pipe)[0].reverse_bits());
+            radio.prefix0.write(|w| w.bits(
+                prefix(0) | prefix(1) << 8 | prefix(2) << 16 | prefix(3) << 24));
+            radio.prefix1.write(|w| w.bits(
+                prefix(4) | prefix(5) << 8 | prefix(6) << 16 | prefix(7) << 24));
+            radio.frequency.write(|w| w.frequency().bits(config.channel));
+        }
+
+        radio.shorts.write(|w| w
+            .ready_start().enabled()
+            .end_disable().enabled()
+        );
+
+        EsbRadio {
+            radio,
+            buffers,
+            config,
+            ptx: Ptx::new(config.retransmit_count),
+            prx: Prx::new(),
+        }
+    }
+
+    /// Stop the radio and release the peripheral and the buffers
+    pub fn free(mut self) -> (RADIO, &'static mut EsbBuffers) {
+        self.disable();
+        compiler_fence(Ordering::AcqRel);
+        (self.radio, self.buffers)
+    }
+
+    /// The configuration
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    /// Disable the radio, this stops any ongoing DMA transfer
+    fn disable(&mut self) {
+        self.radio.events_disabled.reset();
+        self.radio.tasks_disable.write(|w| unsafe { w.bits(1) });
+        while self.radio.events_disabled.read().bits() == 0 {}
+    }
+
+    /// Send a packet and wait for it to be sent
+    fn transmit(&mut self, pipe: u8, pcf: Pcf, data: &[u8]) {
+        self.disable();
+        self.buffers.tx[0] = pcf.length;
+        self.buffers.tx[1] = (pcf.to_bits() & 0x07) as u8;
+        self.buffers.tx[2..2 + data.len()].copy_from_slice(data);
+        compiler_fence(Ordering::AcqRel);
+        let tx_buf = &mut self.buffers.tx as *mut _ as u32;
+        self.radio.packetptr.write(|w| unsafe { w.bits(tx_buf) });
+        self.radio.txaddress.write(|w| unsafe { w.txaddress().bits(pipe) });
+        self.radio.events_end.reset();
+        self.radio.tasks_txen.write(|w| unsafe { w.bits(1) });
+        while self.radio.events_end.read().bits() == 0 {}
+        self.disable();
+    }
+
+    /// Start receiving on `pipes`, bit `n` for pipe `n`
+    fn listen(&mut self, pipes: u8) {
+        compiler_fence(Ordering::AcqRel);
+        let rx_buf = &mut self.buffers.rx as *mut _ as u32;
+        self.radio.packetptr.write(|w| unsafe { w.bits(rx_buf) });
+        self.radio.rxaddresses.write(|w| unsafe { w.bits(u32::from(pipes)) });
+        self.radio.events_address.reset();
+        self.radio.events_end.reset();
+        self.radio.tasks_rxen.write(|w| unsafe { w.bits(1) });
+    }
+
+    /// The received packet, if its CRC is valid
+    fn received(&self) -> Option<(u8, Pcf, u16)> {
+        compiler_fence(Ordering::AcqRel);
+        if !self.radio.crcstatus.read().crcstatus().is_crcok() {
+            return None;
+        }
+        let pipe = self.radio.rxmatch.read().rxmatch().bits();
+        let length = self.buffers.rx[0] & 0x3f;
+        if usize::from(length) > MAX_PAYLOAD {
+            return None;
+        }
+        let s1 = u16::from(self.buffers.rx[1] & 0x07);
+        let pcf = Pcf::from_bits(u16::from(length) << 3 | s1);
+        Some((pipe, pcf, self.radio.rxcrc.read().rxcrc().bits() as u16))
+    }
+
+    /// Send `payload` as primary transmitter, waiting for the
+    /// acknowledgement unless `no_ack`
+    ///
+    /// Returns the payload of the acknowledgement, if it has one. Reception
+    /// is stopped, call `start_receive` to receive again.
+    pub fn send(&mut self, payload: &Payload, no_ack: bool) -> Result<Option<Payload>, TxError> {
+        let pipe = payload.pipe;
+        let pcf = self.ptx.start(pipe, payload.length, no_ack);
+        // One RTC tick is 30.5 µs, round up
+        let ticks = (u64::from(self.config.retransmit_delay) * 32768).div_ceil(1_000_000);
+        loop {
+            self.transmit(pipe, pcf, payload.data());
+            if no_ack {
+                return Ok(None);
+            }
+            self.listen(1 << pipe);
+            let deadline = time::now() + Duration::from_ticks(ticks);
+            let mut address = false;
+            while self.radio.events_end.read().bits() == 0 {
+                if self.radio.events_address.read().bits() != 0 {
+                    address = true;
+                }
+                if !address && time::now() >= deadline {
+                    break;
+                }
+            }
+            let ended = self.radio.events_end.read().bits() != 0;
+            self.radio.events_address.reset();
+            self.disable();
+            if ended {
+                if let Some((_, ack, _)) = self.received() {
+                    let length = usize::from(ack.length);
+                    let ack = if length > 0 {
+                        Payload::new(pipe, &self.buffers.rx[2..2 + length])
+                    }
+                    else {
+                        None
+                    };
+                    return Ok(ack);
+                }
+            }
+            if !self.ptx.retransmit() {
+                return Err(TxError::MaxRetransmits);
+            }
+        }
+    }
+
+    /// Queue a payload for the acknowledgement of the next packet on its
+    /// pipe, returns `false` if a payload is already queued
+    pub fn set_ack_payload(&mut self, payload: Payload) -> bool {
+        self.prx.set_ack_payload(payload)
+    }
+
+    /// Start receiving as primary receiver on `Config::rx_pipes`
+    pub fn start_receive(&mut self) {
+        self.disable();
+        self.listen(self.config.rx_pipes);
+    }
+
+    /// Take a received packet without waiting, acknowledging it
+    ///
+    /// Returns `None` if no new packet has been received. Reception is
+    /// restarted after a packet has been received.
+    pub fn try_receive(&mut self) -> Option<Payload> {
+        if self.radio.events_end.read().bits() == 0 {
+            return None;
+        }
+        self.radio.events_end.reset();
+        let mut payload = None;
+        if let Some((pipe, pcf, crc)) = self.received() {
+            let length = usize::from(pcf.length);
+            let reception = self.prx.receive(pipe, pcf, crc);
+            if reception.new {
+                payload = Payload::new(pipe, &self.buffers.rx[2..2 + length]);
+            }
+            if let Some((ack, ack_payload)) = reception.ack {
+                let data = ack_payload.unwrap_or(Payload::new(pipe, &[]).unwrap());
+                self.transmit(pipe, ack, data.data());
+            }
+        }
+        self.start_receive();
+        payload
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A packet from a bit level encoder written from the nRF24L01+ product
+    /// specification
+    ///
+    /// These are not captured from an nRF24L01+ module, they only check
+    /// `encode` and `decode` against a second reading of the specification.
+    struct Packet {
+        address: &'static [u8],
+        pcf: Pcf,
+        payload: &'static [u8],
+        length: CrcLength,
+        /// Number of bits sent
+        bits: usize,
+        crc: u16,
+        /// The bytes on air, padded with zeros
+        air: &'static [u8],
+    }
+
+    const PACKETS: [Packet; 3] = [
+        Packet {
+            address: &[0xe7; 5],
+            pcf: Pcf { length: 7, pid: 1, no_ack: false },
+            payload: b"forward",
+            length: CrcLength::Two,
+            bits: 129,
+            crc: 0x8f0d,
+            air: &[
+                0xaa, 0xe7, 0xe7, 0xe7, 0xe7, 0xe7, 0x1d, 0x33, 0x37,
+                0xb9, 0x3b, 0xb0, 0xb9, 0x32, 0x47, 0x86, 0x80,
+            ],
+        },
+        Packet {
+            address: &[0x01, 0x02, 0x83],
+            pcf: Pcf { length: 2, pid: 2, no_ack: true },
+            payload: &[0x00, 0xff],
+            length: CrcLength::One,
+            bits: 65,
+            crc: 0x42,
+            air: &[0xaa, 0x83, 0x02, 0x01, 0x0a, 0x80, 0x7f, 0xa1, 0x00],
+        },
+        Packet {
+            address: &[0xc2; 5],
+            pcf: Pcf { length: 0, pid: 3, no_ack: false },
+            payload: &[],
+            length: CrcLength::One,
+            bits: 65,
+            crc: 0xd9,
+            air: &[0xaa, 0xc2, 0xc2, 0xc2, 0xc2, 0xc2, 0x03, 0x6c, 0x80],
+        },
+    ];
+
+    #[test]
+    fn crc_16_check_value() {
+        // CRC-16/CCITT-FALSE, the nRF24L01+ two byte CRC
+        assert_eq!(crc(b"123456789", 0, 72, CrcLength::Two), 0x29b1);
+    }
+
+    #[test]
+    fn encodes_packets() {
+        for packet in PACKETS.iter() {
+            let mut buffer = [0u8; ON_AIR_SIZE];
+            let bits = encode(packet.address, packet.pcf, packet.payload, packet.length,
+                &mut buffer);
+            assert_eq!(bits, packet.bits);
+            assert_eq!(&buffer[..packet.air.len()], packet.air);
+            assert!(buffer[packet.air.len()..].iter().all(|&byte| byte == 0));
+            let end = bits - packet.length.bits();
+            assert_eq!(crc(&buffer, 8, end, packet.length), packet.crc);
+        }
+    }
+
+    #[test]
+    fn decodes_packets() {
+        for packet in PACKETS.iter() {
+            let mut payload = [0u8; MAX_PAYLOAD];
+            let length = packet.address.len();
+            let (address, pcf) = decode(packet.air, length, packet.length, &mut payload)
+                .unwrap();
+            assert_eq!(&address[..length], packet.address);
+            assert_eq!(pcf, packet.pcf);
+            assert_eq!(&payload[..packet.payload.len()], packet.payload);
+        }
+    }
+
+    #[test]
+    fn decode_rejects_damaged_packets() {
+        let packet = &PACKETS[0];
+        let length = packet.address.len();
+        let mut payload = [0u8; MAX_PAYLOAD];
+        for bit in 8..packet.bits {
+            let mut damaged = [0u8; 17];
+            damaged.copy_from_slice(packet.air);
+            damaged[bit / 8] ^= 0x80 >> (bit % 8);
+            assert!(decode(&damaged, length, packet.length, &mut payload).is_none(),
+                "bit {}", bit);
+        }
+        assert!(decode(&packet.air[..16], length, packet.length, &mut payload).is_none());
+        assert!(decode(packet.air, 2, packet.length, &mut payload).is_none());
+    }
+
+    #[test]
+    fn largest_payload_round_trips() {
+        let data: [u8; MAX_PAYLOAD] = core::array::from_fn(|index| index as u8 * 7);
+        let pcf = Pcf { length: 0, pid: 0, no_ack: false };
+        let mut buffer = [0u8; ON_AIR_SIZE];
+        let bits = encode(&[0xe7; 5], pcf, &data, CrcLength::Two, &mut buffer);
+        assert_eq!(bits, 8 + 40 + 9 + MAX_PAYLOAD * 8 + 16);
+        let mut payload = [0u8; MAX_PAYLOAD];
+        let (_, pcf) = decode(&buffer, 5, CrcLength::Two, &mut payload).unwrap();
+        assert_eq!(usize::from(pcf.length), MAX_PAYLOAD);
+        assert_eq!(payload, data);
+        assert_eq!(encode(&[0xe7; 5], pcf, &[0u8; MAX_PAYLOAD + 1], CrcLength::Two,
+            &mut buffer), 0);
+    }
+
+    #[test]
+    fn ptx_counts_pids_and_attempts() {
+        let mut ptx = Ptx::new(2);
+        let pids: [u8; 5] = core::array::from_fn(|_| ptx.start(0, 4, false).pid);
+        assert_eq!(pids, [1, 2, 3, 0, 1]);
+        assert_eq!(ptx.start(1, 4, true), Pcf { length: 4, pid: 1, no_ack: true });
+        assert_eq!(ptx.attempts(), 1);
+        assert!(ptx.retransmit());
+        assert!(ptx.retransmit());
+        assert_eq!(ptx.attempts(), 3);
+        assert!(!ptx.retransmit());
+    }
+
+    #[test]
+    fn prx_drops_retransmissions_and_repeats_ack_payload() {
+        let mut prx = Prx::new();
+        let ack = Payload::new(0, b"ack").unwrap();
+        assert!(prx.set_ack_payload(ack));
+        assert!(!prx.set_ack_payload(ack));
+        let pcf = Pcf { length: 7, pid: 1, no_ack: false };
+        let first = prx.receive(0, pcf, 0x8f0d);
+        assert!(first.new);
+        assert_eq!(first.ack, Some((Pcf { length: 3, pid: 1, no_ack: false }, Some(ack))));
+        // The acknowledgement was lost, the transmitter sends again
+        let again = prx.receive(0, pcf, 0x8f0d);
+        assert!(!again.new);
+        assert_eq!(again.ack, first.ack);
+        // Same PID with another CRC is a new packet
+        let other = prx.receive(0, pcf, 0x1234);
+        assert!(other.new);
+        assert_eq!(other.ack, Some((Pcf { length: 0, pid: 1, no_ack: false }, None)));
+        // Pipes are independent
+        assert!(prx.receive(1, pcf, 0x1234).new);
+        let no_ack = prx.receive(0, Pcf { length: 2, pid: 2, no_ack: true }, 0x42);
+        assert_eq!(no_ack, Reception { new: true, ack: None });
+    }
+}
@@ -0,0 +1,172 @@
+//! Minimal single-core executor
+//!
+//! Runs one future to completion, sleeping until an interrupt wakes it.
+//!
+//! ```notrust
+//! executor::block_on(async {
+//!     loop {
+//!         let package = radio::recv().await;
+//!         leds::scroll("rx", 150, show).await;
+//!         Timer::after_millis(500).await;
+//!     }
+//! })
+//! ```
+
+use core::future::Future;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::task::{Context, Poll, RawWaker, RawWakerVTable, Waker};
+
+static WOKEN: AtomicBool = AtomicBool::new(true);
+
+static VTABLE: RawWakerVTable = RawWakerVTable::new(clone, wake, wake, drop);
+
+fn clone(_: *const ()) -> RawWaker {
+    RawWaker::new(core::ptr::null(), &VTABLE)
+}
+
+fn wake(_: *const ()) {
+    WOKEN.store(true, Ordering::Release);
+    #[cfg(target_arch = "arm")]
+    cortex_m::asm::sev();
+}
+
+fn drop(_: *const ()) {}
+
+fn waker() -> Waker {
+    unsafe { Waker::from_raw(clone(core::ptr::null())) }
+}
+
+/// Sleep until woken
+fn sleep() {
+    #[cfg(target_arch = "arm")]
+    cortex_m::asm::wfe();
+    #[cfg(not(target_arch = "arm"))]
+    core::hint::spin_loop();
+}
+
+/// Poll a future once, returns `None` if it is still pending
+///
+/// The future is only polled if it has been woken since the last poll.
+pub fn poll_once<F: Future>(future: Pin<&mut F>) -> Option<F::Output> {
+    // No atomic swap on the Cortex-M0. A wake between the load and the
+    // store is not lost, the future is polled right after.
+    if !WOKEN.load(Ordering::Acquire) {
+        return None;
+    }
+    WOKEN.store(false, Ordering::Release);
+    let waker = waker();
+    let mut cx = Context::from_waker(&waker);
+    match future.poll(&mut cx) {
+        Poll::Ready(output) => Some(output),
+        Poll::Pending => None,
+    }
+}
+
+/// Run a future to completion
+pub fn block_on<F: Future>(future: F) -> F::Output {
+    let mut future = future;
+    // The future is shadowed and can not be moved after this
+    let mut future = unsafe { Pin::new_unchecked(&mut future) };
+    WOKEN.store(true, Ordering::Release);
+    loop {
+        if let Some(output) = poll_once(future.as_mut()) {
+            return output;
+        }
+        while !WOKEN.load(Ordering::Acquire) {
+            sleep();
+        }
+    }
+}
+
+/// Result of `select`
+pub enum Either<A, B> {
+    First(A),
+    Second(B),
+}
+
+/// Future returned by `select`
+pub struct Select<A, B> {
+    first: A,
+    second: B,
+}
+
+/// Wait for the first of two futures to complete, the other one is dropped
+pub fn select<A: Future, B: Future>(first: A, second: B) -> Select<A, B> {
+    Select { first, second }
+}
+
+impl<A: Future, B: Future> Future for Select<A, B> {
+    type Output = Either<A::Output, B::Output>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        // The fields are never moved out of the pinned struct
+        let this = unsafe { self.get_unchecked_mut() };
+        let first = unsafe { Pin::new_unchecked(&mut this.first) };
+        if let Poll::Ready(output) = first.poll(cx) {
+            return Poll::Ready(Either::First(output));
+        }
+        let second = unsafe { Pin::new_unchecked(&mut this.second) };
+        if let Poll::Ready(output) = second.poll(cx) {
+            return Poll::Ready(Either::Second(output));
+        }
+        Poll::Pending
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::signal::Signal;
+    use crate::sim::SimClock;
+    use crate::time::{Duration, Instant, TimeSource};
+    use crate::timer::Timer;
+
+    /// Advances the clock on every poll, standing in for the RTC interrupt
+    struct Ticker<'a> {
+        clock: &'a SimClock,
+        polls: u32,
+    }
+
+    impl Future for Ticker<'_> {
+        type Output = u32;
+
+        fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<u32> {
+            self.polls += 1;
+            self.clock.advance(Duration::from_millis(1));
+            cx.waker().wake_by_ref();
+            Poll::Pending
+        }
+    }
+
+    // One test, as the executor is global
+    #[test]
+    fn block_on_futures() {
+        assert_eq!(block_on(async { 1 + 1 }), 2);
+
+        let signal = Signal::new();
+        signal.signal(3);
+        assert_eq!(block_on(signal.wait()), 3);
+
+        let clock = SimClock::new();
+        let deadline = Instant::from_ticks(0) + Duration::from_millis(50);
+        let ticker = Ticker { clock: &clock, polls: 0 };
+        match block_on(select(Timer::new(&clock, deadline), ticker)) {
+            Either::First(()) => (),
+            Either::Second(_) => panic!("the ticker never completes"),
+        }
+        assert!(clock.now() >= deadline);
+        assert!(clock.now() - deadline < Duration::from_millis(1));
+
+        // The first future to complete wins
+        let clock = SimClock::new();
+        let first = Timer::new_after(&clock, Duration::from_millis(20));
+        let second = async {
+            Timer::new_after(&clock, Duration::from_millis(5)).await;
+            "second"
+        };
+        let ticker = Ticker { clock: &clock, polls: 0 };
+        let result = block_on(select(select(first, second), ticker));
+        assert!(matches!(result, Either::First(Either::Second("second"))));
+    }
+}
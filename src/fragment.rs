@@ -0,0 +1,325 @@
+//! Fragmentation of large messages
+//!
+//! Messages larger than one datagram are split into fragments, which are
+//! reassembled by the receivers. Fragments are not acknowledged, a message
+//! with a lost fragment is dropped when its reassembly times out. The whole
+//! message is protected by a CRC, carried in every fragment.
+//!
+//! ```notrust
+//! let mut fragmenter = Fragmenter::<512, 2>::new(serial_number, group);
+//! fragmenter.send_large(&mut radio, &image);
+//! ...
+//! let mut buffer = [0u8; 512];
+//! if let Some((source, length)) = fragmenter.recv_large(&mut radio, &mut buffer) {
+//!     ...
+//! }
+//! ```
+
+use byteorder::{ByteOrder, LittleEndian};
+
+use crate::datagram::{self, DatagramHeader, DatagramProtocol};
+use crate::link::Link;
+use crate::radio::{PackageBuffer, CRC_POLY, CRC_PRESET, MAX_PACKAGE_SIZE};
+use crate::time::{Duration, Instant};
+
+/// Size of the fragment header following the datagram header
+pub const HEADER_SIZE: usize = 9;
+/// Largest payload of a fragment
+pub const MAX_FRAGMENT_PAYLOAD: usize =
+    MAX_PACKAGE_SIZE - 1 - (datagram::HEADER_SIZE - 1) - HEADER_SIZE;
+/// Largest number of fragments of a message
+pub const MAX_FRAGMENTS: usize = 255;
+/// Largest message that can be fragmented
+pub const MAX_MESSAGE_SIZE: usize = MAX_FRAGMENTS * MAX_FRAGMENT_PAYLOAD;
+/// A reassembly is dropped when no fragment has been received for this long
+pub const REASSEMBLY_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// CRC-16 of `data`, with the polynomial and preset of the radio CRC
+pub fn crc16(data: &[u8]) -> u16 {
+    let mut crc = CRC_PRESET as u16;
+    for byte in data {
+        crc ^= u16::from(*byte) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 { (crc << 1) ^ CRC_POLY as u16 } else { crc << 1 };
+        }
+    }
+    crc
+}
+
+/// # Fragment Header
+///
+/// ```notrust
+/// | 0 ... 3         | 4 ... 7 | 8  | 9     | 10    | 11 ... 12 | 13 ... 32
+/// ------------------------------------------------------------------------
+/// | datagram header | source  | id | index | count | crc       | payload
+/// ```
+/// Source is the serial number of the sender, the id identifies the message
+/// among the messages of the sender. Every fragment but the last carries
+/// `MAX_FRAGMENT_PAYLOAD` bytes. The CRC is the `crc16` of the whole message.
+struct Fragment {
+    source: u32,
+    id: u8,
+    index: u8,
+    count: u8,
+    crc: u16,
+    length: usize,
+}
+
+impl Fragment {
+    fn pack(&self, group: u8, payload: &[u8], buffer: &mut PackageBuffer) {
+        DatagramHeader::new(group, DatagramProtocol::Fragment, HEADER_SIZE + payload.len())
+            .pack(buffer);
+        let slice = &mut buffer[datagram::HEADER_SIZE..];
+        LittleEndian::write_u32(&mut slice[0..=3], self.source);
+        slice[4] = self.id;
+        slice[5] = self.index;
+        slice[6] = self.count;
+        LittleEndian::write_u16(&mut slice[7..=8], self.crc);
+        slice[HEADER_SIZE..HEADER_SIZE + payload.len()].copy_from_slice(payload);
+    }
+
+    fn unpack(group: u8, buffer: &PackageBuffer) -> Option<Fragment> {
+        let header = DatagramHeader::unpack(buffer);
+        if header.protocol() != DatagramProtocol::Fragment
+            || header.version() != datagram::VERSION
+            || header.group() != group
+            || header.payload_length() < HEADER_SIZE
+            || header.payload_length() > HEADER_SIZE + MAX_FRAGMENT_PAYLOAD
+        {
+            return None;
+        }
+        let slice = &buffer[datagram::HEADER_SIZE..];
+        let fragment = Fragment {
+            source: LittleEndian::read_u32(&slice[0..=3]),
+            id: slice[4],
+            index: slice[5],
+            count: slice[6],
+            crc: LittleEndian::read_u16(&slice[7..=8]),
+            length: header.payload_length() - HEADER_SIZE,
+        };
+        if fragment.index >= fragment.count {
+            return None;
+        }
+        let last = fragment.index + 1 == fragment.count;
+        if !last && fragment.length != MAX_FRAGMENT_PAYLOAD {
+            return None;
+        }
+        Some(fragment)
+    }
+}
+
+/// A message being reassembled
+struct Reassembly<const SIZE: usize> {
+    source: u32,
+    id: u8,
+    count: u8,
+    crc: u16,
+    /// Bit map of the received fragments
+    received: [u32; 8],
+    length: usize,
+    updated: Instant,
+    data: [u8; SIZE],
+}
+
+impl<const SIZE: usize> Reassembly<SIZE> {
+    fn is_received(&self, index: u8) -> bool {
+        self.received[usize::from(index / 32)] & (1 << (index % 32)) != 0
+    }
+
+    fn is_complete(&self) -> bool {
+        (0..self.count).all(|index| self.is_received(index))
+    }
+}
+
+/// # Fragmenter
+///
+/// Sends messages of up to `SIZE` bytes and reassembles up to `SLOTS`
+/// messages at a time. Each slot holds `SIZE` bytes. When all slots are in
+/// use, the reassembly updated longest ago is dropped for a new message.
+pub struct Fragmenter<const SIZE: usize, const SLOTS: usize> {
+    serial_number: u32,
+    group: u8,
+    id: u8,
+    slots: [Option<Reassembly<SIZE>>; SLOTS],
+}
+
+impl<const SIZE: usize, const SLOTS: usize> Fragmenter<SIZE, SLOTS> {
+    /// Create a fragmenter for the device with `serial_number` in `group`
+    pub fn new(serial_number: u32, group: u8) -> Self {
+        Fragmenter {
+            serial_number,
+            group,
+            id: 0,
+            slots: [const { None }; SLOTS],
+        }
+    }
+
+    /// Send a message, returns the number of bytes sent or 0 if the message
+    /// is empty, larger than `SIZE` or a fragment could not be sent
+    pub fn send_large<L: Link>(&mut self, link: &mut L, message: &[u8]) -> usize {
+        if message.is_empty() || message.len() > SIZE || message.len() > MAX_MESSAGE_SIZE {
+            return 0;
+        }
+        let count = message.len().div_ceil(MAX_FRAGMENT_PAYLOAD) as u8;
+        let crc = crc16(message);
+        let id = self.id;
+        self.id = self.id.wrapping_add(1);
+        for (index, payload) in message.chunks(MAX_FRAGMENT_PAYLOAD).enumerate() {
+            let mut frame = [0u8; MAX_PACKAGE_SIZE];
+            Fragment {
+                source: self.serial_number,
+                id,
+                index: index as u8,
+                count,
+                crc,
+                length: payload.len(),
+            }.pack(self.group, payload, &mut frame);
+            if !link.transmit(&frame) {
+                return 0;
+            }
+        }
+        message.len()
+    }
+
+    /// Receive datagrams from the link until a message is complete
+    ///
+    /// Returns the serial number of the sender and the length of the message
+    /// copied to `buffer`, or `None` when there are no more datagrams.
+    pub fn recv_large<L: Link>(&mut self, link: &mut L, buffer: &mut [u8; SIZE])
+        -> Option<(u32, usize)>
+    {
+        while let Some(frame) = link.receive() {
+            let message = self.receive(link.now(), &frame, buffer);
+            if message.is_some() {
+                return message;
+            }
+        }
+        self.expire(link.now());
+        None
+    }
+
+    /// Handle one received datagram
+    ///
+    /// Returns the serial number of the sender and the length of the message
+    /// copied to `buffer` if the datagram completed a message. Datagrams of
+    /// other protocols are ignored.
+    pub fn receive(&mut self, now: Instant, frame: &PackageBuffer, buffer: &mut [u8; SIZE])
+        -> Option<(u32, usize)>
+    {
+        self.expire(now);
+        let fragment = Fragment::unpack(self.group, frame)?;
+        let offset = usize::from(fragment.index) * MAX_FRAGMENT_PAYLOAD;
+        if offset + fragment.length > SIZE || fragment.source == self.serial_number {
+            return None;
+        }
+        let index = self.slot(&fragment, now);
+        let slot = self.slots[index].as_mut().unwrap();
+        if slot.is_received(fragment.index) {
+            return None;
+        }
+        let start = datagram::HEADER_SIZE + HEADER_SIZE;
+        slot.data[offset..offset + fragment.length]
+            .copy_from_slice(&frame[start..start + fragment.length]);
+        slot.received[usize::from(fragment.index / 32)] |= 1 << (fragment.index % 32);
+        slot.updated = now;
+        if fragment.index + 1 == fragment.count {
+            slot.length = offset + fragment.length;
+        }
+        if !slot.is_complete() {
+            return None;
+        }
+        let slot = self.slots[index].take().unwrap();
+        if crc16(&slot.data[..slot.length]) != slot.crc {
+            return None;
+        }
+        buffer[..slot.length].copy_from_slice(&slot.data[..slot.length]);
+        Some((slot.source, slot.length))
+    }
+
+    /// Find the reassembly of a fragment, or start a new reassembly
+    fn slot(&mut self, fragment: &Fragment, now: Instant) -> usize {
+        let found = self.slots.iter().position(|slot| match slot {
+            Some(slot) => slot.source == fragment.source && slot.id == fragment.id
+                && slot.count == fragment.count && slot.crc == fragment.crc,
+            None => false,
+        });
+        if let Some(index) = found {
+            return index;
+        }
+        let index = self.slots.iter()
+            .enumerate()
+            .min_by_key(|(_, slot)| slot.as_ref().map(|slot| slot.updated))
+            .map(|(index, _)| index)
+            .unwrap();
+        // Replaces any earlier message with the same id
+        for slot in self.slots.iter_mut() {
+            if slot.as_ref().is_some_and(|slot|
+                slot.source == fragment.source && slot.id == fragment.id)
+            {
+                *slot = None;
+            }
+        }
+        self.slots[index] = Some(Reassembly {
+            source: fragment.source,
+            id: fragment.id,
+            count: fragment.count,
+            crc: fragment.crc,
+            received: [0; 8],
+            length: 0,
+            updated: now,
+            data: [0u8; SIZE],
+        });
+        index
+    }
+
+    /// Drop reassemblies that have timed out
+    fn expire(&mut self, now: Instant) {
+        for slot in self.slots.iter_mut() {
+            if slot.as_ref().is_some_and(|slot| now - slot.updated > REASSEMBLY_TIMEOUT) {
+                *slot = None;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(index: u8, count: u8, payload: &[u8]) -> PackageBuffer {
+        let mut frame = [0u8; MAX_PACKAGE_SIZE];
+        Fragment { source: 7, id: 1, index, count, crc: crc16(payload), length: payload.len() }
+            .pack(0, payload, &mut frame);
+        frame
+    }
+
+    #[test]
+    fn rejects_index_beyond_count() {
+        assert!(Fragment::unpack(0, &frame(255, 255, &[1, 2, 3])).is_none());
+        assert!(Fragment::unpack(0, &frame(3, 2, &[1, 2, 3])).is_none());
+        assert!(Fragment::unpack(0, &frame(254, 255, &[1, 2, 3])).is_some());
+    }
+
+    #[test]
+    fn reassembles_out_of_order() {
+        let message: [u8; 50] = core::array::from_fn(|i| i as u8);
+        let count = message.len().div_ceil(MAX_FRAGMENT_PAYLOAD) as u8;
+        let crc = crc16(&message);
+        let frames: [PackageBuffer; 3] = core::array::from_fn(|index| {
+            let start = index * MAX_FRAGMENT_PAYLOAD;
+            let payload = &message[start..message.len().min(start + MAX_FRAGMENT_PAYLOAD)];
+            let mut frame = [0u8; MAX_PACKAGE_SIZE];
+            Fragment { source: 7, id: 1, index: index as u8, count, crc, length: payload.len() }
+                .pack(0, payload, &mut frame);
+            frame
+        });
+        let mut fragmenter = Fragmenter::<64, 1>::new(1, 0);
+        let mut buffer = [0u8; 64];
+        let now = Instant::from_ticks(0);
+        assert_eq!(fragmenter.receive(now, &frames[2], &mut buffer), None);
+        assert_eq!(fragmenter.receive(now, &frames[0], &mut buffer), None);
+        assert_eq!(fragmenter.receive(now, &frames[0], &mut buffer), None);
+        assert_eq!(fragmenter.receive(now, &frames[1], &mut buffer), Some((7, 50)));
+        assert_eq!(buffer[..50], message);
+    }
+}
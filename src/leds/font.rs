@@ -0,0 +1,129 @@
+//! 5x5 font for the LED matrix
+//!
+//! Printable ASCII, `' '` to `'~'`. Each glyph is five rows, the most
+//! significant of the five bits is the leftmost column.
+
+use super::Image;
+
+/// First character in the font
+pub const FIRST: char = ' ';
+/// Last character in the font
+pub const LAST: char = '~';
+
+#[rustfmt::skip]
+const GLYPHS: [[u8; 5]; 95] = [
+    [0b00000, 0b00000, 0b00000, 0b00000, 0b00000], // ' '
+    [0b00100, 0b00100, 0b00100, 0b00000, 0b00100], // '!'
+    [0b01010, 0b01010, 0b00000, 0b00000, 0b00000], // '"'
+    [0b01010, 0b11111, 0b01010, 0b11111, 0b01010], // '#'
+    [0b01110, 0b11000, 0b01110, 0b00011, 0b01110], // '$'
+    [0b11001, 0b10010, 0b00100, 0b01001, 0b10011], // '%'
+    [0b01100, 0b10010, 0b01100, 0b10010, 0b01101], // '&'
+    [0b00100, 0b00100, 0b00000, 0b00000, 0b00000], // '''
+    [0b00010, 0b00100, 0b00100, 0b00100, 0b00010], // '('
+    [0b01000, 0b00100, 0b00100, 0b00100, 0b01000], // ')'
+    [0b00000, 0b01010, 0b00100, 0b01010, 0b00000], // '*'
+    [0b00000, 0b00100, 0b01110, 0b00100, 0b00000], // '+'
+    [0b00000, 0b00000, 0b00000, 0b00100, 0b01000], // ','
+    [0b00000, 0b00000, 0b01110, 0b00000, 0b00000], // '-'
+    [0b00000, 0b00000, 0b00000, 0b00000, 0b00100], // '.'
+    [0b00001, 0b00010, 0b00100, 0b01000, 0b10000], // '/'
+    [0b01100, 0b10010, 0b10010, 0b10010, 0b01100], // '0'
+    [0b00100, 0b01100, 0b00100, 0b00100, 0b01110], // '1'
+    [0b11100, 0b00010, 0b01100, 0b10000, 0b11110], // '2'
+    [0b11110, 0b00010, 0b00100, 0b10010, 0b01100], // '3'
+    [0b00110, 0b01010, 0b10010, 0b11110, 0b00010], // '4'
+    [0b11111, 0b10000, 0b11110, 0b00001, 0b11110], // '5'
+    [0b00010, 0b00100, 0b01110, 0b10001, 0b01110], // '6'
+    [0b11111, 0b00010, 0b00100, 0b01000, 0b10000], // '7'
+    [0b01110, 0b10001, 0b01110, 0b10001, 0b01110], // '8'
+    [0b01110, 0b10001, 0b01110, 0b00100, 0b01000], // '9'
+    [0b00000, 0b00100, 0b00000, 0b00100, 0b00000], // ':'
+    [0b00000, 0b00100, 0b00000, 0b00100, 0b01000], // ';'
+    [0b00010, 0b00100, 0b01000, 0b00100, 0b00010], // '<'
+    [0b00000, 0b01110, 0b00000, 0b01110, 0b00000], // '='
+    [0b01000, 0b00100, 0b00010, 0b00100, 0b01000], // '>'
+    [0b01110, 0b00001, 0b00110, 0b00000, 0b00100], // '?'
+    [0b01110, 0b10001, 0b10101, 0b10110, 0b01100], // '@'
+    [0b01100, 0b10010, 0b11110, 0b10010, 0b10010], // 'A'
+    [0b11100, 0b10010, 0b11100, 0b10010, 0b11100], // 'B'
+    [0b01110, 0b10000, 0b10000, 0b10000, 0b01110], // 'C'
+    [0b11100, 0b10010, 0b10010, 0b10010, 0b11100], // 'D'
+    [0b11110, 0b10000, 0b11100, 0b10000, 0b11110], // 'E'
+    [0b11110, 0b10000, 0b11100, 0b10000, 0b10000], // 'F'
+    [0b01110, 0b10000, 0b10110, 0b10010, 0b01100], // 'G'
+    [0b10010, 0b10010, 0b11110, 0b10010, 0b10010], // 'H'
+    [0b11100, 0b01000, 0b01000, 0b01000, 0b11100], // 'I'
+    [0b11110, 0b00010, 0b00010, 0b10010, 0b01100], // 'J'
+    [0b10010, 0b10100, 0b11000, 0b10100, 0b10010], // 'K'
+    [0b10000, 0b10000, 0b10000, 0b10000, 0b11110], // 'L'
+    [0b10001, 0b11011, 0b10101, 0b10001, 0b10001], // 'M'
+    [0b10001, 0b11001, 0b10101, 0b10011, 0b10001], // 'N'
+    [0b01100, 0b10010, 0b10010, 0b10010, 0b01100], // 'O'
+    [0b11100, 0b10010, 0b11100, 0b10000, 0b10000], // 'P'
+    [0b01100, 0b10010, 0b10010, 0b01100, 0b00011], // 'Q'
+    [0b11100, 0b10010, 0b11100, 0b10100, 0b10010], // 'R'
+    [0b01110, 0b10000, 0b01100, 0b00010, 0b11100], // 'S'
+    [0b11111, 0b00100, 0b00100, 0b00100, 0b00100], // 'T'
+    [0b10010, 0b10010, 0b10010, 0b10010, 0b01100], // 'U'
+    [0b10001, 0b10001, 0b10001, 0b01010, 0b00100], // 'V'
+    [0b10001, 0b10001, 0b10101, 0b11011, 0b10001], // 'W'
+    [0b10010, 0b10010, 0b01100, 0b10010, 0b10010], // 'X'
+    [0b10001, 0b01010, 0b00100, 0b00100, 0b00100], // 'Y'
+    [0b11110, 0b00100, 0b01000, 0b10000, 0b11110], // 'Z'
+    [0b01110, 0b01000, 0b01000, 0b01000, 0b01110], // '['
+    [0b10000, 0b01000, 0b00100, 0b00010, 0b00001], // '\'
+    [0b01110, 0b00010, 0b00010, 0b00010, 0b01110], // ']'
+    [0b00100, 0b01010, 0b00000, 0b00000, 0b00000], // '^'
+    [0b00000, 0b00000, 0b00000, 0b00000, 0b11111], // '_'
+    [0b01000, 0b00100, 0b00000, 0b00000, 0b00000], // '`'
+    [0b00000, 0b01110, 0b10010, 0b10010, 0b01111], // 'a'
+    [0b10000, 0b10000, 0b11100, 0b10010, 0b11100], // 'b'
+    [0b00000, 0b01110, 0b10000, 0b10000, 0b01110], // 'c'
+    [0b00010, 0b00010, 0b01110, 0b10010, 0b01110], // 'd'
+    [0b01100, 0b10010, 0b11100, 0b10000, 0b01110], // 'e'
+    [0b00110, 0b01000, 0b01110, 0b01000, 0b01000], // 'f'
+    [0b01110, 0b10010, 0b01110, 0b00010, 0b01100], // 'g'
+    [0b10000, 0b10000, 0b11100, 0b10010, 0b10010], // 'h'
+    [0b01000, 0b00000, 0b01000, 0b01000, 0b01000], // 'i'
+    [0b00010, 0b00000, 0b00010, 0b10010, 0b01100], // 'j'
+    [0b10000, 0b10100, 0b11000, 0b10100, 0b10010], // 'k'
+    [0b01000, 0b01000, 0b01000, 0b01000, 0b00110], // 'l'
+    [0b00000, 0b11010, 0b10101, 0b10101, 0b10101], // 'm'
+    [0b00000, 0b11100, 0b10010, 0b10010, 0b10010], // 'n'
+    [0b00000, 0b01100, 0b10010, 0b10010, 0b01100], // 'o'
+    [0b00000, 0b11100, 0b10010, 0b11100, 0b10000], // 'p'
+    [0b00000, 0b01110, 0b10010, 0b01110, 0b00010], // 'q'
+    [0b00000, 0b01110, 0b10000, 0b10000, 0b10000], // 'r'
+    [0b00000, 0b00110, 0b01000, 0b00100, 0b11000], // 's'
+    [0b01000, 0b01110, 0b01000, 0b01000, 0b00110], // 't'
+    [0b00000, 0b10010, 0b10010, 0b10010, 0b01110], // 'u'
+    [0b00000, 0b10001, 0b10001, 0b01010, 0b00100], // 'v'
+    [0b00000, 0b10001, 0b10101, 0b10101, 0b01010], // 'w'
+    [0b00000, 0b10010, 0b01100, 0b01100, 0b10010], // 'x'
+    [0b00000, 0b10001, 0b01010, 0b00100, 0b11000], // 'y'
+    [0b00000, 0b11110, 0b00100, 0b01000, 0b11110], // 'z'
+    [0b00110, 0b00100, 0b01100, 0b00100, 0b00110], // '{'
+    [0b00100, 0b00100, 0b00100, 0b00100, 0b00100], // '|'
+    [0b11000, 0b01000, 0b01100, 0b01000, 0b11000], // '}'
+    [0b00000, 0b00000, 0b01101, 0b10110, 0b00000], // '~'
+];
+
+/// Get the glyph rows of a character, unknown characters are shown as `'?'`
+pub fn glyph(c: char) -> [u8; 5] {
+    let c = if (FIRST..=LAST).contains(&c) { c } else { '?' };
+    GLYPHS[c as usize - FIRST as usize]
+}
+
+/// Get the image of a character
+pub fn character(c: char) -> Image {
+    let mut image = [[0u8; 5]; 5];
+    for (dst, row) in image.iter_mut().zip(glyph(c).iter()) {
+        for (col, pixel) in dst.iter_mut().enumerate() {
+            if row & (0x10 >> col) != 0 {
+                *pixel = 0xff;
+            }
+        }
+    }
+    image
+}
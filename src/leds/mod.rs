@@ -5,12 +5,16 @@ use nrf51_hal::gpio::gpio::{
     PIN10, PIN11, PIN12, PIN13, PIN14, PIN15, PIN4, PIN5, PIN6, PIN7, PIN8, PIN9,
 };
 use nrf51_hal::gpio::{Output, PushPull};
-use nrf51_hal::prelude::*;
+use nrf51_hal::hal::digital::v2::OutputPin;
+use nrf51_hal::hi_res_timer::Nrf51Timer;
 
+use crate::timer::Timer;
+
+pub mod font;
 pub mod images;
 
-type LED = PIN<Output<PushPull>>;
-type Image = [[u8; 5]; 5];
+type Led = PIN<Output<PushPull>>;
+pub type Image = [[u8; 5]; 5];
 type DisplayBuffer = [[u8; 9]; 3];
 
 const LED_LAYOUT: [[(usize, usize); 5]; 5] = [
@@ -23,8 +27,8 @@ const LED_LAYOUT: [[(usize, usize); 5]; 5] = [
 
 /// On-board 5x5 led matrix
 pub struct Display {
-    rows: [LED; 3],
-    cols: [LED; 9],
+    rows: [Led; 3],
+    cols: [Led; 9],
     row: usize,
     buffer: DisplayBuffer,
     next_buffer: DisplayBuffer,
@@ -33,6 +37,7 @@ pub struct Display {
 
 impl Display {
     /// Initializes all the user LEDs
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         col1: PIN4<Output<PushPull>>,
         col2: PIN5<Output<PushPull>>,
@@ -73,10 +78,10 @@ impl Display {
     /// Clear display
     pub fn clear(&mut self) {
         for row in &mut self.rows {
-            row.set_low();
+            row.set_low().ok();
         }
         for col in &mut self.cols {
-            col.set_high();
+            col.set_high().ok();
         }
     }
 
@@ -100,10 +105,10 @@ impl Display {
         let row_vals = self.buffer[self.row];
         for (col_sig, col_val) in self.cols.iter_mut().zip(row_vals.iter()) {
             if *col_val > 0 {
-                col_sig.set_low();
+                col_sig.set_low().ok();
             }
             else {
-                col_sig.set_high();
+                col_sig.set_high().ok();
             }
         }
         2000
@@ -112,12 +117,12 @@ impl Display {
     fn update_row(&mut self) {
         // clear last column
         for col_sig in self.cols.iter_mut() {
-            col_sig.set_high();
+            col_sig.set_high().ok();
         }
         // disable last row
         {
             let row_sig = self.rows.get_mut(self.row).unwrap();
-            row_sig.set_low();
+            row_sig.set_low().ok();
         }
         // update row
         self.row = (self.row + 1) % self.rows.len();
@@ -132,6 +137,121 @@ impl Display {
         }
         // new row
         let row_sig = self.rows.get_mut(self.row).unwrap();
-        row_sig.set_high();
+        row_sig.set_high().ok();
+    }
+}
+
+/// # Display refresh timer
+///
+/// Multiplexes the rows of a `Display` from a TIMER interrupt. The timer is
+/// run at 1 MHz in 16-bit mode, so any of TIMER0, TIMER1 or TIMER2 can be
+/// used.
+pub struct DisplayTimer<T: Nrf51Timer> {
+    timer: T,
+}
+
+impl<T: Nrf51Timer> DisplayTimer<T> {
+    /// Configure and start the timer
+    pub fn new(timer: T) -> Self {
+        timer.bitmode.write(|w| w.bitmode()._16bit());
+        timer.prescaler.write(|w| unsafe { w.prescaler().bits(4) });
+        timer.intenset.write(|w| w.compare0().set());
+        timer.shorts.write(|w| w.compare0_clear().enabled()
+            .compare0_stop().enabled());
+        timer.cc[0].write(|w| unsafe { w.bits(2000) });
+        timer.tasks_start.write(|w| unsafe { w.bits(1) });
+        DisplayTimer { timer }
+    }
+
+    /// Handle the TIMER interrupt, shows the next row of the display
+    pub fn interrupt(&mut self, display: &mut Display) {
+        self.timer.events_compare[0].reset();
+        let mut delay = 0;
+        while delay == 0 {
+            delay = display.update_col();
+        }
+        self.timer.cc[0].write(|w| unsafe { w.bits(delay) });
+        self.timer.tasks_start.write(|w| unsafe { w.bits(1) });
+    }
+
+    /// Stop the timer and release it
+    pub fn free(self) -> T {
+        self.timer.intenclr.write(|w| w.compare0().clear());
+        self.timer.tasks_stop.write(|w| unsafe { w.bits(1) });
+        self.timer
+    }
+}
+
+/// Columns in a scrolled character, including spacing
+const SCROLL_CHAR_WIDTH: usize = 6;
+
+/// # Scrolling text
+///
+/// Iterates over the images of text scrolling in from the right, one
+/// column per image, until the display is empty again.
+pub struct Scroll<'a> {
+    text: &'a str,
+    position: usize,
+    length: usize,
+}
+
+impl<'a> Scroll<'a> {
+    pub fn new(text: &'a str) -> Self {
+        Scroll {
+            text,
+            position: 1,
+            length: 5 + SCROLL_CHAR_WIDTH * text.chars().count(),
+        }
     }
-}
\ No newline at end of file
+
+    /// Row bits of a column in the scrolled strip
+    fn column(&self, index: usize) -> u8 {
+        if index < 5 || index >= self.length {
+            return 0;
+        }
+        let index = index - 5;
+        let column = index % SCROLL_CHAR_WIDTH;
+        if column >= 5 {
+            return 0;
+        }
+        match self.text.chars().nth(index / SCROLL_CHAR_WIDTH) {
+            Some(c) => {
+                let glyph = font::glyph(c);
+                glyph.iter().enumerate().fold(0, |bits, (row, pixels)| {
+                    if pixels & (0x10 >> column) != 0 { bits | (1 << row) } else { bits }
+                })
+            }
+            None => 0,
+        }
+    }
+}
+
+impl Iterator for Scroll<'_> {
+    type Item = Image;
+
+    fn next(&mut self) -> Option<Image> {
+        if self.position > self.length {
+            return None;
+        }
+        let mut image = [[0u8; 5]; 5];
+        for col in 0..5 {
+            let bits = self.column(self.position + col);
+            for (row, line) in image.iter_mut().enumerate() {
+                if bits & (1 << row) != 0 {
+                    line[col] = 0xff;
+                }
+            }
+        }
+        self.position += 1;
+        Some(image)
+    }
+}
+
+/// Scroll text, `show` is called with every image and `step` milliseconds
+/// apart
+pub async fn scroll<F: FnMut(Image)>(text: &str, step: u32, mut show: F) {
+    for image in Scroll::new(text) {
+        show(image);
+        Timer::after_millis(u64::from(step)).await;
+    }
+}
@@ -2,7 +2,7 @@
 //! 
 //! Wrappers for BBC micro:bit functionality
 
-#![no_std]
+#![cfg_attr(not(test), no_std)]
 
 extern crate nrf51;
 pub extern crate nrf51_hal as hal;
@@ -10,7 +10,35 @@ extern crate byteorder;
 
 pub use nrf51::*;
 
+pub mod aes;
+pub mod air;
+pub mod ble;
+pub mod board;
+pub mod buttons;
+pub mod ccm;
+pub mod esb;
+pub mod executor;
+pub mod fragment;
 pub mod radio;
 pub mod leds;
+pub mod link;
+pub mod mesh;
+pub mod micropython;
 pub mod datagram;
+pub mod diagnostics;
+pub mod dispatch;
 pub mod package;
+pub mod presence;
+pub mod queue;
+mod random;
+pub mod reliable;
+pub mod secure;
+pub mod signal;
+pub mod sim;
+pub mod storage;
+pub mod telemetry;
+pub mod time;
+pub mod timer;
+pub mod timesync;
+
+pub use board::Board;
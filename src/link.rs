@@ -0,0 +1,76 @@
+//! Radio link abstraction
+//!
+//! Protocols built on top of datagrams send and receive through a `Link`,
+//! so they run the same on the radio and in the simulator, see `sim`.
+
+use crate::radio::{self, PackageBuffer, Radio};
+use crate::time::{self, Duration, Instant};
+
+/// # Link
+///
+/// Sends and receives datagrams, `frame[0]` is the datagram length as in
+/// `datagram::DatagramHeader`.
+pub trait Link {
+    /// Send a datagram, returns `false` if it could not be sent
+    fn transmit(&mut self, frame: &PackageBuffer) -> bool;
+    /// Take a received datagram, if any
+    fn receive(&mut self) -> Option<PackageBuffer>;
+    /// The current time
+    fn now(&self) -> Instant;
+
+    /// Take a received datagram and the time of its ADDRESS event, links
+    /// without timestamps return the current time
+    fn receive_timestamped(&mut self) -> Option<(PackageBuffer, Instant)> {
+        let frame = self.receive()?;
+        Some((frame, self.now()))
+    }
+
+    /// Time from `transmit` to the ADDRESS event at the receivers
+    fn address_delay(&self) -> Duration {
+        Duration::from_ticks(0)
+    }
+
+    /// Signal strength of the last received datagram in dBm, if the link
+    /// measures it
+    fn rssi(&self) -> Option<i8> {
+        None
+    }
+
+    /// Number of datagrams dropped for a CRC error, if the link counts them
+    fn crc_errors(&self) -> u32 {
+        0
+    }
+}
+
+impl Link for Radio {
+    fn transmit(&mut self, frame: &PackageBuffer) -> bool {
+        let length = usize::from(frame[0]);
+        length < frame.len() && self.send(&frame[1..=length]) > 0
+    }
+
+    fn receive(&mut self) -> Option<PackageBuffer> {
+        let mut frame = [0u8; radio::MAX_PACKAGE_SIZE];
+        if self.try_receive(&mut frame) > 0 { Some(frame) } else { None }
+    }
+
+    fn now(&self) -> Instant {
+        time::now()
+    }
+
+    fn receive_timestamped(&mut self) -> Option<(PackageBuffer, Instant)> {
+        let frame = Link::receive(self)?;
+        Some((frame, self.timestamp()))
+    }
+
+    fn address_delay(&self) -> Duration {
+        radio::ADDRESS_DELAY
+    }
+
+    fn rssi(&self) -> Option<i8> {
+        Some(Radio::rssi(self))
+    }
+
+    fn crc_errors(&self) -> u32 {
+        Radio::crc_errors(self)
+    }
+}
@@ -0,0 +1,408 @@
+//! Flooding mesh relay
+//!
+//! Relays MakeCode packages to reach further than a single radio. Every
+//! relay rebroadcasts each package it has not seen before, after a random
+//! delay so that neighbouring relays do not collide. Packages are recognised
+//! by the serial number and time in the `PackageHeader`.
+//!
+//! Relayed packages stay MakeCode packages, the time to live and the hop
+//! count are carried in one byte after the package payload, which MakeCode
+//! ignores. Nodes that do not relay still receive relayed packages.
+//!
+//! ```notrust
+//! let mut relay = Relay::<32, 4>::new(serial_number);
+//! loop {
+//!     while let Some(package) = relay.recv(&mut radio) {
+//!         let hops = package.hops();
+//!         let package = package.package();
+//!         ...
+//!     }
+//! }
+//! ```
+
+use crate::datagram::{self, DatagramHeader, DatagramProtocol};
+use crate::link::Link;
+use crate::package::{self, Package, PackageHeader, PackageType};
+use crate::radio::{PackageBuffer, MAX_PACKAGE_SIZE};
+use crate::random::Random;
+use crate::time::{Duration, Instant};
+
+/// Time to live of packages sent or first relayed by a relay
+pub const DEFAULT_TTL: u8 = 7;
+/// Largest time to live and hop count that can be carried
+pub const MAX_HOPS: u8 = 15;
+/// Longest random delay before a package is relayed
+pub const RELAY_JITTER: Duration = Duration::from_millis(20);
+
+/// Offset of the package payload in a frame, after the length byte, the
+/// datagram header and the package header
+const PAYLOAD_OFFSET: usize = datagram::HEADER_SIZE + package::HEADER_SIZE;
+
+/// End of the MakeCode package payload in `frame`, the offset of the mesh
+/// trailer if the frame has one
+///
+/// Returns `None` if the frame is not a MakeCode package.
+pub fn payload_end(frame: &PackageBuffer) -> Option<usize> {
+    let header = PackageHeader::unpack(frame);
+    let length = |offset: usize| frame.get(offset).map(|length| 1 + usize::from(*length));
+    let payload = match header.package_type() {
+        PackageType::Integer => 4,
+        PackageType::IntegerValue => 4 + length(PAYLOAD_OFFSET + 4)?,
+        PackageType::String | PackageType::Buffer => length(PAYLOAD_OFFSET)?,
+        PackageType::Double => 8,
+        PackageType::DoubleValue => 8 + length(PAYLOAD_OFFSET + 8)?,
+        PackageType::Unknown => return None,
+    };
+    let end = PAYLOAD_OFFSET + payload;
+    // The frame holds the length byte and `length` bytes
+    if end > 1 + usize::from(frame[0]) {
+        return None;
+    }
+    Some(end)
+}
+
+/// # Mesh trailer
+///
+/// ```notrust
+/// | 7 ... 4      | 3 ... 0
+/// ------------------------
+/// | time to live | hops
+/// ```
+/// Packages from MakeCode nodes have no trailer, they have travelled no hops
+/// and get `DEFAULT_TTL`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct Trailer {
+    ttl: u8,
+    hops: u8,
+}
+
+impl Trailer {
+    fn read(frame: &PackageBuffer, end: usize) -> Option<Trailer> {
+        if usize::from(frame[0]) == end {
+            Some(Trailer { ttl: frame[end] >> 4, hops: frame[end] & 0x0f })
+        }
+        else {
+            None
+        }
+    }
+
+    /// Write the trailer at `end`, returns `false` if the frame is full
+    fn write(&self, frame: &mut PackageBuffer, end: usize) -> bool {
+        if end >= MAX_PACKAGE_SIZE {
+            return false;
+        }
+        frame[end] = (self.ttl.min(MAX_HOPS) << 4) | self.hops.min(MAX_HOPS);
+        frame[0] = end as u8;
+        true
+    }
+}
+
+/// A package received through the mesh
+#[derive(Clone, Copy)]
+pub struct MeshPackage {
+    frame: PackageBuffer,
+    hops: u8,
+}
+
+impl MeshPackage {
+    /// The received frame, including the mesh trailer
+    pub fn frame(&self) -> &PackageBuffer {
+        &self.frame
+    }
+    /// Number of relays the package has passed through
+    pub fn hops(&self) -> u8 {
+        self.hops
+    }
+    /// Unpack the package
+    pub fn package(&self) -> Package {
+        Package::unpack(&self.frame[..])
+    }
+}
+
+/// Relay counters
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Statistics {
+    /// Packages received for the first time
+    pub received: u32,
+    /// Packages received again, from other relays
+    pub duplicates: u32,
+    /// Packages rebroadcast
+    pub relayed: u32,
+    /// Packages not relayed as their time to live ran out
+    pub expired: u32,
+    /// Packages not relayed as too many packages were waiting
+    pub dropped: u32,
+    /// Highest hop count received
+    pub max_hops: u8,
+}
+
+/// # Relay
+///
+/// Receives and relays MakeCode packages. `CACHE` packages are remembered
+/// to drop duplicates, up to `PENDING` packages wait to be relayed.
+///
+/// The relay only makes progress in `recv`, which should be called often
+/// enough to relay packages in time.
+pub struct Relay<const CACHE: usize, const PENDING: usize> {
+    relaying: bool,
+    cache: [Option<(u32, u32)>; CACHE],
+    cache_next: usize,
+    pending: [Option<(Instant, PackageBuffer)>; PENDING],
+    random: Random,
+    statistics: Statistics,
+}
+
+impl<const CACHE: usize, const PENDING: usize> Relay<CACHE, PENDING> {
+    /// Create a relay, `serial_number` seeds the relay delays
+    pub fn new(serial_number: u32) -> Self {
+        Relay {
+            relaying: true,
+            cache: [None; CACHE],
+            cache_next: 0,
+            pending: [None; PENDING],
+            random: Random::new(serial_number),
+            statistics: Statistics::default(),
+        }
+    }
+
+    /// Turn relaying on or off, packages are still received when off
+    pub fn set_relaying(&mut self, relaying: bool) {
+        self.relaying = relaying;
+        if !relaying {
+            self.pending = [None; PENDING];
+        }
+    }
+
+    /// The relay counters
+    pub fn statistics(&self) -> Statistics {
+        self.statistics
+    }
+
+    /// Send an own package with `DEFAULT_TTL`
+    ///
+    /// The package is remembered, so it is not received when relayed back.
+    /// Returns `false` if the frame is not a MakeCode package or could not
+    /// be sent.
+    pub fn send<L: Link>(&mut self, link: &mut L, frame: &PackageBuffer) -> bool {
+        let end = match payload_end(frame) {
+            Some(end) => end,
+            None => return false,
+        };
+        let header = PackageHeader::unpack(frame);
+        self.remember(header.serial_number(), header.time());
+        let mut frame = *frame;
+        Trailer { ttl: DEFAULT_TTL, hops: 0 }.write(&mut frame, end);
+        link.transmit(&frame)
+    }
+
+    /// Receive packages from the link and relay packages that are due
+    ///
+    /// Returns the next package not received before, or `None` when there
+    /// are no more datagrams. Datagrams of other protocols are dropped.
+    pub fn recv<L: Link>(&mut self, link: &mut L) -> Option<MeshPackage> {
+        let mut package = None;
+        while let Some(frame) = link.receive() {
+            package = self.handle(link.now(), &frame);
+            if package.is_some() {
+                break;
+            }
+        }
+        self.flush(link);
+        package
+    }
+
+    /// Handle one received datagram
+    ///
+    /// Returns the package if it has not been received before and queues it
+    /// to be relayed.
+    pub fn handle(&mut self, now: Instant, frame: &PackageBuffer) -> Option<MeshPackage> {
+        let header = DatagramHeader::unpack(frame);
+        if header.protocol() != DatagramProtocol::Datagram {
+            return None;
+        }
+        let end = payload_end(frame)?;
+        let header = PackageHeader::unpack(frame);
+        if self.is_remembered(header.serial_number(), header.time()) {
+            self.statistics.duplicates += 1;
+            return None;
+        }
+        self.remember(header.serial_number(), header.time());
+        let trailer = Trailer::read(frame, end).unwrap_or(Trailer { ttl: DEFAULT_TTL, hops: 0 });
+        self.statistics.received += 1;
+        self.statistics.max_hops = self.statistics.max_hops.max(trailer.hops);
+        if self.relaying {
+            self.queue(now, frame, end, trailer);
+        }
+        Some(MeshPackage { frame: *frame, hops: trailer.hops })
+    }
+
+    /// Relay the packages that are due
+    pub fn flush<L: Link>(&mut self, link: &mut L) {
+        let now = link.now();
+        for pending in self.pending.iter_mut() {
+            if let Some((deadline, frame)) = pending {
+                if *deadline <= now {
+                    link.transmit(frame);
+                    self.statistics.relayed += 1;
+                    *pending = None;
+                }
+            }
+        }
+    }
+
+    /// Queue a package to be relayed after a random delay
+    fn queue(&mut self, now: Instant, frame: &PackageBuffer, end: usize, trailer: Trailer) {
+        if trailer.ttl == 0 {
+            self.statistics.expired += 1;
+            return;
+        }
+        let slot = match self.pending.iter().position(|pending| pending.is_none()) {
+            Some(slot) => slot,
+            None => {
+                self.statistics.dropped += 1;
+                return;
+            }
+        };
+        let mut frame = *frame;
+        let relayed = Trailer { ttl: trailer.ttl - 1, hops: trailer.hops.saturating_add(1) };
+        // A full frame is relayed as it is, the duplicate cache still stops
+        // it from circulating
+        relayed.write(&mut frame, end);
+        let jitter = self.random.jitter(RELAY_JITTER);
+        self.pending[slot] = Some((now + jitter, frame));
+    }
+
+    fn is_remembered(&self, serial_number: u32, time: u32) -> bool {
+        self.cache.iter().flatten().any(|&key| key == (serial_number, time))
+    }
+
+    fn remember(&mut self, serial_number: u32, time: u32) {
+        if CACHE > 0 {
+            self.cache[self.cache_next] = Some((serial_number, time));
+            self.cache_next = (self.cache_next + 1) % CACHE;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::package::PackageData;
+    use crate::sim::Simulator;
+
+    const NODES: usize = 20;
+
+    type Relays = [Relay<16, 4>; NODES];
+
+    fn package(serial_number: u32, time: u32) -> PackageBuffer {
+        let mut frame = [0u8; MAX_PACKAGE_SIZE];
+        let datagram_header = DatagramHeader::new(0, DatagramProtocol::Datagram,
+            package::HEADER_SIZE + 4);
+        Package {
+            header: PackageHeader::new(datagram_header, PackageType::Integer, time, serial_number),
+            data: PackageData::Integer(42),
+        }.pack(&mut frame);
+        frame
+    }
+
+    fn relays() -> Relays {
+        core::array::from_fn(|index| Relay::new(index as u32 + 1))
+    }
+
+    /// Run all nodes for a second, returns the hops of every package each
+    /// node received, by the serial number of the sender
+    fn run(sim: &mut Simulator<NODES>, relays: &mut Relays) -> [[Option<u8>; NODES]; NODES] {
+        let mut received = [[None; NODES]; NODES];
+        for _ in 0..1000 {
+            for (index, relay) in relays.iter_mut().enumerate() {
+                while let Some(package) = relay.recv(&mut sim.link(index)) {
+                    let header = PackageHeader::unpack(package.frame());
+                    let sender = header.serial_number() as usize - 1;
+                    assert_eq!(received[index][sender], None, "node {} received twice", index);
+                    received[index][sender] = Some(package.hops());
+                }
+            }
+            sim.step();
+        }
+        received
+    }
+
+    #[test]
+    fn line_stops_at_time_to_live() {
+        let mut sim = Simulator::<NODES>::new(1);
+        sim.disconnect_all();
+        for index in 1..NODES {
+            sim.connect(index - 1, index, true);
+        }
+        let mut relays = relays();
+        assert!(relays[0].send(&mut sim.link(0), &package(1, 100)));
+        let received = run(&mut sim, &mut relays);
+        let reach = usize::from(DEFAULT_TTL) + 1;
+        assert_eq!(received[0][0], None);
+        for (index, received) in received.iter().enumerate().skip(1) {
+            let expected = (index <= reach).then(|| index as u8 - 1);
+            assert_eq!(received[0], expected, "node {}", index);
+        }
+        assert_eq!(relays[reach].statistics().expired, 1);
+        assert_eq!(relays[reach].statistics().max_hops, DEFAULT_TTL);
+        assert_eq!(relays[reach + 1].statistics().received, 0);
+    }
+
+    #[test]
+    fn grid_delivers_once_to_every_node() {
+        // 4 rows of 5, nodes in range of their horizontal and vertical
+        // neighbours
+        const COLUMNS: usize = 5;
+        let mut sim = Simulator::<NODES>::new(2);
+        sim.disconnect_all();
+        for index in 0..NODES {
+            if index % COLUMNS != COLUMNS - 1 {
+                sim.connect(index, index + 1, true);
+            }
+            if index + COLUMNS < NODES {
+                sim.connect(index, index + COLUMNS, true);
+            }
+        }
+        let mut relays = relays();
+        let senders = [0, NODES - 1, 7];
+        for (time, &sender) in senders.iter().enumerate() {
+            let frame = package(sender as u32 + 1, time as u32);
+            assert!(relays[sender].send(&mut sim.link(sender), &frame));
+        }
+        let received = run(&mut sim, &mut relays);
+        let distance = |a: usize, b: usize| {
+            (a / COLUMNS).abs_diff(b / COLUMNS) + (a % COLUMNS).abs_diff(b % COLUMNS)
+        };
+        for (index, received) in received.iter().enumerate() {
+            for &sender in senders.iter() {
+                if index == sender {
+                    assert_eq!(received[sender], None);
+                    continue;
+                }
+                let hops = usize::from(received[sender].unwrap_or_else(|| {
+                    panic!("node {} missed the package of node {}", index, sender)
+                }));
+                assert!(hops + 1 >= distance(index, sender));
+                assert!(hops <= usize::from(DEFAULT_TTL));
+            }
+        }
+        let duplicates: u32 = relays.iter().map(|relay| relay.statistics().duplicates).sum();
+        assert!(duplicates > 0);
+        assert!(relays.iter().all(|relay| relay.statistics().dropped == 0));
+    }
+
+    #[test]
+    fn silent_node_still_receives() {
+        let mut sim = Simulator::<NODES>::new(3);
+        let mut relays = relays();
+        for relay in relays.iter_mut().skip(1) {
+            relay.set_relaying(false);
+        }
+        assert!(relays[0].send(&mut sim.link(0), &package(1, 5)));
+        let received = run(&mut sim, &mut relays);
+        for received in received.iter().skip(1) {
+            assert_eq!(received[0], Some(0));
+        }
+        assert!(relays.iter().all(|relay| relay.statistics().relayed == 0));
+    }
+}
@@ -0,0 +1,365 @@
+//! MicroPython radio messages
+//!
+//! The MicroPython `radio` module sends on the same address, group and
+//! channel as MakeCode. `radio.send_bytes` sends the bytes as they are,
+//! `radio.send` sends the string after the prefix 1, 0, 1. The prefix reads
+//! as the datagram header of a MakeCode package in group 0, which is why
+//! `package::Package::unpack` takes strings for packages, use
+//! `Message::is_makecode` to tell them apart.
+//!
+//! MicroPython sends up to `LENGTH` bytes, as MakeCode does, so its frames
+//! are as large as a MakeCode `PackageBuffer`.
+//!
+//! ```notrust
+//! static mut BUFFERS: Buffers<{ micropython::FRAME_SIZE }> = Buffers::new();
+//!
+//! let mut radio = Radio::new(p.RADIO, unsafe { &mut BUFFERS });
+//! micropython::configure(&mut radio)?;
+//! let mut receiver: Receiver = Receiver::new();
+//! micropython::send(&mut radio, "hello");
+//! loop {
+//!     receiver.poll(&mut radio);
+//!     if let Some(message) = receiver.receive_full() {
+//!         ...
+//!     }
+//! }
+//! ```
+//!
+//! ## Reference
+//!
+//! * <https://microbit-micropython.readthedocs.io/en/latest/radio.html>
+//! * <https://github.com/bbcmicrobit/micropython/blob/master/source/microbit/modradio.cpp>
+
+use crate::package::PackageRef;
+use crate::radio::{ConfigError, Radio, RadioConfig};
+use crate::time::Instant;
+
+/// Default channel
+pub const CHANNEL: u8 = 7;
+/// Default largest message in bytes
+pub const LENGTH: usize = 32;
+/// Default number of received messages kept
+pub const QUEUE: usize = 3;
+/// Default transmit power level, 0 dBm, see `RadioConfig::power`
+pub const POWER: u8 = 6;
+/// Prefix of strings
+pub const STRING_PREFIX: [u8; 3] = [1, 0, 1];
+/// Size of a frame, the length and the largest message
+pub const FRAME_SIZE: usize = 1 + LENGTH;
+
+/// A frame of a message, the length followed by the message
+pub type Frame = [u8; FRAME_SIZE];
+
+/// The MakeCode packet format with the MicroPython length and power
+pub fn config() -> RadioConfig {
+    RadioConfig::makecode().max_length(LENGTH as u8).power(POWER)
+}
+
+/// Configure `radio` with the MicroPython defaults, `config` and `CHANNEL`
+pub fn configure(radio: &mut Radio<FRAME_SIZE>) -> Result<(), ConfigError> {
+    radio.set_channel(CHANNEL);
+    radio.set_config(config())
+}
+
+/// Write the message of `data` to `buffer` as `radio.send_bytes`, returns
+/// the message length or 0 if `data` is longer than `LENGTH` bytes
+pub fn encode_bytes(data: &[u8], buffer: &mut [u8; LENGTH]) -> usize {
+    if data.len() > LENGTH {
+        return 0;
+    }
+    buffer[..data.len()].copy_from_slice(data);
+    data.len()
+}
+
+/// Write the message of `text` to `buffer` as `radio.send`, returns the
+/// message length or 0 if `text` is too long
+pub fn encode_str(text: &str, buffer: &mut [u8; LENGTH]) -> usize {
+    let length = STRING_PREFIX.len() + text.len();
+    if length > LENGTH {
+        return 0;
+    }
+    buffer[..STRING_PREFIX.len()].copy_from_slice(&STRING_PREFIX);
+    buffer[STRING_PREFIX.len()..length].copy_from_slice(text.as_bytes());
+    length
+}
+
+/// The string of a message, as `radio.receive`, returns `None` if the
+/// message does not start with `STRING_PREFIX` or is not UTF-8
+pub fn decode_str(message: &[u8]) -> Option<&str> {
+    if !message.starts_with(&STRING_PREFIX) {
+        return None;
+    }
+    core::str::from_utf8(&message[STRING_PREFIX.len()..]).ok()
+}
+
+/// Send `data` as `radio.send_bytes`, returns the number of bytes sent or 0
+/// if `data` is too long
+pub fn send_bytes<const N: usize>(radio: &mut Radio<N>, data: &[u8]) -> usize {
+    let mut buffer = [0u8; LENGTH];
+    let length = encode_bytes(data, &mut buffer);
+    if length == 0 { 0 } else { radio.send(&buffer[..length]) }
+}
+
+/// Send `text` as `radio.send`, returns the number of bytes sent, with the
+/// prefix, or 0 if `text` is too long
+pub fn send<const N: usize>(radio: &mut Radio<N>, text: &str) -> usize {
+    let mut buffer = [0u8; LENGTH];
+    let length = encode_str(text, &mut buffer);
+    if length == 0 { 0 } else { radio.send(&buffer[..length]) }
+}
+
+/// # Message
+///
+/// A received message with its signal strength and the time it was
+/// received, as returned by `radio.receive_full`. MicroPython gives the
+/// time in microseconds, this is the `Instant` of the ADDRESS event.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Message {
+    frame: Frame,
+    rssi: i8,
+    timestamp: Instant,
+}
+
+impl Message {
+    /// Create a message from its bytes, returns `None` if `data` is longer
+    /// than `LENGTH` bytes
+    pub fn new(data: &[u8], rssi: i8, timestamp: Instant) -> Option<Self> {
+        if data.len() > LENGTH {
+            return None;
+        }
+        let mut frame = [0u8; FRAME_SIZE];
+        frame[0] = data.len() as u8;
+        frame[1..=data.len()].copy_from_slice(data);
+        Some(Message { frame, rssi, timestamp })
+    }
+
+    /// The bytes of the message, with the prefix of strings
+    pub fn bytes(&self) -> &[u8] {
+        &self.frame[1..=usize::from(self.frame[0])]
+    }
+    /// The string of the message, see `decode_str`
+    pub fn text(&self) -> Option<&str> {
+        decode_str(self.bytes())
+    }
+    /// The frame, the length followed by the message
+    pub fn frame(&self) -> &Frame {
+        &self.frame
+    }
+    /// Is the message a MakeCode package, see `package::PackageRef`
+    pub fn is_makecode(&self) -> bool {
+        PackageRef::new(&self.frame).is_some()
+    }
+    /// Signal strength in dBm
+    pub fn rssi(&self) -> i8 {
+        self.rssi
+    }
+    /// Time the message was received
+    pub fn timestamp(&self) -> Instant {
+        self.timestamp
+    }
+}
+
+/// A message that is not a string, taken by `Receiver::receive`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct NotString;
+
+/// # Receiver
+///
+/// Keeps up to `MESSAGES` received messages, oldest first, as the
+/// MicroPython queue. Messages received while the queue is full are dropped.
+pub struct Receiver<const MESSAGES: usize = QUEUE> {
+    messages: [Option<Message>; MESSAGES],
+    /// Index of the oldest message
+    first: usize,
+    length: usize,
+    /// Number of messages dropped for a full queue
+    dropped: u32,
+}
+
+impl<const MESSAGES: usize> Receiver<MESSAGES> {
+    /// An empty queue of up to `MESSAGES` messages
+    pub fn new() -> Self {
+        Receiver { messages: [None; MESSAGES], first: 0, length: 0, dropped: 0 }
+    }
+
+    /// Number of messages in the queue
+    pub fn len(&self) -> usize {
+        self.length
+    }
+    /// Is the queue empty
+    pub fn is_empty(&self) -> bool {
+        self.length == 0
+    }
+    /// Number of messages dropped for a full queue
+    pub fn dropped(&self) -> u32 {
+        self.dropped
+    }
+
+    /// Add a received message, returns `false` if it was dropped
+    pub fn push(&mut self, message: Message) -> bool {
+        if self.length == MESSAGES {
+            self.dropped = self.dropped.wrapping_add(1);
+            return false;
+        }
+        self.messages[(self.first + self.length) % MESSAGES] = Some(message);
+        self.length += 1;
+        true
+    }
+
+    /// Add the message received by `radio`, if any, returns `true` if a
+    /// message was queued
+    pub fn poll(&mut self, radio: &mut Radio<FRAME_SIZE>) -> bool {
+        let mut data = [0u8; LENGTH];
+        let length = match radio.frame() {
+            Some(frame) => {
+                let payload = frame.payload();
+                data[..payload.len()].copy_from_slice(payload);
+                payload.len()
+            }
+            None => return false,
+        };
+        let message = Message::new(&data[..length], radio.rssi(), radio.timestamp());
+        radio.start_receive();
+        message.is_some_and(|message| self.push(message))
+    }
+
+    /// Take the oldest message, as `radio.receive_full`
+    pub fn receive_full(&mut self) -> Option<Message> {
+        if self.length == 0 {
+            return None;
+        }
+        let message = self.messages[self.first].take();
+        self.first = (self.first + 1) % MESSAGES;
+        self.length -= 1;
+        message
+    }
+
+    /// Take the oldest message and copy its bytes to `dst`, as
+    /// `radio.receive_bytes`, returns the length
+    pub fn receive_bytes(&mut self, dst: &mut [u8; LENGTH]) -> Option<usize> {
+        let message = self.receive_full()?;
+        let bytes = message.bytes();
+        dst[..bytes.len()].copy_from_slice(bytes);
+        Some(bytes.len())
+    }
+
+    /// Take the oldest message and copy its string to `dst`, as
+    /// `radio.receive`
+    ///
+    /// Returns `Ok(None)` if there is no message and `Err(NotString)` if the
+    /// message is not a string, the message is taken anyway.
+    pub fn receive<'b>(&mut self, dst: &'b mut [u8; LENGTH])
+        -> Result<Option<&'b str>, NotString>
+    {
+        let message = match self.receive_full() {
+            Some(message) => message,
+            None => return Ok(None),
+        };
+        let text = message.text().ok_or(NotString)?;
+        dst[..text.len()].copy_from_slice(text.as_bytes());
+        core::str::from_utf8(&dst[..text.len()]).map(Some).map_err(|_| NotString)
+    }
+}
+
+impl<const MESSAGES: usize> Default for Receiver<MESSAGES> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(data: &[u8]) -> Message {
+        Message::new(data, -60, Instant::from_ticks(0)).unwrap()
+    }
+
+    #[test]
+    fn encodes_strings() {
+        let mut buffer = [0u8; LENGTH];
+        assert_eq!(encode_str("hello", &mut buffer), 8);
+        assert_eq!(&buffer[..8], b"\x01\x00\x01hello");
+        assert_eq!(encode_str("", &mut buffer), 3);
+        let text = "a".repeat(LENGTH - STRING_PREFIX.len());
+        assert_eq!(encode_str(&text, &mut buffer), LENGTH);
+        assert_eq!(decode_str(&buffer), Some(text.as_str()));
+        let text = "a".repeat(LENGTH - STRING_PREFIX.len() + 1);
+        assert_eq!(encode_str(&text, &mut buffer), 0);
+    }
+
+    #[test]
+    fn decodes_strings() {
+        assert_eq!(decode_str(b"\x01\x00\x01hello"), Some("hello"));
+        assert_eq!(decode_str(b"\x01\x00\x01"), Some(""));
+        assert_eq!(decode_str(b"\x01\x00"), None);
+        assert_eq!(decode_str(b"\x01\x01\x01hello"), None);
+        assert_eq!(decode_str(b"hello"), None);
+        assert_eq!(decode_str(b"\x01\x00\x01\xc3\x28"), None);
+    }
+
+    #[test]
+    fn encodes_bytes() {
+        let mut buffer = [0u8; LENGTH];
+        let data: [u8; LENGTH + 1] = core::array::from_fn(|index| index as u8);
+        assert_eq!(encode_bytes(&data[..LENGTH], &mut buffer), LENGTH);
+        assert_eq!(buffer, data[..LENGTH]);
+        assert_eq!(encode_bytes(&data, &mut buffer), 0);
+        assert_eq!(message(&data[..LENGTH]).bytes(), &data[..LENGTH]);
+        assert_eq!(message(&data[..LENGTH]).frame()[0], LENGTH as u8);
+        assert!(Message::new(&data, 0, Instant::from_ticks(0)).is_none());
+    }
+
+    #[test]
+    fn queue_wraps_around() {
+        let mut receiver: Receiver<2> = Receiver::new();
+        assert!(receiver.is_empty());
+        for round in 0..5u8 {
+            assert!(receiver.push(message(&[round, 0])));
+            assert!(receiver.push(message(&[round, 1])));
+            assert!(!receiver.push(message(&[round, 2])));
+            assert_eq!(receiver.len(), 2);
+            assert_eq!(receiver.dropped(), u32::from(round) + 1);
+            assert_eq!(receiver.receive_full().unwrap().bytes(), [round, 0]);
+            assert!(receiver.push(message(&[round, 3])));
+            assert_eq!(receiver.receive_full().unwrap().bytes(), [round, 1]);
+            assert_eq!(receiver.receive_full().unwrap().bytes(), [round, 3]);
+            assert_eq!(receiver.receive_full(), None);
+        }
+    }
+
+    #[test]
+    fn receive_takes_strings() {
+        let mut receiver: Receiver = Receiver::new();
+        let mut buffer = [0u8; LENGTH];
+        assert_eq!(receiver.receive(&mut buffer), Ok(None));
+        receiver.push(message(&[0xff, 0x00]));
+        receiver.push(message(b"\x01\x00\x01hi"));
+        // Not a string, taken anyway
+        assert_eq!(receiver.receive(&mut buffer), Err(NotString));
+        assert_eq!(receiver.len(), 1);
+        assert_eq!(receiver.receive(&mut buffer), Ok(Some("hi")));
+        assert!(receiver.is_empty());
+        receiver.push(message(b"\x01\x00\x01hi"));
+        assert_eq!(receiver.receive_bytes(&mut buffer), Some(5));
+        assert_eq!(&buffer[..5], b"\x01\x00\x01hi");
+        assert_eq!(receiver.receive_bytes(&mut buffer), None);
+    }
+
+    #[test]
+    fn tells_makecode_packages_apart() {
+        assert_eq!(message(b"\x01\x00\x01hi").text(), Some("hi"));
+        assert!(!message(b"\x01\x00\x01hi").is_makecode());
+        let integer = [1, 7, 1, 0, 0xe8, 0x03, 0, 0, 0x78, 0x56, 0x34, 0x12,
+            0xd6, 0xff, 0xff, 0xff];
+        assert!(message(&integer).is_makecode());
+        assert_eq!(message(&integer).text(), None);
+    }
+
+    #[test]
+    fn config_fits_frames() {
+        assert_eq!(config().validate(FRAME_SIZE), Ok(()));
+        assert_eq!(config().validate(FRAME_SIZE - 1), Err(ConfigError::MaxLength));
+        assert_eq!(config().power, POWER);
+    }
+}
@@ -4,7 +4,17 @@ use core::convert::From;
 
 use byteorder::{ByteOrder, LittleEndian};
 
-use crate::datagram::{DatagramHeader, DatagramProtocol};
+use crate::datagram::{self, DatagramHeader, DatagramProtocol};
+use crate::time;
+
+/// Size of the package header following the datagram header
+pub const HEADER_SIZE: usize = 9;
+/// Offset of the payload in a frame, after the datagram and package headers
+pub const PAYLOAD_OFFSET: usize = datagram::HEADER_SIZE + HEADER_SIZE;
+/// Offset of the time in the package header, after the type
+const TIME_OFFSET: usize = 1;
+/// Offset of the serial number in the package header, after the time
+const SERIAL_NUMBER_OFFSET: usize = TIME_OFFSET + 4;
 
 #[derive(Clone, PartialEq)]
 pub enum PackageType {
@@ -74,34 +84,55 @@ pub struct PackageHeader
 }
 
 impl PackageHeader {
+    /// Create a package header, `time` is milliseconds since boot
+    pub fn new(datagram_header: DatagramHeader, package_type: PackageType, time: u32,
+        serial_number: u32) -> PackageHeader
+    {
+        PackageHeader {
+            datagram_header,
+            package_type,
+            time,
+            serial_number,
+        }
+    }
+
+    /// Pack the PackageHeader into the byte slice
+    pub fn pack(&self, buffer: &mut [u8]) {
+        self.datagram_header.pack(buffer);
+        let slice = &mut buffer[datagram::HEADER_SIZE..];
+        slice[0] = u8::from(self.package_type.clone());
+        LittleEndian::write_u32(&mut slice[TIME_OFFSET..SERIAL_NUMBER_OFFSET], self.time);
+        LittleEndian::write_u32(&mut slice[SERIAL_NUMBER_OFFSET..HEADER_SIZE],
+            self.serial_number);
+    }
+
     /// Unpack a PackageHeader from the byte slice
+    ///
+    /// The package type is `PackageType::Unknown` if the slice or the
+    /// datagram is too short for the header.
     pub fn unpack(buffer: &[u8]) -> PackageHeader {
-        let datagram_header = DatagramHeader::unpack(&buffer[..]);
-        let slice = &buffer[4..];
-        let package_type =
-            if datagram_header.protocol() != DatagramProtocol::Datagram
-                || datagram_header.payload_length() <= 8
-        {
-            PackageType::Unknown
-        }
-        else {
-            PackageType::from(slice[0])
+        let datagram_header = DatagramHeader::unpack(buffer);
+        let slice = buffer.get(datagram::HEADER_SIZE..PAYLOAD_OFFSET).filter(|_| {
+            datagram_header.protocol() == DatagramProtocol::Datagram
+                && datagram_header.payload_length() >= HEADER_SIZE
+        });
+        let package_type = match slice {
+            Some(slice) => PackageType::from(slice[0]),
+            None => PackageType::Unknown,
         };
-        if package_type != PackageType::Unknown {
-            PackageHeader {
+        match slice {
+            Some(slice) if package_type != PackageType::Unknown => PackageHeader {
                 datagram_header,
                 package_type,
-                time: LittleEndian::read_u32(&slice[1..=4]),
-                serial_number: LittleEndian::read_u32(&slice[5..=8]),
-            }
-        }
-        else {
-            PackageHeader {
+                time: LittleEndian::read_u32(&slice[TIME_OFFSET..SERIAL_NUMBER_OFFSET]),
+                serial_number: LittleEndian::read_u32(&slice[SERIAL_NUMBER_OFFSET..]),
+            },
+            _ => PackageHeader {
                 datagram_header,
                 package_type: PackageType::Unknown,
                 time: 0,
                 serial_number: 0,
-            }
+            },
         }
     }
 
@@ -120,7 +151,7 @@ impl PackageHeader {
     /// Get the package payload length
     pub fn payload_length(&self) -> usize {
         let length = self.datagram_header.payload_length();
-        if length > 9 { length - 9 } else { 0 }
+        length.saturating_sub(HEADER_SIZE)
     }
 }
 
@@ -142,11 +173,11 @@ pub struct Package {
 impl Package {
     /// Unpack a Package from the byte slice
     pub fn unpack(buffer: &[u8]) -> Package {
-        let header = PackageHeader::unpack(&buffer[..]);
+        let header = PackageHeader::unpack(buffer);
         match header.package_type {
             PackageType::Integer => {
-                if header.payload_length() >= 4 {
-                    let value = LittleEndian::read_i32(&buffer[13..=16]);
+                if header.payload_length() >= 4 && buffer.len() >= PAYLOAD_OFFSET + 4 {
+                    let value = LittleEndian::read_i32(&buffer[PAYLOAD_OFFSET..PAYLOAD_OFFSET + 4]);
                     return Package {
                         header,
                         data: PackageData::Integer(value),
@@ -154,8 +185,8 @@ impl Package {
                 }
             }
             PackageType::IntegerValue => {
-                if header.payload_length() >= 5 {
-                    let value = LittleEndian::read_i32(&buffer[13..=16]);
+                if header.payload_length() >= 5 && buffer.len() >= PAYLOAD_OFFSET + 5 {
+                    let value = LittleEndian::read_i32(&buffer[PAYLOAD_OFFSET..PAYLOAD_OFFSET + 4]);
                     return Package {
                         header,
                         data: PackageData::IntegerValue(value),
@@ -175,4 +206,345 @@ impl Package {
             data: PackageData::Unknown,
         }
     }
-}
\ No newline at end of file
+    /// Create an integer package, stamped with `time::millis`
+    pub fn integer(group: u8, serial_number: u32, value: i32) -> Package {
+        Self::integer_at(group, serial_number, time::millis(), value)
+    }
+
+    /// Create an integer package stamped with `time`, in milliseconds, for
+    /// example `timesync::TimeSync::synced_millis`
+    pub fn integer_at(group: u8, serial_number: u32, time: u32, value: i32) -> Package {
+        let datagram_header = DatagramHeader::new(group, DatagramProtocol::Datagram,
+            HEADER_SIZE + 4);
+        Package {
+            header: PackageHeader::new(datagram_header, PackageType::Integer,
+                time, serial_number),
+            data: PackageData::Integer(value),
+        }
+    }
+
+    /// Pack the Package into the byte slice
+    ///
+    /// Returns the number of bytes written, including the length byte. Only
+    /// integer packages can be packed, for other packages 0 is returned.
+    pub fn pack(&self, buffer: &mut [u8]) -> usize {
+        let offset = PAYLOAD_OFFSET;
+        let payload_length = match self.data {
+            PackageData::Integer(value) => {
+                LittleEndian::write_i32(&mut buffer[offset..offset + 4], value);
+                4
+            }
+            PackageData::IntegerValue(value) => {
+                LittleEndian::write_i32(&mut buffer[offset..offset + 4], value);
+                // Empty name
+                buffer[offset + 4] = 0;
+                5
+            }
+            _ => return 0,
+        };
+        let datagram_header = DatagramHeader::new(self.header.datagram_header.group(),
+            DatagramProtocol::Datagram, HEADER_SIZE + payload_length);
+        let header = PackageHeader::new(datagram_header, self.header.package_type(),
+            self.header.time, self.header.serial_number);
+        header.pack(buffer);
+        offset + payload_length
+    }
+}
+
+/// # Package view
+///
+/// A package borrowed from a received frame, checked once when it is created
+/// and then read without copying. Unlike `Package`, all package types are
+/// decoded.
+///
+/// The payload following the package header is
+///
+/// ```notrust
+/// | type             | payload
+/// ----------------------------------------------------------
+/// | 0, Integer       | i32
+/// | 1, IntegerValue  | i32, name length, name
+/// | 2, String        | string length, string
+/// | 3, Buffer        | buffer length, buffer
+/// | 4, Double        | f64
+/// | 5, DoubleValue   | f64, name length, name
+/// ```
+/// Numbers are little endian and names and strings UTF-8.
+///
+/// ```notrust
+/// if let Some(frame) = radio.frame() {
+///     if let Some(package) = PackageRef::new(frame.as_bytes()) {
+///         if package.name() == Some("speed") { ... }
+///     }
+/// }
+/// radio.start_receive();
+/// ```
+#[derive(Clone, Copy)]
+pub struct PackageRef<'a> {
+    frame: &'a [u8],
+    /// The number, `data` is empty for strings and buffers
+    number: &'a [u8],
+    /// The name, the string or the buffer
+    data: &'a [u8],
+    text: Option<&'a str>,
+}
+
+impl<'a> PackageRef<'a> {
+    /// The package in `frame`, starting with the length
+    ///
+    /// Returns `None` if the frame is cut short, is not a MakeCode package
+    /// or the package is malformed.
+    pub fn new(frame: &'a [u8]) -> Option<Self> {
+        let length = usize::from(*frame.first()?);
+        let frame = frame.get(..=length)?;
+        if frame.len() < PAYLOAD_OFFSET
+            || DatagramProtocol::from(frame[3]) != DatagramProtocol::Datagram
+        {
+            return None;
+        }
+        let payload = &frame[PAYLOAD_OFFSET..];
+        // A number, optionally followed by a name, or a length prefixed
+        // string or buffer
+        let (number, data, text) = match PackageType::from(frame[datagram::HEADER_SIZE]) {
+            PackageType::Integer => (payload.get(..4)?, &payload[..0], false),
+            PackageType::IntegerValue => (payload.get(..4)?, prefixed(&payload[4..])?, true),
+            PackageType::String => (&payload[..0], prefixed(payload)?, true),
+            PackageType::Buffer => (&payload[..0], prefixed(payload)?, false),
+            PackageType::Double => (payload.get(..8)?, &payload[..0], false),
+            PackageType::DoubleValue => (payload.get(..8)?, prefixed(&payload[8..])?, true),
+            PackageType::Unknown => return None,
+        };
+        let text = if text { Some(core::str::from_utf8(data).ok()?) } else { None };
+        Some(PackageRef { frame, number, data, text })
+    }
+
+    /// The group
+    pub fn group(&self) -> u8 {
+        self.frame[2]
+    }
+    /// The datagram version
+    pub fn version(&self) -> u8 {
+        self.frame[1]
+    }
+    /// The package type
+    pub fn package_type(&self) -> PackageType {
+        PackageType::from(self.frame[datagram::HEADER_SIZE])
+    }
+    /// Milliseconds since the sender booted
+    pub fn time(&self) -> u32 {
+        LittleEndian::read_u32(&self.frame[datagram::HEADER_SIZE + TIME_OFFSET..])
+    }
+    /// Serial number of the sender
+    pub fn serial_number(&self) -> u32 {
+        LittleEndian::read_u32(&self.frame[datagram::HEADER_SIZE + SERIAL_NUMBER_OFFSET..])
+    }
+
+    /// The value of an integer package
+    pub fn as_i32(&self) -> Option<i32> {
+        if self.number.len() == 4 { Some(LittleEndian::read_i32(self.number)) } else { None }
+    }
+    /// The value of a double package
+    pub fn as_f64(&self) -> Option<f64> {
+        if self.number.len() == 8 { Some(LittleEndian::read_f64(self.number)) } else { None }
+    }
+    /// The name of a named value package
+    pub fn name(&self) -> Option<&'a str> {
+        match self.package_type() {
+            PackageType::IntegerValue | PackageType::DoubleValue => self.text,
+            _ => None,
+        }
+    }
+    /// The string of a string package
+    pub fn as_str(&self) -> Option<&'a str> {
+        match self.package_type() {
+            PackageType::String => self.text,
+            _ => None,
+        }
+    }
+    /// The bytes of a buffer package
+    pub fn as_bytes(&self) -> Option<&'a [u8]> {
+        match self.package_type() {
+            PackageType::Buffer => Some(self.data),
+            _ => None,
+        }
+    }
+
+    /// The whole frame, starting with the length
+    ///
+    /// The frame can be forwarded as is, with `radio.send(&frame[1..])`.
+    pub fn frame(&self) -> &'a [u8] {
+        self.frame
+    }
+}
+
+/// The bytes following a length at the start of `buffer`
+fn prefixed(buffer: &[u8]) -> Option<&[u8]> {
+    let length = usize::from(*buffer.first()?);
+    buffer.get(1..=length)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const INTEGER: [u8; 17] = [16, 1, 7, 1, 0, 0xe8, 0x03, 0, 0, 0x78, 0x56, 0x34, 0x12,
+        0xd6, 0xff, 0xff, 0xff];
+
+    #[test]
+    fn unpacks_integer_package() {
+        let package = Package::unpack(&INTEGER);
+        assert!(package.header.package_type() == PackageType::Integer);
+        assert_eq!(package.header.datagram_header.group(), 7);
+        assert_eq!(package.header.time(), 1000);
+        assert_eq!(package.header.serial_number(), 0x1234_5678);
+        assert!(matches!(package.data, PackageData::Integer(-42)));
+    }
+
+    #[test]
+    fn packs_what_it_unpacks() {
+        let mut buffer = [0u8; 32];
+        let length = Package::integer_at(7, 0x1234_5678, 1000, -42).pack(&mut buffer);
+        assert_eq!(length, 17);
+        assert_eq!(&buffer[..length], &INTEGER);
+    }
+
+    #[test]
+    fn short_buffers_are_unknown() {
+        for length in 0..PAYLOAD_OFFSET {
+            let header = PackageHeader::unpack(&INTEGER[..length]);
+            assert!(header.package_type() == PackageType::Unknown, "{} bytes", length);
+            assert_eq!(header.serial_number(), 0);
+        }
+        // The length covers the header, the buffer not the value
+        let package = Package::unpack(&INTEGER[..PAYLOAD_OFFSET + 2]);
+        assert!(package.header.package_type() == PackageType::Integer);
+        assert!(matches!(package.data, PackageData::Unknown));
+    }
+
+    #[test]
+    fn short_datagrams_are_unknown() {
+        let mut buffer = INTEGER;
+        buffer[0] = (datagram::HEADER_SIZE + HEADER_SIZE - 2) as u8;
+        assert!(PackageHeader::unpack(&buffer).package_type() == PackageType::Unknown);
+        buffer[0] = 16;
+        buffer[3] = 3;
+        assert!(PackageHeader::unpack(&buffer).package_type() == PackageType::Unknown);
+    }
+
+    /// A frame of a package of `package_type` in group 7, from serial
+    /// number 0x12345678 at time 1000
+    fn package_frame(package_type: PackageType, payload: &[u8]) -> Vec<u8> {
+        let mut frame = INTEGER[..PAYLOAD_OFFSET].to_vec();
+        frame[0] = (PAYLOAD_OFFSET + payload.len() - 1) as u8;
+        frame[datagram::HEADER_SIZE] = u8::from(package_type);
+        frame.extend_from_slice(payload);
+        frame
+    }
+
+    /// A number followed by a length prefixed `name`
+    fn named(number: &[u8], name: &[u8]) -> Vec<u8> {
+        let mut payload = number.to_vec();
+        payload.push(name.len() as u8);
+        payload.extend_from_slice(name);
+        payload
+    }
+
+    #[test]
+    fn references_every_package_type() {
+        let integer = PackageRef::new(&INTEGER).unwrap();
+        assert!(integer.package_type() == PackageType::Integer);
+        assert_eq!((integer.version(), integer.group()), (1, 7));
+        assert_eq!((integer.time(), integer.serial_number()), (1000, 0x1234_5678));
+        assert_eq!((integer.as_i32(), integer.as_f64()), (Some(-42), None));
+        assert_eq!((integer.name(), integer.as_str(), integer.as_bytes()), (None, None, None));
+
+        let payload = named(&(-42i32).to_le_bytes(), b"temp");
+        let frame = package_frame(PackageType::IntegerValue, &payload);
+        let value = PackageRef::new(&frame).unwrap();
+        assert!(value.package_type() == PackageType::IntegerValue);
+        assert_eq!((value.as_i32(), value.name(), value.as_str()), (Some(-42), Some("temp"), None));
+
+        let frame = package_frame(PackageType::String, b"\x05hello");
+        let string = PackageRef::new(&frame).unwrap();
+        assert_eq!((string.as_str(), string.name(), string.as_i32()), (Some("hello"), None, None));
+        assert_eq!(string.as_bytes(), None);
+
+        let frame = package_frame(PackageType::Buffer, &[3, 0xff, 0x00, 0x80]);
+        let buffer = PackageRef::new(&frame).unwrap();
+        assert_eq!(buffer.as_bytes(), Some(&[0xff, 0x00, 0x80][..]));
+        assert_eq!((buffer.as_str(), buffer.as_i32()), (None, None));
+
+        let frame = package_frame(PackageType::Double, &1.5f64.to_le_bytes());
+        let double = PackageRef::new(&frame).unwrap();
+        assert_eq!((double.as_f64(), double.as_i32(), double.name()), (Some(1.5), None, None));
+
+        let payload = named(&(-2.25f64).to_le_bytes(), b"x");
+        let frame = package_frame(PackageType::DoubleValue, &payload);
+        let value = PackageRef::new(&frame).unwrap();
+        assert!(value.package_type() == PackageType::DoubleValue);
+        assert_eq!((value.as_f64(), value.name()), (Some(-2.25), Some("x")));
+    }
+
+    #[test]
+    fn rejects_length_prefixes_past_the_frame() {
+        for package_type in [PackageType::String, PackageType::Buffer] {
+            assert!(PackageRef::new(&package_frame(package_type.clone(), b"\x05hell")).is_none());
+            assert!(PackageRef::new(&package_frame(package_type, b"\x04hell")).is_some());
+        }
+        let mut payload = named(&[0; 4], b"temp");
+        payload[4] = 5;
+        assert!(PackageRef::new(&package_frame(PackageType::IntegerValue, &payload)).is_none());
+        let mut payload = named(&[0; 8], b"temp");
+        payload[8] = 5;
+        assert!(PackageRef::new(&package_frame(PackageType::DoubleValue, &payload)).is_none());
+        // No length at all
+        assert!(PackageRef::new(&package_frame(PackageType::String, &[])).is_none());
+        assert!(PackageRef::new(&package_frame(PackageType::IntegerValue, &[0; 4])).is_none());
+    }
+
+    #[test]
+    fn rejects_names_not_utf8() {
+        let text = [2, 0xc3, 0x28];
+        assert!(PackageRef::new(&package_frame(PackageType::String, &text)).is_none());
+        let payload = named(&[0; 4], &text[1..]);
+        assert!(PackageRef::new(&package_frame(PackageType::IntegerValue, &payload)).is_none());
+        let payload = named(&[0; 8], &text[1..]);
+        assert!(PackageRef::new(&package_frame(PackageType::DoubleValue, &payload)).is_none());
+        // Buffers are bytes
+        let frame = package_frame(PackageType::Buffer, &text);
+        assert_eq!(PackageRef::new(&frame).unwrap().as_bytes(), Some(&text[1..]));
+    }
+
+    #[test]
+    fn rejects_short_frames() {
+        assert!(PackageRef::new(&[]).is_none());
+        // Shorter than the length byte says
+        for length in 0..INTEGER.len() {
+            assert!(PackageRef::new(&INTEGER[..length]).is_none(), "{} bytes", length);
+        }
+        // The length covers the header, not the number
+        let mut frame = INTEGER;
+        frame[0] = PAYLOAD_OFFSET as u8 + 2;
+        assert!(PackageRef::new(&frame).is_none());
+        frame[0] = PAYLOAD_OFFSET as u8 - 2;
+        assert!(PackageRef::new(&frame).is_none());
+        // Not a MakeCode package
+        let mut frame = INTEGER;
+        frame[3] = u8::from(DatagramProtocol::Reliable);
+        assert!(PackageRef::new(&frame).is_none());
+        let mut frame = INTEGER;
+        frame[datagram::HEADER_SIZE] = 6;
+        assert!(PackageRef::new(&frame).is_none());
+    }
+
+    #[test]
+    fn frame_is_the_length_and_the_package() {
+        let mut buffer = [0xaa; crate::radio::MAX_PACKAGE_SIZE];
+        buffer[..INTEGER.len()].copy_from_slice(&INTEGER);
+        let package = PackageRef::new(&buffer).unwrap();
+        assert_eq!(package.frame().len(), usize::from(INTEGER[0]) + 1);
+        assert_eq!(package.frame(), &INTEGER);
+        let frame = package_frame(PackageType::String, b"\x05hello");
+        assert_eq!(PackageRef::new(&frame).unwrap().frame(), &frame[..]);
+    }
+}
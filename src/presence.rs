@@ -0,0 +1,505 @@
+//! Neighbour discovery and presence
+//!
+//! Every board sends a beacon with its serial number and name once a
+//! second. Boards keep a table of the neighbours they hear, with the time
+//! they were last heard, their average signal strength and how many beacons
+//! were received and missed. A neighbour disappears when no beacon has been
+//! received for `NEIGHBOUR_TIMEOUT`.
+//!
+//! Beacons are only received from boards in the same group, so the table
+//! shows who is on the right group.
+//!
+//! ```notrust
+//! let mut presence = Presence::<25>::new(serial_number, group);
+//! loop {
+//!     while let Some(event) = presence.poll(&mut radio) {
+//!         match event {
+//!             Event::Appeared(neighbour) => ...,
+//!             Event::Disappeared(neighbour) => ...,
+//!         }
+//!     }
+//!     display.display(presence.image());
+//! }
+//! ```
+
+use byteorder::{ByteOrder, LittleEndian};
+
+use crate::datagram::{self, DatagramHeader, DatagramProtocol};
+use crate::leds::Image;
+use crate::link::Link;
+use crate::radio::{PackageBuffer, MAX_PACKAGE_SIZE};
+use crate::random::Random;
+use crate::time::{Duration, Instant};
+
+/// Length of a board name
+pub const NAME_LENGTH: usize = 5;
+/// Size of the beacon following the datagram header
+pub const BEACON_SIZE: usize = 5 + NAME_LENGTH;
+/// Time between beacons
+pub const BEACON_PERIOD: Duration = Duration::from_secs(1);
+/// Longest random delay added to the beacon period
+pub const BEACON_JITTER: Duration = Duration::from_millis(100);
+/// A neighbour disappears when no beacon has been received for this long,
+/// three beacons
+pub const NEIGHBOUR_TIMEOUT: Duration = Duration::from_millis(3500);
+/// Weight of a new sample in the signal strength average, as a power of two
+const RSSI_WEIGHT: u32 = 3;
+/// Fraction bits of the signal strength average
+const RSSI_FRACTION: u32 = 4;
+
+/// Board name, lower case ASCII letters
+pub type Name = [u8; NAME_LENGTH];
+
+/// The name MakeCode and the micro:bit runtime give the board with
+/// `serial_number`, such as "zogat"
+///
+/// ## Reference
+///
+/// * <https://github.com/lancaster-university/microbit-dal/blob/master/source/core/MicroBitDevice.cpp>
+pub fn friendly_name(serial_number: u32) -> Name {
+    const CONSONANTS: [u8; 5] = *b"zvgpt";
+    const VOWELS: [u8; 5] = *b"uoiea";
+    let mut name = [0u8; NAME_LENGTH];
+    let mut n = serial_number;
+    let mut d = 5u32;
+    let mut ld = 1u32;
+    for i in 0..NAME_LENGTH {
+        let h = (n % d) / ld;
+        n -= h;
+        d *= 5;
+        ld *= 5;
+        let letters = if i % 2 == 0 { &CONSONANTS } else { &VOWELS };
+        name[NAME_LENGTH - i - 1] = letters[h as usize];
+    }
+    name
+}
+
+/// # Beacon
+///
+/// ```notrust
+/// | 0 ... 3         | 4 ... 7       | 8        | 9 ... 13
+/// -------------------------------------------------------
+/// | datagram header | serial number | sequence | name
+/// ```
+/// The sequence number is counted for every beacon, so that receivers can
+/// count the beacons they missed.
+struct Beacon {
+    serial_number: u32,
+    sequence: u8,
+    name: Name,
+}
+
+impl Beacon {
+    fn pack(&self, group: u8, buffer: &mut PackageBuffer) {
+        DatagramHeader::new(group, DatagramProtocol::Presence, BEACON_SIZE).pack(buffer);
+        let slice = &mut buffer[datagram::HEADER_SIZE..];
+        LittleEndian::write_u32(&mut slice[0..=3], self.serial_number);
+        slice[4] = self.sequence;
+        slice[5..5 + NAME_LENGTH].copy_from_slice(&self.name);
+    }
+
+    fn unpack(group: u8, buffer: &PackageBuffer) -> Option<Beacon> {
+        let header = DatagramHeader::unpack(buffer);
+        if header.protocol() != DatagramProtocol::Presence
+            || header.version() != datagram::VERSION
+            || header.group() != group
+            || header.payload_length() != BEACON_SIZE
+        {
+            return None;
+        }
+        let slice = &buffer[datagram::HEADER_SIZE..];
+        let mut name = [0u8; NAME_LENGTH];
+        name.copy_from_slice(&slice[5..5 + NAME_LENGTH]);
+        Some(Beacon {
+            serial_number: LittleEndian::read_u32(&slice[0..=3]),
+            sequence: slice[4],
+            name,
+        })
+    }
+}
+
+/// A board heard by this board
+#[derive(Clone, Copy, Debug)]
+pub struct Neighbour {
+    serial_number: u32,
+    name: Name,
+    first_seen: Instant,
+    last_seen: Instant,
+    sequence: u8,
+    /// Average signal strength in 1/16 dBm
+    rssi: Option<i32>,
+    received: u32,
+    missed: u32,
+}
+
+impl Neighbour {
+    /// Serial number of the neighbour
+    pub fn serial_number(&self) -> u32 {
+        self.serial_number
+    }
+    /// Name of the neighbour, empty if it is not valid UTF-8
+    pub fn name(&self) -> &str {
+        core::str::from_utf8(&self.name).unwrap_or("")
+    }
+    /// Time the first beacon of the neighbour was received
+    pub fn first_seen(&self) -> Instant {
+        self.first_seen
+    }
+    /// Time the latest beacon of the neighbour was received
+    pub fn last_seen(&self) -> Instant {
+        self.last_seen
+    }
+    /// Average signal strength in dBm, if the link measures it
+    pub fn rssi(&self) -> Option<i8> {
+        self.rssi.map(|rssi| (rssi >> RSSI_FRACTION) as i8)
+    }
+    /// Number of beacons received
+    pub fn received(&self) -> u32 {
+        self.received
+    }
+    /// Number of beacons missed, counted from the sequence numbers
+    pub fn missed(&self) -> u32 {
+        self.missed
+    }
+
+    fn update(&mut self, now: Instant, beacon: &Beacon, rssi: Option<i8>) {
+        let gap = beacon.sequence.wrapping_sub(self.sequence);
+        // A board that restarts starts over from sequence number 0, the
+        // missed beacons must have had the time to be sent
+        let elapsed = (now - self.last_seen).as_ticks();
+        if gap > 1 && gap < 0x80 && elapsed >= BEACON_PERIOD.as_ticks() * u64::from(gap - 1) {
+            self.missed = self.missed.wrapping_add(u32::from(gap - 1));
+        }
+        self.sequence = beacon.sequence;
+        self.name = beacon.name;
+        self.last_seen = now;
+        self.received += 1;
+        if let Some(sample) = rssi {
+            let sample = i32::from(sample) << RSSI_FRACTION;
+            self.rssi = Some(match self.rssi {
+                Some(average) => average + ((sample - average) >> RSSI_WEIGHT),
+                None => sample,
+            });
+        }
+    }
+}
+
+/// Presence events
+#[derive(Clone, Copy, Debug)]
+pub enum Event {
+    /// A board was heard for the first time or again after it disappeared
+    Appeared(Neighbour),
+    /// No beacon has been received from a board for `NEIGHBOUR_TIMEOUT`
+    Disappeared(Neighbour),
+}
+
+/// # Presence
+///
+/// Sends beacons and keeps a table of up to `NEIGHBOURS` neighbours. Boards
+/// heard while the table is full are ignored until a neighbour disappears.
+pub struct Presence<const NEIGHBOURS: usize> {
+    serial_number: u32,
+    group: u8,
+    name: Name,
+    sequence: u8,
+    neighbours: [Option<Neighbour>; NEIGHBOURS],
+    next_beacon: Instant,
+    random: Random,
+    ignored: u32,
+}
+
+impl<const NEIGHBOURS: usize> Presence<NEIGHBOURS> {
+    /// Create the presence service of the device with `serial_number` in
+    /// `group`, named with `friendly_name`
+    pub fn new(serial_number: u32, group: u8) -> Self {
+        Presence {
+            serial_number,
+            group,
+            name: friendly_name(serial_number),
+            sequence: 0,
+            neighbours: [None; NEIGHBOURS],
+            next_beacon: Instant::from_ticks(0),
+            random: Random::new(serial_number),
+            ignored: 0,
+        }
+    }
+
+    /// Change the name sent in beacons
+    pub fn set_name(&mut self, name: Name) {
+        self.name = name;
+    }
+
+    /// The name sent in beacons
+    pub fn name(&self) -> &str {
+        core::str::from_utf8(&self.name).unwrap_or("")
+    }
+
+    /// The neighbours, in table order
+    pub fn neighbours(&self) -> impl Iterator<Item = &Neighbour> {
+        self.neighbours.iter().flatten()
+    }
+
+    /// Number of neighbours
+    pub fn count(&self) -> usize {
+        self.neighbours().count()
+    }
+
+    /// The neighbour with `serial_number`, if present
+    pub fn neighbour(&self, serial_number: u32) -> Option<&Neighbour> {
+        self.neighbours().find(|neighbour| neighbour.serial_number == serial_number)
+    }
+
+    /// Number of beacons ignored as the table was full
+    pub fn ignored(&self) -> u32 {
+        self.ignored
+    }
+
+    /// Send a beacon when it is due, receive beacons and forget neighbours
+    /// that have not been heard for a while
+    ///
+    /// Returns the next event, or `None` when there are no more datagrams.
+    /// Datagrams of other protocols are dropped.
+    pub fn poll<L: Link>(&mut self, link: &mut L) -> Option<Event> {
+        let now = link.now();
+        if now >= self.next_beacon {
+            self.send(link, now);
+        }
+        while let Some(frame) = link.receive() {
+            if let Some(event) = self.handle(link.now(), &frame, link.rssi()) {
+                return Some(event);
+            }
+        }
+        self.expire(link.now())
+    }
+
+    /// Handle a received datagram with signal strength `rssi`
+    ///
+    /// Returns `Event::Appeared` if the datagram is a beacon from a new
+    /// neighbour.
+    pub fn handle(&mut self, now: Instant, frame: &PackageBuffer, rssi: Option<i8>)
+        -> Option<Event>
+    {
+        let beacon = Beacon::unpack(self.group, frame)?;
+        if beacon.serial_number == self.serial_number {
+            return None;
+        }
+        if let Some(neighbour) = self.neighbours.iter_mut().flatten()
+            .find(|neighbour| neighbour.serial_number == beacon.serial_number)
+        {
+            neighbour.update(now, &beacon, rssi);
+            return None;
+        }
+        let slot = match self.neighbours.iter().position(|neighbour| neighbour.is_none()) {
+            Some(slot) => slot,
+            None => {
+                self.ignored = self.ignored.wrapping_add(1);
+                return None;
+            }
+        };
+        let mut neighbour = Neighbour {
+            serial_number: beacon.serial_number,
+            name: beacon.name,
+            first_seen: now,
+            last_seen: now,
+            sequence: beacon.sequence,
+            rssi: None,
+            received: 0,
+            missed: 0,
+        };
+        neighbour.update(now, &beacon, rssi);
+        self.neighbours[slot] = Some(neighbour);
+        Some(Event::Appeared(neighbour))
+    }
+
+    /// Remove a neighbour not heard for `NEIGHBOUR_TIMEOUT`
+    ///
+    /// Returns `Event::Disappeared` for the removed neighbour, call until
+    /// `None` is returned to remove all of them.
+    pub fn expire(&mut self, now: Instant) -> Option<Event> {
+        let slot = self.neighbours.iter_mut()
+            .find(|slot| matches!(slot, Some(neighbour)
+                if now - neighbour.last_seen > NEIGHBOUR_TIMEOUT))?;
+        slot.take().map(Event::Disappeared)
+    }
+
+    /// The neighbours on the LED matrix
+    ///
+    /// Each entry of the table has its own LED, left to right and top to
+    /// bottom, lit while the neighbour is present. The first 25 entries are
+    /// shown.
+    pub fn image(&self) -> Image {
+        let mut image = [[0u8; 5]; 5];
+        for (index, neighbour) in self.neighbours.iter().take(25).enumerate() {
+            if neighbour.is_some() {
+                image[index / 5][index % 5] = 0xff;
+            }
+        }
+        image
+    }
+
+    fn send<L: Link>(&mut self, link: &mut L, now: Instant) {
+        let jitter = self.random.jitter(BEACON_JITTER);
+        self.next_beacon = now + BEACON_PERIOD + jitter;
+        let mut frame = [0u8; MAX_PACKAGE_SIZE];
+        Beacon {
+            serial_number: self.serial_number,
+            sequence: self.sequence,
+            name: self.name,
+        }.pack(self.group, &mut frame);
+        self.sequence = self.sequence.wrapping_add(1);
+        link.transmit(&frame);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sim::Simulator;
+
+    fn beacon(serial_number: u32, sequence: u8) -> PackageBuffer {
+        let mut frame = [0u8; MAX_PACKAGE_SIZE];
+        Beacon { serial_number, sequence, name: friendly_name(serial_number) }
+            .pack(0, &mut frame);
+        frame
+    }
+
+    fn at(seconds: u64) -> Instant {
+        Instant::from_ticks(0) + Duration::from_secs(seconds)
+    }
+
+    /// Names from a port of `microbit_friendly_name` of microbit-dal, which
+    /// subtracts the digit, not the digit times its weight, from the serial
+    /// number
+    #[test]
+    fn friendly_names() {
+        let names: [(u32, &[u8; 5]); 8] = [
+            (0, b"zuzuz"),
+            (1, b"zuzuv"),
+            (5, b"zuzoz"),
+            (3124, b"tatat"),
+            (3125, b"zuzuz"),
+            (0x1234_5678, b"vazav"),
+            (0xdead_beef, b"zegot"),
+            (0xffff_ffff, b"gevaz"),
+        ];
+        for (serial_number, name) in names {
+            assert_eq!(&friendly_name(serial_number), name, "{:08x}", serial_number);
+        }
+        assert_eq!(Presence::<1>::new(0x1234_5678, 0).name(), "vazav");
+    }
+
+    #[test]
+    fn counts_missed_beacons() {
+        let mut presence = Presence::<2>::new(1, 0);
+        assert!(matches!(presence.handle(at(0), &beacon(2, 250), Some(-60)),
+            Some(Event::Appeared(_))));
+        // Two missed, then the sequence number wraps with one missed
+        assert!(presence.handle(at(3), &beacon(2, 253), Some(-60)).is_none());
+        assert!(presence.handle(at(5), &beacon(2, 255), Some(-60)).is_none());
+        assert!(presence.handle(at(7), &beacon(2, 1), Some(-60)).is_none());
+        let neighbour = presence.neighbour(2).unwrap();
+        assert_eq!((neighbour.received(), neighbour.missed()), (4, 4));
+        assert_eq!(neighbour.rssi(), Some(-60));
+        // The board restarts, from sequence number 0, then 1 and 2
+        for (seconds, sequence) in [(8, 0), (9, 1), (10, 2)] {
+            presence.handle(at(seconds), &beacon(2, sequence), None);
+        }
+        let neighbour = presence.neighbour(2).unwrap();
+        assert_eq!((neighbour.received(), neighbour.missed()), (7, 4));
+        // Restarted shortly after a sequence number above 128
+        let mut presence = Presence::<2>::new(1, 0);
+        presence.handle(at(0), &beacon(2, 200), None);
+        presence.handle(at(1), &beacon(2, 0), None);
+        assert_eq!(presence.neighbour(2).unwrap().missed(), 0);
+    }
+
+    #[test]
+    fn ignores_boards_while_full() {
+        let mut presence = Presence::<2>::new(1, 0);
+        assert!(presence.handle(at(0), &beacon(1, 0), None).is_none());
+        assert!(presence.handle(at(0), &beacon(2, 0), None).is_some());
+        assert!(presence.handle(at(0), &beacon(3, 0), None).is_some());
+        assert!(presence.handle(at(0), &beacon(4, 0), None).is_none());
+        assert_eq!(presence.ignored(), 1);
+        assert!(presence.neighbour(4).is_none());
+        // Room again once a neighbour disappears
+        presence.handle(at(3), &beacon(3, 1), None);
+        assert!(matches!(presence.expire(at(4)), Some(Event::Disappeared(neighbour))
+            if neighbour.serial_number() == 2));
+        assert!(presence.expire(at(4)).is_none());
+        assert!(presence.handle(at(4), &beacon(4, 1), None).is_some());
+    }
+
+    #[test]
+    fn shows_table_entries() {
+        let mut presence = Presence::<30>::new(1, 0);
+        for serial_number in 2..30 {
+            presence.handle(at(0), &beacon(serial_number, 0), None);
+        }
+        assert_eq!(presence.image(), [[0xff; 5]; 5]);
+        let mut presence = Presence::<25>::new(1, 0);
+        for serial_number in [2, 3, 4, 5, 6, 7] {
+            presence.handle(at(0), &beacon(serial_number, 0), None);
+        }
+        presence.handle(at(3), &beacon(3, 1), None);
+        presence.handle(at(3), &beacon(7, 1), None);
+        while presence.expire(at(4)).is_some() {}
+        let mut image = [[0u8; 5]; 5];
+        image[0][1] = 0xff;
+        image[1][0] = 0xff;
+        assert_eq!(presence.image(), image);
+    }
+
+    const NODES: usize = 3;
+
+    /// Neighbour events with the time, the node, `true` for appeared and the
+    /// serial number of the neighbour
+    type Events = Vec<(Instant, usize, bool, u32)>;
+
+    fn run(sim: &mut Simulator<NODES>, presences: &mut [Presence<4>; NODES],
+        events: &mut Events, millis: u64)
+    {
+        for _ in 0..millis {
+            for (index, presence) in presences.iter_mut().enumerate() {
+                while let Some(event) = presence.poll(&mut sim.link(index)) {
+                    let (appeared, neighbour) = match event {
+                        Event::Appeared(neighbour) => (true, neighbour),
+                        Event::Disappeared(neighbour) => (false, neighbour),
+                    };
+                    events.push((sim.now(), index, appeared, neighbour.serial_number()));
+                }
+            }
+            sim.step();
+        }
+    }
+
+    #[test]
+    fn neighbours_appear_and_disappear() {
+        let mut sim = Simulator::<NODES>::new(1);
+        let mut presences: [Presence<4>; NODES] =
+            core::array::from_fn(|index| Presence::new(index as u32 + 1, 0));
+        let mut events = Events::new();
+        run(&mut sim, &mut presences, &mut events, 2000);
+        for presence in &presences {
+            assert_eq!(presence.count(), NODES - 1);
+        }
+        assert_eq!(presences[0].neighbour(3).unwrap().name(), "zuzup");
+        // Node 2 is switched off
+        sim.connect(0, 2, false);
+        sim.connect(1, 2, false);
+        let off = sim.now();
+        run(&mut sim, &mut presences, &mut events, 6000);
+        assert_eq!(events.iter().filter(|event| event.2).count(), 6);
+        let gone: Vec<_> = events.iter().filter(|event| !event.2).collect();
+        assert_eq!(gone.len(), 4);
+        for (time, index, _, serial_number) in gone {
+            // Node 2 lost its neighbours as they lost node 2
+            assert!(*serial_number == 3 || *index == 2);
+            let late = *time - off;
+            assert!(late + BEACON_PERIOD + BEACON_JITTER > NEIGHBOUR_TIMEOUT);
+            assert!(late <= NEIGHBOUR_TIMEOUT + Duration::from_millis(1));
+        }
+        assert_eq!(presences[0].count(), 1);
+        assert_eq!(presences[2].count(), 0);
+    }
+}
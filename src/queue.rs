@@ -0,0 +1,224 @@
+//! Lock-free single producer, single consumer queue
+//!
+//! Used to hand data between interrupt handlers and the application without
+//! critical sections. Only atomic loads and stores are needed, which the
+//! Cortex-M0 provides.
+
+use core::cell::UnsafeCell;
+use core::mem::MaybeUninit;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+/// # Queue
+///
+/// Fixed size ring buffer holding at most `N - 1` items, `N` must be at
+/// least 2.
+///
+/// The queue is split into a `Producer` and a `Consumer` that can be given
+/// to different execution contexts.
+///
+/// ```notrust
+/// static mut QUEUE: Queue<u32, 4> = Queue::new();
+///
+/// let (producer, consumer) = unsafe { QUEUE.split() };
+/// ```
+pub struct Queue<T, const N: usize> {
+    head: AtomicUsize,
+    tail: AtomicUsize,
+    buffer: UnsafeCell<MaybeUninit<[T; N]>>,
+}
+
+impl<T, const N: usize> Queue<T, N> {
+    /// One slot is always free, a queue needs at least 2 slots to hold an
+    /// item
+    const MIN_SIZE: () = assert!(N >= 2, "a queue needs at least 2 slots");
+
+    /// Create an empty queue
+    pub const fn new() -> Self {
+        let () = Self::MIN_SIZE;
+        Queue {
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+            buffer: UnsafeCell::new(MaybeUninit::uninit()),
+        }
+    }
+
+    /// Maximum number of items in the queue
+    pub const fn capacity(&self) -> usize {
+        N - 1
+    }
+
+    /// Number of items in the queue
+    pub fn len(&self) -> usize {
+        let head = self.head.load(Ordering::Acquire);
+        let tail = self.tail.load(Ordering::Acquire);
+        (tail + N - head) % N
+    }
+
+    /// Is the queue empty
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Split the queue into producer and consumer
+    pub fn split(&mut self) -> (Producer<'_, T, N>, Consumer<'_, T, N>) {
+        (Producer { queue: self }, Consumer { queue: self })
+    }
+
+    fn slot(&self, index: usize) -> *mut T {
+        unsafe { (*self.buffer.get()).as_mut_ptr().cast::<T>().add(index) }
+    }
+
+    unsafe fn enqueue(&self, item: T) -> Result<(), T> {
+        let tail = self.tail.load(Ordering::Relaxed);
+        let next = (tail + 1) % N;
+        if next == self.head.load(Ordering::Acquire) {
+            return Err(item);
+        }
+        self.slot(tail).write(item);
+        self.tail.store(next, Ordering::Release);
+        Ok(())
+    }
+
+    unsafe fn dequeue(&self) -> Option<T> {
+        let head = self.head.load(Ordering::Relaxed);
+        if head == self.tail.load(Ordering::Acquire) {
+            return None;
+        }
+        let item = self.slot(head).read();
+        self.head.store((head + 1) % N, Ordering::Release);
+        Some(item)
+    }
+}
+
+impl<T, const N: usize> Default for Queue<T, N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T, const N: usize> Drop for Queue<T, N> {
+    fn drop(&mut self) {
+        while unsafe { self.dequeue() }.is_some() {}
+    }
+}
+
+/// The producing end of a `Queue`
+pub struct Producer<'a, T, const N: usize> {
+    queue: &'a Queue<T, N>,
+}
+
+unsafe impl<T: Send, const N: usize> Send for Producer<'_, T, N> {}
+
+impl<T, const N: usize> Producer<'_, T, N> {
+    /// Add an item to the queue, returns the item if the queue is full
+    pub fn enqueue(&mut self, item: T) -> Result<(), T> {
+        unsafe { self.queue.enqueue(item) }
+    }
+
+    /// Is there room for another item
+    pub fn ready(&self) -> bool {
+        self.queue.len() < self.queue.capacity()
+    }
+}
+
+/// The consuming end of a `Queue`
+pub struct Consumer<'a, T, const N: usize> {
+    queue: &'a Queue<T, N>,
+}
+
+unsafe impl<T: Send, const N: usize> Send for Consumer<'_, T, N> {}
+
+impl<T, const N: usize> Consumer<'_, T, N> {
+    /// Take the oldest item from the queue
+    pub fn dequeue(&mut self) -> Option<T> {
+        unsafe { self.queue.dequeue() }
+    }
+
+    /// Is there an item in the queue
+    pub fn ready(&self) -> bool {
+        !self.queue.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::rc::Rc;
+
+    #[test]
+    fn full_and_empty() {
+        let mut queue: Queue<u32, 4> = Queue::new();
+        assert_eq!(queue.capacity(), 3);
+        let (mut producer, mut consumer) = queue.split();
+        assert!(!consumer.ready());
+        assert_eq!(consumer.dequeue(), None);
+        for item in 0..3 {
+            assert!(producer.ready());
+            assert_eq!(producer.enqueue(item), Ok(()));
+        }
+        assert!(!producer.ready());
+        assert_eq!(producer.enqueue(3), Err(3));
+        assert!(consumer.ready());
+        assert_eq!(consumer.dequeue(), Some(0));
+        assert!(producer.ready());
+        assert_eq!(queue.len(), 2);
+    }
+
+    #[test]
+    fn wraps_around() {
+        let mut queue: Queue<u32, 3> = Queue::new();
+        let (mut producer, mut consumer) = queue.split();
+        for item in 0..100 {
+            assert_eq!(producer.enqueue(2 * item), Ok(()));
+            assert_eq!(producer.enqueue(2 * item + 1), Ok(()));
+            assert_eq!(consumer.dequeue(), Some(2 * item));
+            assert_eq!(consumer.dequeue(), Some(2 * item + 1));
+            assert_eq!(consumer.dequeue(), None);
+        }
+    }
+
+    #[test]
+    fn drops_items_left_in_the_queue() {
+        let item = Rc::new(());
+        {
+            let mut queue: Queue<Rc<()>, 4> = Queue::new();
+            let (mut producer, mut consumer) = queue.split();
+            for _ in 0..3 {
+                producer.enqueue(item.clone()).unwrap();
+            }
+            // Taken items are dropped by the consumer, once
+            drop(consumer.dequeue());
+            assert_eq!(Rc::strong_count(&item), 3);
+        }
+        assert_eq!(Rc::strong_count(&item), 1);
+    }
+
+    #[test]
+    fn hands_items_between_threads() {
+        const ITEMS: u32 = 100_000;
+        let mut queue: Queue<u32, 8> = Queue::new();
+        let (mut producer, mut consumer) = queue.split();
+        std::thread::scope(|scope| {
+            scope.spawn(move || {
+                for item in 0..ITEMS {
+                    let mut item = item;
+                    while let Err(back) = producer.enqueue(item) {
+                        item = back;
+                        std::thread::yield_now();
+                    }
+                }
+            });
+            let mut expected = 0;
+            while expected < ITEMS {
+                match consumer.dequeue() {
+                    Some(item) => {
+                        assert_eq!(item, expected);
+                        expected += 1;
+                    }
+                    None => std::thread::yield_now(),
+                }
+            }
+        });
+        assert!(queue.is_empty());
+    }
+}
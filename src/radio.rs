@@ -3,79 +3,733 @@
 use core::sync::atomic::Ordering;
 use core::sync::atomic::compiler_fence;
 
-use nrf51::RADIO;
+use nrf51::{Interrupt, NVIC, RADIO};
 use nrf51::radio::state::STATER;
 
+use crate::ble;
+use crate::queue::{Consumer, Producer, Queue};
+use crate::signal::{Signal, Wait};
+use crate::time::{self, Duration, Instant};
+
 pub const BASE_ADDRESS: u32 = 0x75626974;
 pub const DEFAULT_GROUP: u8 = 0;
 pub const DEFAULT_CHANNEL: u8 = 7;
-pub const MAX_PACKAGE_SIZE: usize = 32;
+/// Highest channel, 2500 MHz
+pub const MAX_CHANNEL: u8 = 100;
+/// Size of a MakeCode frame, the length and the largest payload of 32 bytes
+pub const MAX_PACKAGE_SIZE: usize = 33;
+/// Largest payload of the nRF51
+pub const MAX_PAYLOAD_SIZE: usize = 254;
+/// Largest frame, the S0, length and S1 fields and the largest payload
+pub const MAX_FRAME_SIZE: usize = 3 + MAX_PAYLOAD_SIZE;
 // pub const HEADER_SIZE: usize = 4;
 // pub const MAXIMUM_RX_BUFFERS: usize = 4;
 pub const CRC_POLY: u32 = 0x00011021;
 pub const CRC_PRESET: u32 = 0x0000ffff;
 pub const WHITENING_IV: u8 = 0x18;
 
+pub const TX_QUEUE_LENGTH: usize = 4;
+/// Highest transmit power level, +4 dBm
+pub const MAX_POWER: u8 = 7;
+
+/// Time from starting a transmission to the ADDRESS event at the receivers,
+/// the 140 µs ramp up and the preamble and address at 1 Mbit
+pub const ADDRESS_DELAY: Duration = Duration::from_ticks(6);
+
+/// A MakeCode frame, the length followed by the package
 pub type PackageBuffer = [u8; MAX_PACKAGE_SIZE];
 
+/// Queue of frames waiting to be sent by a `RadioRx`
+pub type TxQueue<const N: usize = MAX_PACKAGE_SIZE> = Queue<[u8; N], TX_QUEUE_LENGTH>;
+
+static RECEIVED: Signal<PackageBuffer> = Signal::new();
+
+/// Wait for a package passed on by `RadioRx::wake`
+pub fn recv() -> Wait<'static, PackageBuffer> {
+    RECEIVED.wait()
+}
+
+/// # Radio DMA buffers
+///
+/// The radio reads and writes packages directly from memory. The buffers
+/// must stay in place for as long as the radio is running, which is why the
+/// radio only accepts them with a `'static` lifetime.
+///
+/// Frames are up to `N` bytes, 2 to `MAX_FRAME_SIZE`, 33 for MakeCode.
+///
+/// ```notrust
+/// static mut BUFFERS: Buffers = Buffers::new();
+///
+/// let radio = Radio::new(p.RADIO, unsafe { &mut BUFFERS });
+/// ```
+pub struct Buffers<const N: usize = MAX_PACKAGE_SIZE> {
+    pub(crate) rx: [u8; N],
+    pub(crate) tx: [u8; N],
+    /// Received BLE advertising PDU, these are larger than packages
+    pub(crate) ble: [u8; ble::MAX_PDU_SIZE],
+}
+
+impl<const N: usize> Buffers<N> {
+    pub const fn new() -> Self {
+        assert!(N >= 2 && N <= MAX_FRAME_SIZE);
+        Buffers {
+            rx: [0u8; N],
+            tx: [0u8; N],
+            ble: [0u8; ble::MAX_PDU_SIZE],
+        }
+    }
+}
+
+impl<const N: usize> Default for Buffers<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Air data rate
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Mode {
+    /// Nordic proprietary 250 kbit/s, the longest range
+    Nrf250Kbit,
+    /// Nordic proprietary 1 Mbit/s, used by MakeCode
+    Nrf1Mbit,
+    /// Nordic proprietary 2 Mbit/s
+    Nrf2Mbit,
+}
+
+/// CRC length in bytes
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CrcLength {
+    Disabled,
+    One,
+    Two,
+    Three,
+}
+
+impl CrcLength {
+    pub(crate) fn bytes(self) -> u32 {
+        match self {
+            CrcLength::Disabled => 0,
+            CrcLength::One => 1,
+            CrcLength::Two => 2,
+            CrcLength::Three => 3,
+        }
+    }
+}
+
+/// Invalid radio configurations
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConfigError {
+    /// The base address is not 2 to 4 bytes
+    BaseLength,
+    /// The length field is not 1 to 8 bits or the S1 field is longer than
+    /// 8 bits
+    FieldLength,
+    /// The fields and the largest payload do not fit the buffers or the
+    /// payload is larger than `MAX_PAYLOAD_SIZE`
+    MaxLength,
+    /// The CRC polynomial is zero or the polynomial or the preset does not
+    /// fit the CRC length
+    Crc,
+    /// The whitening initial value is larger than 0x3f
+    Whitening,
+    /// The transmit power level is larger than `MAX_POWER`
+    Power,
+}
+
+/// # Radio configuration
+///
+/// The data rate and the packet format. The packet is stored in memory as
+///
+/// ```notrust
+/// | 0 or 1 byte | 1 byte | 0 or 1 byte | 0 ... max length bytes
+/// -------------------------------------------------------------
+/// | S0          | length | S1          | payload
+/// ```
+/// The address is the prefix, the group, followed by the base address. See
+/// `RadioConfig::makecode` for the default.
+///
+/// ```notrust
+/// let config = RadioConfig::makecode().mode(Mode::Nrf250Kbit).base_address(0x1234_5678, 4);
+/// radio.set_config(config)?;
+/// ```
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RadioConfig {
+    pub(crate) mode: Mode,
+    pub(crate) base_address: u32,
+    pub(crate) base_length: u8,
+    pub(crate) prefix: u8,
+    pub(crate) s0: bool,
+    pub(crate) length_bits: u8,
+    pub(crate) s1_bits: u8,
+    pub(crate) max_length: u8,
+    pub(crate) crc: CrcLength,
+    pub(crate) crc_polynomial: u32,
+    pub(crate) crc_preset: u32,
+    pub(crate) crc_skip_address: bool,
+    pub(crate) whitening: Option<u8>,
+    pub(crate) power: u8,
+}
+
+impl RadioConfig {
+    /// The MakeCode format, 1 Mbit/s, a 4 byte base address, an 8-bit
+    /// length, a 16-bit CRC over the address, whitening and the highest
+    /// transmit power
+    ///
+    /// The largest payload is 32 bytes, as in microbit-dal, so the length
+    /// byte and the payload take a 33 byte `PackageBuffer`.
+    pub const fn makecode() -> Self {
+        RadioConfig {
+            mode: Mode::Nrf1Mbit,
+            base_address: BASE_ADDRESS,
+            base_length: 4,
+            prefix: DEFAULT_GROUP,
+            s0: false,
+            length_bits: 8,
+            s1_bits: 0,
+            max_length: MAX_PACKAGE_SIZE as u8 - 1,
+            crc: CrcLength::Two,
+            crc_polynomial: CRC_POLY & 0xffff,
+            crc_preset: CRC_PRESET,
+            crc_skip_address: false,
+            whitening: Some(WHITENING_IV),
+            power: MAX_POWER,
+        }
+    }
+
+    /// Change the data rate
+    pub fn mode(mut self, mode: Mode) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    /// Change the base address, only the `length` most significant bytes
+    /// are used
+    pub fn base_address(mut self, base_address: u32, length: u8) -> Self {
+        self.base_address = base_address;
+        self.base_length = length;
+        self
+    }
+
+    /// Change the prefix, the group
+    pub fn prefix(mut self, prefix: u8) -> Self {
+        self.prefix = prefix;
+        self
+    }
+
+    /// Change the S0 field, `true` for a one byte field
+    pub fn s0(mut self, s0: bool) -> Self {
+        self.s0 = s0;
+        self
+    }
+
+    /// Change the number of bits of the length field
+    pub fn length_bits(mut self, bits: u8) -> Self {
+        self.length_bits = bits;
+        self
+    }
+
+    /// Change the number of bits of the S1 field
+    pub fn s1_bits(mut self, bits: u8) -> Self {
+        self.s1_bits = bits;
+        self
+    }
+
+    /// Change the largest payload, longer packages are truncated
+    pub fn max_length(mut self, max_length: u8) -> Self {
+        self.max_length = max_length;
+        self
+    }
+
+    /// Change the CRC length, polynomial and preset
+    pub fn crc(mut self, length: CrcLength, polynomial: u32, preset: u32) -> Self {
+        self.crc = length;
+        self.crc_polynomial = polynomial;
+        self.crc_preset = preset;
+        self
+    }
+
+    /// Leave the address out of the CRC
+    pub fn crc_skip_address(mut self, skip: bool) -> Self {
+        self.crc_skip_address = skip;
+        self
+    }
+
+    /// Change the whitening initial value, `None` disables whitening
+    pub fn whitening(mut self, whitening: Option<u8>) -> Self {
+        self.whitening = whitening;
+        self
+    }
+
+    /// Change the transmit power level, 0 to `MAX_POWER`, the levels of
+    /// MakeCode and MicroPython, -30, -20, -16, -12, -8, -4, 0 and +4 dBm
+    pub fn power(mut self, level: u8) -> Self {
+        self.power = level;
+        self
+    }
+
+    /// Size of the S0, length and S1 fields in memory
+    pub fn header_size(&self) -> usize {
+        usize::from(self.s0) + 1 + usize::from(self.s1_bits > 0)
+    }
+
+    /// Check the configuration for frames of up to `frame_size` bytes
+    pub fn validate(&self, frame_size: usize) -> Result<(), ConfigError> {
+        if !(2..=4).contains(&self.base_length) {
+            return Err(ConfigError::BaseLength);
+        }
+        if !(1..=8).contains(&self.length_bits) || self.s1_bits > 8 {
+            return Err(ConfigError::FieldLength);
+        }
+        let max_length = usize::from(self.max_length);
+        if self.header_size() + max_length > frame_size
+            || max_length > MAX_PAYLOAD_SIZE
+            || max_length >= 1 << self.length_bits
+        {
+            return Err(ConfigError::MaxLength);
+        }
+        if self.crc != CrcLength::Disabled {
+            let mask = (1u64 << (8 * self.crc.bytes())) - 1;
+            if self.crc_polynomial == 0
+                || u64::from(self.crc_polynomial) & !mask != 0
+                || u64::from(self.crc_preset) & !mask != 0
+            {
+                return Err(ConfigError::Crc);
+            }
+        }
+        if self.whitening.is_some_and(|whitening| whitening > 0x3f) {
+            return Err(ConfigError::Whitening);
+        }
+        if self.power > MAX_POWER {
+            return Err(ConfigError::Power);
+        }
+        Ok(())
+    }
+
+    /// Write the fields and `payload` to `buffer`, S0 and S1 are zero
+    ///
+    /// Returns the payload length or 0 if the payload is too large.
+    pub fn pack(&self, payload: &[u8], buffer: &mut [u8]) -> usize {
+        if payload.len() > usize::from(self.max_length)
+            || self.header_size() + payload.len() > buffer.len()
+        {
+            return 0;
+        }
+        let header = self.header_size();
+        buffer[..header].iter_mut().for_each(|byte| *byte = 0);
+        buffer[usize::from(self.s0)] = payload.len() as u8;
+        buffer[header..header + payload.len()].copy_from_slice(payload);
+        payload.len()
+    }
+
+    /// The payload length in a received `buffer`
+    pub fn payload_length(&self, buffer: &[u8]) -> usize {
+        let mask = ((1u16 << self.length_bits) - 1) as u8;
+        usize::from(buffer[usize::from(self.s0)] & mask).min(usize::from(self.max_length))
+    }
+}
+
+impl Default for RadioConfig {
+    fn default() -> Self {
+        Self::makecode()
+    }
+}
+
+/// # Frame
+///
+/// A view of a frame in a buffer, with the fields of a `RadioConfig`.
+#[derive(Clone, Copy, Debug)]
+pub struct FrameRef<'a> {
+    bytes: &'a [u8],
+    header_size: usize,
+}
+
+impl<'a> FrameRef<'a> {
+    /// The frame at the start of `buffer`, returns `None` if the buffer is
+    /// shorter than the frame
+    pub fn new(config: &RadioConfig, buffer: &'a [u8]) -> Option<Self> {
+        let header_size = config.header_size();
+        if buffer.len() < header_size {
+            return None;
+        }
+        let end = header_size + config.payload_length(buffer);
+        buffer.get(..end).map(|bytes| FrameRef { bytes, header_size })
+    }
+
+    /// The frame, the fields followed by the payload
+    ///
+    /// For MakeCode frames this is what `Package::unpack` and the other
+    /// protocols take.
+    pub fn as_bytes(&self) -> &'a [u8] {
+        self.bytes
+    }
+
+    /// The payload
+    pub fn payload(&self) -> &'a [u8] {
+        &self.bytes[self.header_size..]
+    }
+}
 
 /// # The micro:bit radio
 /// 
 /// The goal is to be able to communicate with software written with MakeCode
 /// or similar.
 /// 
-/// The radio is configured as Nordic properitary 1 Mbit radio, 16-bit CRC.
+/// The radio is configured as Nordic properitary 1 Mbit radio, 16-bit CRC,
+/// other data rates and packet formats can be set with `Radio::with_config`
+/// or `Radio::set_config`.
+/// BLE advertisements can be sent in between, see `Radio::advertise`, and
+/// received instead of packages, see `Radio::scan`.
+///
+/// The DMA buffers are borrowed for `'static`, so the `Radio` can be moved
+/// around, into a mutex for example, while a reception is ongoing.
+///
+/// Received packages are timestamped with the RTC1 time of their ADDRESS
+/// event, see `Radio::timestamp`, and their signal strength is sampled, see
+/// `Radio::rssi`.
+///
+/// Frames are up to `N` bytes, see `Buffers`, larger frames can be used
+/// with other packet formats. `Radio::frame` gives a view of a received
+/// frame without copying it.
 /// 
 /// ## Reference
 /// 
 /// * <https://github.com/lancaster-university/microbit-dal/blob/master/source/drivers/MicroBitRadio.cpp>
-pub struct Radio {
+pub struct Radio<const N: usize = MAX_PACKAGE_SIZE> {
     radio: RADIO,
-    rx_buf: PackageBuffer,
-    tx_buf: PackageBuffer,
+    buffers: &'static mut Buffers<N>,
+    config: RadioConfig,
+    /// Time of the ADDRESS event of the package being received
+    address_time: Option<Instant>,
+    /// Time of the ADDRESS event of the last received package
+    timestamp: Instant,
+    /// Negative signal strength of the last received package
+    rssi: u8,
+    /// Number of packages received with a CRC error
+    crc_errors: u32,
+    /// Advertising channel scanned, `None` when receiving packages
+    scan_channel: Option<u8>,
+    /// Group and channel to go back to when scanning stops
+    saved: (u32, u32),
 }
 
-impl Radio {
-    pub fn new(radio: RADIO) -> Self {
+impl<const N: usize> Radio<N> {
+    /// Configure the radio for MakeCode packages, or the largest that fit
+    /// smaller buffers
+    pub fn new(radio: RADIO, buffers: &'static mut Buffers<N>) -> Self {
+        let max_length = N.min(MAX_PACKAGE_SIZE) - 1;
+        let config = RadioConfig::makecode().max_length(max_length as u8);
+        Self::with_config(radio, buffers, config).expect("buffers of at least 2 bytes")
+    }
+
+    /// Configure the radio with `config`, the group is the prefix of
+    /// `config` and the channel `DEFAULT_CHANNEL`
+    ///
+    /// Returns an error if the configuration is not valid for frames of `N`
+    /// bytes, see `RadioConfig::validate`.
+    pub fn with_config(radio: RADIO, buffers: &'static mut Buffers<N>, config: RadioConfig)
+        -> Result<Self, ConfigError>
+    {
         assert!(radio.state.read().state().is_disabled());
+        config.validate(N)?;
 
-        radio.mode.write(|w| w.mode().nrf_1mbit());
-        radio.txpower.write(|w| w.txpower().pos4d_bm());
+        let mut radio = Self {
+            radio,
+            buffers,
+            config,
+            address_time: None,
+            timestamp: Instant::from_ticks(0),
+            rssi: 0,
+            crc_errors: 0,
+            scan_channel: None,
+            saved: (0, 0),
+        };
+        radio.configure();
+        unsafe {
+            radio.radio.prefix0.write(|w| w.ap0().bits(config.prefix));
+            radio.radio.frequency.write(|w| w.frequency().bits(DEFAULT_CHANNEL));
+        }
+        Ok(radio)
+    }
+
+    /// Change the data rate and the packet format, the group is set to the
+    /// prefix of `config`
+    ///
+    /// The radio is left as it is if the configuration is not valid.
+    /// `ADDRESS_DELAY` and thus the timestamps assume 1 Mbit/s.
+    pub fn set_config(&mut self, config: RadioConfig) -> Result<(), ConfigError>
+    {
+        config.validate(N)?;
+        self.config = config;
+        if self.scan_channel.is_some() {
+            // Applied when the scan stops
+            self.saved.0 = u32::from(config.prefix);
+            return Ok(());
+        }
+        self.disable();
+        self.configure();
+        self.set_group(config.prefix);
+        self.start_receive();
+        Ok(())
+    }
+
+    /// The data rate and the packet format
+    pub fn config(&self) -> &RadioConfig {
+        &self.config
+    }
+
+    /// Configure the radio for the packages of the configuration, the group
+    /// and the channel are left as they are
+    fn configure(&mut self) {
+        let config = self.config;
+        let radio = &self.radio;
+        match config.mode {
+            Mode::Nrf250Kbit => radio.mode.write(|w| w.mode().nrf_250kbit()),
+            Mode::Nrf1Mbit => radio.mode.write(|w| w.mode().nrf_1mbit()),
+            Mode::Nrf2Mbit => radio.mode.write(|w| w.mode().nrf_2mbit()),
+        }
+        radio.txpower.write(|w| {
+            let power = w.txpower();
+            match config.power {
+                0 => power.neg30d_bm(),
+                1 => power.neg20d_bm(),
+                2 => power.neg16d_bm(),
+                3 => power.neg12d_bm(),
+                4 => power.neg8d_bm(),
+                5 => power.neg4d_bm(),
+                6 => power._0d_bm(),
+                _ => power.pos4d_bm(),
+            }
+        });
 
         unsafe {
-            // On-air package length field size, 8-bits
-            radio.pcnf0.write(|w| w.lflen().bits(8));
-            // Configure maximum package size
-            // Base address length, 5
-            // Enable whitening
+            // Field sizes in bits, S0 in bytes
+            radio.pcnf0.write(|w| w
+                .lflen().bits(config.length_bits)
+                .s0len().bit(config.s0)
+                .s1len().bits(config.s1_bits)
+            );
             radio.pcnf1.write(|w| w
-                .maxlen().bits(MAX_PACKAGE_SIZE as u8)
-                .balen().bits(4)
-                .whiteen().set_bit()
+                .maxlen().bits(config.max_length)
+                .balen().bits(config.base_length)
+                .whiteen().bit(config.whitening.is_some())
             );
-            // Initialise 16-bit CRC
-            radio.crccnf.write(|w| w.len().two());
-            radio.crcinit.write(|w| w.bits(CRC_PRESET));
-            radio.crcpoly.write(|w| w.crcpoly().bits(CRC_POLY & 0x0000ffff));
-            // Configure base address
-            radio.base0.write(|w| w.bits(BASE_ADDRESS));
-            radio.prefix0.write(|w| w.ap0().bits(DEFAULT_GROUP));
-            radio.frequency.write(|w| w.frequency().bits(DEFAULT_CHANNEL));
+            radio.crccnf.write(|w| {
+                match config.crc {
+                    CrcLength::Disabled => w.len().disabled(),
+                    CrcLength::One => w.len().one(),
+                    CrcLength::Two => w.len().two(),
+                    CrcLength::Three => w.len().three(),
+                };
+                w.skipaddr().bit(config.crc_skip_address)
+            });
+            radio.crcinit.write(|w| w.bits(config.crc_preset));
+            radio.crcpoly.write(|w| w.crcpoly().bits(config.crc_polynomial));
+            // The base address is the most significant bytes
+            radio.base0.write(|w| w.bits(config.base_address));
 
             radio.datawhiteiv.write(|w|
-                w.datawhiteiv().bits(WHITENING_IV));
+                w.datawhiteiv().bits(config.whitening.unwrap_or(0)));
         }
 
         radio.shorts.write(|w| w
             .ready_start().enabled()
             .end_disable().enabled()
+            .address_rssistart().enabled()
+            .disabled_rssistop().enabled()
         );
+    }
 
-        Self {
-            radio,
-            rx_buf: [0u8; MAX_PACKAGE_SIZE],
-            tx_buf: [0u8; MAX_PACKAGE_SIZE],
+    /// Configure the radio for BLE advertising packets, the whitening and
+    /// the frequency depend on the channel and are left as they are
+    fn configure_ble(&mut self) {
+        let radio = &self.radio;
+        radio.mode.write(|w| w.mode().ble_1mbit());
+        unsafe {
+            // S0 is the PDU type and flags, then the 8-bit length
+            radio.pcnf0.write(|w| w.s0len().set_bit().lflen().bits(8).s1len().bits(0));
+            radio.pcnf1.write(|w| w
+                .maxlen().bits(ble::MAX_PDU_SIZE as u8 - 2)
+                .balen().bits(3)
+                .endian().little()
+                .whiteen().enabled()
+            );
+            radio.crccnf.write(|w| w.len().three().skipaddr().skip());
+            radio.crcinit.write(|w| w.bits(ble::CRC_INIT));
+            radio.crcpoly.write(|w| w.bits(ble::CRC_POLY));
+            radio.base0.write(|w| w.bits(ble::ACCESS_ADDRESS << 8));
+            radio.prefix0.write(|w| w.bits(ble::ACCESS_ADDRESS >> 24));
+            radio.txaddress.write(|w| w.txaddress().bits(0));
+        }
+    }
+
+    /// Send a BLE advertising PDU on the three advertising channels, see
+    /// `ble::AdvPdu`
+    ///
+    /// The radio is switched to BLE for the advertising event and back,
+    /// reception or the scan is restarted afterwards. Returns the number of
+    /// bytes sent on each channel or 0 if the PDU is too large.
+    pub fn advertise(&mut self, pdu: &[u8]) -> usize
+    {
+        if pdu.len() < 2 || pdu.len() > ble::MAX_PDU_SIZE {
+            return 0;
+        }
+        // The radio is stopped before this returns, so the PDU can be sent
+        // from the stack
+        let mut buffer = [0u8; ble::MAX_PDU_SIZE];
+        buffer[..pdu.len()].copy_from_slice(pdu);
+        self.disable();
+        let prefix = self.radio.prefix0.read().bits();
+        let frequency = self.radio.frequency.read().bits();
+
+        self.configure_ble();
+        self.radio.packetptr.write(|w| unsafe { w.bits(&mut buffer as *mut _ as u32) });
+        self.radio.shorts.write(|w| w.ready_start().enabled().end_disable().enabled());
+        for (channel, offset) in ble::ADVERTISING_CHANNELS.iter() {
+            unsafe {
+                self.radio.datawhiteiv.write(|w| w.datawhiteiv().bits(*channel));
+                self.radio.frequency.write(|w| w.frequency().bits(*offset));
+            }
+            compiler_fence(Ordering::AcqRel);
+            self.radio.events_end.reset();
+            self.radio.tasks_txen.write(|w| unsafe { w.bits(1) });
+            while self.radio.events_end.read().bits() == 0 {}
+            self.disable();
+        }
+        compiler_fence(Ordering::AcqRel);
+
+        if let Some(channel) = self.scan_channel {
+            self.listen_ble(channel);
+            return pdu.len();
+        }
+        self.configure();
+        unsafe {
+            self.radio.prefix0.write(|w| w.bits(prefix));
+            self.radio.frequency.write(|w| w.bits(frequency));
         }
+        self.start_receive();
+        pdu.len()
+    }
+
+    /// Receive BLE advertisements on advertising `channel` instead of
+    /// packages, see `ble::Scanner`
+    ///
+    /// Returns `false` if `channel` is not an advertising channel. Packages
+    /// are neither sent nor received until `stop_scan` is called.
+    pub fn start_scan(&mut self, channel: u8) -> bool
+    {
+        if ble::frequency(channel).is_none() {
+            return false;
+        }
+        self.radio.intenclr.write(|w| w.end().clear());
+        self.disable();
+        if self.scan_channel.is_none() {
+            self.saved = (self.radio.prefix0.read().bits(), self.radio.frequency.read().bits());
+            self.configure_ble();
+        }
+        self.scan_channel = Some(channel);
+        self.listen_ble(channel);
+        true
+    }
+
+    /// Stop scanning and receive packages again
+    pub fn stop_scan(&mut self)
+    {
+        if self.scan_channel.take().is_none() {
+            return;
+        }
+        self.disable();
+        self.configure();
+        unsafe {
+            self.radio.prefix0.write(|w| w.bits(self.saved.0));
+            self.radio.frequency.write(|w| w.bits(self.saved.1));
+        }
+        self.start_receive();
+    }
+
+    /// The advertising channel scanned, `None` when receiving packages
+    pub fn scan_channel(&self) -> Option<u8> {
+        self.scan_channel
+    }
+
+    /// Take a received advertisement without waiting, returns `None` if
+    /// no advertisement has been received or if it is not one of the types
+    /// decoded by `ble::AdvReport`
+    ///
+    /// Reception is restarted after an advertisement has been taken.
+    pub fn try_receive_advertisement(&mut self) -> Option<ble::AdvReport>
+    {
+        let channel = self.scan_channel?;
+        if self.radio.events_end.read().bits() == 0 {
+            return None;
+        }
+        compiler_fence(Ordering::AcqRel);
+        let crc_ok = self.radio.crcstatus.read().crcstatus().is_crcok();
+        let rssi = -(self.radio.rssisample.read().rssisample().bits().min(127) as i8);
+        let report = if crc_ok {
+            ble::AdvReport::parse(&self.buffers.ble, rssi, channel)
+        }
+        else {
+            None
+        };
+        self.listen_ble(channel);
+        report
+    }
+
+    /// Scan the advertising channels, hopping as `scanner` says, and take a
+    /// received advertisement without waiting
+    ///
+    /// Call `stop_scan` to receive packages again.
+    pub fn scan(&mut self, scanner: &mut ble::Scanner) -> Option<ble::AdvReport>
+    {
+        if self.scan_channel.is_none() {
+            scanner.reset();
+        }
+        let report = self.try_receive_advertisement();
+        if let Some(channel) = scanner.hop(time::now()) {
+            self.start_scan(channel);
+        }
+        report
+    }
+
+    /// Start receiving on advertising `channel`, the radio is configured
+    /// for BLE
+    fn listen_ble(&mut self, channel: u8)
+    {
+        let frequency = ble::frequency(channel).unwrap_or(ble::ADVERTISING_CHANNELS[0].1);
+        self.disable();
+        unsafe {
+            self.radio.datawhiteiv.write(|w| w.datawhiteiv().bits(channel));
+            self.radio.frequency.write(|w| w.frequency().bits(frequency));
+        }
+        compiler_fence(Ordering::AcqRel);
+        let ble_buf = &mut self.buffers.ble as *mut _ as u32;
+        self.radio.packetptr.write(|w| unsafe { w.bits(ble_buf) });
+        self.radio.rxaddresses.write(|w| w.addr0().enabled());
+        self.radio.shorts.write(|w| w
+            .ready_start().enabled()
+            .end_disable().enabled()
+            .address_rssistart().enabled()
+            .disabled_rssistop().enabled()
+        );
+        self.radio.events_end.reset();
+        self.radio.events_address.reset();
+        self.radio.tasks_rxen.write(|w| unsafe { w.bits(1) });
+    }
+
+    /// Stop the radio and release the peripheral and the buffers
+    pub fn free(mut self) -> (RADIO, &'static mut Buffers<N>) {
+        self.radio.intenclr.write(|w| w.end().clear().address().clear());
+        self.disable();
+        compiler_fence(Ordering::AcqRel);
+        (self.radio, self.buffers)
+    }
+
+    /// Disable the radio, this stops any ongoing DMA transfer
+    fn disable(&mut self) {
+        self.radio.events_disabled.reset();
+        self.radio.tasks_disable.write(|w| unsafe { w.bits(1) });
+        while self.radio.events_disabled.read().bits() == 0 {}
     }
 
     /// Returns the current radio state.
@@ -89,58 +743,309 @@ impl Radio {
         self.radio.prefix0.write(|w| unsafe { w.ap0().bits(group) });
     }
 
+    /// Change the channel, the frequency is 2400 MHz + `channel` MHz
+    ///
+    /// Takes effect the next time the radio is enabled.
+    pub fn set_channel(&mut self, channel: u8)
+    {
+        self.radio.frequency.write(|w| unsafe { w.frequency().bits(channel.min(MAX_CHANNEL)) });
+    }
+
+    /// The channel
+    pub fn channel(&self) -> u8 {
+        self.radio.frequency.read().frequency().bits()
+    }
+
+    /// Measure the signal strength on `channel` in dBm, without receiving
+    ///
+    /// The radio is left disabled on the channel, call `start_receive` to
+    /// receive again.
+    pub fn measure_rssi(&mut self, channel: u8) -> i8
+    {
+        self.disable();
+        self.set_channel(channel);
+        self.radio.events_ready.reset();
+        // Reception starts when ready, through the READY to START short
+        self.radio.tasks_rxen.write(|w| unsafe { w.bits(1) });
+        while self.radio.events_ready.read().bits() == 0 {}
+        self.radio.events_rssiend.reset();
+        self.radio.tasks_rssistart.write(|w| unsafe { w.bits(1) });
+        while self.radio.events_rssiend.read().bits() == 0 {}
+        let sample = self.radio.rssisample.read().rssisample().bits();
+        self.disable();
+        self.radio.events_end.reset();
+        self.radio.events_address.reset();
+        -(sample.min(127) as i8)
+    }
+
+    /// Number of packages received with a CRC error, these are dropped
+    pub fn crc_errors(&self) -> u32 {
+        self.crc_errors
+    }
+
     pub fn start_receive(&mut self)
     {
         compiler_fence(Ordering::AcqRel);
-        let rx_buf = &mut self.rx_buf as *mut _ as u32;
+        let rx_buf = &mut self.buffers.rx as *mut _ as u32;
         self.radio.packetptr.write(|w| unsafe { w.bits(rx_buf) });
         self.radio.rxaddresses.write(|w| w.addr0().enabled());
+        self.radio.events_address.reset();
+        self.address_time = None;
         self.radio.intenset.write(|w| w.end().set());
         self.radio.tasks_rxen.write(|w| unsafe { w.bits(1) });
     }
 
-    pub fn receive(&mut self, dst: &mut PackageBuffer) -> usize
+    /// Capture the time of the ADDRESS event, if it has happened
+    ///
+    /// The time is read when this is called, call it from the RADIO
+    /// interrupt with the ADDRESS interrupt enabled for the best accuracy.
+    pub fn capture_address(&mut self)
     {
-        compiler_fence(Ordering::AcqRel);
-        self.radio.events_end.reset();
-        if self.radio.crcstatus.read().crcstatus().is_crcok() {
-            let length = self.rx_buf[0];
+        if self.radio.events_address.read().bits() != 0 {
+            self.radio.events_address.reset();
+            if self.address_time.is_none() {
+                self.address_time = Some(time::now());
+            }
+        }
+    }
+
+    /// Time of the ADDRESS event of the last received package
+    pub fn timestamp(&self) -> Instant {
+        self.timestamp
+    }
+
+    /// Signal strength of the last received package in dBm
+    pub fn rssi(&self) -> i8 {
+        -(self.rssi.min(127) as i8)
+    }
+
+    pub fn receive(&mut self, dst: &mut [u8; N]) -> usize
+    {
+        if self.end() {
+            let length = self.config.payload_length(&self.buffers.rx);
             if length > 0 {
-                dst.copy_from_slice(&self.rx_buf[..MAX_PACKAGE_SIZE]);
-                return length as usize
+                dst.copy_from_slice(&self.buffers.rx[..]);
+                return length
             }
         }
         0
     }
 
-    pub fn send(&mut self, src: &[u8]) -> usize
+    /// The received frame, without copying it, returns `None` if no frame
+    /// has been received
+    ///
+    /// Reception is restarted after a CRC error. Otherwise the frame stays
+    /// in the receive buffer until `start_receive` is called.
+    pub fn frame(&mut self) -> Option<FrameRef<'_>>
     {
-        if src.len() < MAX_PACKAGE_SIZE {
-            return 0;
+        self.capture_address();
+        if self.scan_channel.is_some() || self.radio.events_end.read().bits() == 0 {
+            return None;
         }
-        let len = src.len() as u8;
+        if !self.end() {
+            self.start_receive();
+            return None;
+        }
+        FrameRef::new(&self.config, &self.buffers.rx)
+    }
+
+    /// Handle the END event of a reception, returns `true` if the CRC is
+    /// good
+    fn end(&mut self) -> bool
+    {
         compiler_fence(Ordering::AcqRel);
-        self.radio.events_disabled.reset();
-        self.radio.tasks_disable.write(|w| unsafe { w.bits(1) });
-        while self.radio.events_disabled.read().bits() == 0 {}
+        self.capture_address();
+        self.timestamp = self.address_time.take().unwrap_or_else(time::now);
+        self.rssi = self.radio.rssisample.read().rssisample().bits();
+        self.radio.events_end.reset();
+        let crc_ok = self.radio.crcstatus.read().crcstatus().is_crcok();
+        if !crc_ok {
+            self.crc_errors = self.crc_errors.wrapping_add(1);
+        }
+        crc_ok
+    }
 
-        self.tx_buf[0] = len;
-        self.tx_buf[1..].copy_from_slice(src);
+    /// Take a received package without waiting, returns 0 if no package
+    /// has been received
+    ///
+    /// Reception is restarted after a package has been taken.
+    pub fn try_receive(&mut self, dst: &mut [u8; N]) -> usize
+    {
+        if self.scan_channel.is_some() {
+            return 0;
+        }
+        self.capture_address();
+        if self.radio.events_end.read().bits() == 0 {
+            return 0;
+        }
+        let length = self.receive(dst);
+        self.start_receive();
+        length
+    }
 
+    /// Start sending the package in the transmit buffer
+    fn start_transmit(&mut self)
+    {
+        compiler_fence(Ordering::AcqRel);
         self.radio.txaddress.write(|w| unsafe { w.txaddress().bits(0) });
-        let tx_buf = &mut self.tx_buf as *mut _ as u32;
+        let tx_buf = &mut self.buffers.tx as *mut _ as u32;
         self.radio.packetptr.write(|w| unsafe { w.bits(tx_buf) });
-        self.radio.tasks_txen.write(|w| unsafe { w.bits(1) });
-        
         self.radio.events_end.reset();
+        self.radio.tasks_txen.write(|w| unsafe { w.bits(1) });
+    }
+
+    pub fn send(&mut self, src: &[u8]) -> usize
+    {
+        if src.len() > usize::from(self.config.max_length) || self.scan_channel.is_some() {
+            return 0;
+        }
+        let len = src.len() as u8;
+        compiler_fence(Ordering::AcqRel);
+        self.disable();
+
+        self.config.pack(src, &mut self.buffers.tx);
+
+        self.start_transmit();
         while self.radio.events_end.read().bits() == 0 {}
 
-        self.radio.events_disabled.reset();
-        self.radio.tasks_disable.write(|w| unsafe { w.bits(1) });
-        while self.radio.events_disabled.read().bits() == 0 {}
+        self.disable();
 
         self.start_receive();
 
-        return len as usize;
+        len as usize
+    }
+    /// Split the radio into a transmitter and a receiver
+    ///
+    /// The receiver keeps the peripheral and shall be serviced from the
+    /// RADIO interrupt. The transmitter queues packages and pends the RADIO
+    /// interrupt, it can be used from any other context.
+    pub fn split(mut self, queue: &'static mut TxQueue<N>) -> (RadioTx<N>, RadioRx<N>) {
+        let (producer, consumer) = queue.split();
+        self.start_receive();
+        self.radio.intenset.write(|w| w.address().set());
+        (
+            RadioTx { queue: producer, config: self.config },
+            RadioRx { radio: self, queue: consumer, transmitting: false },
+        )
+    }
+}
+
+/// # Radio transmitter
+///
+/// Queues packages for the `RadioRx` to send.
+pub struct RadioTx<const N: usize = MAX_PACKAGE_SIZE> {
+    queue: Producer<'static, [u8; N], TX_QUEUE_LENGTH>,
+    config: RadioConfig,
+}
+
+impl<const N: usize> RadioTx<N> {
+    /// Queue a package, returns the number of bytes queued or 0 if the
+    /// package is too large or the queue is full
+    pub fn send(&mut self, src: &[u8]) -> usize
+    {
+        if src.len() > usize::from(self.config.max_length) {
+            return 0;
+        }
+        let mut package = [0u8; N];
+        self.config.pack(src, &mut package);
+        if self.queue.enqueue(package).is_err() {
+            return 0;
+        }
+        NVIC::pend(Interrupt::RADIO);
+        src.len()
+    }
+
+    /// Is there room in the queue for another package
+    pub fn ready(&self) -> bool {
+        self.queue.ready()
+    }
+}
+
+/// # Radio receiver
+///
+/// Owns the radio peripheral, receives packages and sends the packages
+/// queued by the `RadioTx`.
+pub struct RadioRx<const N: usize = MAX_PACKAGE_SIZE> {
+    radio: Radio<N>,
+    queue: Consumer<'static, [u8; N], TX_QUEUE_LENGTH>,
+    transmitting: bool,
+}
+
+impl<const N: usize> RadioRx<N> {
+    /// Change the group
+    pub fn set_group(&mut self, group: u8)
+    {
+        self.radio.set_group(group);
+    }
+
+    /// Time of the ADDRESS event of the last received package
+    pub fn timestamp(&self) -> Instant {
+        self.radio.timestamp()
+    }
+
+    /// Signal strength of the last received package in dBm
+    pub fn rssi(&self) -> i8 {
+        self.radio.rssi()
+    }
+
+    /// Number of packages received with a CRC error
+    pub fn crc_errors(&self) -> u32 {
+        self.radio.crc_errors()
+    }
+
+    /// Handle the RADIO interrupt
+    ///
+    /// Returns the received package, if any. The package is returned by
+    /// value so that it can be handed to another task.
+    pub fn interrupt(&mut self) -> Option<[u8; N]>
+    {
+        compiler_fence(Ordering::AcqRel);
+        let end = self.radio.radio.events_end.read().bits() != 0;
+        if self.transmitting {
+            // The ADDRESS event of the package being sent
+            self.radio.radio.events_address.reset();
+        }
+        else {
+            self.radio.capture_address();
+        }
+        let mut package = None;
+        if end {
+            if self.transmitting {
+                self.radio.radio.events_end.reset();
+                self.transmitting = false;
+            }
+            else {
+                let mut buffer = [0u8; N];
+                if self.radio.receive(&mut buffer) > 0 {
+                    package = Some(buffer);
+                }
+            }
+        }
+        else if self.transmitting || self.radio.address_time.is_some()
+        {
+            // Busy, queued packages are sent when the current package ends
+            return None;
+        }
+        if let Some(package) = self.queue.dequeue() {
+            self.radio.disable();
+            self.radio.buffers.tx = package;
+            self.radio.start_transmit();
+            self.transmitting = true;
+        }
+        else if end {
+            self.radio.start_receive();
+        }
+        package
+    }
+}
+
+impl RadioRx {
+    /// Handle the RADIO interrupt and pass any received package on to
+    /// `recv`
+    pub fn wake(&mut self)
+    {
+        if let Some(package) = self.interrupt() {
+            RECEIVED.signal(package);
+        }
     }
 }
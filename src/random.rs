@@ -0,0 +1,68 @@
+//! Pseudo-random numbers
+//!
+//! The protocols add random delays so that boards started together do not
+//! keep sending at the same time. `Random` is a xorshift32 generator, good
+//! enough for jitter, seeded from the serial number of the board.
+//!
+//! ## Reference
+//!
+//! * <https://www.jstatsoft.org/article/view/v008i14>
+
+use crate::time::Duration;
+
+/// # Random
+///
+/// xorshift32 generator, the state is never zero.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Random(u32);
+
+impl Random {
+    /// Create a generator from `seed`, any seed, 0 included
+    pub(crate) const fn new(seed: u32) -> Self {
+        Random(seed | 1)
+    }
+
+    /// The next number
+    pub(crate) fn next_u32(&mut self) -> u32 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 17;
+        self.0 ^= self.0 << 5;
+        self.0
+    }
+
+    /// The next number below `bound`, which must not be 0
+    pub(crate) fn below(&mut self, bound: u64) -> u64 {
+        u64::from(self.next_u32()) % bound
+    }
+
+    /// A random delay of up to `max`, `max` included
+    pub(crate) fn jitter(&mut self, max: Duration) -> Duration {
+        Duration::from_ticks(self.below(max.as_ticks() + 1))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn zero_seed_is_not_stuck() {
+        let mut random = Random::new(0);
+        let first = random.next_u32();
+        assert_ne!(first, 0);
+        assert_ne!(random.next_u32(), first);
+    }
+
+    #[test]
+    fn jitter_stays_within_max() {
+        let mut random = Random::new(0x1234_5678);
+        let max = Duration::from_ticks(10);
+        let mut seen = [false; 11];
+        for _ in 0..1000 {
+            let jitter = random.jitter(max);
+            assert!(jitter <= max);
+            seen[jitter.as_ticks() as usize] = true;
+        }
+        assert!(seen.iter().all(|&seen| seen));
+    }
+}
@@ -0,0 +1,558 @@
+//! Reliable delivery over datagrams
+//!
+//! Datagrams are acknowledged by the receiver and sent again until the
+//! acknowledgement arrives. Every sender keeps a sequence number per
+//! destination, which the receiver uses to drop duplicates caused by lost
+//! acknowledgements. The sequence numbers are in a session, picked at random
+//! when the sender starts and changed when it forgets a destination, so a
+//! sender that restarts its sequence numbers is not taken for a duplicate.
+//!
+//! ```notrust
+//! let seed = rng.value.read().bits();
+//! let mut endpoint = Endpoint::<4>::new(serial_number, group, seed);
+//! endpoint.send_reliable(&mut radio, peer, b"vote 3")?;
+//! ...
+//! endpoint.poll(&mut radio);
+//! while let Some(message) = endpoint.recv() { ... }
+//! ```
+//!
+//! Received datagrams of other protocols are dropped by the `Endpoint`.
+
+use byteorder::{ByteOrder, LittleEndian};
+
+use crate::datagram::{self, DatagramHeader, DatagramProtocol};
+use crate::link::Link;
+use crate::radio::{PackageBuffer, MAX_PACKAGE_SIZE};
+use crate::random::Random;
+use crate::time::{Duration, Instant};
+
+/// Size of the reliable header following the datagram header
+pub const HEADER_SIZE: usize = 12;
+/// Largest payload of a reliable datagram
+pub const MAX_PAYLOAD: usize = MAX_PACKAGE_SIZE - 1 - (datagram::HEADER_SIZE - 1) - HEADER_SIZE;
+/// Time to wait for the first acknowledgement, doubled for every retry
+pub const ACK_TIMEOUT: Duration = Duration::from_millis(10);
+/// Number of retransmissions before giving up
+pub const RETRIES: u8 = 5;
+/// Number of received messages held until taken with `Endpoint::recv`
+pub const INBOX_LENGTH: usize = 4;
+
+const KIND_DATA: u8 = 0;
+const KIND_ACK: u8 = 1;
+
+/// The datagram was not acknowledged
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Timeout;
+
+/// # Reliable Header
+///
+/// ```notrust
+/// | 0 ... 3         | 4    | 5        | 6 ... 7 | 8 ... 11 | 12 ... 15   | 16 ... 32
+/// ----------------------------------------------------------------------------------
+/// | datagram header | kind | sequence | session | source   | destination | payload
+/// ```
+/// Kind is either
+///  * 0, Data
+///  * 1, Acknowledgement of the data with the same session and sequence
+///    number
+///
+/// Source and destination are serial numbers.
+struct Frame {
+    kind: u8,
+    sequence: u8,
+    session: u16,
+    source: u32,
+    destination: u32,
+    length: usize,
+}
+
+impl Frame {
+    fn pack(&self, group: u8, payload: &[u8], buffer: &mut PackageBuffer) {
+        DatagramHeader::new(group, DatagramProtocol::Reliable, HEADER_SIZE + payload.len())
+            .pack(buffer);
+        let slice = &mut buffer[datagram::HEADER_SIZE..];
+        slice[0] = self.kind;
+        slice[1] = self.sequence;
+        LittleEndian::write_u16(&mut slice[2..=3], self.session);
+        LittleEndian::write_u32(&mut slice[4..=7], self.source);
+        LittleEndian::write_u32(&mut slice[8..=11], self.destination);
+        slice[HEADER_SIZE..HEADER_SIZE + payload.len()].copy_from_slice(payload);
+    }
+
+    fn unpack(group: u8, buffer: &PackageBuffer) -> Option<Frame> {
+        let header = DatagramHeader::unpack(buffer);
+        if header.protocol() != DatagramProtocol::Reliable
+            || header.version() != datagram::VERSION
+            || header.group() != group
+            || header.payload_length() < HEADER_SIZE
+            || header.payload_length() > HEADER_SIZE + MAX_PAYLOAD
+        {
+            return None;
+        }
+        let slice = &buffer[datagram::HEADER_SIZE..];
+        Some(Frame {
+            kind: slice[0],
+            sequence: slice[1],
+            session: LittleEndian::read_u16(&slice[2..=3]),
+            source: LittleEndian::read_u32(&slice[4..=7]),
+            destination: LittleEndian::read_u32(&slice[8..=11]),
+            length: header.payload_length() - HEADER_SIZE,
+        })
+    }
+}
+
+/// A message received by an `Endpoint`
+#[derive(Clone, Copy)]
+pub struct Message {
+    source: u32,
+    length: usize,
+    payload: [u8; MAX_PAYLOAD],
+}
+
+impl Message {
+    /// Serial number of the sender
+    pub fn source(&self) -> u32 {
+        self.source
+    }
+    /// The message payload
+    pub fn payload(&self) -> &[u8] {
+        &self.payload[..self.length]
+    }
+}
+
+/// A device datagrams are sent to
+#[derive(Clone, Copy)]
+struct Destination {
+    serial_number: u32,
+    /// Sequence number of the next datagram sent to the device
+    sequence: u8,
+}
+
+/// A device datagrams are received from
+#[derive(Clone, Copy)]
+struct Source {
+    serial_number: u32,
+    /// Session and sequence number of the last datagram received
+    session: u16,
+    sequence: u8,
+}
+
+struct Outgoing {
+    frame: PackageBuffer,
+    destination: u32,
+    session: u16,
+    sequence: u8,
+    transmissions: u8,
+    deadline: Instant,
+}
+
+/// # Reliable endpoint
+///
+/// Sends one acknowledged datagram at a time and acknowledges datagrams
+/// sent to it. Sequence numbers are kept for `PEERS` destinations and
+/// `PEERS` sources, at least one, in separate tables so that receiving from
+/// many devices does not forget the destinations. The oldest destination or
+/// source is forgotten when a new one shows up.
+///
+/// A source forgotten while its datagram is retransmitted gets the
+/// datagram twice, `PEERS` should be larger than the number of devices
+/// sending at the same time.
+///
+/// The endpoint only makes progress when polled, `send_reliable` polls until
+/// the datagram has been acknowledged. `start_send` and `poll` are the same
+/// without blocking.
+pub struct Endpoint<const PEERS: usize> {
+    serial_number: u32,
+    group: u8,
+    /// Session of the sequence numbers sent
+    session: u16,
+    destinations: [Option<Destination>; PEERS],
+    destinations_evict: usize,
+    sources: [Option<Source>; PEERS],
+    sources_evict: usize,
+    outgoing: Option<Outgoing>,
+    inbox: [Option<Message>; INBOX_LENGTH],
+    inbox_head: usize,
+    random: Random,
+}
+
+impl<const PEERS: usize> Endpoint<PEERS> {
+    /// Create an endpoint for the device with `serial_number` in `group`
+    ///
+    /// `seed` picks the session and the first sequence numbers, it must be
+    /// different every time the device starts, take it from the RNG.
+    pub fn new(serial_number: u32, group: u8, seed: u32) -> Self {
+        const { assert!(PEERS > 0, "an endpoint needs room for a peer") };
+        let mut random = Random::new(seed ^ serial_number);
+        Endpoint {
+            serial_number,
+            group,
+            session: random.next_u32() as u16,
+            destinations: [None; PEERS],
+            destinations_evict: 0,
+            sources: [None; PEERS],
+            sources_evict: 0,
+            outgoing: None,
+            inbox: [None; INBOX_LENGTH],
+            inbox_head: 0,
+            random,
+        }
+    }
+
+    /// Serial number of this endpoint
+    pub fn serial_number(&self) -> u32 {
+        self.serial_number
+    }
+
+    /// Is a datagram waiting to be acknowledged
+    pub fn is_sending(&self) -> bool {
+        self.outgoing.is_some()
+    }
+
+    /// Send `payload` to the device with serial number `destination` and
+    /// wait for the acknowledgement
+    ///
+    /// A datagram started with `start_send` is completed first. Datagrams
+    /// received while waiting are acknowledged and kept for `recv`.
+    pub fn send_reliable<L: Link>(&mut self, link: &mut L, destination: u32, payload: &[u8])
+        -> Result<(), Timeout>
+    {
+        assert!(payload.len() <= MAX_PAYLOAD);
+        while self.outgoing.is_some() {
+            self.poll(link);
+        }
+        self.start_send(link.now(), destination, payload);
+        loop {
+            if let Some(result) = self.poll(link) {
+                return result;
+            }
+        }
+    }
+
+    /// Start sending `payload` to the device with serial number
+    /// `destination`, the result is returned by `poll`
+    ///
+    /// Returns `false` if the payload is too large or another datagram is
+    /// waiting to be acknowledged.
+    pub fn start_send(&mut self, now: Instant, destination: u32, payload: &[u8]) -> bool {
+        if payload.len() > MAX_PAYLOAD || self.outgoing.is_some() {
+            return false;
+        }
+        let sequence = self.next_sequence(destination);
+        let mut frame = [0u8; MAX_PACKAGE_SIZE];
+        Frame {
+            kind: KIND_DATA,
+            sequence,
+            session: self.session,
+            source: self.serial_number,
+            destination,
+            length: payload.len(),
+        }.pack(self.group, payload, &mut frame);
+        self.outgoing = Some(Outgoing {
+            frame,
+            destination,
+            session: self.session,
+            sequence,
+            transmissions: 0,
+            deadline: now,
+        });
+        true
+    }
+
+    /// Handle received datagrams and retransmit
+    ///
+    /// Returns the result of the datagram being sent, once it has been
+    /// acknowledged or has timed out.
+    pub fn poll<L: Link>(&mut self, link: &mut L) -> Option<Result<(), Timeout>> {
+        let mut result = None;
+        while let Some(frame) = link.receive() {
+            if self.handle(link, &frame) {
+                result = Some(Ok(()));
+            }
+        }
+        let now = link.now();
+        let expired = match &self.outgoing {
+            Some(outgoing) => now >= outgoing.deadline,
+            None => false,
+        };
+        if expired {
+            let backoff = self.backoff();
+            let outgoing = self.outgoing.as_mut().unwrap();
+            if outgoing.transmissions > RETRIES {
+                self.outgoing = None;
+                return Some(Err(Timeout));
+            }
+            outgoing.transmissions += 1;
+            let ticks = backoff.as_ticks() << (outgoing.transmissions - 1);
+            outgoing.deadline = now + Duration::from_ticks(ticks);
+            link.transmit(&outgoing.frame);
+        }
+        result
+    }
+
+    /// Take a received message
+    pub fn recv(&mut self) -> Option<Message> {
+        let message = self.inbox[self.inbox_head].take()?;
+        self.inbox_head = (self.inbox_head + 1) % INBOX_LENGTH;
+        Some(message)
+    }
+
+    /// Handle a received datagram, returns `true` if it acknowledged the
+    /// outgoing datagram
+    fn handle<L: Link>(&mut self, link: &mut L, buffer: &PackageBuffer) -> bool {
+        let frame = match Frame::unpack(self.group, buffer) {
+            Some(frame) => frame,
+            None => return false,
+        };
+        if frame.destination != self.serial_number {
+            return false;
+        }
+        match frame.kind {
+            KIND_ACK => {
+                let acknowledged = match &self.outgoing {
+                    Some(outgoing) => outgoing.destination == frame.source
+                        && outgoing.session == frame.session
+                        && outgoing.sequence == frame.sequence,
+                    None => false,
+                };
+                if acknowledged {
+                    self.outgoing = None;
+                }
+                acknowledged
+            }
+            KIND_DATA => {
+                let (session, sequence) = (frame.session, frame.sequence);
+                let duplicate = self.sources.iter().flatten().any(|source| {
+                    source.serial_number == frame.source
+                        && source.session == session
+                        && source.sequence == sequence
+                });
+                if !duplicate {
+                    let slot = (self.inbox_head + self.inbox_len()) % INBOX_LENGTH;
+                    if self.inbox[slot].is_some() {
+                        // No room, the sender will try again
+                        return false;
+                    }
+                    let offset = datagram::HEADER_SIZE + HEADER_SIZE;
+                    let mut message = Message {
+                        source: frame.source,
+                        length: frame.length,
+                        payload: [0u8; MAX_PAYLOAD],
+                    };
+                    message.payload[..frame.length]
+                        .copy_from_slice(&buffer[offset..offset + frame.length]);
+                    self.inbox[slot] = Some(message);
+                    self.received(frame.source, session, sequence);
+                }
+                let mut ack = [0u8; MAX_PACKAGE_SIZE];
+                Frame {
+                    kind: KIND_ACK,
+                    sequence,
+                    session,
+                    source: self.serial_number,
+                    destination: frame.source,
+                    length: 0,
+                }.pack(self.group, &[], &mut ack);
+                link.transmit(&ack);
+                false
+            }
+            _ => false,
+        }
+    }
+
+    fn inbox_len(&self) -> usize {
+        self.inbox.iter().filter(|message| message.is_some()).count()
+    }
+
+    /// Take the next sequence number to `destination`
+    ///
+    /// A new destination replaces the oldest one and starts at a random
+    /// sequence number. The forgotten destination may still hold the
+    /// sequence number sent to it last, so the session is changed.
+    fn next_sequence(&mut self, serial_number: u32) -> u8 {
+        let index = match self.destinations.iter().position(|destination| {
+            destination.is_some_and(|destination| destination.serial_number == serial_number)
+        }) {
+            Some(index) => index,
+            None => {
+                let index = self.destinations_evict;
+                self.destinations_evict = (index + 1) % PEERS;
+                if self.destinations[index].is_some() {
+                    self.session = self.session.wrapping_add(1);
+                }
+                let sequence = self.random.next_u32() as u8;
+                self.destinations[index] = Some(Destination { serial_number, sequence });
+                index
+            }
+        };
+        let destination = self.destinations[index].as_mut().unwrap();
+        let sequence = destination.sequence;
+        destination.sequence = sequence.wrapping_add(1);
+        sequence
+    }
+
+    /// Keep the session and sequence number received last from
+    /// `serial_number`, replacing the oldest source for a new one
+    fn received(&mut self, serial_number: u32, session: u16, sequence: u8) {
+        let index = match self.sources.iter()
+            .position(|source| source.is_some_and(|source| source.serial_number == serial_number))
+        {
+            Some(index) => index,
+            None => {
+                let index = self.sources_evict;
+                self.sources_evict = (index + 1) % PEERS;
+                index
+            }
+        };
+        self.sources[index] = Some(Source { serial_number, session, sequence });
+    }
+
+    /// Acknowledgement timeout with random jitter, so that two endpoints
+    /// colliding do not retry at the same time
+    fn backoff(&mut self) -> Duration {
+        let jitter = self.random.below(ACK_TIMEOUT.as_ticks() / 2);
+        Duration::from_ticks(ACK_TIMEOUT.as_ticks() + jitter)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sim::Simulator;
+
+    fn endpoints() -> [Endpoint<2>; 2] {
+        [Endpoint::new(1, 0, 0x1111), Endpoint::new(2, 0, 0x2222)]
+    }
+
+    #[test]
+    fn delivers_without_loss() {
+        let mut sim = Simulator::<2>::new(1);
+        let mut endpoints = endpoints();
+        assert_eq!(sim.send_reliable(&mut endpoints, 0, 2, b"vote 3"), Ok(()));
+        let message = endpoints[1].recv().unwrap();
+        assert_eq!(message.source(), 1);
+        assert_eq!(message.payload(), b"vote 3");
+        assert!(endpoints[1].recv().is_none());
+        assert!(endpoints[0].recv().is_none());
+    }
+
+    #[test]
+    fn survives_loss_without_duplicates() {
+        let mut sim = Simulator::<2>::new(0x5eed);
+        sim.set_loss(30);
+        let mut endpoints = endpoints();
+        let mut delivered = 0;
+        let mut timeouts = 0;
+        for index in 0..100u8 {
+            let result = sim.send_reliable(&mut endpoints, 0, 2, &[index]);
+            let mut received = Vec::new();
+            // Let retransmissions in flight arrive
+            for _ in 0..50 {
+                endpoints[1].poll(&mut sim.link(1));
+                sim.step();
+            }
+            while let Some(message) = endpoints[1].recv() {
+                received.push(message.payload()[0]);
+            }
+            match result {
+                Ok(()) => {
+                    assert_eq!(received, [index]);
+                    delivered += 1;
+                }
+                // The data may have arrived with all acknowledgements lost
+                Err(Timeout) => {
+                    assert!(received.is_empty() || received == [index]);
+                    timeouts += 1;
+                }
+            }
+            while endpoints[0].poll(&mut sim.link(0)).is_some() {}
+        }
+        assert!(delivered >= 90, "delivered {} timed out {}", delivered, timeouts);
+        let (sent, lost) = sim.statistics();
+        assert!(lost > 0 && lost < sent);
+    }
+
+    #[test]
+    fn duplicate_is_acknowledged_and_dropped() {
+        let mut sim = Simulator::<2>::new(1);
+        let mut endpoints = endpoints();
+        assert!(endpoints[0].start_send(sim.now(), 2, b"once"));
+        assert_eq!(endpoints[0].poll(&mut sim.link(0)), None);
+        assert_eq!(endpoints[1].poll(&mut sim.link(1)), None);
+        // Lose the acknowledgement
+        assert!(sim.link(0).receive().is_some());
+        for _ in 0..ACK_TIMEOUT.as_millis() * 2 {
+            sim.step();
+        }
+        // Sent again, acknowledged again
+        assert_eq!(endpoints[0].poll(&mut sim.link(0)), None);
+        assert_eq!(endpoints[1].poll(&mut sim.link(1)), None);
+        assert_eq!(endpoints[0].poll(&mut sim.link(0)), Some(Ok(())));
+        assert_eq!(endpoints[1].recv().unwrap().payload(), b"once");
+        assert!(endpoints[1].recv().is_none());
+    }
+
+    /// Send `payload` and check that `to` receives it once
+    fn delivered<const NODES: usize>(sim: &mut Simulator<NODES>,
+        endpoints: &mut [Endpoint<1>; NODES], from: usize, to: usize, payload: &[u8])
+    {
+        let destination = endpoints[to].serial_number();
+        assert_eq!(sim.send_reliable(endpoints, from, destination, payload), Ok(()));
+        assert_eq!(endpoints[to].recv().unwrap().payload(), payload);
+        assert!(endpoints[to].recv().is_none());
+    }
+
+    #[test]
+    fn more_destinations_than_peers() {
+        let mut sim = Simulator::<3>::new(1);
+        let mut endpoints = [1, 2, 3].map(|serial_number| Endpoint::<1>::new(serial_number, 0,
+            serial_number * 0x1234));
+        // Each message forgets the other destination
+        for round in 0..20u8 {
+            delivered(&mut sim, &mut endpoints, 0, 1, &[round, 2]);
+            delivered(&mut sim, &mut endpoints, 0, 2, &[round, 3]);
+        }
+    }
+
+    #[test]
+    fn receiving_does_not_forget_destinations() {
+        let mut sim = Simulator::<4>::new(1);
+        let mut endpoints = [1, 2, 3, 4].map(|serial_number| Endpoint::<1>::new(serial_number,
+            0, serial_number * 0x1234));
+        for round in 0..20u8 {
+            delivered(&mut sim, &mut endpoints, 0, 1, &[round]);
+            delivered(&mut sim, &mut endpoints, 2, 0, &[round]);
+            delivered(&mut sim, &mut endpoints, 3, 0, &[round]);
+        }
+    }
+
+    #[test]
+    fn sender_restart_is_not_a_duplicate() {
+        let mut sim = Simulator::<2>::new(1);
+        let mut endpoints = endpoints();
+        for boot in 1..20u32 {
+            assert_eq!(sim.send_reliable(&mut endpoints, 0, 2, &[boot as u8]), Ok(()));
+            assert_eq!(endpoints[1].recv().unwrap().payload(), [boot as u8]);
+            let last = endpoints[0].destinations[0].unwrap().sequence.wrapping_sub(1);
+            endpoints[0] = Endpoint::new(1, 0, boot.wrapping_mul(0x9e37_79b9));
+            // The restarted sender starts at the sequence number sent last
+            endpoints[0].destinations[0] = Some(Destination { serial_number: 2, sequence: last });
+            assert_eq!(sim.send_reliable(&mut endpoints, 0, 2, b"vote"), Ok(()));
+            assert_eq!(endpoints[1].recv().unwrap().payload(), b"vote");
+        }
+    }
+
+    #[test]
+    fn times_out_when_unreachable() {
+        let mut sim = Simulator::<2>::new(1);
+        sim.disconnect_all();
+        let mut endpoints = endpoints();
+        let start = sim.now();
+        assert_eq!(sim.send_reliable(&mut endpoints, 0, 2, b"hello"), Err(Timeout));
+        assert!(!endpoints[0].is_sending());
+        // All retries with backoff were waited for
+        let least = ACK_TIMEOUT.as_ticks() * ((1 << (RETRIES + 1)) - 1);
+        assert!((sim.now() - start).as_ticks() >= least);
+        assert_eq!(sim.statistics(), (0, 0));
+    }
+}
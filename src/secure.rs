@@ -0,0 +1,284 @@
+//! Secure datagrams
+//!
+//! Datagrams encrypted and authenticated with AES-CCM and a pre-shared key.
+//! Every sender numbers its datagrams, receivers drop datagrams that do not
+//! have a higher number than the last datagram from the same sender, so
+//! recorded datagrams can not be replayed.
+//!
+//! ```notrust
+//! let ccm = HwCcm::new(board.ccm, &KEY);
+//! let mut secure = Secure::<_, 8>::new(ccm, serial_number, group, counter);
+//! secure.send_secure(&mut radio, b"vote 3");
+//! ...
+//! let mut buffer = [0u8; secure::MAX_PAYLOAD];
+//! if let Some((source, length)) = secure.recv_secure(&mut radio, &mut buffer) {
+//!     ...
+//! }
+//! ```
+//!
+//! A key must never be used twice with the same counter by a sender. Store
+//! `Secure::counter` in flash, or start from a value known to be higher,
+//! when the device restarts.
+
+use byteorder::{ByteOrder, LittleEndian};
+
+use crate::ccm::{Ccm, Mic, Nonce, MIC_SIZE};
+use crate::datagram::{self, DatagramHeader, DatagramProtocol};
+use crate::link::Link;
+use crate::radio::{PackageBuffer, MAX_PACKAGE_SIZE};
+
+/// Size of the secure header following the datagram header
+pub const HEADER_SIZE: usize = 8;
+/// Largest payload of a secure datagram
+pub const MAX_PAYLOAD: usize =
+    MAX_PACKAGE_SIZE - 1 - (datagram::HEADER_SIZE - 1) - HEADER_SIZE - MIC_SIZE;
+
+/// # Secure Header
+///
+/// ```notrust
+/// | 0 ... 3         | 4 ... 7 | 8 ... 11 | 12 ...     | 4 bytes
+/// -------------------------------------------------------------
+/// | datagram header | source  | counter  | ciphertext | MIC
+/// ```
+/// Source is the serial number of the sender. The nonce is made from the
+/// counter, the source and the group, so a datagram does not authenticate
+/// when any of them has been changed.
+fn nonce(source: u32, counter: u32, group: u8) -> Nonce {
+    let mut nonce = [0u8; 13];
+    LittleEndian::write_u32(&mut nonce[0..=3], counter);
+    LittleEndian::write_u32(&mut nonce[5..=8], source);
+    nonce[9] = group;
+    nonce[10] = u8::from(DatagramProtocol::Secure);
+    nonce
+}
+
+/// # Secure endpoint
+///
+/// Seals and opens secure datagrams with a `Ccm`, `ccm::HwCcm` on the
+/// device or `ccm::SoftCcm`. The last counter is kept for `PEERS` senders,
+/// the oldest sender is forgotten when a new sender shows up. Datagrams of a
+/// forgotten sender can be replayed, so `PEERS` should cover the group.
+pub struct Secure<C: Ccm, const PEERS: usize> {
+    ccm: C,
+    serial_number: u32,
+    group: u8,
+    counter: u32,
+    peers: [Option<(u32, u32)>; PEERS],
+    evict: usize,
+}
+
+impl<C: Ccm, const PEERS: usize> Secure<C, PEERS> {
+    /// Create a secure endpoint for the device with `serial_number` in
+    /// `group`, the first datagram is sent with `counter`
+    pub fn new(ccm: C, serial_number: u32, group: u8, counter: u32) -> Self {
+        Secure {
+            ccm,
+            serial_number,
+            group,
+            counter,
+            peers: [None; PEERS],
+            evict: 0,
+        }
+    }
+
+    /// The counter of the next datagram sent
+    pub fn counter(&self) -> u32 {
+        self.counter
+    }
+
+    /// Release the CCM
+    pub fn free(self) -> C {
+        self.ccm
+    }
+
+    /// Seal `payload` into `frame`
+    ///
+    /// Returns the number of bytes written, including the length byte, or 0
+    /// if the payload is empty or too large, the counter is used up or the
+    /// CCM failed.
+    pub fn seal(&mut self, payload: &[u8], frame: &mut PackageBuffer) -> usize {
+        if payload.is_empty() || payload.len() > MAX_PAYLOAD || self.counter == u32::MAX {
+            return 0;
+        }
+        let counter = self.counter;
+        self.counter += 1;
+        let length = HEADER_SIZE + payload.len() + MIC_SIZE;
+        DatagramHeader::new(self.group, DatagramProtocol::Secure, length).pack(frame);
+        let slice = &mut frame[datagram::HEADER_SIZE..];
+        LittleEndian::write_u32(&mut slice[0..=3], self.serial_number);
+        LittleEndian::write_u32(&mut slice[4..=7], counter);
+        let data = &mut slice[HEADER_SIZE..HEADER_SIZE + payload.len()];
+        data.copy_from_slice(payload);
+        let nonce = nonce(self.serial_number, counter, self.group);
+        let mic = match self.ccm.seal(&nonce, datagram::VERSION, data) {
+            Ok(mic) => mic,
+            Err(_) => return 0,
+        };
+        slice[HEADER_SIZE + payload.len()..length].copy_from_slice(&mic);
+        datagram::HEADER_SIZE + length
+    }
+
+    /// Open a secure datagram
+    ///
+    /// Returns the serial number of the sender and the length of the payload
+    /// copied to `buffer`. Datagrams of other protocols, datagrams that do
+    /// not authenticate and replayed datagrams return `None`.
+    pub fn open(&mut self, frame: &PackageBuffer, buffer: &mut [u8]) -> Option<(u32, usize)> {
+        let header = DatagramHeader::unpack(frame);
+        if header.protocol() != DatagramProtocol::Secure
+            || header.version() != datagram::VERSION
+            || header.group() != self.group
+            || header.payload_length() <= HEADER_SIZE + MIC_SIZE
+            || header.payload_length() > HEADER_SIZE + MAX_PAYLOAD + MIC_SIZE
+        {
+            return None;
+        }
+        let length = header.payload_length() - HEADER_SIZE - MIC_SIZE;
+        if buffer.len() < length {
+            return None;
+        }
+        let slice = &frame[datagram::HEADER_SIZE..];
+        let source = LittleEndian::read_u32(&slice[0..=3]);
+        let counter = LittleEndian::read_u32(&slice[4..=7]);
+        if source == self.serial_number || self.is_replayed(source, counter) {
+            return None;
+        }
+        let mut mic: Mic = [0u8; MIC_SIZE];
+        mic.copy_from_slice(&slice[HEADER_SIZE + length..HEADER_SIZE + length + MIC_SIZE]);
+        let data = &mut buffer[..length];
+        data.copy_from_slice(&slice[HEADER_SIZE..HEADER_SIZE + length]);
+        let nonce = nonce(source, counter, self.group);
+        if !self.ccm.open(&nonce, datagram::VERSION, data, &mic) {
+            data.iter_mut().for_each(|byte| *byte = 0);
+            return None;
+        }
+        self.accept(source, counter);
+        Some((source, length))
+    }
+
+    /// Seal and send `payload`, returns the number of bytes sent or 0
+    pub fn send_secure<L: Link>(&mut self, link: &mut L, payload: &[u8]) -> usize {
+        let mut frame = [0u8; MAX_PACKAGE_SIZE];
+        if self.seal(payload, &mut frame) == 0 || !link.transmit(&frame) {
+            return 0;
+        }
+        payload.len()
+    }
+
+    /// Receive datagrams from the link until a secure datagram opens
+    ///
+    /// Returns the serial number of the sender and the length of the payload
+    /// copied to `buffer`, or `None` when there are no more datagrams.
+    pub fn recv_secure<L: Link>(&mut self, link: &mut L, buffer: &mut [u8])
+        -> Option<(u32, usize)>
+    {
+        while let Some(frame) = link.receive() {
+            let message = self.open(&frame, buffer);
+            if message.is_some() {
+                return message;
+            }
+        }
+        None
+    }
+
+    fn is_replayed(&self, source: u32, counter: u32) -> bool {
+        self.peers.iter().flatten()
+            .any(|&(peer, last)| peer == source && counter <= last)
+    }
+
+    /// Remember the counter of an authenticated datagram
+    fn accept(&mut self, source: u32, counter: u32) {
+        if let Some(peer) = self.peers.iter_mut().flatten().find(|(peer, _)| *peer == source) {
+            peer.1 = counter;
+            return;
+        }
+        if PEERS > 0 {
+            self.peers[self.evict] = Some((source, counter));
+            self.evict = (self.evict + 1) % PEERS;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::aes::SoftAes;
+    use crate::ccm::SoftCcm;
+
+    const KEY: [u8; 16] = *b"micro:bit secret";
+
+    fn endpoint(serial_number: u32, counter: u32) -> Secure<SoftCcm<SoftAes>, 2> {
+        Secure::new(SoftCcm::new(SoftAes::new(&KEY)), serial_number, 5, counter)
+    }
+
+    fn sealed(sender: &mut Secure<SoftCcm<SoftAes>, 2>, payload: &[u8]) -> PackageBuffer {
+        let mut frame = [0u8; MAX_PACKAGE_SIZE];
+        assert_ne!(sender.seal(payload, &mut frame), 0);
+        frame
+    }
+
+    #[test]
+    fn opens_sealed_datagram() {
+        let mut sender = endpoint(1, 10);
+        let mut receiver = endpoint(2, 0);
+        let frame = sealed(&mut sender, b"vote 3");
+        assert_eq!(sender.counter(), 11);
+        let mut buffer = [0u8; MAX_PAYLOAD];
+        assert_eq!(receiver.open(&frame, &mut buffer), Some((1, 6)));
+        assert_eq!(&buffer[..6], b"vote 3");
+    }
+
+    #[test]
+    fn drops_replayed_and_older_datagrams() {
+        let mut sender = endpoint(1, 10);
+        let mut receiver = endpoint(2, 0);
+        let mut buffer = [0u8; MAX_PAYLOAD];
+        let first = sealed(&mut sender, b"first");
+        let second = sealed(&mut sender, b"second");
+        assert_eq!(receiver.open(&second, &mut buffer), Some((1, 6)));
+        assert_eq!(receiver.open(&second, &mut buffer), None);
+        assert_eq!(receiver.open(&first, &mut buffer), None);
+        let third = sealed(&mut sender, b"third");
+        assert_eq!(receiver.open(&third, &mut buffer), Some((1, 5)));
+    }
+
+    #[test]
+    fn rewritten_counter_does_not_authenticate() {
+        let mut sender = endpoint(1, 10);
+        let mut receiver = endpoint(2, 0);
+        let mut buffer = [0u8; MAX_PAYLOAD];
+        let mut frame = sealed(&mut sender, b"vote 3");
+        frame[datagram::HEADER_SIZE + 4] = 11;
+        assert_eq!(receiver.open(&frame, &mut buffer), None);
+        assert_eq!(buffer, [0u8; MAX_PAYLOAD]);
+        // A failed datagram does not move the counter on
+        frame[datagram::HEADER_SIZE + 4] = 10;
+        assert_eq!(receiver.open(&frame, &mut buffer), Some((1, 6)));
+    }
+
+    #[test]
+    fn forgotten_sender_can_be_replayed() {
+        let mut receiver = endpoint(9, 0);
+        let mut buffer = [0u8; MAX_PAYLOAD];
+        let mut senders = [endpoint(1, 0), endpoint(2, 0), endpoint(3, 0)];
+        let replay = sealed(&mut senders[0], b"hello");
+        assert_eq!(receiver.open(&replay, &mut buffer), Some((1, 5)));
+        assert_eq!(receiver.open(&replay, &mut buffer), None);
+        for sender in senders.iter_mut().skip(1) {
+            let frame = sealed(sender, b"hello");
+            assert!(receiver.open(&frame, &mut buffer).is_some());
+        }
+        // Two other senders pushed the first out of the two peers
+        assert_eq!(receiver.open(&replay, &mut buffer), Some((1, 5)));
+    }
+
+    #[test]
+    fn ignores_own_and_other_group_datagrams() {
+        let mut sender = endpoint(1, 0);
+        let mut buffer = [0u8; MAX_PAYLOAD];
+        let frame = sealed(&mut sender, b"vote 3");
+        assert_eq!(sender.open(&frame, &mut buffer), None);
+        let mut other = Secure::<_, 2>::new(SoftCcm::new(SoftAes::new(&KEY)), 2, 6, 0);
+        assert_eq!(other.open(&frame, &mut buffer), None);
+    }
+}
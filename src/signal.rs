@@ -0,0 +1,215 @@
+//! Interrupt to task signalling
+//!
+//! A `Signal` passes a value from an interrupt handler to a future and wakes
+//! the task waiting for it.
+
+use core::cell::UnsafeCell;
+use core::future::Future;
+use core::pin::Pin;
+use core::task::{Context, Poll, Waker};
+
+/// Run `f` with interrupts disabled
+#[cfg(target_arch = "arm")]
+pub(crate) fn free<R>(f: impl FnOnce() -> R) -> R {
+    cortex_m::interrupt::free(|_| f())
+}
+
+/// Run `f` holding a global lock
+///
+/// On the host the crate runs on several threads, the tests in parallel
+/// and the tools, a spin lock stands in for disabling interrupts. `f` must
+/// not call `free` again.
+#[cfg(not(target_arch = "arm"))]
+pub(crate) fn free<R>(f: impl FnOnce() -> R) -> R {
+    use core::sync::atomic::{AtomicBool, Ordering};
+
+    static LOCKED: AtomicBool = AtomicBool::new(false);
+
+    /// Releases the lock, also when `f` panics
+    struct Guard;
+
+    impl Drop for Guard {
+        fn drop(&mut self) {
+            LOCKED.store(false, Ordering::Release);
+        }
+    }
+
+    while LOCKED.compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed)
+        .is_err()
+    {
+        core::hint::spin_loop();
+    }
+    let _guard = Guard;
+    f()
+}
+
+/// # Signal
+///
+/// Holds the latest signalled value until it is taken. Signalling again
+/// before the value has been taken replaces the value.
+pub struct Signal<T> {
+    value: UnsafeCell<Option<T>>,
+    waker: UnsafeCell<Option<Waker>>,
+}
+
+// All accesses are done with interrupts disabled, or the lock held on the
+// host, see `free`
+unsafe impl<T: Send> Sync for Signal<T> {}
+
+impl<T> Signal<T> {
+    pub const fn new() -> Self {
+        Signal {
+            value: UnsafeCell::new(None),
+            waker: UnsafeCell::new(None),
+        }
+    }
+
+    /// Store a value and wake the waiting task
+    pub fn signal(&self, value: T) {
+        let waker = free(|| unsafe {
+            *self.value.get() = Some(value);
+            (*self.waker.get()).take()
+        });
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    }
+
+    /// Take the value, if any
+    pub fn take(&self) -> Option<T> {
+        free(|| unsafe { (*self.value.get()).take() })
+    }
+
+    /// Is there a value waiting to be taken
+    pub fn is_signalled(&self) -> bool {
+        free(|| unsafe { (*self.value.get()).is_some() })
+    }
+
+    /// Take the value, or register the task to be woken by the next signal
+    pub fn poll_take(&self, cx: &mut Context<'_>) -> Poll<T> {
+        free(|| unsafe {
+            match (*self.value.get()).take() {
+                Some(value) => Poll::Ready(value),
+                None => {
+                    let waker = &mut *self.waker.get();
+                    match waker {
+                        Some(waker) if waker.will_wake(cx.waker()) => (),
+                        _ => *waker = Some(cx.waker().clone()),
+                    }
+                    Poll::Pending
+                }
+            }
+        })
+    }
+
+    /// Wait for the next value
+    pub fn wait(&self) -> Wait<'_, T> {
+        Wait { signal: self }
+    }
+}
+
+impl<T> Default for Signal<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Future returned by `Signal::wait`
+pub struct Wait<'a, T> {
+    signal: &'a Signal<T>,
+}
+
+impl<T> Future for Wait<'_, T> {
+    type Output = T;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<T> {
+        self.signal.poll_take(cx)
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::task::Wake;
+
+    /// Counts the times it is woken
+    pub(crate) struct Counter(AtomicUsize);
+
+    impl Counter {
+        pub(crate) fn count(&self) -> usize {
+            self.0.load(Ordering::SeqCst)
+        }
+    }
+
+    impl Wake for Counter {
+        fn wake(self: Arc<Self>) {
+            self.0.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    pub(crate) fn counting_waker() -> (Arc<Counter>, Waker) {
+        let counter = Arc::new(Counter(AtomicUsize::new(0)));
+        (counter.clone(), Waker::from(counter))
+    }
+
+    #[test]
+    fn wakes_waiting_task() {
+        let signal = Signal::new();
+        let (counter, waker) = counting_waker();
+        let mut cx = Context::from_waker(&waker);
+        assert!(signal.poll_take(&mut cx).is_pending());
+        assert_eq!(counter.count(), 0);
+        signal.signal(7);
+        assert_eq!(counter.count(), 1);
+        assert_eq!(signal.poll_take(&mut cx), Poll::Ready(7));
+        // The waker is taken by the signal, a second signal does not wake
+        signal.signal(8);
+        assert_eq!(counter.count(), 1);
+        assert_eq!(signal.take(), Some(8));
+    }
+
+    #[test]
+    fn latest_value_replaces_earlier() {
+        let signal = Signal::new();
+        signal.signal(1);
+        signal.signal(2);
+        assert!(signal.is_signalled());
+        assert_eq!(signal.take(), Some(2));
+        assert!(!signal.is_signalled());
+        assert_eq!(signal.take(), None);
+    }
+
+    #[test]
+    fn shared_between_threads() {
+        const VALUES: usize = 10_000;
+        static SIGNAL: Signal<usize> = Signal::new();
+        let taken = std::thread::scope(|scope| {
+            scope.spawn(|| (1..=VALUES).for_each(|value| SIGNAL.signal(value)));
+            let taker = scope.spawn(|| {
+                let mut latest = 0;
+                while latest < VALUES {
+                    if let Some(value) = SIGNAL.take() {
+                        assert!(value > latest);
+                        latest = value;
+                    }
+                }
+                latest
+            });
+            taker.join().unwrap()
+        });
+        assert_eq!(taken, VALUES);
+    }
+
+    #[test]
+    fn wait_future() {
+        let signal = Signal::new();
+        let (_, waker) = counting_waker();
+        let mut cx = Context::from_waker(&waker);
+        let mut wait = signal.wait();
+        assert!(Pin::new(&mut wait).poll(&mut cx).is_pending());
+        signal.signal("ready");
+        assert_eq!(Pin::new(&mut wait).poll(&mut cx), Poll::Ready("ready"));
+    }
+}
@@ -0,0 +1,302 @@
+//! Radio simulator
+//!
+//! Runs protocols on a simulated, lossy radio with a simulated clock, on
+//! the host or on the micro:bit itself. By default every node reaches every
+//! other node, `Simulator::connect` builds other topologies. Each datagram
+//! is lost with a configurable probability and received with the signal
+//! strength set by `Simulator::set_rssi`.
+//!
+//! ```notrust
+//! let mut sim = Simulator::<2>::new(0x1234);
+//! sim.set_loss(30);
+//! let mut endpoints = [Endpoint::<4>::new(1, 0, 1), Endpoint::<4>::new(2, 0, 2)];
+//! sim.send_reliable(&mut endpoints, 0, 2, b"vote 3")?;
+//! let message = endpoints[1].recv();
+//! ```
+//!
+//! `SimClock` drives `timer::Timer` futures on the host.
+
+use core::cell::Cell;
+
+use crate::link::Link;
+use crate::radio::PackageBuffer;
+use crate::random::Random;
+use crate::reliable::{Endpoint, Timeout};
+use crate::signal::Signal;
+use crate::time::{Duration, Instant, TimeSource};
+
+/// Number of datagrams a node can hold before it drops datagrams, enough
+/// for a fragmented message of 512 bytes
+pub const MAILBOX_LENGTH: usize = 32;
+/// Time advanced by `Simulator::step`
+pub const STEP: Duration = Duration::from_millis(1);
+/// Signal strength between nodes in dBm, unless set otherwise
+pub const DEFAULT_RSSI: i8 = -60;
+
+/// Datagrams received by a node with the simulated time they were sent and
+/// their signal strength, oldest first
+struct Mailbox {
+    frames: [(Instant, i8, PackageBuffer); MAILBOX_LENGTH],
+    head: usize,
+    length: usize,
+}
+
+impl Mailbox {
+    const fn new() -> Self {
+        Mailbox {
+            frames: [(Instant::from_ticks(0), 0, [0u8; crate::radio::MAX_PACKAGE_SIZE]);
+                MAILBOX_LENGTH],
+            head: 0,
+            length: 0,
+        }
+    }
+
+    fn push(&mut self, sent: Instant, rssi: i8, frame: &PackageBuffer) -> bool {
+        if self.length == MAILBOX_LENGTH {
+            return false;
+        }
+        self.frames[(self.head + self.length) % MAILBOX_LENGTH] = (sent, rssi, *frame);
+        self.length += 1;
+        true
+    }
+
+    fn pop(&mut self) -> Option<(Instant, i8, PackageBuffer)> {
+        if self.length == 0 {
+            return None;
+        }
+        let frame = self.frames[self.head];
+        self.head = (self.head + 1) % MAILBOX_LENGTH;
+        self.length -= 1;
+        Some(frame)
+    }
+}
+
+/// # Simulator
+///
+/// `NODES` nodes sharing a simulated radio channel. Nodes send and receive
+/// through the `Link` returned by `Simulator::link`, the time only moves on
+/// with `Simulator::step`.
+///
+/// Every node has its own clock, which can be offset from and run faster or
+/// slower than the simulated time, see `Simulator::set_clock`.
+pub struct Simulator<const NODES: usize> {
+    mailboxes: [Mailbox; NODES],
+    /// Which nodes are in range of each other
+    connected: [[bool; NODES]; NODES],
+    /// Signal strength between nodes in dBm
+    rssi: [[i8; NODES]; NODES],
+    /// Signal strength of the last datagram received by each node
+    received_rssi: [Option<i8>; NODES],
+    /// Clock offset in ticks and drift in parts per million of each node
+    clocks: [(u64, i32); NODES],
+    now: Instant,
+    loss: u8,
+    random: Random,
+    sent: u32,
+    lost: u32,
+}
+
+impl<const NODES: usize> Simulator<NODES> {
+    /// Create a simulator without loss, `seed` makes the losses repeatable
+    pub fn new(seed: u32) -> Self {
+        Simulator {
+            mailboxes: [const { Mailbox::new() }; NODES],
+            connected: [[true; NODES]; NODES],
+            rssi: [[DEFAULT_RSSI; NODES]; NODES],
+            received_rssi: [None; NODES],
+            clocks: [(0, 0); NODES],
+            now: Instant::from_ticks(0),
+            loss: 0,
+            random: Random::new(seed),
+            sent: 0,
+            lost: 0,
+        }
+    }
+
+    /// Lose `percent` of all datagrams
+    pub fn set_loss(&mut self, percent: u8) {
+        self.loss = percent.min(100);
+    }
+
+    /// Put nodes `a` and `b` in or out of range of each other
+    pub fn connect(&mut self, a: usize, b: usize, connected: bool) {
+        self.connected[a][b] = connected;
+        self.connected[b][a] = connected;
+    }
+
+    /// Put all nodes out of range of each other
+    pub fn disconnect_all(&mut self) {
+        self.connected = [[false; NODES]; NODES];
+    }
+
+    /// Are nodes `a` and `b` in range of each other
+    pub fn is_connected(&self, a: usize, b: usize) -> bool {
+        self.connected[a][b]
+    }
+
+    /// Set the signal strength between nodes `a` and `b` in dBm
+    pub fn set_rssi(&mut self, a: usize, b: usize, rssi: i8) {
+        self.rssi[a][b] = rssi;
+        self.rssi[b][a] = rssi;
+    }
+
+    /// Offset the clock of node `index` by `offset` and let it drift by
+    /// `ppm` parts per million
+    pub fn set_clock(&mut self, index: usize, offset: Duration, ppm: i32) {
+        self.clocks[index] = (offset.as_ticks(), ppm);
+    }
+
+    /// The time on the clock of node `index` at the simulated time `at`
+    pub fn local_time(&self, index: usize, at: Instant) -> Instant {
+        let (offset, ppm) = self.clocks[index];
+        let ticks = i128::from(at.as_ticks());
+        let drift = ticks * i128::from(ppm) / 1_000_000;
+        Instant::from_ticks((ticks + drift) as u64 + offset)
+    }
+
+    /// The simulated time
+    pub fn now(&self) -> Instant {
+        self.now
+    }
+
+    /// Advance the simulated time by `STEP`
+    pub fn step(&mut self) {
+        self.now += STEP;
+    }
+
+    /// Number of datagrams sent and lost, counted per receiver
+    pub fn statistics(&self) -> (u32, u32) {
+        (self.sent, self.lost)
+    }
+
+    /// The link of node `index`
+    pub fn link(&mut self, index: usize) -> SimLink<'_, NODES> {
+        assert!(index < NODES);
+        SimLink { sim: self, index }
+    }
+
+    /// Send `payload` from node `from` to the device with serial number
+    /// `destination`, polling all nodes until the datagram has been
+    /// acknowledged or has timed out
+    ///
+    /// This is `Endpoint::send_reliable` with the other nodes running.
+    pub fn send_reliable<const PEERS: usize>(&mut self, endpoints: &mut [Endpoint<PEERS>; NODES],
+        from: usize, destination: u32, payload: &[u8]) -> Result<(), Timeout>
+    {
+        let now = self.local_time(from, self.now);
+        assert!(endpoints[from].start_send(now, destination, payload));
+        loop {
+            for (index, endpoint) in endpoints.iter_mut().enumerate() {
+                let result = endpoint.poll(&mut self.link(index));
+                if index == from {
+                    if let Some(result) = result {
+                        return result;
+                    }
+                }
+            }
+            self.step();
+        }
+    }
+
+    fn is_lost(&mut self) -> bool {
+        self.random.below(100) < u64::from(self.loss)
+    }
+}
+
+/// # Simulated link
+///
+/// The `Link` of one simulator node.
+pub struct SimLink<'a, const NODES: usize> {
+    sim: &'a mut Simulator<NODES>,
+    index: usize,
+}
+
+impl<const NODES: usize> Link for SimLink<'_, NODES> {
+    fn transmit(&mut self, frame: &PackageBuffer) -> bool {
+        let own = self.index;
+        for index in 0..NODES {
+            if index == own || !self.sim.connected[own][index] {
+                continue;
+            }
+            self.sim.sent += 1;
+            let now = self.sim.now;
+            let rssi = self.sim.rssi[own][index];
+            if self.sim.is_lost() || !self.sim.mailboxes[index].push(now, rssi, frame) {
+                self.sim.lost += 1;
+            }
+        }
+        true
+    }
+
+    fn receive(&mut self) -> Option<PackageBuffer> {
+        self.receive_timestamped().map(|(frame, _)| frame)
+    }
+
+    fn now(&self) -> Instant {
+        self.sim.local_time(self.index, self.sim.now)
+    }
+
+    /// The timestamp is the local time at which the datagram was sent
+    fn receive_timestamped(&mut self) -> Option<(PackageBuffer, Instant)> {
+        let (sent, rssi, frame) = self.sim.mailboxes[self.index].pop()?;
+        self.sim.received_rssi[self.index] = Some(rssi);
+        Some((frame, self.sim.local_time(self.index, sent)))
+    }
+
+    fn rssi(&self) -> Option<i8> {
+        self.sim.received_rssi[self.index]
+    }
+}
+
+/// # Simulated clock
+///
+/// A `time::TimeSource` for `timer::Timer` futures. The time only moves on
+/// with `SimClock::advance`, which signals the alarm once it is reached.
+pub struct SimClock {
+    now: Cell<Instant>,
+    alarm_at: Cell<Option<Instant>>,
+    alarm: Signal<()>,
+}
+
+impl SimClock {
+    pub const fn new() -> Self {
+        SimClock {
+            now: Cell::new(Instant::from_ticks(0)),
+            alarm_at: Cell::new(None),
+            alarm: Signal::new(),
+        }
+    }
+
+    /// Advance the time by `duration`
+    pub fn advance(&self, duration: Duration) {
+        let now = self.now.get() + duration;
+        self.now.set(now);
+        if self.alarm_at.get().is_some_and(|at| at <= now) {
+            self.alarm_at.set(None);
+            self.alarm.signal(());
+        }
+    }
+}
+
+impl Default for SimClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl TimeSource for SimClock {
+    fn now(&self) -> Instant {
+        self.now.get()
+    }
+
+    fn set_alarm(&self, at: Instant) {
+        self.alarm_at.set(Some(at));
+        if at <= self.now.get() {
+            self.advance(Duration::from_ticks(0));
+        }
+    }
+
+    fn alarm(&self) -> &Signal<()> {
+        &self.alarm
+    }
+}
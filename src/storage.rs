@@ -0,0 +1,779 @@
+//! Key value storage in flash
+//!
+//! Values are kept in a log of records over a range of flash pages,
+//! reserved in `memory.x`. Setting a value appends a record, the latest
+//! record of a key holds its value. When the pages are full, the live
+//! records of the oldest page are copied to a spare page and the oldest page
+//! is erased, so the pages are erased in turn, which spreads the wear.
+//!
+//! Records are checked with a CRC written last, a write cut short by a
+//! power failure leaves a record that is ignored. The store is left as it
+//! was before the interrupted `set`, `remove` or garbage collection.
+//!
+//! The store works on a `Flash`, `Nvmc` on the micro:bit or `RamFlash`, which
+//! can fail the power and corrupt the contents to check the store on the
+//! host.
+//!
+//! ```notrust
+//! const KEY_GROUP: u16 = 1;
+//!
+//! let mut store = Store::new(Nvmc::new(p.NVMC));
+//! let mut buffer = [0u8; 1];
+//! if store.get(KEY_GROUP, &mut buffer) == Some(1) {
+//!     radio.set_group(buffer[0]);
+//! }
+//! store.set(KEY_GROUP, &[group])?;
+//! ```
+//!
+//! ## Reference
+//!
+//! * nRF51 Series Reference Manual 3.0, chapter 6
+
+use nrf51::NVMC;
+
+/// Size of a flash page of the nRF51
+pub const PAGE_SIZE: usize = 1024;
+/// Start of the pages reserved in `memory.x`
+pub const STORAGE_START: usize = 0x0003_f000;
+/// Number of pages reserved in `memory.x`
+pub const STORAGE_PAGES: usize = 4;
+/// Largest value in bytes
+pub const MAX_VALUE_SIZE: usize = 255;
+
+/// An erased word
+const ERASED: u32 = 0xffff_ffff;
+/// Magic of a page in use, cleared before the page is erased
+const PAGE_MAGIC: u32 = 0x5542_4b56;
+/// Size of the page header, the sequence number, the source page and the
+/// magic
+const PAGE_HEADER_SIZE: usize = 12;
+/// Size of the record header, the header word and the CRC
+const RECORD_HEADER_SIZE: usize = 8;
+/// Tag of a record with a value
+const TAG_VALUE: u8 = 0x5a;
+/// Tag of a record of a removed value
+const TAG_REMOVED: u8 = 0x3c;
+
+/// # Flash
+///
+/// Pages of flash, addressed in bytes from the first page. Words are read
+/// and written at word aligned offsets. Writes can only clear bits, erasing
+/// a page sets all of its bits.
+pub trait Flash {
+    /// Size of a page in bytes
+    fn page_size(&self) -> usize;
+    /// Number of pages
+    fn pages(&self) -> usize;
+    /// Read the word at `offset`
+    fn read(&self, offset: usize) -> u32;
+    /// Write the word at `offset`
+    fn write(&mut self, offset: usize, word: u32);
+    /// Erase `page`
+    fn erase(&mut self, page: usize);
+}
+
+/// # Flash controller
+///
+/// The pages reserved for storage in `memory.x`, `STORAGE_PAGES` pages from
+/// `STORAGE_START`. The CPU stalls while a word is written or a page erased.
+pub struct Nvmc {
+    nvmc: NVMC,
+}
+
+impl Nvmc {
+    pub fn new(nvmc: NVMC) -> Self {
+        Nvmc { nvmc }
+    }
+
+    pub fn free(self) -> NVMC {
+        self.nvmc
+    }
+
+    fn wait(&self) {
+        while self.nvmc.ready.read().ready().is_busy() {}
+    }
+}
+
+impl Flash for Nvmc {
+    fn page_size(&self) -> usize {
+        PAGE_SIZE
+    }
+
+    fn pages(&self) -> usize {
+        STORAGE_PAGES
+    }
+
+    fn read(&self, offset: usize) -> u32 {
+        assert!(offset < STORAGE_PAGES * PAGE_SIZE);
+        unsafe { core::ptr::read_volatile((STORAGE_START + offset) as *const u32) }
+    }
+
+    fn write(&mut self, offset: usize, word: u32) {
+        assert!(offset < STORAGE_PAGES * PAGE_SIZE);
+        self.nvmc.config.write(|w| w.wen().wen());
+        self.wait();
+        unsafe { core::ptr::write_volatile((STORAGE_START + offset) as *mut u32, word) };
+        self.wait();
+        self.nvmc.config.write(|w| w.wen().ren());
+    }
+
+    fn erase(&mut self, page: usize) {
+        assert!(page < STORAGE_PAGES);
+        self.nvmc.config.write(|w| w.wen().een());
+        self.wait();
+        let address = (STORAGE_START + page * PAGE_SIZE) as u32;
+        self.nvmc.erasepage.write(|w| unsafe { w.bits(address) });
+        self.wait();
+        self.nvmc.config.write(|w| w.wen().ren());
+    }
+}
+
+/// # Flash in RAM
+///
+/// `PAGES` pages of `PAGE_SIZE` bytes, which behave as flash. The power can
+/// be failed after a number of writes and erases, the write or erase that
+/// fails is only partly done and the ones after are dropped. Bits can be
+/// flipped to corrupt the contents.
+#[derive(Clone)]
+pub struct RamFlash<const PAGES: usize> {
+    pages: [[u32; PAGE_SIZE / 4]; PAGES],
+    /// Writes and erases left before the power fails
+    remaining: Option<u32>,
+    erases: [u32; PAGES],
+}
+
+impl<const PAGES: usize> RamFlash<PAGES> {
+    /// Create erased flash
+    pub const fn new() -> Self {
+        RamFlash { pages: [[ERASED; PAGE_SIZE / 4]; PAGES], remaining: None, erases: [0; PAGES] }
+    }
+
+    /// Fail the power after `operations` more writes and erases, `None`
+    /// restores the power
+    pub fn power_fail_after(&mut self, operations: Option<u32>) {
+        self.remaining = operations;
+    }
+
+    /// Has the power failed
+    pub fn power_failed(&self) -> bool {
+        self.remaining == Some(0)
+    }
+
+    /// Flip the bits of `mask` in the word at `offset`
+    pub fn corrupt(&mut self, offset: usize, mask: u32) {
+        self.pages[offset / PAGE_SIZE][offset % PAGE_SIZE / 4] ^= mask;
+    }
+
+    /// Number of times `page` was erased
+    pub fn erases(&self, page: usize) -> u32 {
+        self.erases[page]
+    }
+
+    /// Count an operation, returns `None` if the power has failed, or
+    /// `Some(true)` if the operation is cut short by the power failing
+    fn operation(&mut self) -> Option<bool> {
+        match self.remaining {
+            Some(0) => None,
+            Some(remaining) => {
+                self.remaining = Some(remaining - 1);
+                Some(remaining == 1)
+            }
+            None => Some(false),
+        }
+    }
+}
+
+impl<const PAGES: usize> Default for RamFlash<PAGES> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const PAGES: usize> Flash for RamFlash<PAGES> {
+    fn page_size(&self) -> usize {
+        PAGE_SIZE
+    }
+
+    fn pages(&self) -> usize {
+        PAGES
+    }
+
+    fn read(&self, offset: usize) -> u32 {
+        self.pages[offset / PAGE_SIZE][offset % PAGE_SIZE / 4]
+    }
+
+    fn write(&mut self, offset: usize, word: u32) {
+        let cut_short = match self.operation() {
+            Some(cut_short) => cut_short,
+            None => return,
+        };
+        // Only the low half word is written when the power fails
+        let word = if cut_short { word | 0xffff_0000 } else { word };
+        self.pages[offset / PAGE_SIZE][offset % PAGE_SIZE / 4] &= word;
+    }
+
+    fn erase(&mut self, page: usize) {
+        let cut_short = match self.operation() {
+            Some(cut_short) => cut_short,
+            None => return,
+        };
+        // Only the first half of the page is erased when the power fails
+        let words = if cut_short { PAGE_SIZE / 8 } else { PAGE_SIZE / 4 };
+        self.pages[page][..words].iter_mut().for_each(|word| *word = ERASED);
+        self.erases[page] += 1;
+    }
+}
+
+/// Storage errors
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StorageError {
+    /// The value is larger than `MAX_VALUE_SIZE`
+    TooLarge,
+    /// The live values fill the pages
+    Full,
+}
+
+/// A record in the log
+#[derive(Clone, Copy)]
+struct Record {
+    /// Offset of the record header
+    offset: usize,
+    key: u16,
+    length: usize,
+    tag: u8,
+    /// The CRC matches
+    valid: bool,
+}
+
+impl Record {
+    fn size(&self) -> usize {
+        record_size(self.length)
+    }
+}
+
+/// Size of a record with a value of `length` bytes
+fn record_size(length: usize) -> usize {
+    RECORD_HEADER_SIZE + length.div_ceil(4) * 4
+}
+
+/// CRC-32 of `data`, continuing from `crc`, start with 0
+fn crc32(crc: u32, data: &[u8]) -> u32 {
+    let mut crc = !crc;
+    for byte in data {
+        crc ^= u32::from(*byte);
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xedb8_8320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+/// # Key value store
+///
+/// Keeps values of up to `MAX_VALUE_SIZE` bytes by 16-bit key. One page is
+/// kept spare for garbage collection, so a flash of `n` pages holds up to
+/// `n - 1` pages of records. Needs at least 2 pages.
+pub struct Store<F: Flash> {
+    flash: F,
+    /// Page records are appended to, if any
+    head: Option<usize>,
+    /// Offset of the next record in the head page
+    position: usize,
+    /// Sequence number of the head page
+    sequence: u32,
+}
+
+impl<F: Flash> Store<F> {
+    /// Open the store in `flash`, undoing a garbage collection cut short
+    pub fn new(flash: F) -> Self {
+        assert!(flash.pages() >= 2 && flash.page_size().is_multiple_of(4));
+        let mut store = Store { flash, head: None, position: 0, sequence: 0 };
+        store.mount();
+        store
+    }
+
+    /// The flash
+    pub fn free(self) -> F {
+        self.flash
+    }
+
+    /// Copy the value of `key` to `buffer`, returns its length, or `None`
+    /// if there is no value or it does not fit `buffer`
+    pub fn get(&self, key: u16, buffer: &mut [u8]) -> Option<usize> {
+        let record = self.latest(key)?;
+        if record.tag != TAG_VALUE || record.length > buffer.len() {
+            return None;
+        }
+        self.read_value(&record, &mut buffer[..record.length]);
+        Some(record.length)
+    }
+
+    /// Is there a value for `key`
+    pub fn contains(&self, key: u16) -> bool {
+        self.latest(key).is_some_and(|record| record.tag == TAG_VALUE)
+    }
+
+    /// Set the value of `key`, nothing is written if the value is unchanged
+    pub fn set(&mut self, key: u16, value: &[u8]) -> Result<(), StorageError> {
+        if value.len() > MAX_VALUE_SIZE {
+            return Err(StorageError::TooLarge);
+        }
+        if let Some(record) = self.latest(key) {
+            let mut current = [0u8; MAX_VALUE_SIZE];
+            if record.tag == TAG_VALUE && record.length == value.len() {
+                self.read_value(&record, &mut current[..record.length]);
+                if current[..record.length] == *value {
+                    return Ok(());
+                }
+            }
+        }
+        self.append(key, TAG_VALUE, value)
+    }
+
+    /// Remove the value of `key`
+    pub fn remove(&mut self, key: u16) -> Result<(), StorageError> {
+        if !self.contains(key) {
+            return Ok(());
+        }
+        self.append(key, TAG_REMOVED, &[])
+    }
+
+    /// Erase all values
+    pub fn clear(&mut self) {
+        for page in 0..self.flash.pages() {
+            self.flash.erase(page);
+        }
+        self.head = None;
+        self.position = 0;
+        self.sequence = 0;
+    }
+
+    /// Find the head page and the end of its records, a head page that was
+    /// being filled by a garbage collection cut short is erased
+    fn mount(&mut self) {
+        loop {
+            self.head = None;
+            let mut page = None;
+            while let Some(next) = self.next_page(page) {
+                page = Some(next);
+            }
+            let head = match page {
+                Some(head) => head,
+                None => return,
+            };
+            let source = self.flash.read(self.page_offset(head) + 4) as usize;
+            if source < self.flash.pages()
+                && self.sequence_of(source)
+                    .is_some_and(|sequence| sequence < self.sequence_of(head).unwrap_or(0))
+            {
+                // The source page is still there, the copies may be partial
+                self.release(head);
+                continue;
+            }
+            self.head = Some(head);
+            self.sequence = self.sequence_of(head).unwrap_or(0);
+            let end = self.page_offset(head) + self.flash.page_size();
+            let mut offset = self.page_offset(head) + PAGE_HEADER_SIZE;
+            while let Some(record) = self.record(head, offset) {
+                offset += record.size();
+            }
+            // Words left partly written are not written again
+            self.position = if (offset..end).step_by(4).all(|at| self.flash.read(at) == ERASED) {
+                offset
+            }
+            else {
+                end
+            };
+            return;
+        }
+    }
+
+    fn page_offset(&self, page: usize) -> usize {
+        page * self.flash.page_size()
+    }
+
+    /// Sequence number of `page`, `None` if it is not in use
+    fn sequence_of(&self, page: usize) -> Option<u32> {
+        let offset = self.page_offset(page);
+        if self.flash.read(offset + 8) == PAGE_MAGIC {
+            Some(self.flash.read(offset))
+        }
+        else {
+            None
+        }
+    }
+
+    /// The page in use following the page `after`, or the oldest page
+    fn next_page(&self, after: Option<usize>) -> Option<usize> {
+        let after = after.and_then(|page| self.sequence_of(page));
+        (0..self.flash.pages())
+            .filter_map(|page| self.sequence_of(page).map(|sequence| (sequence, page)))
+            .filter(|(sequence, _)| after.is_none_or(|after| *sequence > after))
+            .min()
+            .map(|(_, page)| page)
+    }
+
+    /// The record at `offset` in `page`, `None` at the end of the records
+    fn record(&self, page: usize, offset: usize) -> Option<Record> {
+        let page_end = self.page_offset(page + 1);
+        if offset + RECORD_HEADER_SIZE > page_end {
+            return None;
+        }
+        let header = self.flash.read(offset);
+        let tag = (header >> 24) as u8;
+        let length = ((header >> 16) & 0xff) as usize;
+        if (tag != TAG_VALUE && tag != TAG_REMOVED) || offset + record_size(length) > page_end {
+            return None;
+        }
+        let mut record = Record { offset, key: header as u16, length, tag, valid: false };
+        let mut value = [0u8; MAX_VALUE_SIZE];
+        self.read_value(&record, &mut value[..length]);
+        let crc = crc32(crc32(0, &header.to_le_bytes()), &value[..length]);
+        record.valid = self.flash.read(offset + 4) == crc;
+        Some(record)
+    }
+
+    fn read_value(&self, record: &Record, buffer: &mut [u8]) {
+        let start = record.offset + RECORD_HEADER_SIZE;
+        for (index, chunk) in buffer.chunks_mut(4).enumerate() {
+            let word = self.flash.read(start + 4 * index).to_le_bytes();
+            chunk.copy_from_slice(&word[..chunk.len()]);
+        }
+    }
+
+    /// The records of `page`, in the order they were written
+    fn records(&self, page: usize) -> impl Iterator<Item = Record> + '_ {
+        let mut offset = self.page_offset(page) + PAGE_HEADER_SIZE;
+        core::iter::from_fn(move || {
+            let record = self.record(page, offset)?;
+            offset += record.size();
+            Some(record)
+        })
+    }
+
+    /// The latest valid record of `key`
+    fn latest(&self, key: u16) -> Option<Record> {
+        let mut latest = None;
+        let mut page = None;
+        while let Some(next) = self.next_page(page) {
+            page = Some(next);
+            if let Some(record) = self.records(next)
+                .filter(|record| record.valid && record.key == key)
+                .last()
+            {
+                latest = Some(record);
+            }
+        }
+        latest
+    }
+
+    /// Number of pages not in use
+    fn free_pages(&self) -> usize {
+        (0..self.flash.pages()).filter(|page| self.sequence_of(*page).is_none()).count()
+    }
+
+    /// Take a page not in use as the head page, `source` is the page copied
+    /// to it by a garbage collection
+    fn open(&mut self, source: Option<usize>) -> bool {
+        let page = match (0..self.flash.pages()).find(|page| self.sequence_of(*page).is_none()) {
+            Some(page) => page,
+            None => return false,
+        };
+        let offset = self.page_offset(page);
+        let size = self.flash.page_size();
+        if !(offset..offset + size).step_by(4).all(|at| self.flash.read(at) == ERASED) {
+            self.flash.erase(page);
+        }
+        let sequence = if self.head.is_some() { self.sequence.wrapping_add(1) } else { 0 };
+        // The magic last, the page is in use once it is written
+        self.flash.write(offset, sequence);
+        self.flash.write(offset + 4, source.map_or(ERASED, |source| source as u32));
+        self.flash.write(offset + 8, PAGE_MAGIC);
+        self.head = Some(page);
+        self.position = offset + PAGE_HEADER_SIZE;
+        self.sequence = sequence;
+        true
+    }
+
+    /// Take `page` out of use and erase it
+    fn release(&mut self, page: usize) {
+        let offset = self.page_offset(page);
+        self.flash.write(offset + 8, 0);
+        self.flash.erase(page);
+    }
+
+    /// Copy the live records of the oldest page to a new head page and
+    /// erase the oldest page
+    fn collect(&mut self) -> bool {
+        let oldest = match self.next_page(None) {
+            Some(oldest) => oldest,
+            None => return false,
+        };
+        if !self.open(Some(oldest)) {
+            return false;
+        }
+        let mut offset = self.page_offset(oldest) + PAGE_HEADER_SIZE;
+        while let Some(record) = self.record(oldest, offset) {
+            offset += record.size();
+            // Removed values need no record, older records are in this page
+            let live = record.valid && record.tag == TAG_VALUE
+                && self.latest(record.key).is_some_and(|latest| latest.offset == record.offset);
+            if live {
+                for at in (0..record.size()).step_by(4) {
+                    let word = self.flash.read(record.offset + at);
+                    self.flash.write(self.position + at, word);
+                }
+                self.position += record.size();
+            }
+        }
+        self.release(oldest);
+        true
+    }
+
+    /// Append a record, making room if needed
+    fn append(&mut self, key: u16, tag: u8, value: &[u8]) -> Result<(), StorageError> {
+        let size = record_size(value.len());
+        if PAGE_HEADER_SIZE + size > self.flash.page_size() {
+            return Err(StorageError::TooLarge);
+        }
+        let mut collections = 0;
+        loop {
+            if let Some(head) = self.head {
+                if self.position + size <= self.page_offset(head) + self.flash.page_size() {
+                    break;
+                }
+            }
+            // One page is kept for garbage collection
+            if self.free_pages() >= 2 {
+                self.open(None);
+                continue;
+            }
+            if collections == self.flash.pages() || !self.collect() {
+                return Err(StorageError::Full);
+            }
+            collections += 1;
+        }
+        let header = u32::from(tag) << 24 | (value.len() as u32) << 16 | u32::from(key);
+        let crc = crc32(crc32(0, &header.to_le_bytes()), value);
+        // The CRC last, the record is valid once it is written
+        self.flash.write(self.position, header);
+        for (index, chunk) in value.chunks(4).enumerate() {
+            let mut word = [0xff; 4];
+            word[..chunk.len()].copy_from_slice(chunk);
+            self.flash.write(self.position + RECORD_HEADER_SIZE + 4 * index,
+                u32::from_le_bytes(word));
+        }
+        self.flash.write(self.position + 4, crc);
+        self.position += size;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    use crate::random::Random;
+
+    const KEYS: u16 = 12;
+
+    /// Values expected in the store
+    type Model = HashMap<u16, Vec<u8>>;
+
+    /// A value of up to 60 bytes, different on each call
+    fn value(random: &mut Random) -> Vec<u8> {
+        let length = random.below(60) as usize;
+        let first = random.next_u32() as u8;
+        (0..length).map(|index| first.wrapping_add(index as u8)).collect()
+    }
+
+    fn get<F: Flash>(store: &Store<F>, key: u16) -> Option<Vec<u8>> {
+        let mut buffer = [0u8; MAX_VALUE_SIZE];
+        store.get(key, &mut buffer).map(|length| buffer[..length].to_vec())
+    }
+
+    fn assert_model<F: Flash>(store: &Store<F>, model: &Model) {
+        for key in 0..KEYS {
+            assert_eq!(get(store, key), model.get(&key).cloned(), "key {}", key);
+        }
+    }
+
+    fn erases<const PAGES: usize>(flash: &RamFlash<PAGES>) -> u32 {
+        (0..PAGES).map(|page| flash.erases(page)).sum()
+    }
+
+    /// Run `set` on `flash` with the power failing after each operation in
+    /// turn, until it completes, and check the store after it is mounted
+    /// again
+    ///
+    /// Returns the number of writes and erases of the `set`.
+    fn cut_short(flash: &RamFlash<4>, model: &Model, key: u16, value: &[u8]) -> u32 {
+        let mut operations = 1;
+        loop {
+            let mut flash = flash.clone();
+            flash.power_fail_after(Some(operations));
+            let mut store = Store::new(flash);
+            let result = store.set(key, value);
+            let mut flash = store.free();
+            if !flash.power_failed() {
+                assert_eq!(result, Ok(()));
+                return operations - 1;
+            }
+            flash.power_fail_after(None);
+            let mut store = Store::new(flash);
+            let mut model = model.clone();
+            // Either the old or the new value
+            if get(&store, key).as_deref() == Some(value) {
+                model.insert(key, value.to_vec());
+            }
+            assert_model(&store, &model);
+            // The store keeps working
+            for key in 0..KEYS {
+                store.set(key, &[key as u8; 40]).unwrap();
+                model.insert(key, vec![key as u8; 40]);
+            }
+            assert_model(&Store::new(store.free()), &model);
+            operations += 1;
+        }
+    }
+
+    #[test]
+    fn keeps_values_across_mounts() {
+        let mut random = Random::new(7);
+        let mut store = Store::new(RamFlash::<4>::new());
+        let mut model = Model::new();
+        for _ in 0..400 {
+            let key = random.below(u64::from(KEYS)) as u16;
+            if random.below(7) == 0 {
+                store.remove(key).unwrap();
+                model.remove(&key);
+            }
+            else {
+                let value = value(&mut random);
+                store.set(key, &value).unwrap();
+                model.insert(key, value);
+            }
+        }
+        assert_model(&store, &model);
+        assert_model(&Store::new(store.free()), &model);
+    }
+
+    #[test]
+    fn unchanged_value_is_not_written() {
+        let mut store = Store::new(RamFlash::<2>::new());
+        store.set(1, b"value").unwrap();
+        let position = store.position;
+        store.set(1, b"value").unwrap();
+        assert_eq!(store.position, position);
+        store.remove(2).unwrap();
+        assert_eq!(store.position, position);
+    }
+
+    #[test]
+    fn refuses_values_when_full() {
+        let mut store = Store::new(RamFlash::<2>::new());
+        assert_eq!(store.set(0, &[0; MAX_VALUE_SIZE + 1]), Err(StorageError::TooLarge));
+        let mut keys = 0;
+        while store.set(keys, &[keys as u8; 100]).is_ok() {
+            keys += 1;
+        }
+        assert_eq!(store.set(keys, &[0; 100]), Err(StorageError::Full));
+        for key in 0..keys {
+            assert_eq!(get(&store, key), Some(vec![key as u8; 100]));
+        }
+        store.remove(0).unwrap();
+        assert_eq!(store.set(keys, &[0; 100]), Ok(()));
+    }
+
+    #[test]
+    fn cut_short_set_keeps_a_value() {
+        let mut random = Random::new(3);
+        let mut store = Store::new(RamFlash::<4>::new());
+        let mut model = Model::new();
+        for key in 0..KEYS {
+            let value = value(&mut random);
+            store.set(key, &value).unwrap();
+            model.insert(key, value);
+        }
+        let flash = store.free();
+        // The header, 15 words of value and the CRC
+        assert_eq!(cut_short(&flash, &model, 3, &[0x5a; 60]), 17);
+        let mut store = Store::new(flash);
+        store.remove(3).unwrap();
+        model.remove(&3);
+        assert_eq!(cut_short(&store.free(), &model, 3, b"back"), 3);
+    }
+
+    #[test]
+    fn cut_short_collection_keeps_all_values() {
+        let mut random = Random::new(11);
+        let mut store = Store::new(RamFlash::<4>::new());
+        let mut model = Model::new();
+        // Values in the oldest page that are copied by the collection
+        for key in 0..4 {
+            store.set(key, &[key as u8; 20]).unwrap();
+            model.insert(key, vec![key as u8; 20]);
+        }
+        // Set values up to the one that collects the oldest page
+        let (key, value) = loop {
+            let key = 4 + random.below(u64::from(KEYS) - 4) as u16;
+            let value = value(&mut random);
+            let mut trial = Store::new(store.flash.clone());
+            trial.set(key, &value).unwrap();
+            if erases(&trial.free()) > erases(&store.flash) {
+                break (key, value);
+            }
+            store.set(key, &value).unwrap();
+            model.insert(key, value);
+        };
+        let flash = store.free();
+        let operations = cut_short(&flash, &model, key, &value);
+        // Opening the new page, copying the live records, releasing the
+        // oldest page and writing the record were all cut short
+        let kept = 4 * (record_size(20) / 4);
+        let record = record_size(value.len()) / 4;
+        assert!(operations as usize >= 3 + kept + 2 + record, "{} operations", operations);
+    }
+
+    #[test]
+    fn damaged_records_are_ignored() {
+        let mut store = Store::new(RamFlash::<2>::new());
+        store.set(1, b"old").unwrap();
+        store.set(1, b"new").unwrap();
+        store.set(2, b"only").unwrap();
+        let mut flash = store.free();
+        // The value of the second record, after the page header and the
+        // first record
+        let second = PAGE_HEADER_SIZE + record_size(3);
+        flash.corrupt(second + RECORD_HEADER_SIZE, 1);
+        // The CRC of the third record
+        flash.corrupt(second + record_size(3) + 4, 0x100);
+        let mut store = Store::new(flash);
+        assert_eq!(get(&store, 1).as_deref(), Some(&b"old"[..]));
+        assert_eq!(get(&store, 2), None);
+        assert!(!store.contains(2));
+        // Records after the damaged ones are found
+        store.set(2, b"again").unwrap();
+        let store = Store::new(store.free());
+        assert_eq!(get(&store, 2).as_deref(), Some(&b"again"[..]));
+    }
+
+    #[test]
+    fn spreads_erases_over_the_pages() {
+        let mut store = Store::new(RamFlash::<4>::new());
+        for index in 0..2000u32 {
+            let key = (index % 8) as u16;
+            store.set(key, &index.to_le_bytes().repeat(10)).unwrap();
+        }
+        let erases: Vec<u32> = (0..4).map(|page| store.flash.erases(page)).collect();
+        let least = *erases.iter().min().unwrap();
+        let most = *erases.iter().max().unwrap();
+        assert!(least > 10, "{:?}", erases);
+        assert!(most - least <= 1, "{:?}", erases);
+        for key in 0..8u32 {
+            let index = 1992 + key;
+            assert_eq!(get(&store, key as u16), Some(index.to_le_bytes().repeat(10)));
+        }
+    }
+}
@@ -0,0 +1,337 @@
+//! Telemetry from named values
+//!
+//! MakeCode programs broadcast named values with `radio.sendValue("temp",
+//! 21)`. An `Aggregator` keeps a table of the values heard, one entry per
+//! board and name, with the latest value, the lowest, the highest and the
+//! mean, the number of samples and the time the value was last received.
+//! An entry is removed when the value has not been received for the
+//! timeout.
+//!
+//! The table can be written to the UART as CSV, with `Aggregator::csv`.
+//!
+//! ```notrust
+//! let mut aggregator = Aggregator::<32>::new(telemetry::DEFAULT_TIMEOUT);
+//! loop {
+//!     aggregator.poll(&mut radio);
+//!     if report_due {
+//!         aggregator.expire(time::now());
+//!         write!(tx, "{}", aggregator.csv());
+//!     }
+//! }
+//! ```
+
+use core::fmt;
+
+use crate::link::Link;
+use crate::package::{PackageRef, PAYLOAD_OFFSET};
+use crate::radio::MAX_PACKAGE_SIZE;
+use crate::time::{Duration, Instant};
+
+/// Longest name, what fits an integer value package
+pub const MAX_NAME_LENGTH: usize = MAX_PACKAGE_SIZE - PAYLOAD_OFFSET - 5;
+/// Default time after which an entry not received is removed
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// A named value from a board
+#[derive(Clone, Copy, Debug)]
+pub struct Entry {
+    serial_number: u32,
+    name: [u8; MAX_NAME_LENGTH],
+    name_length: u8,
+    latest: f64,
+    min: f64,
+    max: f64,
+    sum: f64,
+    count: u32,
+    last_seen: Instant,
+}
+
+impl Entry {
+    /// Serial number of the board
+    pub fn serial_number(&self) -> u32 {
+        self.serial_number
+    }
+    /// Name of the value
+    pub fn name(&self) -> &str {
+        core::str::from_utf8(&self.name[..usize::from(self.name_length)]).unwrap_or("")
+    }
+    /// The latest value
+    pub fn latest(&self) -> f64 {
+        self.latest
+    }
+    /// The lowest value
+    pub fn min(&self) -> f64 {
+        self.min
+    }
+    /// The highest value
+    pub fn max(&self) -> f64 {
+        self.max
+    }
+    /// The mean of the values
+    pub fn mean(&self) -> f64 {
+        self.sum / f64::from(self.count)
+    }
+    /// Number of values received
+    pub fn count(&self) -> u32 {
+        self.count
+    }
+    /// Time the latest value was received
+    pub fn last_seen(&self) -> Instant {
+        self.last_seen
+    }
+
+    fn is(&self, serial_number: u32, name: &str) -> bool {
+        self.serial_number == serial_number && self.name() == name
+    }
+
+    fn update(&mut self, now: Instant, value: f64) {
+        self.latest = value;
+        self.min = self.min.min(value);
+        self.max = self.max.max(value);
+        self.sum += value;
+        self.count = self.count.saturating_add(1);
+        self.last_seen = now;
+    }
+}
+
+/// # Aggregator
+///
+/// Keeps up to `ENTRIES` named values, received as integer value or double
+/// value packages. Values heard while the table is full are ignored until
+/// an entry expires.
+pub struct Aggregator<const ENTRIES: usize> {
+    entries: [Option<Entry>; ENTRIES],
+    timeout: Duration,
+    ignored: u32,
+}
+
+impl<const ENTRIES: usize> Aggregator<ENTRIES> {
+    /// Create an aggregator removing entries not received for `timeout`
+    pub fn new(timeout: Duration) -> Self {
+        Aggregator { entries: [None; ENTRIES], timeout, ignored: 0 }
+    }
+
+    /// The entries, in table order
+    pub fn entries(&self) -> impl Iterator<Item = &Entry> {
+        self.entries.iter().flatten()
+    }
+
+    /// Number of entries
+    pub fn count(&self) -> usize {
+        self.entries().count()
+    }
+
+    /// The entry of `name` from the board with `serial_number`, if present
+    pub fn entry(&self, serial_number: u32, name: &str) -> Option<&Entry> {
+        self.entries().find(|entry| entry.is(serial_number, name))
+    }
+
+    /// Number of values ignored as the table was full
+    pub fn ignored(&self) -> u32 {
+        self.ignored
+    }
+
+    /// Remove all entries
+    pub fn clear(&mut self) {
+        self.entries = [None; ENTRIES];
+    }
+
+    /// Handle the datagrams received on `link`, returns the number of
+    /// values added
+    pub fn poll<L: Link>(&mut self, link: &mut L) -> usize {
+        let mut added = 0;
+        while let Some(frame) = link.receive() {
+            if self.handle(link.now(), &frame) {
+                added += 1;
+            }
+        }
+        added
+    }
+
+    /// Handle a received frame, starting with the length
+    ///
+    /// Returns `true` if the frame is a named value that was added.
+    pub fn handle(&mut self, now: Instant, frame: &[u8]) -> bool {
+        let package = match PackageRef::new(frame) {
+            Some(package) => package,
+            None => return false,
+        };
+        let name = match package.name() {
+            Some(name) if name.len() <= MAX_NAME_LENGTH => name,
+            _ => return false,
+        };
+        let value = match (package.as_i32(), package.as_f64()) {
+            (Some(value), _) => f64::from(value),
+            (None, Some(value)) => value,
+            _ => return false,
+        };
+        let serial_number = package.serial_number();
+        if let Some(entry) = self.entries.iter_mut().flatten()
+            .find(|entry| entry.is(serial_number, name))
+        {
+            entry.update(now, value);
+            return true;
+        }
+        let slot = match self.entries.iter().position(|entry| entry.is_none()) {
+            Some(slot) => slot,
+            None => {
+                self.ignored = self.ignored.wrapping_add(1);
+                return false;
+            }
+        };
+        let mut entry = Entry {
+            serial_number,
+            name: [0u8; MAX_NAME_LENGTH],
+            name_length: name.len() as u8,
+            latest: value,
+            min: value,
+            max: value,
+            sum: 0.0,
+            count: 0,
+            last_seen: now,
+        };
+        entry.name[..name.len()].copy_from_slice(name.as_bytes());
+        entry.update(now, value);
+        self.entries[slot] = Some(entry);
+        true
+    }
+
+    /// Remove the entries not received for the timeout, returns the number
+    /// of entries removed
+    pub fn expire(&mut self, now: Instant) -> usize {
+        let mut removed = 0;
+        for slot in self.entries.iter_mut() {
+            if matches!(slot, Some(entry) if now - entry.last_seen > self.timeout) {
+                *slot = None;
+                removed += 1;
+            }
+        }
+        removed
+    }
+
+    /// The entries as CSV, to be written with `core::fmt::Write`
+    pub fn csv(&self) -> Csv<'_, ENTRIES> {
+        Csv(self)
+    }
+}
+
+/// # CSV table
+///
+/// A header line followed by a line per entry, the serial number, the name,
+/// the latest, lowest, highest and mean value, the number of values and the
+/// time the latest value was received in milliseconds. Names are quoted,
+/// with quotes in the name doubled, as they may hold commas.
+///
+/// ```notrust
+/// serial,name,latest,min,max,mean,count,last_seen
+/// 1f2e3d4c,"temp",21,19,23,21.25,8,53210
+/// ```
+pub struct Csv<'a, const ENTRIES: usize>(&'a Aggregator<ENTRIES>);
+
+impl<const ENTRIES: usize> fmt::Display for Csv<'_, ENTRIES> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "serial,name,latest,min,max,mean,count,last_seen")?;
+        for entry in self.0.entries() {
+            writeln!(f, "{:08x},{},{},{},{},{:.2},{},{}", entry.serial_number,
+                Quoted(entry.name()), entry.latest, entry.min, entry.max, entry.mean(),
+                entry.count, entry.last_seen.as_millis())?;
+        }
+        Ok(())
+    }
+}
+
+/// A CSV field in quotes, quotes in the field are doubled
+struct Quoted<'a>(&'a str);
+
+impl fmt::Display for Quoted<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("\"")?;
+        for (index, part) in self.0.split('"').enumerate() {
+            if index > 0 {
+                f.write_str("\"\"")?;
+            }
+            f.write_str(part)?;
+        }
+        f.write_str("\"")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A named integer value package from `serial_number`
+    fn value(serial_number: u32, name: &str, value: i32) -> [u8; MAX_PACKAGE_SIZE] {
+        let mut frame = [0u8; MAX_PACKAGE_SIZE];
+        let length = PAYLOAD_OFFSET + 5 + name.len();
+        frame[..5].copy_from_slice(&[length as u8 - 1, 1, 0, 1, 1]);
+        frame[9..13].copy_from_slice(&serial_number.to_le_bytes());
+        frame[13..17].copy_from_slice(&value.to_le_bytes());
+        frame[17] = name.len() as u8;
+        frame[18..length].copy_from_slice(name.as_bytes());
+        frame
+    }
+
+    fn at(millis: u64) -> Instant {
+        Instant::from_ticks(0) + Duration::from_millis(millis)
+    }
+
+    #[test]
+    fn keeps_an_entry_per_board_and_name() {
+        let mut aggregator = Aggregator::<4>::new(DEFAULT_TIMEOUT);
+        assert!(aggregator.handle(at(0), &value(1, "temp", 20)));
+        assert!(aggregator.handle(at(10), &value(1, "temp", 24)));
+        assert!(aggregator.handle(at(20), &value(1, "light", 100)));
+        assert!(aggregator.handle(at(30), &value(2, "temp", 18)));
+        assert!(aggregator.handle(at(40), &value(1, "temp", 19)));
+        assert_eq!(aggregator.count(), 3);
+        let entry = aggregator.entry(1, "temp").unwrap();
+        assert_eq!((entry.latest(), entry.min(), entry.max()), (19.0, 19.0, 24.0));
+        assert_eq!((entry.mean(), entry.count()), (21.0, 3));
+        assert_eq!(entry.last_seen(), at(40));
+        assert_eq!(aggregator.entry(2, "temp").unwrap().latest(), 18.0);
+        assert!(aggregator.entry(2, "light").is_none());
+    }
+
+    #[test]
+    fn ignores_values_while_full() {
+        let mut aggregator = Aggregator::<2>::new(DEFAULT_TIMEOUT);
+        assert!(aggregator.handle(at(0), &value(1, "a", 1)));
+        assert!(aggregator.handle(at(0), &value(1, "b", 1)));
+        assert!(!aggregator.handle(at(0), &value(1, "c", 1)));
+        assert!(aggregator.handle(at(0), &value(1, "a", 2)));
+        assert_eq!(aggregator.ignored(), 1);
+        // Not a named value
+        let mut frame = value(1, "a", 1);
+        frame[4] = 0;
+        assert!(!aggregator.handle(at(0), &frame));
+        assert_eq!(aggregator.ignored(), 1);
+    }
+
+    #[test]
+    fn expires_entries_not_received() {
+        let timeout = Duration::from_secs(10);
+        let mut aggregator = Aggregator::<4>::new(timeout);
+        aggregator.handle(at(0), &value(1, "a", 1));
+        aggregator.handle(at(5_000), &value(1, "b", 1));
+        assert_eq!(aggregator.expire(at(10_000)), 0);
+        assert_eq!(aggregator.expire(at(10_001)), 1);
+        assert!(aggregator.entry(1, "a").is_none());
+        assert!(aggregator.entry(1, "b").is_some());
+        // The slot is free again
+        aggregator.handle(at(12_000), &value(2, "a", 1));
+        assert_eq!(aggregator.expire(at(20_000)), 1);
+        assert_eq!(aggregator.count(), 1);
+    }
+
+    #[test]
+    fn writes_csv() {
+        let mut aggregator = Aggregator::<4>::new(DEFAULT_TIMEOUT);
+        aggregator.handle(at(1_000), &value(0x1f2e_3d4c, "temp", 19));
+        aggregator.handle(at(2_000), &value(0x1f2e_3d4c, "temp", 24));
+        aggregator.handle(at(3_000), &value(0x0000_00ab, "a,\"b\"\nc", -1));
+        assert_eq!(aggregator.csv().to_string(), "serial,name,latest,min,max,mean,count,last_seen\n\
+            1f2e3d4c,\"temp\",24,19,24,21.50,2,2000\n\
+            000000ab,\"a,\"\"b\"\"\nc\",-1,-1,-1,-1.00,1,3000\n");
+    }
+}
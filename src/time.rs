@@ -0,0 +1,476 @@
+//! Monotonic system time
+//!
+//! RTC1 runs at 32.768 kHz and is extended to 64 bits by counting half
+//! periods of the 24-bit counter. The counter is read without disabling
+//! interrupts.
+//!
+//! ## Reference
+//!
+//! * <https://github.com/embassy-rs/embassy/blob/main/embassy-nrf/src/time_driver.rs>
+
+use core::ops::{Add, AddAssign, Sub};
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use nrf51::{Interrupt, NVIC, RTC1};
+
+use crate::signal::Signal;
+
+/// RTC ticks per second
+pub const TICKS_PER_SECOND: u64 = 32_768;
+
+/// Compare channel used by `timer::Timer` futures
+const ALARM_CHANNEL: usize = 0;
+/// Compare channel used for `Clock::schedule`
+const SCHEDULE_CHANNEL: usize = 1;
+/// Compare channel marking the middle of a period
+const HALF_PERIOD_CHANNEL: usize = 3;
+const HALF_PERIOD: u32 = 0x0080_0000;
+
+/// Number of half periods since the clock was started
+static PERIOD: AtomicU32 = AtomicU32::new(0);
+
+/// Wakes `timer::Timer` futures
+static ALARM: Signal<()> = Signal::new();
+
+/// # Duration
+///
+/// A span of time in RTC ticks.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Duration(u64);
+
+impl Duration {
+    pub const fn from_ticks(ticks: u64) -> Self {
+        Duration(ticks)
+    }
+
+    /// Duration from milliseconds, rounded up to whole ticks
+    pub const fn from_millis(millis: u64) -> Self {
+        Duration((millis * TICKS_PER_SECOND).div_ceil(1000))
+    }
+
+    pub const fn from_secs(secs: u64) -> Self {
+        Duration(secs * TICKS_PER_SECOND)
+    }
+
+    pub const fn as_ticks(&self) -> u64 {
+        self.0
+    }
+
+    pub const fn as_millis(&self) -> u64 {
+        self.0 * 1000 / TICKS_PER_SECOND
+    }
+
+    pub const fn as_secs(&self) -> u64 {
+        self.0 / TICKS_PER_SECOND
+    }
+}
+
+impl Add for Duration {
+    type Output = Duration;
+
+    fn add(self, other: Duration) -> Duration {
+        Duration(self.0 + other.0)
+    }
+}
+
+/// # Instant
+///
+/// A point in time, RTC ticks since the clock was started.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Instant(u64);
+
+impl Instant {
+    pub const fn from_ticks(ticks: u64) -> Self {
+        Instant(ticks)
+    }
+
+    /// The current time
+    pub fn now() -> Self {
+        now()
+    }
+
+    pub const fn as_ticks(&self) -> u64 {
+        self.0
+    }
+
+    pub const fn as_millis(&self) -> u64 {
+        self.0 * 1000 / TICKS_PER_SECOND
+    }
+
+    /// Time passed since `earlier`, zero if `earlier` is later than `self`
+    pub fn duration_since(&self, earlier: Instant) -> Duration {
+        Duration(self.0.saturating_sub(earlier.0))
+    }
+}
+
+impl Add<Duration> for Instant {
+    type Output = Instant;
+
+    fn add(self, duration: Duration) -> Instant {
+        Instant(self.0 + duration.0)
+    }
+}
+
+impl AddAssign<Duration> for Instant {
+    fn add_assign(&mut self, duration: Duration) {
+        self.0 += duration.0;
+    }
+}
+
+impl Sub for Instant {
+    type Output = Duration;
+
+    fn sub(self, earlier: Instant) -> Duration {
+        self.duration_since(earlier)
+    }
+}
+
+fn rtc() -> &'static nrf51::rtc0::RegisterBlock {
+    // Only read, or written by the `Clock` owning RTC1 and by `Rtc` setting
+    // the alarm
+    unsafe { &*RTC1::ptr() }
+}
+
+/// Combine the half period count and the 24-bit counter into 64-bit ticks
+///
+/// Even periods are in the lower half of the counter range and odd periods
+/// in the upper half. A counter that has not caught up with the period, or
+/// the other way around, is resolved by the expected most significant bit.
+pub fn extend(period: u32, counter: u32) -> u64 {
+    (u64::from(period) << 23) + u64::from((counter ^ ((period & 1) << 23)) & 0x00ff_ffff)
+}
+
+/// The current time
+///
+/// Reads zero until a `Clock` has been started.
+pub fn now() -> Instant {
+    let period = PERIOD.load(Ordering::Acquire);
+    core::sync::atomic::compiler_fence(Ordering::Acquire);
+    let counter = rtc().counter.read().bits();
+    Instant(extend(period, counter))
+}
+
+/// # Time source
+///
+/// The clock of `timer::Timer` futures, `Rtc` on the micro:bit or
+/// `sim::SimClock` on the host.
+pub trait TimeSource {
+    /// The current time
+    fn now(&self) -> Instant;
+    /// Signal `alarm` at `at`, or soon after, replacing the previous alarm
+    fn set_alarm(&self, at: Instant);
+    /// Signalled at the alarm, and whenever deadlines should be checked again
+    fn alarm(&self) -> &Signal<()>;
+}
+
+impl<S: TimeSource> TimeSource for &S {
+    fn now(&self) -> Instant {
+        (*self).now()
+    }
+
+    fn set_alarm(&self, at: Instant) {
+        (*self).set_alarm(at)
+    }
+
+    fn alarm(&self) -> &Signal<()> {
+        (*self).alarm()
+    }
+}
+
+/// # RTC1 time source
+///
+/// The time kept by the `Clock`, the alarm is compare channel
+/// `ALARM_CHANNEL`.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Rtc;
+
+impl TimeSource for Rtc {
+    fn now(&self) -> Instant {
+        now()
+    }
+
+    fn set_alarm(&self, at: Instant) {
+        // The compare register must be at least two ticks ahead
+        let earliest = now() + Duration::from_ticks(2);
+        let at = if at < earliest { earliest } else { at };
+        let compare = (at.as_ticks() & 0x00ff_ffff) as u32;
+        rtc().cc[ALARM_CHANNEL].write(|w| unsafe { w.bits(compare) });
+    }
+
+    fn alarm(&self) -> &Signal<()> {
+        &ALARM
+    }
+}
+
+/// Milliseconds since the clock was started, wraps after 49 days
+///
+/// This is the time in the MakeCode package header.
+pub fn millis() -> u32 {
+    now().as_millis() as u32
+}
+
+/// # Clock
+///
+/// Owns RTC1 and keeps the system time. Call `Clock::interrupt` from the
+/// RTC1 interrupt.
+pub struct Clock {
+    rtc: RTC1,
+    missed: bool,
+}
+
+impl Clock {
+    /// Start RTC1 at 32.768 kHz, the low frequency clock must be running
+    pub fn new(rtc: RTC1) -> Self {
+        rtc.tasks_stop.write(|w| unsafe { w.bits(1) });
+        rtc.tasks_clear.write(|w| unsafe { w.bits(1) });
+        rtc.prescaler.write(|w| unsafe { w.bits(0) });
+        PERIOD.store(0, Ordering::Release);
+        rtc.cc[HALF_PERIOD_CHANNEL].write(|w| unsafe { w.bits(HALF_PERIOD) });
+        rtc.events_ovrflw.reset();
+        rtc.events_compare[HALF_PERIOD_CHANNEL].reset();
+        rtc.events_compare[ALARM_CHANNEL].reset();
+        rtc.events_compare[SCHEDULE_CHANNEL].reset();
+        rtc.intenset.write(|w| w
+            .ovrflw().set()
+            .compare0().set()
+            .compare1().set()
+            .compare3().set()
+        );
+        rtc.tasks_start.write(|w| unsafe { w.bits(1) });
+        Clock { rtc, missed: false }
+    }
+
+    /// Schedule an interrupt at `at`, used to drive `Timers`
+    ///
+    /// Deadlines further away than half a period are reached through the
+    /// period interrupts, which also return `true` from `interrupt`.
+    pub fn schedule(&mut self, at: Option<Instant>) {
+        match at {
+            Some(at) => {
+                // The compare register must be at least two ticks ahead
+                let earliest = now() + Duration::from_ticks(2);
+                let at = if at < earliest { earliest } else { at };
+                let compare = (at.as_ticks() & 0x00ff_ffff) as u32;
+                self.rtc.cc[SCHEDULE_CHANNEL].write(|w| unsafe { w.bits(compare) });
+                self.rtc.intenset.write(|w| w.compare1().set());
+                if now() + Duration::from_ticks(2) > at {
+                    // The counter may have passed the compare value
+                    self.missed = true;
+                    NVIC::pend(Interrupt::RTC1);
+                }
+            }
+            None => {
+                self.rtc.intenclr.write(|w| w.compare1().clear());
+            }
+        }
+    }
+
+    /// Handle the RTC1 interrupt
+    ///
+    /// Returns `true` when scheduled timers may have expired.
+    pub fn interrupt(&mut self) -> bool {
+        let mut expired = self.missed;
+        self.missed = false;
+        if self.rtc.events_ovrflw.read().bits() != 0 {
+            self.rtc.events_ovrflw.reset();
+            self.next_period();
+            expired = true;
+        }
+        if self.rtc.events_compare[HALF_PERIOD_CHANNEL].read().bits() != 0 {
+            self.rtc.events_compare[HALF_PERIOD_CHANNEL].reset();
+            self.next_period();
+            expired = true;
+        }
+        if self.rtc.events_compare[SCHEDULE_CHANNEL].read().bits() != 0 {
+            self.rtc.events_compare[SCHEDULE_CHANNEL].reset();
+            expired = true;
+        }
+        if self.rtc.events_compare[ALARM_CHANNEL].read().bits() != 0 {
+            self.rtc.events_compare[ALARM_CHANNEL].reset();
+            ALARM.signal(());
+        }
+        else if expired {
+            // Let timer futures re-evaluate far away deadlines
+            ALARM.signal(());
+        }
+        expired
+    }
+
+    fn next_period(&mut self) {
+        // Only written here, no read-modify-write race
+        let period = PERIOD.load(Ordering::Relaxed);
+        PERIOD.store(period.wrapping_add(1), Ordering::Release);
+    }
+
+    /// Stop the RTC and release it
+    pub fn free(self) -> RTC1 {
+        self.rtc.intenclr.write(|w| w
+            .ovrflw().clear()
+            .compare0().clear()
+            .compare1().clear()
+            .compare3().clear()
+        );
+        self.rtc.tasks_stop.write(|w| unsafe { w.bits(1) });
+        self.rtc
+    }
+}
+
+/// Identifies a started software timer
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TimerId(usize);
+
+impl TimerId {
+    /// Index of the timer slot
+    pub fn index(&self) -> usize {
+        self.0
+    }
+}
+
+#[derive(Clone, Copy)]
+struct TimerSlot {
+    deadline: Instant,
+    period: Option<Duration>,
+}
+
+/// # Software timers
+///
+/// A fixed number of one-shot and periodic timers. `Timers` does not read
+/// the clock itself, the current time is passed in, so it can be driven by
+/// a simulated clock.
+///
+/// ```notrust
+/// fn RTC1() {
+///     if clock.interrupt() {
+///         timers.expire(time::now(), |id| { ... });
+///         clock.schedule(timers.next_deadline());
+///     }
+/// }
+/// ```
+pub struct Timers<const N: usize> {
+    slots: [Option<TimerSlot>; N],
+}
+
+impl<const N: usize> Timers<N> {
+    pub const fn new() -> Self {
+        Timers { slots: [None; N] }
+    }
+
+    fn start(&mut self, slot: TimerSlot) -> Option<TimerId> {
+        let index = self.slots.iter().position(|s| s.is_none())?;
+        self.slots[index] = Some(slot);
+        Some(TimerId(index))
+    }
+
+    /// Start a timer expiring once at `deadline`, returns `None` if all
+    /// timers are in use
+    pub fn start_at(&mut self, deadline: Instant) -> Option<TimerId> {
+        self.start(TimerSlot { deadline, period: None })
+    }
+
+    /// Start a timer expiring once, `delay` after `now`
+    pub fn start_once(&mut self, now: Instant, delay: Duration) -> Option<TimerId> {
+        self.start_at(now + delay)
+    }
+
+    /// Start a timer expiring every `period`, starting `period` after `now`
+    pub fn start_periodic(&mut self, now: Instant, period: Duration) -> Option<TimerId> {
+        if period.as_ticks() == 0 {
+            return None;
+        }
+        self.start(TimerSlot { deadline: now + period, period: Some(period) })
+    }
+
+    /// Stop a timer
+    pub fn cancel(&mut self, id: TimerId) {
+        if let Some(slot) = self.slots.get_mut(id.0) {
+            *slot = None;
+        }
+    }
+
+    /// Is the timer running
+    pub fn is_running(&self, id: TimerId) -> bool {
+        self.slots.get(id.0).is_some_and(|slot| slot.is_some())
+    }
+
+    /// The earliest deadline of all running timers
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.slots.iter().flatten().map(|slot| slot.deadline).min()
+    }
+
+    /// Take the next timer that has expired at `now`
+    ///
+    /// One-shot timers are stopped, periodic timers are restarted. A
+    /// periodic timer that has fallen behind skips the missed periods.
+    pub fn poll_expired(&mut self, now: Instant) -> Option<TimerId> {
+        let (index, slot) = self.slots.iter_mut().enumerate()
+            .filter_map(|(index, slot)| slot.as_mut().map(|slot| (index, slot)))
+            .filter(|(_, slot)| slot.deadline <= now)
+            .min_by_key(|(_, slot)| slot.deadline)?;
+        match slot.period {
+            Some(period) => {
+                let behind = (now - slot.deadline).as_ticks() / period.as_ticks();
+                slot.deadline += Duration::from_ticks(period.as_ticks() * (behind + 1));
+            }
+            None => self.slots[index] = None,
+        }
+        Some(TimerId(index))
+    }
+
+    /// Call `f` for every timer that has expired at `now`
+    pub fn expire<F: FnMut(TimerId)>(&mut self, now: Instant, mut f: F) {
+        while let Some(id) = self.poll_expired(now) {
+            f(id);
+        }
+    }
+}
+
+impl<const N: usize> Default for Timers<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn extend_resolves_period() {
+        assert_eq!(extend(0, 5), 5);
+        // The counter passed the middle before the period was counted
+        assert_eq!(extend(0, HALF_PERIOD + 1), u64::from(HALF_PERIOD) + 1);
+        assert_eq!(extend(1, HALF_PERIOD + 1), u64::from(HALF_PERIOD) + 1);
+        // The counter wrapped before the period was counted
+        assert_eq!(extend(2, 3), 2 * u64::from(HALF_PERIOD) + 3);
+        assert_eq!(extend(1, 3), 2 * u64::from(HALF_PERIOD) + 3);
+    }
+
+    #[test]
+    fn timers_expire_in_order() {
+        let start = Instant::from_ticks(0);
+        let mut timers = Timers::<3>::new();
+        let late = timers.start_once(start, Duration::from_ticks(20)).unwrap();
+        let early = timers.start_once(start, Duration::from_ticks(10)).unwrap();
+        let periodic = timers.start_periodic(start, Duration::from_ticks(15)).unwrap();
+        assert_eq!(timers.start_once(start, Duration::from_ticks(1)), None);
+        assert_eq!(timers.next_deadline(), Some(Instant::from_ticks(10)));
+        let mut expired = Vec::new();
+        timers.expire(Instant::from_ticks(20), |id| expired.push(id));
+        assert_eq!(expired, [early, periodic, late]);
+        assert!(!timers.is_running(early));
+        assert!(timers.is_running(periodic));
+        assert_eq!(timers.next_deadline(), Some(Instant::from_ticks(30)));
+    }
+
+    #[test]
+    fn periodic_timer_skips_missed_periods() {
+        let mut timers = Timers::<1>::new();
+        let id = timers.start_periodic(Instant::from_ticks(0), Duration::from_ticks(10)).unwrap();
+        assert_eq!(timers.poll_expired(Instant::from_ticks(35)), Some(id));
+        assert_eq!(timers.poll_expired(Instant::from_ticks(35)), None);
+        assert_eq!(timers.next_deadline(), Some(Instant::from_ticks(40)));
+        timers.cancel(id);
+        assert_eq!(timers.next_deadline(), None);
+        assert_eq!(timers.start_periodic(Instant::from_ticks(0), Duration::from_ticks(0)), None);
+    }
+}
@@ -0,0 +1,134 @@
+//! Timer futures
+//!
+//! `Timer` futures are driven by a `time::TimeSource`, the `time::Clock` on
+//! RTC1 by default, or `sim::SimClock` on the host.
+
+use core::future::Future;
+use core::pin::Pin;
+use core::task::{Context, Poll};
+
+use crate::time::{Duration, Instant, Rtc, TimeSource};
+
+/// # Timer
+///
+/// Future that completes at a deadline. There is one alarm for timer
+/// futures, so only one timer should be waited on at a time. Use
+/// `time::Timers` for more timers.
+pub struct Timer<S: TimeSource = Rtc> {
+    source: S,
+    deadline: Instant,
+    armed: bool,
+}
+
+impl Timer {
+    /// Complete at the given instant
+    pub fn at(deadline: Instant) -> Self {
+        Timer::new(Rtc, deadline)
+    }
+
+    /// Complete after a duration
+    pub fn after(duration: Duration) -> Self {
+        Timer::new_after(Rtc, duration)
+    }
+
+    /// Complete after the given number of RTC ticks
+    pub fn after_ticks(ticks: u64) -> Self {
+        Self::after(Duration::from_ticks(ticks))
+    }
+
+    /// Complete after the given number of milliseconds
+    pub fn after_millis(millis: u64) -> Self {
+        Self::after(Duration::from_millis(millis))
+    }
+}
+
+impl<S: TimeSource> Timer<S> {
+    /// Complete at `deadline` on the clock of `source`
+    pub fn new(source: S, deadline: Instant) -> Self {
+        Timer { source, deadline, armed: false }
+    }
+
+    /// Complete `duration` from now on the clock of `source`
+    pub fn new_after(source: S, duration: Duration) -> Self {
+        let deadline = source.now() + duration;
+        Self::new(source, deadline)
+    }
+
+    /// Set the alarm, deadlines further away are reached through the period
+    /// interrupts of the clock
+    fn arm(&mut self) {
+        self.source.set_alarm(self.deadline);
+        self.armed = true;
+    }
+}
+
+impl<S: TimeSource + Unpin> Future for Timer<S> {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        if !self.armed {
+            self.arm();
+        }
+        loop {
+            if self.source.now() >= self.deadline {
+                return Poll::Ready(());
+            }
+            if self.source.alarm().poll_take(cx).is_pending() {
+                return Poll::Pending;
+            }
+            // Woken by another timer or a period interrupt
+            self.arm();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::signal::tests::counting_waker;
+    use crate::sim::SimClock;
+
+    #[test]
+    fn completes_at_deadline() {
+        let clock = SimClock::new();
+        let (counter, waker) = counting_waker();
+        let mut cx = Context::from_waker(&waker);
+        let mut timer = Timer::new_after(&clock, Duration::from_millis(10));
+        assert!(Pin::new(&mut timer).poll(&mut cx).is_pending());
+        clock.advance(Duration::from_millis(9));
+        assert_eq!(counter.count(), 0);
+        clock.advance(Duration::from_millis(1));
+        assert_eq!(counter.count(), 1);
+        assert!(Pin::new(&mut timer).poll(&mut cx).is_ready());
+    }
+
+    #[test]
+    fn past_deadline_is_ready() {
+        let clock = SimClock::new();
+        clock.advance(Duration::from_secs(1));
+        let (counter, waker) = counting_waker();
+        let mut cx = Context::from_waker(&waker);
+        let mut timer = Timer::new(&clock, Instant::from_ticks(5));
+        assert!(Pin::new(&mut timer).poll(&mut cx).is_ready());
+        assert_eq!(counter.count(), 0);
+    }
+
+    #[test]
+    fn rearms_when_woken_early() {
+        let clock = SimClock::new();
+        let (counter, waker) = counting_waker();
+        let mut cx = Context::from_waker(&waker);
+        let mut timer = Timer::new(&clock, Instant::from_ticks(100));
+        assert!(Pin::new(&mut timer).poll(&mut cx).is_pending());
+        // Another timer took the alarm and woke this one
+        clock.set_alarm(Instant::from_ticks(10));
+        clock.advance(Duration::from_ticks(10));
+        assert_eq!(counter.count(), 1);
+        assert!(Pin::new(&mut timer).poll(&mut cx).is_pending());
+        clock.advance(Duration::from_ticks(89));
+        assert_eq!(counter.count(), 1);
+        clock.advance(Duration::from_ticks(1));
+        assert_eq!(counter.count(), 2);
+        assert!(Pin::new(&mut timer).poll(&mut cx).is_ready());
+    }
+}
@@ -0,0 +1,402 @@
+//! Radio time synchronisation
+//!
+//! One root node sends its time in beacons. Receivers timestamp beacons at
+//! the ADDRESS event and estimate the offset and the skew of their clock
+//! against the root clock by linear regression over the latest beacons.
+//! Synchronised nodes send beacons too, with the root time they estimate,
+//! so the time floods through the network as in FTSP.
+//!
+//! ```notrust
+//! let mut sync = TimeSync::new(serial_number, group, is_root);
+//! loop {
+//!     sync.poll(&mut radio);
+//!     if sync.synced_millis() % 1000 < 500 { ... }
+//!     let package = Package::integer_at(group, serial_number, sync.synced_millis(), 7);
+//! }
+//! ```
+//!
+//! ## Reference
+//!
+//! * Maróti et al., The Flooding Time Synchronization Protocol, SenSys 2004
+
+use byteorder::{ByteOrder, LittleEndian};
+
+use crate::datagram::{self, DatagramHeader, DatagramProtocol};
+use crate::link::Link;
+use crate::radio::{PackageBuffer, MAX_PACKAGE_SIZE};
+use crate::random::Random;
+use crate::time::{self, Duration, Instant};
+
+/// Size of the beacon following the datagram header
+pub const BEACON_SIZE: usize = 13;
+/// Time between beacons
+pub const BEACON_PERIOD: Duration = Duration::from_secs(1);
+/// Longest random delay added to the beacon period
+pub const BEACON_JITTER: Duration = Duration::from_millis(100);
+/// Number of beacons used for the estimate
+pub const TABLE_LENGTH: usize = 8;
+/// Number of beacons needed before a node counts as synchronised
+pub const SYNC_ENTRIES: usize = 3;
+/// The root is forgotten when no new beacon has been received for this long
+pub const ROOT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Root time as a linear function of the local time
+#[derive(Clone, Copy, Debug)]
+struct Estimate {
+    /// Mean local time of the beacons
+    local: u64,
+    /// Mean root minus local time of the beacons
+    offset: i64,
+    /// Skew as a fraction
+    numerator: i64,
+    denominator: i64,
+}
+
+impl Estimate {
+    const IDENTITY: Estimate = Estimate { local: 0, offset: 0, numerator: 0, denominator: 0 };
+
+    fn global(&self, local: Instant) -> Instant {
+        let delta = local.as_ticks() as i64 - self.local as i64;
+        let skew = if self.denominator != 0 {
+            (i128::from(self.numerator) * i128::from(delta) / i128::from(self.denominator)) as i64
+        }
+        else {
+            0
+        };
+        Instant::from_ticks((local.as_ticks() as i64 + self.offset + skew) as u64)
+    }
+}
+
+/// # Beacon
+///
+/// ```notrust
+/// | 0 ... 3         | 4 ... 7 | 8        | 9 ... 16
+/// -------------------------------------------------
+/// | datagram header | root    | sequence | root time
+/// ```
+/// Root is the serial number of the root node and the sequence number is
+/// counted by the root. The root time is in RTC ticks, at the ADDRESS event
+/// of the beacon.
+struct Beacon {
+    root: u32,
+    sequence: u8,
+    time: u64,
+}
+
+impl Beacon {
+    fn pack(&self, group: u8, buffer: &mut PackageBuffer) {
+        DatagramHeader::new(group, DatagramProtocol::TimeSync, BEACON_SIZE).pack(buffer);
+        let slice = &mut buffer[datagram::HEADER_SIZE..];
+        LittleEndian::write_u32(&mut slice[0..=3], self.root);
+        slice[4] = self.sequence;
+        LittleEndian::write_u64(&mut slice[5..=12], self.time);
+    }
+
+    fn unpack(group: u8, buffer: &PackageBuffer) -> Option<Beacon> {
+        let header = DatagramHeader::unpack(buffer);
+        if header.protocol() != DatagramProtocol::TimeSync
+            || header.version() != datagram::VERSION
+            || header.group() != group
+            || header.payload_length() != BEACON_SIZE
+        {
+            return None;
+        }
+        let slice = &buffer[datagram::HEADER_SIZE..];
+        Some(Beacon {
+            root: LittleEndian::read_u32(&slice[0..=3]),
+            sequence: slice[4],
+            time: LittleEndian::read_u64(&slice[5..=12]),
+        })
+    }
+}
+
+/// # Time synchronisation
+///
+/// Keeps the estimate of the root time on one node. The root is the node
+/// created as root, when there are several the one with the lowest serial
+/// number wins.
+pub struct TimeSync {
+    serial_number: u32,
+    group: u8,
+    is_root: bool,
+    root: Option<u32>,
+    /// Latest sequence number of the root
+    sequence: Option<u8>,
+    /// Local time and root minus local time of the latest beacons
+    table: [Option<(u64, i64)>; TABLE_LENGTH],
+    next: usize,
+    estimate: Option<Estimate>,
+    updated: Instant,
+    next_beacon: Instant,
+    random: Random,
+}
+
+impl TimeSync {
+    /// Create the time synchronisation of the device with `serial_number`
+    /// in `group`, `is_root` if this node keeps the root time
+    pub fn new(serial_number: u32, group: u8, is_root: bool) -> Self {
+        let mut sync = TimeSync {
+            serial_number,
+            group,
+            is_root,
+            root: None,
+            sequence: None,
+            table: [None; TABLE_LENGTH],
+            next: 0,
+            estimate: None,
+            updated: Instant::from_ticks(0),
+            next_beacon: Instant::from_ticks(0),
+            random: Random::new(serial_number),
+        };
+        if is_root {
+            sync.root = Some(serial_number);
+            sync.estimate = Some(Estimate::IDENTITY);
+        }
+        sync
+    }
+
+    /// Serial number of the root, if known
+    pub fn root(&self) -> Option<u32> {
+        self.root
+    }
+
+    /// Is this node the root
+    pub fn is_root(&self) -> bool {
+        self.root == Some(self.serial_number)
+    }
+
+    /// Has this node received enough beacons to know the root time
+    pub fn is_synced(&self) -> bool {
+        self.is_root() || self.table.iter().flatten().count() >= SYNC_ENTRIES
+    }
+
+    /// Root time at the local time `local`
+    pub fn global(&self, local: Instant) -> Option<Instant> {
+        if !self.is_synced() {
+            return None;
+        }
+        self.estimate.map(|estimate| estimate.global(local))
+    }
+
+    /// The root time, or the local time of `link` when not synchronised
+    pub fn now<L: Link>(&self, link: &L) -> Instant {
+        let local = link.now();
+        self.global(local).unwrap_or(local)
+    }
+
+    /// Milliseconds of `now`, wraps after 49 days
+    ///
+    /// This is the time in the MakeCode package header.
+    pub fn millis<L: Link>(&self, link: &L) -> u32 {
+        self.now(link).as_millis() as u32
+    }
+
+    /// Milliseconds of root time, or of `time::now` when not synchronised,
+    /// wraps after 49 days
+    ///
+    /// This is the time for the MakeCode package header, see
+    /// `package::Package::integer_at`. Needs the `time::Clock` on RTC1,
+    /// `millis` takes the local time from a `Link` instead.
+    pub fn synced_millis(&self) -> u32 {
+        match self.global(time::now()) {
+            Some(now) => now.as_millis() as u32,
+            None => time::millis(),
+        }
+    }
+
+    /// Receive beacons and send a beacon when it is due
+    ///
+    /// Datagrams of other protocols are dropped.
+    pub fn poll<L: Link>(&mut self, link: &mut L) {
+        while let Some((frame, timestamp)) = link.receive_timestamped() {
+            self.handle(&frame, timestamp);
+        }
+        let now = link.now();
+        if !self.is_root() && self.root.is_some() && now - self.updated > ROOT_TIMEOUT {
+            self.root = None;
+            self.sequence = None;
+        }
+        if self.is_root && self.root.is_none() {
+            self.root = Some(self.serial_number);
+            self.estimate = Some(Estimate::IDENTITY);
+            self.clear();
+        }
+        if now >= self.next_beacon {
+            self.send(link, now);
+        }
+    }
+
+    /// Handle a received datagram, `timestamp` is the local time of its
+    /// ADDRESS event
+    ///
+    /// Returns `true` if the datagram was a new beacon.
+    pub fn handle(&mut self, frame: &PackageBuffer, timestamp: Instant) -> bool {
+        let beacon = match Beacon::unpack(self.group, frame) {
+            Some(beacon) => beacon,
+            None => return false,
+        };
+        match self.root {
+            Some(root) if beacon.root > root => return false,
+            Some(root) if beacon.root == root => {
+                let newer = match self.sequence {
+                    Some(sequence) => (beacon.sequence.wrapping_sub(sequence) as i8) > 0,
+                    None => true,
+                };
+                if !newer || root == self.serial_number {
+                    return false;
+                }
+            }
+            _ => {
+                // A new root, or a root with a lower serial number
+                self.root = Some(beacon.root);
+                self.clear();
+            }
+        }
+        self.sequence = Some(beacon.sequence);
+        self.updated = timestamp;
+        let local = timestamp.as_ticks();
+        self.table[self.next] = Some((local, beacon.time as i64 - local as i64));
+        self.next = (self.next + 1) % TABLE_LENGTH;
+        self.estimate = Some(self.regression());
+        true
+    }
+
+    /// Send a beacon, if this node knows the root time
+    fn send<L: Link>(&mut self, link: &mut L, now: Instant) {
+        let jitter = self.random.jitter(BEACON_JITTER);
+        self.next_beacon = now + BEACON_PERIOD + jitter;
+        let (root, global) = match (self.root, self.global(now)) {
+            (Some(root), Some(global)) => (root, global),
+            _ => return,
+        };
+        let sequence = if self.is_root() {
+            let sequence = self.sequence.map_or(0, |sequence| sequence.wrapping_add(1));
+            self.sequence = Some(sequence);
+            sequence
+        }
+        else {
+            match self.sequence {
+                Some(sequence) => sequence,
+                None => return,
+            }
+        };
+        let mut frame = [0u8; MAX_PACKAGE_SIZE];
+        Beacon {
+            root,
+            sequence,
+            time: (global + link.address_delay()).as_ticks(),
+        }.pack(self.group, &mut frame);
+        link.transmit(&frame);
+    }
+
+    fn clear(&mut self) {
+        self.table = [None; TABLE_LENGTH];
+        self.next = 0;
+        self.sequence = None;
+        if !self.is_root() {
+            self.estimate = None;
+        }
+    }
+
+    /// Least squares fit of the offset against the local time
+    fn regression(&self) -> Estimate {
+        let entries = self.table.iter().flatten();
+        let (reference, _) = match entries.clone().next() {
+            Some(entry) => *entry,
+            None => return Estimate::IDENTITY,
+        };
+        let count = entries.clone().count() as i64;
+        let (local_sum, offset_sum) = entries.clone().fold((0i64, 0i64), |(local, offset), entry| {
+            (local + (entry.0 as i64 - reference as i64), offset + entry.1)
+        });
+        let local_mean = local_sum / count;
+        let offset_mean = offset_sum / count;
+        let (numerator, denominator) = entries.fold((0i64, 0i64), |(n, d), entry| {
+            let local = entry.0 as i64 - reference as i64 - local_mean;
+            let offset = entry.1 - offset_mean;
+            (n + local * offset, d + local * local)
+        });
+        Estimate {
+            local: (reference as i64 + local_mean) as u64,
+            offset: offset_mean,
+            numerator,
+            denominator,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sim::Simulator;
+
+    const NODES: usize = 6;
+
+    /// Root time minus the estimate of each node, in ticks
+    fn errors(sim: &mut Simulator<NODES>, syncs: &[TimeSync; NODES]) -> [Option<i64>; NODES] {
+        let root = sim.local_time(0, sim.now()).as_ticks() as i64;
+        core::array::from_fn(|index| {
+            let sync = &syncs[index];
+            sync.is_synced().then(|| sync.now(&sim.link(index)).as_ticks() as i64 - root)
+        })
+    }
+
+    #[test]
+    fn converges_over_several_hops() {
+        // A line of nodes with offset clocks running up to 80 ppm apart
+        let mut sim = Simulator::<NODES>::new(7);
+        sim.disconnect_all();
+        for index in 1..NODES {
+            sim.connect(index - 1, index, true);
+            let offset = Duration::from_secs(37 * index as u64);
+            sim.set_clock(index, offset, if index % 2 == 0 { 40 } else { -40 });
+        }
+        let mut syncs: [TimeSync; NODES] =
+            core::array::from_fn(|index| TimeSync::new(index as u32 + 1, 0, index == 0));
+        let mut worst = [0i64; NODES];
+        for step in 0..60_000 {
+            for (index, sync) in syncs.iter_mut().enumerate() {
+                sync.poll(&mut sim.link(index));
+            }
+            // Allow ten seconds per hop to fill the tables
+            if step >= 20_000 {
+                for (worst, error) in worst.iter_mut().zip(errors(&mut sim, &syncs)) {
+                    let error = error.expect("node not synchronised");
+                    *worst = (*worst).max(error.abs());
+                }
+            }
+            sim.step();
+        }
+        assert!(syncs.iter().all(|sync| sync.root() == Some(1)));
+        assert_eq!(worst[0], 0);
+        // Each hop adds up to two ticks, 61 µs, of error
+        for (index, worst) in worst.iter().enumerate() {
+            assert!(*worst <= 2 * index as i64, "node {} off by {} ticks", index, worst);
+        }
+    }
+
+    #[test]
+    fn unsynchronised_node_uses_local_time() {
+        let mut sim = Simulator::<2>::new(1);
+        sim.set_clock(1, Duration::from_secs(5), 0);
+        let sync = TimeSync::new(2, 0, false);
+        assert!(!sync.is_synced());
+        assert_eq!(sync.now(&sim.link(1)), Instant::from_ticks(Duration::from_secs(5).as_ticks()));
+    }
+
+    #[test]
+    fn lowest_serial_number_becomes_root() {
+        let mut sim = Simulator::<3>::new(3);
+        let mut syncs = [TimeSync::new(5, 0, true), TimeSync::new(2, 0, true),
+            TimeSync::new(9, 0, false)];
+        for _ in 0..5000 {
+            for (index, sync) in syncs.iter_mut().enumerate() {
+                sync.poll(&mut sim.link(index));
+            }
+            sim.step();
+        }
+        assert!(syncs.iter().all(|sync| sync.root() == Some(2)));
+        assert!(!syncs[0].is_root());
+        assert!(syncs[2].is_synced());
+    }
+}
@@ -0,0 +1,378 @@
+//! Decode micro:bit radio packages from recorded IQ samples
+//!
+//! Reads complex samples recorded with a software defined radio centred on
+//! the channel, demodulates the 1 Mbit GFSK and decodes the packages with
+//! `ubit::air::Deframer`. The samples are interleaved I and Q, either 32-bit
+//! floats (cf32, GNU Radio) or signed bytes (cs8, HackRF).
+//!
+//! The tool runs on the host, `.cargo/config.toml` builds it for the host
+//! target instead of the micro:bit.
+//!
+//! ```notrust
+//! cargo run -- --rate 4000000 capture.cs8
+//! ```
+//!
+//! Prints a line per package as the examples print them on the UART. The
+//! examples do not print integer packages, for them the line has the value,
+//! the time and the serial number.
+
+use std::collections::VecDeque;
+use std::env;
+use std::fs::File;
+use std::io::{self, BufReader, Read};
+use std::process;
+
+use ubit::air::Deframer;
+use ubit::package::{Package, PackageData};
+use ubit::radio::{PackageBuffer, RadioConfig};
+
+/// Bit rate of the radio
+const BIT_RATE: f32 = 1_000_000.0;
+/// Number of sampling phases per bit
+const PHASES: usize = 4;
+
+/// Sample formats
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Format {
+    /// Interleaved little endian 32-bit floats
+    Cf32,
+    /// Interleaved signed bytes
+    Cs8,
+}
+
+impl Format {
+    fn from_name(name: &str) -> Option<Format> {
+        match name {
+            "cf32" | "fc32" => Some(Format::Cf32),
+            "cs8" | "sc8" => Some(Format::Cs8),
+            _ => None,
+        }
+    }
+
+    /// Size of a sample, I and Q
+    fn size(self) -> usize {
+        match self {
+            Format::Cf32 => 8,
+            Format::Cs8 => 2,
+        }
+    }
+
+    fn sample(self, bytes: &[u8]) -> (f32, f32) {
+        match self {
+            Format::Cf32 => (
+                f32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]),
+                f32::from_le_bytes([bytes[4], bytes[5], bytes[6], bytes[7]]),
+            ),
+            Format::Cs8 => (f32::from(bytes[0] as i8), f32::from(bytes[1] as i8)),
+        }
+    }
+}
+
+/// # GFSK discriminator
+///
+/// The frequency of each sample is estimated from the phase change since
+/// the previous one, positive frequencies are ones. The frequencies are
+/// averaged over a bit, so the average is largest at the end of a bit.
+struct Discriminator {
+    previous: (f32, f32),
+    /// Frequencies of the latest bit, oldest first
+    frequencies: VecDeque<f32>,
+    sum: f32,
+    invert: bool,
+}
+
+impl Discriminator {
+    fn new(samples_per_bit: f64, invert: bool) -> Self {
+        let samples = samples_per_bit.round().max(1.0) as usize;
+        Discriminator {
+            previous: (0.0, 0.0),
+            frequencies: vec![0.0; samples].into(),
+            sum: 0.0,
+            invert,
+        }
+    }
+
+    /// Add the next sample, returns the average frequency, in arbitrary
+    /// units
+    fn push(&mut self, sample: (f32, f32)) -> f32 {
+        let (i, q) = sample;
+        let (pi, pq) = self.previous;
+        self.previous = sample;
+        // Imaginary part of the sample times the conjugate of the previous
+        // one, its sign is the sign of the frequency
+        let mut frequency = pi * q - pq * i;
+        if self.invert {
+            frequency = -frequency;
+        }
+        self.sum += frequency - self.frequencies.pop_front().unwrap_or(0.0);
+        self.frequencies.push_back(frequency);
+        self.sum
+    }
+}
+
+/// # Sampling phase
+///
+/// Takes a bit every bit period, at a fixed offset into the bit. A packet
+/// is short enough that the clocks of the sender and the recording do not
+/// drift apart during it, so there is no clock recovery. Instead `PHASES`
+/// phases spread over the bit each look for packets.
+struct Phase {
+    /// Sample index of the next bit
+    next: f64,
+    deframer: Deframer,
+}
+
+/// # Package decoder
+///
+/// Demodulates the samples and looks for packages in every phase. A
+/// package found by more than one phase is returned once.
+struct Decoder {
+    samples_per_bit: f64,
+    discriminator: Discriminator,
+    phases: Vec<Phase>,
+    /// The latest package, found again by the neighbouring phases
+    latest: Option<(u64, u8, PackageBuffer)>,
+    /// Index of the next sample
+    index: u64,
+}
+
+impl Decoder {
+    fn new(samples_per_bit: f64, invert: bool) -> Self {
+        Decoder {
+            samples_per_bit,
+            discriminator: Discriminator::new(samples_per_bit, invert),
+            phases: (0..PHASES)
+                .map(|phase| Phase {
+                    next: samples_per_bit * (1.0 + phase as f64 / PHASES as f64),
+                    deframer: Deframer::new(RadioConfig::makecode()),
+                })
+                .collect(),
+            latest: None,
+            index: 0,
+        }
+    }
+
+    /// Add the next sample, returns the sample index, the group and the
+    /// package when a new package has been found
+    fn push(&mut self, sample: (f32, f32)) -> Option<(u64, u8, PackageBuffer)> {
+        let index = self.index;
+        let samples_per_bit = self.samples_per_bit;
+        self.index += 1;
+        let average = self.discriminator.push(sample);
+        let mut found = None;
+        for phase in self.phases.iter_mut() {
+            if (index as f64) < phase.next {
+                continue;
+            }
+            phase.next += samples_per_bit;
+            let (group, buffer) = match phase.deframer.push(average > 0.0) {
+                Some(packet) => packet,
+                None => continue,
+            };
+            let repeated = self.latest.is_some_and(|(at, latest_group, latest_buffer)|
+                index - at < 2 * samples_per_bit as u64
+                    && latest_group == group && latest_buffer == buffer);
+            self.latest = Some((index, group, buffer));
+            if !repeated {
+                found = Some((index, group, buffer));
+            }
+        }
+        found
+    }
+}
+
+struct Options {
+    path: String,
+    format: Format,
+    sample_rate: f32,
+    group: Option<u8>,
+    invert: bool,
+}
+
+fn usage() -> ! {
+    eprintln!("usage: iqdecode --rate <samples per second> [--format cf32|cs8] \
+        [--group <group>] [--invert] <file>");
+    eprintln!("the format defaults to the file extension, the group to all groups");
+    process::exit(2);
+}
+
+fn parse_options() -> Options {
+    let mut path = None;
+    let mut format = None;
+    let mut sample_rate = None;
+    let mut group = None;
+    let mut invert = false;
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--rate" => sample_rate = args.next().and_then(|rate| rate.parse::<f32>().ok()),
+            "--format" => format = Some(args.next().and_then(|name| Format::from_name(&name))
+                .unwrap_or_else(|| usage())),
+            "--group" => group = Some(args.next().and_then(|group| group.parse::<u8>().ok())
+                .unwrap_or_else(|| usage())),
+            "--invert" => invert = true,
+            _ if arg.starts_with("--") => usage(),
+            _ => path = Some(arg),
+        }
+    }
+    let path = path.unwrap_or_else(|| usage());
+    let format = format
+        .or_else(|| path.rsplit('.').next().and_then(Format::from_name))
+        .unwrap_or_else(|| usage());
+    let sample_rate = match sample_rate {
+        Some(rate) if rate >= 2.0 * BIT_RATE => rate,
+        _ => usage(),
+    };
+    Options { path, format, sample_rate, group, invert }
+}
+
+/// Print `package` as the examples do
+fn print_package(buffer: &PackageBuffer) {
+    let package = Package::unpack(&buffer[..]);
+    let header = &package.header;
+    match package.data {
+        PackageData::Integer(value) => print!("Integer {} time {} serial {:08x}\n\r",
+            value, header.time(), header.serial_number()),
+        PackageData::IntegerValue(value) => print!("IntegerValue {} time {} serial {:08x}\n\r",
+            value, header.time(), header.serial_number()),
+        PackageData::Other => print!("Other Package\n\r"),
+        PackageData::Unknown => print!("Unknown Package\n\r"),
+    }
+}
+
+fn main() -> io::Result<()> {
+    let options = parse_options();
+    let samples_per_bit = f64::from(options.sample_rate / BIT_RATE);
+    let mut reader = BufReader::new(File::open(&options.path)?);
+    let mut decoder = Decoder::new(samples_per_bit, options.invert);
+    let mut chunk = vec![0u8; options.format.size() * 4096];
+    let mut packages = 0u32;
+    loop {
+        let length = read_full(&mut reader, &mut chunk)?;
+        for bytes in chunk[..length].chunks_exact(options.format.size()) {
+            if let Some((_, group, buffer)) = decoder.push(options.format.sample(bytes)) {
+                if options.group.is_none_or(|wanted| wanted == group) {
+                    print_package(&buffer);
+                    packages += 1;
+                }
+            }
+        }
+        if length < chunk.len() {
+            break;
+        }
+    }
+    eprintln!("{} packages", packages);
+    Ok(())
+}
+
+/// Fill `buffer` unless the end of the file is reached, returns the number
+/// of bytes read
+fn read_full<R: Read>(reader: &mut R, buffer: &mut [u8]) -> io::Result<usize> {
+    let mut length = 0;
+    while length < buffer.len() {
+        match reader.read(&mut buffer[length..])? {
+            0 => break,
+            count => length += count,
+        }
+    }
+    Ok(length)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::convert::TryFrom;
+    use std::f32::consts::PI;
+
+    use ubit::air::{self, ON_AIR_SIZE};
+
+    /// A MakeCode integer package
+    const FRAME: [u8; 17] = [16, 1, 0, 1, 0, 0xe8, 0x03, 0, 0, 0x78, 0x56, 0x34, 0x12,
+        42, 0, 0, 0];
+    const GROUP: u8 = 5;
+
+    /// The bits of `bytes`, the first bit sent lowest
+    fn bits(bytes: &[u8]) -> Vec<bool> {
+        bytes.iter().flat_map(|byte| (0..8).map(move |bit| byte & (1 << bit) != 0)).collect()
+    }
+
+    /// Modulate `bits` with GFSK, a modulation index of 0.5 and a Gaussian
+    /// filter with BT 0.5, as the radio sends at 1 Mbit
+    fn modulate(bits: &[bool], samples_per_bit: usize, invert: bool) -> Vec<(f32, f32)> {
+        let frequencies: Vec<f32> = bits.iter()
+            .flat_map(|bit| vec![if *bit { 1.0 } else { -1.0 }; samples_per_bit])
+            .collect();
+        // Standard deviation of the filter in bits
+        let sigma = 2f32.ln().sqrt() / (2.0 * PI * 0.5) * samples_per_bit as f32;
+        let span = (3.0 * sigma).ceil() as isize;
+        let kernel: Vec<f32> = (-span..=span)
+            .map(|offset| (-(offset as f32).powi(2) / (2.0 * sigma * sigma)).exp())
+            .collect();
+        let total: f32 = kernel.iter().sum();
+        let mut phase = 0.0f32;
+        (0..frequencies.len() as isize)
+            .map(|index| {
+                let frequency: f32 = kernel.iter().zip(-span..)
+                    .filter_map(|(weight, offset)| {
+                        let index = usize::try_from(index + offset).ok()?;
+                        Some(weight * frequencies.get(index)?)
+                    })
+                    .sum::<f32>() / total;
+                // A quarter turn per bit
+                phase += PI / 2.0 * frequency / samples_per_bit as f32;
+                let sample = (100.0 * phase.cos(), 100.0 * phase.sin());
+                if invert { (sample.0, -sample.1) } else { sample }
+            })
+            .collect()
+    }
+
+    /// The on air bits of `FRAME` in `GROUP` between some noise
+    fn stream(packets: usize) -> Vec<bool> {
+        let config = RadioConfig::makecode().prefix(GROUP);
+        let mut packet = [0u8; ON_AIR_SIZE];
+        let size = air::encode(&config, &FRAME, &mut packet);
+        assert_ne!(size, 0);
+        let mut stream = bits(&[0x3c, 0xa5, 0x0f]);
+        for _ in 0..packets {
+            stream.extend(bits(&packet[..size]));
+            stream.extend(bits(&[0x96, 0x00, 0xe1]));
+        }
+        stream
+    }
+
+    fn decode(samples: &[(f32, f32)], samples_per_bit: usize, invert: bool)
+        -> Vec<(u64, u8, PackageBuffer)>
+    {
+        let mut decoder = Decoder::new(samples_per_bit as f64, invert);
+        samples.iter().filter_map(|sample| decoder.push(*sample)).collect()
+    }
+
+    #[test]
+    fn finds_each_package_once() {
+        for samples_per_bit in [2, 4] {
+            for invert in [false, true] {
+                let samples = modulate(&stream(2), samples_per_bit, invert);
+                // Start the recording anywhere in a bit
+                for skip in 0..samples_per_bit {
+                    let found = decode(&samples[skip..], samples_per_bit, invert);
+                    assert_eq!(found.len(), 2,
+                        "{} samples per bit, invert {}, skip {}", samples_per_bit, invert, skip);
+                    for (_, group, buffer) in found {
+                        assert_eq!(group, GROUP);
+                        assert_eq!(buffer[..FRAME.len()], FRAME);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn inverted_spectrum_needs_invert() {
+        for samples_per_bit in [2, 4] {
+            let samples = modulate(&stream(1), samples_per_bit, true);
+            assert!(decode(&samples, samples_per_bit, false).is_empty());
+            let samples = modulate(&stream(1), samples_per_bit, false);
+            assert!(decode(&samples, samples_per_bit, true).is_empty());
+        }
+    }
+}